@@ -0,0 +1,19 @@
+/**
+ * Signature nounces of the signed chatrouille queries already processed,
+ * kept until the queries expire to detect replays.
+ *
+ * The nounce is signed with the query, a replay has the same nounce.
+ * Two identical queries signed separately have different nounces, they are both accepted.
+ */
+CREATE TABLE seen_query_signatures (
+  nounce TEXT
+    PRIMARY KEY
+    NOT NULL
+    CONSTRAINT valid_nounce
+      CHECK (nounce ~ '^[a-zA-Z0-9+/]{43}$'),
+  expiration_time BIGINT
+    NOT NULL
+);
+
+CREATE INDEX seen_query_signatures_expiration_time
+  ON seen_query_signatures (expiration_time);
@@ -0,0 +1,32 @@
+/**
+ * Receipts of the signed chatrouille queries that changed the data of a citizen,
+ * kept as proofs that the citizen requested the changes.
+ *
+ * The receipt only contains hashes, the payload of the query
+ * is kept with it to verify the signature later.
+ * A signed query can contain several mutations, its receipt is kept once per mutation.
+ */
+CREATE TABLE query_receipts (
+  nounce TEXT
+    NOT NULL
+    CONSTRAINT valid_nounce
+      CHECK (nounce ~ '^[a-zA-Z0-9+/]{43}$'),
+  citizen_identifier TEXT
+    NOT NULL
+    REFERENCES citizens(identifier)
+    ON DELETE CASCADE,
+  mutation TEXT
+    NOT NULL,
+  payload TEXT
+    NOT NULL,
+  receipt TEXT
+    NOT NULL
+    CONSTRAINT valid_receipt
+      CHECK (receipt ~ '^([a-zA-Z0-9+/]{214}|[a-zA-Z0-9+/]{299})$'),
+  creation_time BIGINT
+    NOT NULL,
+  PRIMARY KEY (nounce, mutation)
+);
+
+CREATE INDEX query_receipts_citizen_identifier
+  ON query_receipts (citizen_identifier);
@@ -0,0 +1,2 @@
+DROP TABLE citizen_personal_data_history;
+ALTER TABLE citizens DROP COLUMN aead_data_version;
@@ -0,0 +1,30 @@
+/**
+ * The personal data of the citizens is versioned,
+ * an update based on an older version than the current one is refused.
+ */
+ALTER TABLE citizens
+  ADD COLUMN aead_data_version BIGINT
+    NOT NULL
+    DEFAULT 1
+    CONSTRAINT valid_aead_data_version
+      CHECK (aead_data_version > 0);
+
+/**
+ * The last replaced versions of the personal data of the citizens,
+ * so a citizen can roll back a bad edit.
+ */
+CREATE TABLE citizen_personal_data_history (
+  citizen_identifier TEXT
+    NOT NULL
+    REFERENCES citizens(identifier)
+    ON DELETE CASCADE,
+  aead_data_version BIGINT
+    NOT NULL,
+  aead_data TEXT
+    NOT NULL
+    CONSTRAINT valid_aead_data
+      CHECK (aead_data ~ '^[a-zA-Z0-9+/]{55,}$'),
+  replacement_time BIGINT
+    NOT NULL,
+  PRIMARY KEY (citizen_identifier, aead_data_version)
+);
@@ -12,6 +12,13 @@ pub fn new_keys() {
   println!("x448_private_key: {}", to_base64_string(x448_private_key.as_bytes()));
   println!("x448_public_key: {}", to_base64_string(x448_public_key.as_bytes()));
 
+  let mlkem768_private_key = key_utils::gen_mlkem_private_key();
+  println!("mlkem768_private_key: {}", key_utils::mlkem_private_key_to_base64(&mlkem768_private_key));
+  println!(
+    "mlkem768_public_key: {}",
+    key_utils::mlkem_public_key_to_base64(mlkem768_private_key.encapsulation_key())
+  );
+
   let ed25519_keypair = key_utils::gen_ed25519_keypair();
   println!("ed25519_private_key: {}", to_base64_string(ed25519_keypair.secret.as_bytes()));
   println!("ed25519_public_key: {}", to_base64_string(ed25519_keypair.public.as_bytes()));
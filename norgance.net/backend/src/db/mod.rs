@@ -97,6 +97,168 @@ pub fn load_citizen_personal_data(
   Ok(result)
 }
 
+/// Number of replaced versions of the personal data kept for each citizen.
+pub const PERSONAL_DATA_HISTORY_LENGTH: i64 = 7;
+
+/// Outcome of `update_citizen_personal_data`.
+pub enum PersonalDataUpdate {
+  /// With the new version.
+  Updated(i64),
+  /// The personal data changed since the expected version, with the current version.
+  Stale(i64),
+  CitizenNotFound,
+}
+
+pub fn load_versioned_citizen_personal_data(
+  db: &DbPooledConnection,
+  input_identifier: &str,
+) -> Result<Option<models::CitizenPersonalData>> {
+  use diesel::prelude::*;
+  use schema::citizens::dsl::*;
+
+  let result = citizens
+    .filter(identifier.eq(input_identifier))
+    .select((aead_data, aead_data_version))
+    .limit(1)
+    .load::<models::CitizenPersonalData>(db)
+    .context(QueryError)?
+    .pop();
+
+  Ok(result)
+}
+
+/// The replaced versions of the personal data, the most recent first.
+pub fn load_citizen_personal_data_history(
+  db: &DbPooledConnection,
+  input_identifier: &str,
+) -> Result<Vec<models::CitizenPersonalData>> {
+  use diesel::prelude::*;
+  use schema::citizen_personal_data_history::dsl::*;
+
+  let result = citizen_personal_data_history
+    .filter(citizen_identifier.eq(input_identifier))
+    .select((aead_data, aead_data_version))
+    .order(aead_data_version.desc())
+    .limit(PERSONAL_DATA_HISTORY_LENGTH)
+    .load::<models::CitizenPersonalData>(db)
+    .context(QueryError)?;
+
+  Ok(result)
+}
+
+/// Replaces the personal data of the citizen if it's still at the expected version,
+/// and moves the replaced data to the history.
+///
+/// It must run in a transaction, the citizen row stays locked until its end.
+pub fn update_citizen_personal_data(
+  db: &DbPooledConnection,
+  input_identifier: &str,
+  expected_version: i64,
+  new_aead_data: &str,
+) -> Result<PersonalDataUpdate> {
+  use diesel::prelude::*;
+  use schema::citizen_personal_data_history::dsl as history;
+  use schema::citizens::dsl::*;
+  use std::convert::TryFrom;
+
+  let current = citizens
+    .filter(identifier.eq(input_identifier))
+    .select((aead_data, aead_data_version))
+    .for_update()
+    .first::<models::CitizenPersonalData>(db)
+    .optional()
+    .context(QueryError)?;
+  let current = match current {
+    Some(current) => current,
+    None => return Ok(PersonalDataUpdate::CitizenNotFound),
+  };
+  if current.aead_data_version != expected_version {
+    return Ok(PersonalDataUpdate::Stale(current.aead_data_version));
+  }
+
+  let new_version = current.aead_data_version + 1;
+  diesel::update(citizens.filter(identifier.eq(input_identifier)))
+    .set((aead_data.eq(new_aead_data), aead_data_version.eq(new_version)))
+    .execute(db)
+    .context(QueryError)?;
+
+  let server_timestamp =
+    match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+      Ok(t) => i64::try_from(t.as_secs()).unwrap_or(i64::MAX),
+      Err(_) => 0,
+    };
+  diesel::insert_into(history::citizen_personal_data_history)
+    .values(&models::NewCitizenPersonalDataHistory {
+      citizen_identifier: input_identifier,
+      aead_data_version: current.aead_data_version,
+      aead_data: &current.aead_data,
+      replacement_time: server_timestamp,
+    })
+    .execute(db)
+    .context(QueryError)?;
+
+  // Only the last versions are kept
+  diesel::delete(
+    history::citizen_personal_data_history
+      .filter(history::citizen_identifier.eq(input_identifier))
+      .filter(history::aead_data_version.le(new_version - 1 - PERSONAL_DATA_HISTORY_LENGTH)),
+  )
+  .execute(db)
+  .context(QueryError)?;
+
+  Ok(PersonalDataUpdate::Updated(new_version))
+}
+
+/// Replaces the access key and the personal data of the citizen, sealed with
+/// the keys derived from the new password, if the personal data is still at the expected version.
+///
+/// The history was sealed with the keys derived from the old password, it is deleted.
+/// It must run in a transaction, the citizen row stays locked until its end.
+pub fn change_citizen_password(
+  db: &DbPooledConnection,
+  input_identifier: &str,
+  expected_version: i64,
+  new_access_key: &str,
+  new_aead_data: &str,
+) -> Result<PersonalDataUpdate> {
+  use diesel::prelude::*;
+  use schema::citizen_personal_data_history::dsl as history;
+  use schema::citizens::dsl::*;
+
+  let current_version = citizens
+    .filter(identifier.eq(input_identifier))
+    .select(aead_data_version)
+    .for_update()
+    .first::<i64>(db)
+    .optional()
+    .context(QueryError)?;
+  let current_version = match current_version {
+    Some(current_version) => current_version,
+    None => return Ok(PersonalDataUpdate::CitizenNotFound),
+  };
+  if current_version != expected_version {
+    return Ok(PersonalDataUpdate::Stale(current_version));
+  }
+
+  let new_version = current_version + 1;
+  diesel::update(citizens.filter(identifier.eq(input_identifier)))
+    .set((
+      access_key.eq(new_access_key),
+      aead_data.eq(new_aead_data),
+      aead_data_version.eq(new_version),
+    ))
+    .execute(db)
+    .context(QueryError)?;
+
+  diesel::delete(
+    history::citizen_personal_data_history.filter(history::citizen_identifier.eq(input_identifier)),
+  )
+  .execute(db)
+  .context(QueryError)?;
+
+  Ok(PersonalDataUpdate::Updated(new_version))
+}
+
 pub fn load_citizen_public_keys(
   db: &DbPooledConnection,
   input_identifier: &str,
@@ -140,6 +302,90 @@ pub fn load_citizen_access_key(
   Ok(Some(public_key))
 }
 
+/// Records the signature nounce of a chatrouille query until it expires.
+///
+/// Returns false if the signature nounce was already seen, meaning the query is a replay.
+/// Identical queries signed separately have different nounces, they are not replays.
+pub fn record_query_signature(
+  db: &DbPooledConnection,
+  input_nounce: &[u8],
+  input_expiration_time: u64,
+) -> Result<bool> {
+  use diesel::prelude::*;
+  use schema::seen_query_signatures::dsl::*;
+  use std::convert::TryFrom;
+
+  let input_expiration_time = i64::try_from(input_expiration_time).unwrap_or(i64::MAX);
+  let nounce_base64 = base64::encode_config(input_nounce, base64::STANDARD_NO_PAD);
+
+  let inserted = diesel::insert_into(seen_query_signatures)
+    .values(&models::NewSeenQuerySignature {
+      nounce: &nounce_base64,
+      expiration_time: input_expiration_time,
+    })
+    .on_conflict_do_nothing()
+    .execute(db)
+    .context(QueryError)?;
+
+  Ok(inserted == 1)
+}
+
+/// Deletes the signatures of the expired queries, they are rejected anyway.
+///
+/// Called periodically rather than on every query.
+pub fn purge_expired_query_signatures(db_pool: &DbPool) -> Result<usize> {
+  use diesel::prelude::*;
+  use schema::seen_query_signatures::dsl::*;
+  use std::convert::TryFrom;
+
+  let db = db_pool.get().context(DatabasePoolCreation)?;
+  let server_timestamp =
+    match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+      Ok(t) => i64::try_from(t.as_secs()).unwrap_or(i64::MAX),
+      Err(_) => 0,
+    };
+
+  diesel::delete(seen_query_signatures.filter(expiration_time.lt(server_timestamp)))
+    .execute(&db)
+    .context(QueryError)
+}
+
+/// Keeps the receipt of a signed query, as a proof that the citizen requested the mutation.
+/// The same mutation twice in a query, with aliases, is recorded once.
+pub fn record_query_receipt(
+  db: &DbPooledConnection,
+  input_citizen_identifier: &str,
+  input_mutation: &str,
+  input_payload: &str,
+  input_receipt: &chatrouille::QueryReceipt,
+) -> Result<()> {
+  use diesel::prelude::*;
+  use schema::query_receipts::dsl::*;
+  use std::convert::TryFrom;
+
+  let server_timestamp =
+    match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+      Ok(t) => i64::try_from(t.as_secs()).unwrap_or(i64::MAX),
+      Err(_) => 0,
+    };
+  let nounce_base64 = base64::encode_config(input_receipt.nounce(), base64::STANDARD_NO_PAD);
+
+  diesel::insert_into(query_receipts)
+    .values(&models::NewQueryReceipt {
+      nounce: &nounce_base64,
+      citizen_identifier: input_citizen_identifier,
+      mutation: input_mutation,
+      payload: input_payload,
+      receipt: &input_receipt.to_base64(),
+      creation_time: server_timestamp,
+    })
+    .on_conflict_do_nothing()
+    .execute(db)
+    .context(QueryError)?;
+
+  Ok(())
+}
+
 pub fn health_check(db: &DbPooledConnection) -> Result<()> {
   use diesel::prelude::*;
 
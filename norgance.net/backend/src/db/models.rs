@@ -7,6 +7,13 @@ pub struct Citizen {
     pub public_x25519_dalek: String,
     pub public_ed25519_dalek: String,
     pub aead_data: String,
+    pub aead_data_version: i64,
+}
+
+#[derive(diesel::Queryable)]
+pub struct CitizenPersonalData {
+    pub aead_data: String,
+    pub aead_data_version: i64,
 }
 
 #[derive(diesel::Queryable)]
@@ -59,3 +66,36 @@ pub struct NewSharedDocument<'a> {
     pub aead_data: &'a str,
     pub data_ed25519_dalek_signature: &'a str,
 }
+
+use super::schema::seen_query_signatures;
+
+#[derive(Insertable)]
+#[table_name="seen_query_signatures"]
+pub struct NewSeenQuerySignature<'a> {
+    pub nounce: &'a str,
+    pub expiration_time: i64,
+}
+
+use super::schema::query_receipts;
+
+#[derive(Insertable)]
+#[table_name="query_receipts"]
+pub struct NewQueryReceipt<'a> {
+    pub nounce: &'a str,
+    pub citizen_identifier: &'a str,
+    pub mutation: &'a str,
+    pub payload: &'a str,
+    pub receipt: &'a str,
+    pub creation_time: i64,
+}
+
+use super::schema::citizen_personal_data_history;
+
+#[derive(Insertable)]
+#[table_name="citizen_personal_data_history"]
+pub struct NewCitizenPersonalDataHistory<'a> {
+    pub citizen_identifier: &'a str,
+    pub aead_data_version: i64,
+    pub aead_data: &'a str,
+    pub replacement_time: i64,
+}
@@ -5,6 +5,16 @@ table! {
         public_x25519_dalek -> Text,
         public_ed25519_dalek -> Text,
         aead_data -> Text,
+        aead_data_version -> Int8,
+    }
+}
+
+table! {
+    citizen_personal_data_history (citizen_identifier, aead_data_version) {
+        citizen_identifier -> Text,
+        aead_data_version -> Int8,
+        aead_data -> Text,
+        replacement_time -> Int8,
     }
 }
 
@@ -16,6 +26,24 @@ table! {
     }
 }
 
+table! {
+    query_receipts (nounce, mutation) {
+        nounce -> Text,
+        citizen_identifier -> Text,
+        mutation -> Text,
+        payload -> Text,
+        receipt -> Text,
+        creation_time -> Int8,
+    }
+}
+
+table! {
+    seen_query_signatures (nounce) {
+        nounce -> Text,
+        expiration_time -> Int8,
+    }
+}
+
 table! {
     shared_documents (identifier) {
         identifier -> Text,
@@ -24,10 +52,15 @@ table! {
     }
 }
 
+joinable!(citizen_personal_data_history -> citizens (citizen_identifier));
 joinable!(identity_documents -> citizens (citizen_identifier));
+joinable!(query_receipts -> citizens (citizen_identifier));
 
 allow_tables_to_appear_in_same_query!(
+    citizen_personal_data_history,
     citizens,
     identity_documents,
+    query_receipts,
+    seen_query_signatures,
     shared_documents,
 );
@@ -0,0 +1,242 @@
+use chatrouille::{key_utils, mlkem, x448};
+
+/// A server x448 private key with its validity period, as stored in the vault.
+///
+/// The optional ML-KEM key enables the hybrid owl version for this key.
+/// Both private keys are wiped when they are dropped.
+pub struct KeyringEntry {
+    pub private_key: key_utils::ZeroizingSecret,
+    pub mlkem_private_key: Option<mlkem::DecapsulationKey>,
+    pub activation_time: u64,
+    pub retirement_time: Option<u64>,
+}
+
+pub struct ServerKey {
+    pub key_id: key_utils::KeyId,
+    pub private_key: key_utils::ZeroizingSecret,
+    pub public_key_base64: String,
+    pub public_key_signature_base64: String,
+    pub mlkem_private_key: Option<mlkem::DecapsulationKey>,
+    pub mlkem_public_key_base64: Option<String>,
+    pub mlkem_public_key_signature_base64: Option<String>,
+    pub activation_time: u64,
+    pub retirement_time: Option<u64>,
+}
+
+impl ServerKey {
+    fn new(entry: KeyringEntry, keypair: &ed25519_dalek::Keypair) -> ServerKey {
+        use ed25519_dalek::Signer;
+
+        let public_key = x448::PublicKey::from(&*entry.private_key);
+        let signature = keypair.sign(public_key.as_bytes());
+
+        // The ML-KEM public key is signed together with the x448 public key,
+        // so a client can't be given the ML-KEM key of another server key.
+        let mlkem_public_key = entry
+            .mlkem_private_key
+            .as_ref()
+            .map(mlkem::DecapsulationKey::encapsulation_key);
+        let mlkem_public_key_signature = mlkem_public_key.map(|mlkem_public_key| {
+            keypair.sign(&[public_key.as_bytes(), mlkem_public_key.as_bytes()].concat())
+        });
+
+        ServerKey {
+            key_id: key_utils::key_id(&public_key),
+            private_key: entry.private_key,
+            public_key_base64: key_utils::public_key_to_base64(&public_key),
+            public_key_signature_base64: base64::encode_config(
+                signature.to_bytes(),
+                base64::STANDARD_NO_PAD,
+            ),
+            mlkem_public_key_base64: mlkem_public_key.map(key_utils::mlkem_public_key_to_base64),
+            mlkem_public_key_signature_base64: mlkem_public_key_signature
+                .map(|signature| base64::encode_config(signature.to_bytes(), base64::STANDARD_NO_PAD)),
+            mlkem_private_key: entry.mlkem_private_key,
+            activation_time: entry.activation_time,
+            retirement_time: entry.retirement_time,
+        }
+    }
+
+    #[must_use]
+    pub fn is_valid_at(&self, timestamp: u64) -> bool {
+        self.activation_time <= timestamp
+            && match self.retirement_time {
+                Some(retirement_time) => timestamp < retirement_time,
+                None => true,
+            }
+    }
+}
+
+/// The server chatrouille keys.
+///
+/// During a rotation, the new key is activated before the old one is retired,
+/// so clients that cached the old key can still talk to the server.
+///
+/// The ed25519 keypair signs the x448 public keys and the responses.
+pub struct Keyring {
+    keys: Vec<ServerKey>,
+    signing_keypair: ed25519_dalek::Keypair,
+}
+
+fn now() -> u64 {
+    match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+        Ok(t) => t.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+impl Keyring {
+    /// The public keys are signed with the server ed25519 keypair.
+    pub fn new(entries: Vec<KeyringEntry>, signing_keypair: ed25519_dalek::Keypair) -> Keyring {
+        let mut keys: Vec<ServerKey> = entries
+            .into_iter()
+            .map(|entry| ServerKey::new(entry, &signing_keypair))
+            .collect();
+
+        // Most recent keys first
+        keys.sort_by_key(|key| std::cmp::Reverse(key.activation_time));
+
+        Keyring {
+            keys,
+            signing_keypair,
+        }
+    }
+
+    #[must_use]
+    pub fn signing_keypair(&self) -> &ed25519_dalek::Keypair {
+        &self.signing_keypair
+    }
+
+    pub fn valid_keys_at(&self, timestamp: u64) -> impl Iterator<Item = &ServerKey> {
+        self.keys
+            .iter()
+            .filter(move |key| key.is_valid_at(timestamp))
+    }
+
+    /// The key the clients should use, the most recently activated one.
+    #[must_use]
+    pub fn current_key_at(&self, timestamp: u64) -> Option<&ServerKey> {
+        self.valid_keys_at(timestamp).next()
+    }
+
+    #[must_use]
+    pub fn find_at(&self, key_id: key_utils::KeyId, timestamp: u64) -> Option<&ServerKey> {
+        self.valid_keys_at(timestamp)
+            .find(|key| key.key_id == key_id)
+    }
+}
+
+impl chatrouille::PrivateKeyring for Keyring {
+    fn private_key(&self, key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+        self.find_at(*key_id, now()).map(|key| &*key.private_key)
+    }
+
+    /// The legacy clients have a single hardcoded key, it's one of the valid keys.
+    fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+        self.valid_keys_at(now())
+            .map(|key| &*key.private_key)
+            .collect()
+    }
+
+    fn mlkem_private_key(&self, key_id: &key_utils::KeyId) -> Option<&mlkem::DecapsulationKey> {
+        self.find_at(*key_id, now())
+            .and_then(|key| key.mlkem_private_key.as_ref())
+    }
+}
+
+#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let keypair = key_utils::gen_ed25519_keypair();
+        let old_private_key = key_utils::gen_private_key();
+        let old_key_id = key_utils::key_id(&key_utils::gen_public_key(&old_private_key));
+        let new_private_key = key_utils::gen_private_key();
+        let new_key_id = key_utils::key_id(&key_utils::gen_public_key(&new_private_key));
+
+        let keyring = Keyring::new(
+            vec![
+                KeyringEntry {
+                    private_key: old_private_key.into(),
+                    mlkem_private_key: None,
+                    activation_time: 100,
+                    retirement_time: Some(300),
+                },
+                KeyringEntry {
+                    private_key: new_private_key.into(),
+                    mlkem_private_key: None,
+                    activation_time: 200,
+                    retirement_time: None,
+                },
+            ],
+            keypair,
+        );
+
+        // Not active yet
+        assert!(keyring.current_key_at(50).is_none());
+
+        // Only the old key
+        assert_eq!(keyring.current_key_at(150).unwrap().key_id, old_key_id);
+        assert!(keyring.find_at(new_key_id, 150).is_none());
+
+        // Overlap window
+        assert_eq!(keyring.valid_keys_at(250).count(), 2);
+        assert_eq!(keyring.current_key_at(250).unwrap().key_id, new_key_id);
+        assert!(keyring.find_at(old_key_id, 250).is_some());
+
+        // The old key is retired
+        assert_eq!(keyring.valid_keys_at(300).count(), 1);
+        assert!(keyring.find_at(old_key_id, 300).is_none());
+        assert!(keyring.find_at(new_key_id, 300).is_some());
+    }
+
+    #[test]
+    fn test_signatures() {
+        use ed25519_dalek::Verifier;
+        use std::convert::TryFrom;
+
+        let keypair = key_utils::gen_ed25519_keypair();
+        let private_key = key_utils::gen_private_key();
+        let public_key = key_utils::gen_public_key(&private_key);
+
+        let keyring = Keyring::new(
+            vec![KeyringEntry {
+                private_key: private_key.into(),
+                mlkem_private_key: Some(key_utils::gen_mlkem_private_key()),
+                activation_time: 0,
+                retirement_time: None,
+            }],
+            keypair,
+        );
+        let key = keyring.current_key_at(now()).unwrap();
+
+        let signature_bytes = base64::decode(&key.public_key_signature_base64).unwrap();
+        let signature = ed25519_dalek::Signature::try_from(&signature_bytes[..]).unwrap();
+        assert!(keyring
+            .signing_keypair()
+            .public
+            .verify(public_key.as_bytes(), &signature)
+            .is_ok());
+
+        let mlkem_public_key_base64 = key.mlkem_public_key_base64.as_ref().unwrap();
+        let mlkem_public_key =
+            key_utils::mlkem_public_key_from_base64(mlkem_public_key_base64).unwrap();
+        let signature_bytes =
+            base64::decode(key.mlkem_public_key_signature_base64.as_ref().unwrap()).unwrap();
+        let signature = ed25519_dalek::Signature::try_from(&signature_bytes[..]).unwrap();
+        let signed_data = [public_key.as_bytes(), mlkem_public_key.as_bytes()].concat();
+        assert!(keyring
+            .signing_keypair()
+            .public
+            .verify(&signed_data, &signature)
+            .is_ok());
+        assert!(keyring
+            .signing_keypair()
+            .public
+            .verify(mlkem_public_key.as_bytes(), &signature)
+            .is_err());
+    }
+}
@@ -20,6 +20,7 @@
 
 mod commandline;
 mod db;
+mod keyring;
 mod server;
 mod validation;
 mod vault;
@@ -74,6 +75,44 @@ async fn main() {
         .await
         .expect("Unable to load server secrets");
 
+    let chatrouille_settings = server::ChatrouilleSettings {
+        // Chunked chatrouille queries can be larger than regular ones
+        max_body_size: env::var("CHATROUILLE_MAX_BODY_SIZE")
+            .unwrap_or_else(|_| String::from("1048576"))
+            .parse::<usize>()
+            .expect("Invalid CHATROUILLE_MAX_BODY_SIZE"),
+        // Regular queries are small, but they can be compressed
+        max_payload_size: env::var("CHATROUILLE_MAX_PAYLOAD_SIZE")
+            .unwrap_or_else(|_| String::from("65536"))
+            .parse::<usize>()
+            .expect("Invalid CHATROUILLE_MAX_PAYLOAD_SIZE"),
+        max_lifetime: env::var("CHATROUILLE_MAX_LIFETIME")
+            .unwrap_or_else(|_| String::from("300"))
+            .parse::<u64>()
+            .expect("Invalid CHATROUILLE_MAX_LIFETIME"),
+        clock_skew: env::var("CHATROUILLE_CLOCK_SKEW")
+            .unwrap_or_else(|_| String::from("30"))
+            .parse::<u64>()
+            .expect("Invalid CHATROUILLE_CLOCK_SKEW"),
+        accept_legacy_version: env::var("CHATROUILLE_ACCEPT_LEGACY_VERSION")
+            .unwrap_or_else(|_| String::from("true"))
+            .parse::<lenient_bool::LenientBool>()
+            .unwrap_or_default()
+            .into(),
+        // The citizens authenticate once per session
+        max_session_lifetime: env::var("CHATROUILLE_MAX_SESSION_LIFETIME")
+            .unwrap_or_else(|_| String::from("3600"))
+            .parse::<u64>()
+            .expect("Invalid CHATROUILLE_MAX_SESSION_LIFETIME"),
+        // Once the clients use an oblivious relay, the server can refuse the direct queries
+        accept_direct_queries: env::var("CHATROUILLE_ACCEPT_DIRECT_QUERIES")
+            .unwrap_or_else(|_| String::from("true"))
+            .parse::<lenient_bool::LenientBool>()
+            .unwrap_or_default()
+            .into(),
+    };
+    let relay_bearer = env::var("CHATROUILLE_RELAY_BEARER").ok();
+
     #[cfg(feature = "development")]
     let authentication_bearer =
         env::var("AUTHENTICATION_BEARER").unwrap_or_else(|_| String::from("development-bearer"));
@@ -87,6 +126,22 @@ async fn main() {
         };
     });
 
+    // The signatures of the expired queries are purged every minute, not on every query
+    let purge_db_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval_minute = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval_minute.tick().await;
+            let db_pool = purge_db_pool.clone();
+            // Diesel is blocking
+            match tokio::task::spawn_blocking(move || db::purge_expired_query_signatures(&db_pool)).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => eprintln!("purge_expired_query_signatures error: {}", e),
+                Err(e) => eprintln!("purge_expired_query_signatures task error: {}", e),
+            };
+        }
+    });
+
     server::server_main(
         addr,
         server::ServerData::new(
@@ -94,8 +149,10 @@ async fn main() {
             Arc::clone(&arc_vault_client),
             #[cfg(feature = "development")]
             authentication_bearer,
-            server_secrets.x448_private_key,
+            server_secrets.x448_keyring,
             server_secrets.ed25519_keypair,
+            chatrouille_settings,
+            relay_bearer,
         ),
     )
     .await
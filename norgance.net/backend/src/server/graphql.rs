@@ -1,9 +1,11 @@
-use juniper::{EmptySubscription, FieldResult, RootNode};
+use chatrouille::padding::PaddingPolicy;
+use juniper::{FieldResult, RootNode};
 use snafu::{ResultExt, Snafu};
 use std::sync::Arc;
 
 use crate::db;
 use crate::server::check_password_quality;
+use crate::server::session;
 use crate::validation;
 use crate::vault;
 
@@ -20,6 +22,15 @@ pub enum NorganceError {
 
     #[snafu(display("Error while communicating with the vault: {}", source))]
     VaultError { source: vault::VaultError },
+
+    #[snafu(display("The mutation requires a signed query"))]
+    UnsignedMutation,
+
+    #[snafu(display("Unable to get server time"))]
+    ServerTime,
+
+    #[snafu(display("The citizen doesn't exist"))]
+    CitizenNotFound,
 }
 
 /**
@@ -60,6 +71,30 @@ pub struct CitizenRegistrationResult {
     valid_aead_data: bool,
 }
 
+/// The encrypted personal data of a citizen, with its version.
+#[derive(juniper::GraphQLObject, Clone)]
+pub struct CitizenPersonalData {
+    aead_data: String,
+    version: i32,
+}
+
+#[derive(juniper::GraphQLObject, Clone)]
+pub struct CitizenPersonalDataUpdateResult {
+    success: bool,
+    valid_aead_data: bool,
+    /// The current version, the new one when the update succeeded.
+    version: i32,
+}
+
+#[derive(juniper::GraphQLObject, Clone)]
+pub struct PasswordChangeResult {
+    success: bool,
+    valid_access_key: bool,
+    valid_aead_data: bool,
+    /// The current version of the personal data, the new one when the change succeeded.
+    version: i32,
+}
+
 #[derive(juniper::GraphQLObject, Clone)]
 pub struct NorgancePublicKey {
     public_ed25519_dalek: String,
@@ -72,15 +107,59 @@ pub struct NorgancePublicKey {
 pub struct Ctx {
     pub db_pool: Arc<db::DbPool>,
     pub vault_client: Arc<vault::Client>,
+    pub sessions: Arc<session::Sessions>,
     pub citizen_identifier: Option<String>,
+    pub signed_query: Option<SignedQuery>,
 }
 impl juniper::Context for Ctx {}
 
+/// The verified signature of the citizen query, with the payload needed to check it later.
+pub struct SignedQuery {
+    pub receipt: chatrouille::QueryReceipt,
+    pub payload: String,
+}
+
 fn db_connection(context: &Ctx) -> Result<db::DbPooledConnection, NorganceError> {
     let db = context.db_pool.get().context(DatabaseConnectionError)?;
     Ok(db)
 }
 
+/// The mutations changing the data of a citizen keep the receipt of the query,
+/// in the same transaction as the change.
+fn record_query_receipt(
+    context: &Ctx,
+    db: &db::DbPooledConnection,
+    mutation: &str,
+) -> FieldResult<()> {
+    let (identifier, signed_query) = match (&context.citizen_identifier, &context.signed_query) {
+        (Some(identifier), Some(signed_query)) => (identifier, signed_query),
+        _ => return Err(NorganceError::UnsignedMutation.into()),
+    };
+    db::record_query_receipt(
+        db,
+        identifier,
+        mutation,
+        &signed_query.payload,
+        &signed_query.receipt,
+    )?;
+    Ok(())
+}
+
+// The versions stay far below i32::MAX, GraphQL integers are 32 bits
+fn graphql_version(version: i64) -> i32 {
+    use std::convert::TryFrom;
+    i32::try_from(version).unwrap_or(i32::MAX)
+}
+
+impl From<db::models::CitizenPersonalData> for CitizenPersonalData {
+    fn from(personal_data: db::models::CitizenPersonalData) -> Self {
+        CitizenPersonalData {
+            aead_data: personal_data.aead_data,
+            version: graphql_version(personal_data.aead_data_version),
+        }
+    }
+}
+
 /**
  * Query
  **/
@@ -119,6 +198,31 @@ impl Query {
         }
     }
 
+    /// Same as `loadCitizenPersonalData`, with the version to give to `updateCitizenPersonalData`.
+    fn loadVersionedCitizenPersonalData(context: &Ctx) -> FieldResult<Option<CitizenPersonalData>> {
+        let identifier = match &context.citizen_identifier {
+            Some(identifier) => identifier,
+            None => return Ok(None),
+        };
+        let db = db_connection(context)?;
+
+        let personal_data = db::load_versioned_citizen_personal_data(&db, &identifier)?;
+        Ok(personal_data.map(CitizenPersonalData::from))
+    }
+
+    /// The previous versions of the personal data, the most recent first.
+    /// Rolling back is updating the personal data with one of them.
+    fn loadCitizenPersonalDataHistory(context: &Ctx) -> FieldResult<Vec<CitizenPersonalData>> {
+        let identifier = match &context.citizen_identifier {
+            Some(identifier) => identifier,
+            None => return Ok(Vec::new()),
+        };
+        let db = db_connection(context)?;
+
+        let history = db::load_citizen_personal_data_history(&db, &identifier)?;
+        Ok(history.into_iter().map(CitizenPersonalData::from).collect())
+    }
+
     /// Returns the public keys of a citizen
     fn loadCitizenPublicKeys(
         context: &Ctx,
@@ -230,14 +334,352 @@ impl Mutation {
 
         Ok(result)
     }
+
+    /// Replaces the encrypted personal data of the citizen, the query must be signed.
+    ///
+    /// The update is refused when `expectedVersion` is not the current version,
+    /// the citizen edited an outdated copy and must load the personal data again.
+    fn updateCitizenPersonalData(
+        context: &Ctx,
+        aead_data: String,
+        expected_version: i32,
+    ) -> FieldResult<CitizenPersonalDataUpdateResult> {
+        use diesel::Connection;
+
+        let identifier = match (&context.citizen_identifier, &context.signed_query) {
+            (Some(identifier), Some(_)) => identifier,
+            _ => return Err(NorganceError::UnsignedMutation.into()),
+        };
+
+        if !validation::aead_data_base64_no_padding(&aead_data) {
+            return Ok(CitizenPersonalDataUpdateResult {
+                success: false,
+                valid_aead_data: false,
+                version: expected_version,
+            });
+        }
+
+        let db = db_connection(context)?;
+        let update = db.transaction::<_, juniper::FieldError, _>(|| {
+            let update = db::update_citizen_personal_data(
+                &db,
+                identifier,
+                i64::from(expected_version),
+                &aead_data,
+            )?;
+            if let db::PersonalDataUpdate::Updated(_) = update {
+                record_query_receipt(context, &db, "updateCitizenPersonalData")?;
+            }
+            Ok(update)
+        })?;
+
+        match update {
+            db::PersonalDataUpdate::Updated(version) => Ok(CitizenPersonalDataUpdateResult {
+                success: true,
+                valid_aead_data: true,
+                version: graphql_version(version),
+            }),
+            db::PersonalDataUpdate::Stale(version) => Ok(CitizenPersonalDataUpdateResult {
+                success: false,
+                valid_aead_data: true,
+                version: graphql_version(version),
+            }),
+            db::PersonalDataUpdate::CitizenNotFound => Err(NorganceError::CitizenNotFound.into()),
+        }
+    }
+
+    /// Replaces the access key and the personal data sealed with the keys derived
+    /// from the new password, the query must be signed with the current access key.
+    ///
+    /// The change is refused when `expectedVersion` is not the current version
+    /// of the personal data, so no update sealed with the old password is lost.
+    fn changePassword(
+        context: &Ctx,
+        new_access_key: String,
+        aead_data: String,
+        expected_version: i32,
+    ) -> FieldResult<PasswordChangeResult> {
+        use diesel::Connection;
+
+        let identifier = match (&context.citizen_identifier, &context.signed_query) {
+            (Some(identifier), Some(_)) => identifier,
+            _ => return Err(NorganceError::UnsignedMutation.into()),
+        };
+
+        let valid_access_key = validation::curve25519_public_key_base64_no_padding(&new_access_key);
+        let valid_aead_data = validation::aead_data_base64_no_padding(&aead_data);
+        if !valid_access_key || !valid_aead_data {
+            return Ok(PasswordChangeResult {
+                success: false,
+                valid_access_key,
+                valid_aead_data,
+                version: expected_version,
+            });
+        }
+
+        let db = db_connection(context)?;
+        let change = db.transaction::<_, juniper::FieldError, _>(|| {
+            let change = db::change_citizen_password(
+                &db,
+                identifier,
+                i64::from(expected_version),
+                &new_access_key,
+                &aead_data,
+            )?;
+            if let db::PersonalDataUpdate::Updated(_) = change {
+                record_query_receipt(context, &db, "changePassword")?;
+            }
+            Ok(change)
+        })?;
+
+        match change {
+            db::PersonalDataUpdate::Updated(version) => {
+                // The sessions authenticated with the old password end
+                context.sessions.revoke(identifier);
+                Ok(PasswordChangeResult {
+                    success: true,
+                    valid_access_key: true,
+                    valid_aead_data: true,
+                    version: graphql_version(version),
+                })
+            }
+            db::PersonalDataUpdate::Stale(version) => Ok(PasswordChangeResult {
+                success: false,
+                valid_access_key: true,
+                valid_aead_data: true,
+                version: graphql_version(version),
+            }),
+            db::PersonalDataUpdate::CitizenNotFound => Err(NorganceError::CitizenNotFound.into()),
+        }
+    }
+}
+
+/**
+ * Subscription
+ **/
+pub struct Subscription;
+
+type ServerTimeStream = std::pin::Pin<Box<dyn futures::Stream<Item = FieldResult<f64>> + Send>>;
+
+// Between one second and one hour
+const MIN_SERVER_TIME_INTERVAL: i32 = 1;
+const MAX_SERVER_TIME_INTERVAL: i32 = 3600;
+
+/// The subscriptions are only delivered over the chatrouille sessions.
+#[juniper::graphql_subscription(
+    Context = Ctx,
+)]
+impl Subscription {
+    /// Sends the server time every `interval` seconds, as a unix timestamp,
+    /// so the clients can set the expiration time of their queries.
+    async fn serverTime(interval: i32) -> ServerTimeStream {
+        use futures::StreamExt;
+        use std::convert::TryFrom;
+
+        let interval = interval
+            .max(MIN_SERVER_TIME_INTERVAL)
+            .min(MAX_SERVER_TIME_INTERVAL);
+        let period = std::time::Duration::from_secs(u64::try_from(interval).unwrap_or(1));
+        let stream = tokio::time::interval(period).map(|_| {
+            match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+                // Exact until the year 285 million
+                #[allow(clippy::cast_precision_loss)]
+                Ok(time) => Ok(time.as_secs() as f64),
+                Err(_) => Err(NorganceError::ServerTime.into()),
+            }
+        });
+        Box::pin(stream)
+    }
 }
 
-pub type Schema = RootNode<'static, Query, Mutation, EmptySubscription<Ctx>>;
+pub type Schema = RootNode<'static, Query, Mutation, Subscription>;
 
 pub fn new_root_node() -> Arc<Schema> {
-    Arc::new(RootNode::new(
-        Query,
-        Mutation,
-        EmptySubscription::<Ctx>::new(),
-    ))
+    Arc::new(RootNode::new(Query, Mutation, Subscription))
+}
+
+/// The responses of the fields returning citizen data have a fixed size,
+/// so their length doesn't leak anything about the citizen.
+fn root_field_fixed_size(field_name: &str) -> Option<usize> {
+    match field_name {
+        "isIdentifierAvailable"
+        | "checkPasswordQuality"
+        | "updateCitizenPersonalData"
+        | "changePassword" => Some(256),
+        "loadCitizenPublicKeys" | "registerCitizenship" => Some(1024),
+        "loadCitizenPersonalData" | "loadVersionedCitizenPersonalData" => Some(16384),
+        // All the versions together
+        "loadCitizenPersonalDataHistory" => Some(131_072),
+        _ => None,
+    }
+}
+
+/// Names of the root fields of the operation executed by the query document.
+///
+/// The fragments spread at the root are followed. An operation that can't be
+/// selected has no fields, juniper refuses it anyway.
+fn operation_root_fields<'a>(query: &'a str, operation_name: Option<&str>) -> Vec<&'a str> {
+    use graphql_parser::query::{Definition, OperationDefinition, Selection, SelectionSet};
+    use std::collections::HashMap;
+
+    fn collect<'a>(
+        selection_set: &SelectionSet<'a, &'a str>,
+        fragments: &HashMap<&'a str, SelectionSet<'a, &'a str>>,
+        visited_fragments: &mut Vec<&'a str>,
+        fields: &mut Vec<&'a str>,
+    ) {
+        for selection in &selection_set.items {
+            match selection {
+                Selection::Field(field) => fields.push(field.name),
+                Selection::InlineFragment(inline_fragment) => collect(
+                    &inline_fragment.selection_set,
+                    fragments,
+                    visited_fragments,
+                    fields,
+                ),
+                Selection::FragmentSpread(spread) => {
+                    if visited_fragments.contains(&spread.fragment_name) {
+                        continue;
+                    }
+                    visited_fragments.push(spread.fragment_name);
+                    if let Some(fragment) = fragments.get(spread.fragment_name) {
+                        collect(fragment, fragments, visited_fragments, fields);
+                    }
+                }
+            }
+        }
+    }
+
+    let document = match graphql_parser::parse_query::<&str>(query) {
+        Ok(document) => document,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut fragments = HashMap::new();
+    let mut operations = Vec::new();
+    for definition in document.definitions {
+        match definition {
+            Definition::Fragment(fragment) => {
+                fragments.insert(fragment.name, fragment.selection_set);
+            }
+            Definition::Operation(operation) => operations.push(match operation {
+                OperationDefinition::SelectionSet(selection_set) => (None, selection_set),
+                OperationDefinition::Query(query) => (query.name, query.selection_set),
+                OperationDefinition::Mutation(mutation) => (mutation.name, mutation.selection_set),
+                OperationDefinition::Subscription(subscription) => {
+                    (subscription.name, subscription.selection_set)
+                }
+            }),
+        }
+    }
+
+    let selection_set = match operation_name {
+        Some(operation_name) => operations
+            .into_iter()
+            .find(|(name, _)| *name == Some(operation_name))
+            .map(|(_, selection_set)| selection_set),
+        None if operations.len() == 1 => operations.pop().map(|(_, selection_set)| selection_set),
+        None => None,
+    };
+
+    let mut fields = Vec::new();
+    if let Some(selection_set) = selection_set {
+        collect(&selection_set, &fragments, &mut Vec::new(), &mut fields);
+    }
+    fields
+}
+
+/// The responses of the operations returning citizen data have a fixed size,
+/// the other responses are padded to power of two buckets.
+///
+/// The policy comes from the root fields of the parsed operations, the operation name
+/// is only used to select the operation. With several root fields, or a batch of requests,
+/// the largest fixed size is used.
+pub fn response_padding_policy<'a>(
+    requests: impl IntoIterator<Item = &'a juniper::http::GraphQLRequest>,
+) -> PaddingPolicy {
+    requests
+        .into_iter()
+        .filter_map(|request| {
+            // The query of the request isn't public in juniper, but it's serialized
+            let serialized_request = serde_json::to_value(request).ok()?;
+            let query = serialized_request.get("query")?.as_str()?;
+            operation_root_fields(query, request.operation_name())
+                .into_iter()
+                .filter_map(root_field_fixed_size)
+                .max()
+        })
+        .max()
+        .map_or(
+            PaddingPolicy::PowerOfTwo { minimum: 512 },
+            PaddingPolicy::FixedSize,
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::http::GraphQLRequest;
+
+    fn padding_policy(query: &str, operation_name: Option<&str>) -> PaddingPolicy {
+        let request = GraphQLRequest::new(
+            String::from(query),
+            operation_name.map(String::from),
+            None,
+        );
+        response_padding_policy(std::iter::once(&request))
+    }
+
+    #[test]
+    fn test_response_padding_policy() {
+        assert_eq!(
+            padding_policy("{ loadCitizenPersonalData }", None),
+            PaddingPolicy::FixedSize(16384)
+        );
+        // The operation name given by the client doesn't matter
+        assert_eq!(
+            padding_policy(
+                "query isIdentifierAvailable { loadCitizenPersonalData }",
+                Some("isIdentifierAvailable")
+            ),
+            PaddingPolicy::FixedSize(16384)
+        );
+        assert_eq!(
+            padding_policy(
+                "query a { getNorgancePublicKeys { creationTime } } query b { loadCitizenPublicKeys(identifier: \"abc\") { publicEd25519Dalek } }",
+                Some("b")
+            ),
+            PaddingPolicy::FixedSize(1024)
+        );
+        // Hidden in fragments, or next to other fields
+        assert_eq!(
+            padding_policy(
+                "query { getNorgancePublicKeys { creationTime } ...data } fragment data on Query { ... on Query { loadCitizenPersonalDataHistory { version } } }",
+                None
+            ),
+            PaddingPolicy::FixedSize(131_072)
+        );
+        assert_eq!(
+            padding_policy("{ getNorgancePublicKeys { creationTime } }", None),
+            PaddingPolicy::PowerOfTwo { minimum: 512 }
+        );
+        assert_eq!(
+            padding_policy("not graphql", Some("loadCitizenPersonalData")),
+            PaddingPolicy::PowerOfTwo { minimum: 512 }
+        );
+
+        // The largest fixed size of the batch
+        let batch = vec![
+            GraphQLRequest::new(
+                String::from("{ isIdentifierAvailable(identifier: \"abc\") }"),
+                None,
+                None,
+            ),
+            GraphQLRequest::new(String::from("{ loadCitizenPersonalData }"), None, None),
+        ];
+        assert_eq!(
+            response_padding_policy(&batch),
+            PaddingPolicy::FixedSize(16384)
+        );
+    }
 }
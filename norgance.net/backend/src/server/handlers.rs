@@ -1,20 +1,90 @@
 use crate::db;
+use crate::keyring;
 use crate::server::graphql;
+use crate::server::session;
+use crate::server::ChatrouilleSettings;
 use crate::vault;
 
 extern crate futures;
 
+use chatrouille::envelope::Envelope;
+use chatrouille::padding::PaddingPolicy;
+use chatrouille::{x448, ChatrouilleError};
 use hyper::{Body, Request, Response, StatusCode};
 use serde_json::json;
-use snafu::Snafu;
+use snafu::{ResultExt, Snafu};
 use std::sync::Arc;
 
 #[derive(Debug, Snafu)]
 pub enum NorganceChatrouilleError {
     #[snafu(display("TooBig"))]
     TooBig,
+
+    #[snafu(display("This chatrouille version is not supported anymore"))]
+    UnsupportedVersion,
+
+    #[snafu(display("{}", source))]
+    InvalidContainer {
+        source: ChatrouilleError,
+    },
+
+    #[snafu(display("Unable to get server time"))]
+    ServerTime,
+
+    #[snafu(display("The request has expired"))]
+    Expired,
+
+    #[snafu(display("The request expires too late"))]
+    ExpiresTooLate,
+
+    #[snafu(display("citizenIdentifier requires a signed query"))]
+    UnsignedCitizenQuery,
+
+    #[snafu(display("Unauthorized citizen identifier"))]
+    UnauthorizedCitizen,
+
+    #[snafu(display("The request has already been processed"))]
+    Replay,
+
+    #[snafu(display("The query doesn't open a session"))]
+    InvalidSessionOpening,
+
+    #[snafu(display("Unable to query the database"))]
+    DatabaseConnection { source: r2d2::Error },
+
+    #[snafu(display("{}", source))]
+    Database { source: db::NorganceDatabaseError },
+
+    #[snafu(display("{}", source))]
+    ResponseSerialization { source: serde_json::Error },
 }
 
+impl NorganceChatrouilleError {
+    /// The errors are sent encrypted, with the HTTP status as error code.
+    /// The regular 🦆 clients get them in plain JSON, with this HTTP status.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            NorganceChatrouilleError::TooBig => StatusCode::PAYLOAD_TOO_LARGE,
+            NorganceChatrouilleError::UnsupportedVersion => StatusCode::UNPROCESSABLE_ENTITY,
+            NorganceChatrouilleError::InvalidContainer { .. }
+            | NorganceChatrouilleError::ExpiresTooLate
+            | NorganceChatrouilleError::InvalidSessionOpening => StatusCode::BAD_REQUEST,
+            NorganceChatrouilleError::Expired => StatusCode::GONE,
+            NorganceChatrouilleError::UnsignedCitizenQuery
+            | NorganceChatrouilleError::UnauthorizedCitizen => StatusCode::FORBIDDEN,
+            NorganceChatrouilleError::Replay => StatusCode::CONFLICT,
+            NorganceChatrouilleError::ServerTime
+            | NorganceChatrouilleError::DatabaseConnection { .. }
+            | NorganceChatrouilleError::Database { .. }
+            | NorganceChatrouilleError::ResponseSerialization { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+/// The body of the chatrouille envelopes, the expiration time and the
+/// citizen identifier are in the envelope.
 #[derive(Debug, serde::Deserialize, PartialEq)]
 #[serde(bound = "juniper::InputValue<S>: serde::Deserialize<'de>")] // ??
 pub struct NorganceChatrouilleContainer<S = juniper::DefaultScalarValue>
@@ -22,13 +92,6 @@ where
     S: juniper::ScalarValue,
 {
     graphql: juniper::http::GraphQLBatchRequest<S>,
-
-    #[serde(rename = "citizenIdentifier")]
-    //#[serde(bound(deserialize = "juniper::InputValue<S>: serde::Deserialize<'de> + serde::Serialize"))]
-    citizen_identifier: Option<String>,
-
-    #[serde(rename = "exp")]
-    expiration_time: u64,
 }
 
 #[cfg(feature = "development")]
@@ -48,6 +111,32 @@ pub fn json_response(json: &serde_json::value::Value, status: StatusCode) -> Res
         .expect("Unable to build json response")
 }
 
+/// Header carrying the bearer of the trusted oblivious relays.
+const RELAY_AUTHENTICATION_HEADER: &str = "relay-authentication";
+
+/// Whether the server accepts the query, either relayed by a trusted oblivious relay
+/// or sent directly by the client when the direct queries are still accepted.
+pub fn is_accepted_origin(
+    req: &Request<Body>,
+    relay_bearer: Option<&str>,
+    settings: ChatrouilleSettings,
+) -> bool {
+    if settings.accept_direct_queries {
+        return true;
+    }
+    match (relay_bearer, req.headers().get(RELAY_AUTHENTICATION_HEADER)) {
+        (Some(bearer), Some(h)) => orion::util::secure_cmp(h.as_bytes(), bearer.as_bytes()).is_ok(),
+        _ => false,
+    }
+}
+
+pub fn direct_query_forbidden() -> ResultHandler {
+    Ok(json_error(
+        "The queries must go through an oblivious relay",
+        StatusCode::FORBIDDEN,
+    ))
+}
+
 pub fn json_ok(json: &serde_json::value::Value) -> Response<Body> {
     json_response(json, StatusCode::OK)
 }
@@ -64,25 +153,78 @@ where
     )
 }
 
-async fn read_request_body(
+// Regular chatrouille queries are packed in one go and are small
+const MAX_PACKED_QUERY_SIZE: usize = 4200;
+
+/// Reads and unpacks a chatrouille query from the request body.
+///
+/// Chunked queries are unpacked while the body is streamed,
+/// and can be as large as `max_chunked_body_size`.
+/// Regular queries can't be larger than `max_payload_size` once uncompressed.
+async fn unpack_request_body(
     req: Request<Body>,
-    limit: usize,
-) -> Result<(Vec<u8>, bool), hyper::Error> {
+    keyring: &keyring::Keyring,
+    max_chunked_body_size: usize,
+    max_payload_size: usize,
+) -> Result<Result<chatrouille::UnpackedQuery, Response<Body>>, hyper::Error> {
     use futures::TryStreamExt;
 
-    let body = req.into_body();
-    body.try_fold(
-        (Vec::new(), false),
-        |(mut data, too_long), chunk| async move {
-            if too_long || data.len() + chunk.len() > limit {
-                Ok((data, true))
-            } else {
-                data.extend_from_slice(&chunk);
-                Ok((data, false))
+    let payload_too_large = || {
+        json_response(
+            &json!({
+              "error": "payload too large"
+            }),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )
+    };
+
+    let mut body = req.into_body();
+    let mut data: Vec<u8> = Vec::new();
+    let mut body_size: usize = 0;
+    let mut unpacker: Option<chatrouille::chunked::Unpacker> = None;
+
+    while let Some(chunk) = body.try_next().await? {
+        body_size += chunk.len();
+
+        if let Some(unpacker) = unpacker.as_mut() {
+            if body_size > max_chunked_body_size {
+                return Ok(Err(payload_too_large()));
             }
-        },
-    )
-    .await
+            if let Err(x) = unpacker.update(&chunk) {
+                return Ok(Err(json_error(x, StatusCode::UNPROCESSABLE_ENTITY)));
+            }
+            continue;
+        }
+
+        data.extend_from_slice(&chunk);
+
+        if data.len() >= chatrouille::PACKET_MODE_PREFIX_LENGTH
+            && chatrouille::packet_mode(&data).is_chunked()
+        {
+            if body_size > max_chunked_body_size {
+                return Ok(Err(payload_too_large()));
+            }
+            let mut chunked_unpacker =
+                chatrouille::chunked::Unpacker::query(keyring, max_chunked_body_size);
+            if let Err(x) = chunked_unpacker.update(&data) {
+                return Ok(Err(json_error(x, StatusCode::UNPROCESSABLE_ENTITY)));
+            }
+            data.clear();
+            unpacker = Some(chunked_unpacker);
+        } else if body_size > MAX_PACKED_QUERY_SIZE {
+            return Ok(Err(payload_too_large()));
+        }
+    }
+
+    let unpacked_query = match unpacker {
+        Some(unpacker) => unpacker.finish_query(),
+        None => chatrouille::unpack_query(&data, keyring, max_payload_size),
+    };
+
+    Ok(match unpacked_query {
+        Ok(unpacked_query) => Ok(unpacked_query),
+        Err(x) => Err(json_error(x, StatusCode::UNPROCESSABLE_ENTITY)),
+    })
 }
 
 fn get_timestamp() -> Result<u64, Response<Body>> {
@@ -110,6 +252,7 @@ pub async fn graphql(
     root_node: Arc<graphql::Schema>,
     arc_db_pool: Arc<db::DbPool>,
     vault_client: Arc<vault::Client>,
+    sessions: Arc<session::Sessions>,
     authentication_bearer: Arc<String>,
 ) -> ResultHandler {
     let headers = req.headers();
@@ -137,7 +280,9 @@ pub async fn graphql(
     let context_for_query = Arc::new(graphql::Ctx {
         db_pool: arc_db_pool,
         vault_client,
+        sessions,
         citizen_identifier,
+        signed_query: None,
     });
 
     juniper_hyper::graphql(root_node, context_for_query, req).await
@@ -160,163 +305,274 @@ pub fn not_found() -> ResultHandler {
         .expect("Unable to build not found response"))
 }
 
-#[allow(clippy::too_many_lines)]
 pub async fn chatrouille(
     req: Request<Body>,
     root_node: Arc<graphql::Schema>,
     arc_db_pool: Arc<db::DbPool>,
     vault_client: Arc<vault::Client>,
-    private_key: Arc<x448::Secret>,
+    sessions: Arc<session::Sessions>,
+    keyring: Arc<keyring::Keyring>,
+    settings: ChatrouilleSettings,
 ) -> ResultHandler {
-    use chatrouille::VerifyUnpackedQuerySignature;
-    let (body, body_too_long) = read_request_body(req, 4200).await?;
-
-    if body_too_long {
-        return Ok(json_response(
-            &json!({
-              "error": "payload too large"
-            }),
-            StatusCode::PAYLOAD_TOO_LARGE,
-        ));
-    }
-
-    let payload = chatrouille::unpack_query(&body, &private_key);
-
-    let unpacked_query = match payload {
+    let unpacked_query = match unpack_request_body(
+        req,
+        &keyring,
+        settings.max_body_size,
+        settings.max_payload_size,
+    )
+    .await?
+    {
         Ok(unpacked_query) => unpacked_query,
-        Err(x) => return Ok(json_error(x, StatusCode::UNPROCESSABLE_ENTITY)),
-    };
-
-    let graphql_request: NorganceChatrouilleContainer =
-        match serde_json::from_slice(&unpacked_query.payload) {
-            Ok(batch) => batch,
-            Err(x) => {
-                return Ok(json_error(x, StatusCode::BAD_REQUEST));
-            }
-        };
-
-    let server_timestamp = match get_timestamp() {
-        Ok(t) => t,
         Err(response) => return Ok(response),
     };
-    if graphql_request.expiration_time < server_timestamp {
-        return Ok(json_response(
-            &json!({
-              "error": "The request has expired"
-            }),
-            StatusCode::GONE,
-        ));
-    }
-
-    let citizen_identifier = graphql_request.citizen_identifier;
-
-    if citizen_identifier.is_some() {
-        let signature = match unpacked_query.signature {
-            None => {
-                return Ok(json_response(
-                    &json!({
-                      "error": "citizenIdentifier requires a signed query"
-                    }),
-                    StatusCode::FORBIDDEN,
-                ));
-            }
-            Some(signature) => signature,
-        };
 
-        let db_connection = match arc_db_pool.get() {
-            Ok(db_connection) => db_connection,
-            Err(_) => {
-                return Ok(json_response(
-                    &json!({
-                      "error": "Unable to query the database"
-                    }),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ));
+    let answer = answer_chatrouille_query(
+        &unpacked_query,
+        root_node,
+        arc_db_pool,
+        vault_client,
+        sessions,
+        settings,
+    )
+    .await;
+
+    // The regular 🦆 clients only unpack the unsigned responses, and read the errors in plain JSON
+    let legacy = unpacked_query.version == chatrouille::Version::Duck
+        && !unpacked_query.mode.is_chunked();
+
+    // Once the query is decrypted, even the errors are encrypted
+    let encrypted_response = match answer {
+        Ok((response_payload, padding)) => {
+            if legacy {
+                chatrouille::pack_response_with_codec(
+                    &response_payload,
+                    &unpacked_query.shared_secret,
+                    unpacked_query.version,
+                    padding,
+                    unpacked_query.codec,
+                )
+            } else if unpacked_query.mode.is_chunked() {
+                chatrouille::chunked::pack_chunked_response(
+                    &response_payload,
+                    &unpacked_query.shared_secret,
+                )
+            } else {
+                // Signed, so the client can prove that Norgance answered its query
+                chatrouille::pack_signed_response_with_codec(
+                    &response_payload,
+                    &unpacked_query.shared_secret,
+                    &chatrouille::query_hash(&unpacked_query.payload),
+                    keyring.signing_keypair(),
+                    unpacked_query.version,
+                    padding,
+                    unpacked_query.codec,
+                )
             }
-        };
+        }
+        Err(error) if legacy => return Ok(json_error(&error, error.status())),
+        Err(error) => chatrouille::pack_error(
+            error.status().as_u16(),
+            &error.to_string(),
+            &unpacked_query.shared_secret,
+            unpacked_query.version,
+        ),
+    };
+    let encrypted_response = match encrypted_response {
+        Ok(encrypted_response) => encrypted_response,
+        Err(x) => {
+            return Ok(json_error(x, StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
 
-        #[allow(clippy::unwrap_used)]
-        let public_key =
-            match db::load_citizen_access_key(&db_connection, &citizen_identifier.clone().unwrap())
-            {
-                Ok(public_key) => match public_key {
-                    Some(public_key) => public_key,
-                    None => {
-                        return Ok(json_response(
-                            &json!({
-                              "error": "Unauthorized citizen identifier"
-                            }),
-                            StatusCode::FORBIDDEN,
-                        ));
-                    }
-                },
-                Err(x) => {
-                    return Ok(json_error(x, StatusCode::INTERNAL_SERVER_ERROR));
-                }
-            };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+        .header("Access-Control-Allow-Origin", ACCESS_CONTROL_ORIGIN)
+        .body(Body::from(encrypted_response))
+        .expect("Unable to build response"))
+}
 
-        if signature.verify(&public_key).is_err() {
-            return Ok(json_response(
-                &json!({
-                  "error": "Unauthorized citizen identifier"
-                }),
-                StatusCode::FORBIDDEN,
-            ));
-        };
-    }
+/// Checks and executes a decrypted query, and returns the response payload
+/// with the padding policy of the GraphQL operation.
+async fn answer_chatrouille_query(
+    unpacked_query: &chatrouille::UnpackedQuery,
+    root_node: Arc<graphql::Schema>,
+    arc_db_pool: Arc<db::DbPool>,
+    vault_client: Arc<vault::Client>,
+    sessions: Arc<session::Sessions>,
+    settings: ChatrouilleSettings,
+) -> Result<(Vec<u8>, PaddingPolicy), NorganceChatrouilleError> {
+    let (envelope, signed_query): (Envelope<NorganceChatrouilleContainer>, _) =
+        check_chatrouille_query(unpacked_query, &arc_db_pool, settings)?;
 
     let context_for_query = graphql::Ctx {
         db_pool: Arc::clone(&arc_db_pool),
-        citizen_identifier,
+        citizen_identifier: envelope.citizen_identifier,
         vault_client,
+        sessions,
+        signed_query,
+    };
+    let graphql_request = envelope.body;
+    let padding = match &graphql_request.graphql {
+        juniper::http::GraphQLBatchRequest::Single(request) => {
+            graphql::response_padding_policy(std::iter::once(request))
+        }
+        juniper::http::GraphQLBatchRequest::Batch(requests) => {
+            graphql::response_padding_policy(requests)
+        }
     };
     let graphql_response = graphql_request
         .graphql
         .execute(&*root_node, &context_for_query)
         .await;
-    let response_payload = match serde_json::to_vec(&graphql_response) {
-        Ok(response_payload) => response_payload,
-        Err(x) => {
-            return Ok(json_error(x, StatusCode::INTERNAL_SERVER_ERROR));
+    let response_payload = serde_json::to_vec(&graphql_response).context(ResponseSerialization)?;
+    Ok((response_payload, padding))
+}
+
+/// Checks the version, the expiration, and the citizen signature of a decrypted query,
+/// and returns its envelope with the verified signed query of the citizen.
+pub fn check_chatrouille_query<T: serde::de::DeserializeOwned>(
+    unpacked_query: &chatrouille::UnpackedQuery,
+    db_pool: &db::DbPool,
+    settings: ChatrouilleSettings,
+) -> Result<(Envelope<T>, Option<graphql::SignedQuery>), NorganceChatrouilleError> {
+    use chatrouille::VerifyUnpackedQuerySignature;
+
+    // The chunked queries only exist in the duck version
+    if unpacked_query.version == chatrouille::Version::Duck
+        && !unpacked_query.mode.is_chunked()
+        && !settings.accept_legacy_version
+    {
+        return Err(NorganceChatrouilleError::UnsupportedVersion);
+    }
+
+    let envelope: Envelope<T> = match chatrouille::envelope::unpack_json(unpacked_query) {
+        Ok(envelope) => envelope,
+        Err(ChatrouilleError::UnsignedCitizenIdentifier) => {
+            return Err(NorganceChatrouilleError::UnsignedCitizenQuery)
         }
+        Err(error) => return Err(NorganceChatrouilleError::InvalidContainer { source: error }),
     };
 
-    let encrypted_response =
-        match chatrouille::pack_response(&response_payload, &unpacked_query.shared_secret) {
-            Ok(encrypted_response) => encrypted_response,
-            Err(x) => {
-                return Ok(json_error(x, StatusCode::INTERNAL_SERVER_ERROR));
+    let server_timestamp = match get_timestamp() {
+        Ok(t) => t,
+        Err(_) => return Err(NorganceChatrouilleError::ServerTime),
+    };
+    match envelope.check_expiration(server_timestamp, settings.max_lifetime, settings.clock_skew) {
+        Ok(()) => (),
+        Err(ChatrouilleError::Expired) => return Err(NorganceChatrouilleError::Expired),
+        Err(_) => return Err(NorganceChatrouilleError::ExpiresTooLate),
+    }
+    let expiration_time = envelope.expiration_time;
+
+    let signed_query = match &envelope.citizen_identifier {
+        Some(identifier) => {
+            let signature = match &unpacked_query.signature {
+                Some(signature) => signature,
+                None => return Err(NorganceChatrouilleError::UnsignedCitizenQuery),
+            };
+
+            let db_connection = db_pool.get().context(DatabaseConnection)?;
+
+            let public_key = match db::load_citizen_access_key(&db_connection, identifier)
+                .context(Database)?
+            {
+                Some(public_key) => public_key,
+                None => return Err(NorganceChatrouilleError::UnauthorizedCitizen),
+            };
+
+            if signature.verify(&public_key).is_err() {
+                return Err(NorganceChatrouilleError::UnauthorizedCitizen);
             }
-        };
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
-        .header("Access-Control-Allow-Origin", ACCESS_CONTROL_ORIGIN)
-        .body(Body::from(encrypted_response))
-        .expect("Unable to build response"))
+            // The signature is remembered until the query expires, so it cannot be replayed
+            let first_time = db::record_query_signature(
+                &db_connection,
+                signature.nounce(),
+                expiration_time + settings.clock_skew,
+            )
+            .context(Database)?;
+            if !first_time {
+                return Err(NorganceChatrouilleError::Replay);
+            }
+
+            // The payload was parsed as JSON, it's valid UTF-8
+            Some(graphql::SignedQuery {
+                receipt: signature.receipt(),
+                payload: String::from_utf8_lossy(&unpacked_query.payload).into_owned(),
+            })
+        }
+        None => None,
+    };
+
+    Ok((envelope, signed_query))
 }
 
-pub fn chatrouille_information(public_key: &str, signature: &str) -> ResultHandler {
+pub fn chatrouille_information(keyring: &keyring::Keyring) -> ResultHandler {
+    use chatrouille::key_utils::key_id_to_base64;
+
     let time = match get_timestamp() {
         Ok(t) => t,
         Err(response) => return Ok(response),
     };
 
+    let current_key = match keyring.current_key_at(time) {
+        Some(key) => key,
+        None => {
+            return Ok(json_response(
+                &json!({
+                  "error": "No valid server key"
+                }),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ))
+        }
+    };
+
+    let keys: Vec<serde_json::value::Value> = keyring
+        .valid_keys_at(time)
+        .map(|key| {
+            json!({
+                "key_id": key_id_to_base64(&key.key_id),
+                "public_key_x448": key.public_key_base64,
+                "public_key_x448_signature": key.public_key_signature_base64,
+                "public_key_mlkem768": key.mlkem_public_key_base64,
+                "public_key_mlkem768_signature": key.mlkem_public_key_signature_base64,
+                "activation_time": key.activation_time,
+                "retirement_time": key.retirement_time,
+            })
+        })
+        .collect();
+
     Ok(json_ok(&json!({
-            "public_key_x448": public_key,
-            "public_key_x448_signature": signature,
+            "key_id": key_id_to_base64(&current_key.key_id),
+            "public_key_x448": current_key.public_key_base64,
+            "public_key_x448_signature": current_key.public_key_signature_base64,
+            "public_key_mlkem768": current_key.mlkem_public_key_base64,
+            "public_key_mlkem768_signature": current_key.mlkem_public_key_signature_base64,
+            "keys": keys,
             "time": time,
     })))
 }
 
 #[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
 #[cfg(test)]
-mod tests {
+pub(super) mod tests {
     use super::*;
+    use chatrouille::compressor::Codec;
     use chatrouille::key_utils;
     use tokio_test::block_on;
 
+    const MAX_CHUNKED_BODY_SIZE: usize = 65536;
+    pub(in crate::server) const SETTINGS: ChatrouilleSettings = ChatrouilleSettings {
+        max_body_size: MAX_CHUNKED_BODY_SIZE,
+        max_payload_size: 65536,
+        max_lifetime: 300,
+        clock_skew: 30,
+        accept_legacy_version: true,
+        max_session_lifetime: 3600,
+        accept_direct_queries: true,
+    };
+
     fn read_response_body(response: Response<Body>) -> Vec<u8> {
         use futures::TryStreamExt;
         block_on(
@@ -330,33 +586,146 @@ mod tests {
         .unwrap()
     }
 
+    /// The errors after the decryption are encrypted.
+    fn read_error(
+        response: Response<Body>,
+        shared_secret: &key_utils::SharedSecret,
+    ) -> (u16, String) {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = read_response_body(response);
+        match chatrouille::unpack_response(&body, shared_secret, SETTINGS.max_payload_size) {
+            Err(chatrouille::ChatrouilleError::ServerError { code, message }) => (code, message),
+            _ => panic!("Expected an encrypted error"),
+        }
+    }
+
     fn body_contains(response: Response<Body>, text: &str) -> bool {
         let body = read_response_body(response);
         let body_text = std::str::from_utf8(&body).unwrap();
         body_text.contains(text)
     }
 
-    fn setup_chatrouille() -> (
-        Arc<x448::Secret>,
-        x448::PublicKey,
-        Arc<graphql::Schema>,
-        Arc<db::DbPool>,
-        Arc<vault::Client>,
-    ) {
-        let private_key = key_utils::gen_private_key();
-        let public_key = key_utils::gen_public_key(&private_key);
-        let root_node = graphql::new_root_node();
-        let db_pool = db::create_connection_pool().expect("Unable to create connection pool");
-        let vault_client_future = vault::Client::from_env();
-        let vault_client = block_on(vault_client_future).expect("Unable to create vault client");
-
-        (
-            Arc::new(private_key),
-            public_key,
-            root_node,
-            Arc::new(db_pool),
-            Arc::new(vault_client),
-        )
+    /// The chatrouille payload of a graphql query, signed by the citizen if there is one.
+    pub(in crate::server) fn graphql_payload(
+        graphql: serde_json::Value,
+        citizen_identifier: Option<&str>,
+        exp: u64,
+    ) -> Vec<u8> {
+        let mut payload = json!({
+            "graphql": graphql,
+            "exp": exp,
+        });
+        if let Some(citizen_identifier) = citizen_identifier {
+            payload["citizenIdentifier"] = json!(citizen_identifier);
+        }
+        serde_json::to_vec(&payload).unwrap()
+    }
+
+    fn load_citizen_public_keys(identifier: &str) -> serde_json::Value {
+        json!({
+            "operationName": "loadCitizenPublicKeys",
+            "variables": {
+                "identifier": identifier
+            },
+            "query": "query loadCitizenPublicKeys($identifier: String!) { loadCitizenPublicKeys(identifier: $identifier) { publicEd25519Dalek }}"
+        })
+    }
+
+    pub(in crate::server) struct TestServer {
+        pub(in crate::server) keyring: Arc<keyring::Keyring>,
+        pub(in crate::server) public_key: x448::PublicKey,
+        pub(in crate::server) root_node: Arc<graphql::Schema>,
+        pub(in crate::server) db_pool: Arc<db::DbPool>,
+        pub(in crate::server) vault_client: Arc<vault::Client>,
+        pub(in crate::server) sessions: Arc<session::Sessions>,
+    }
+
+    impl TestServer {
+        pub(in crate::server) fn new() -> Self {
+            let private_key = key_utils::gen_private_key();
+            let public_key = key_utils::gen_public_key(&private_key);
+            let keyring = keyring::Keyring::new(
+                vec![keyring::KeyringEntry {
+                    private_key: private_key.into(),
+                    mlkem_private_key: Some(key_utils::gen_mlkem_private_key()),
+                    activation_time: 0,
+                    retirement_time: None,
+                }],
+                key_utils::gen_ed25519_keypair(),
+            );
+            let db_pool = db::create_connection_pool().expect("Unable to create connection pool");
+            let vault_client_future = vault::Client::from_env();
+            let vault_client =
+                block_on(vault_client_future).expect("Unable to create vault client");
+
+            Self {
+                keyring: Arc::new(keyring),
+                public_key,
+                root_node: graphql::new_root_node(),
+                db_pool: Arc::new(db_pool),
+                vault_client: Arc::new(vault_client),
+                sessions: Arc::new(session::Sessions::default()),
+            }
+        }
+
+        fn send(&self, packed_query: Vec<u8>) -> Response<Body> {
+            let request = Request::builder().body(Body::from(packed_query)).unwrap();
+            block_on(chatrouille(
+                request,
+                Arc::clone(&self.root_node),
+                Arc::clone(&self.db_pool),
+                Arc::clone(&self.vault_client),
+                Arc::clone(&self.sessions),
+                Arc::clone(&self.keyring),
+                SETTINGS,
+            ))
+            .unwrap()
+        }
+
+        fn read_signed_response(
+            &self,
+            response: Response<Body>,
+            shared_secret: &key_utils::SharedSecret,
+            payload: &[u8],
+        ) -> serde_json::Value {
+            assert_eq!(response.status(), StatusCode::OK);
+            let (response, receipt) = chatrouille::unpack_signed_response(
+                &read_response_body(response),
+                shared_secret,
+                &chatrouille::query_hash(payload),
+                &self.keyring.signing_keypair().public,
+                SETTINGS.max_payload_size,
+            )
+            .unwrap();
+            assert!(receipt
+                .verify(&self.keyring.signing_keypair().public)
+                .is_ok());
+            serde_json::from_slice(&response).unwrap()
+        }
+
+        /// Sends a signed graphql query, returning the response with what's needed to read it.
+        fn send_signed_graphql(
+            &self,
+            graphql: serde_json::Value,
+            identifier: &str,
+            access_keypair: &ed25519_dalek::Keypair,
+        ) -> (Response<Body>, key_utils::SharedSecret, Vec<u8>) {
+            let payload = graphql_payload(graphql, Some(identifier), get_timestamp().unwrap() + 60);
+            let (query, shared_secret) =
+                chatrouille::pack_signed_query(&payload, &self.public_key, access_keypair).unwrap();
+            (self.send(query), shared_secret, payload)
+        }
+
+        pub(in crate::server) fn signed_graphql(
+            &self,
+            graphql: serde_json::Value,
+            identifier: &str,
+            access_keypair: &ed25519_dalek::Keypair,
+        ) -> serde_json::Value {
+            let (response, shared_secret, payload) =
+                self.send_signed_graphql(graphql, identifier, access_keypair);
+            self.read_signed_response(response, &shared_secret, &payload)
+        }
     }
 
     fn random_string(size: usize) -> String {
@@ -370,7 +739,7 @@ mod tests {
             .collect()
     }
 
-    fn create_test_citizen_in_db(
+    pub(in crate::server) fn create_test_citizen_in_db(
         db: &db::DbPooledConnection,
     ) -> (String, ed25519_dalek::Keypair, ed25519_dalek::Keypair) {
         use crate::db::models::{Citizen, NewCitizen};
@@ -413,20 +782,34 @@ mod tests {
         (identifier, access_keypair, keypair_ed25519)
     }
 
+    #[test]
+    fn test_is_accepted_origin() {
+        let relay_only = ChatrouilleSettings {
+            accept_direct_queries: false,
+            ..SETTINGS
+        };
+        let direct = Request::builder().body(Body::empty()).unwrap();
+        assert!(is_accepted_origin(&direct, None, SETTINGS));
+        assert!(!is_accepted_origin(&direct, Some("relay"), relay_only));
+
+        let relayed = Request::builder()
+            .header(RELAY_AUTHENTICATION_HEADER, "relay")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_accepted_origin(&relayed, Some("relay"), relay_only));
+        assert!(!is_accepted_origin(
+            &relayed,
+            Some("another relay"),
+            relay_only
+        ));
+        assert!(!is_accepted_origin(&relayed, None, relay_only));
+    }
+
     #[test]
     fn test_chatrouille_empty() {
-        let (private_key, _, root_node, db_pool, vault_client) = setup_chatrouille();
+        let server = TestServer::new();
 
-        // Empty
-        let request = Request::builder().body(Body::empty()).unwrap();
-        let response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
-        .unwrap();
+        let response = server.send(Vec::new());
         assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
         assert!(body_contains(response, "too small"));
     }
@@ -435,7 +818,7 @@ mod tests {
     fn test_chatrouille_random() {
         use rand::prelude::*;
 
-        let (private_key, _, root_node, db_pool, vault_client) = setup_chatrouille();
+        let server = TestServer::new();
 
         // Random data
         let mut random_data = [0_u8; 256];
@@ -443,230 +826,593 @@ mod tests {
         // Make sure the prefix is always invalid
         random_data[0] = 0;
 
-        let request = Request::builder()
-            .body(Body::from(random_data.to_vec()))
-            .unwrap();
-        let response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
-        .unwrap();
+        let response = server.send(random_data.to_vec());
         assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
         assert!(body_contains(response, "prefix is invalid"));
     }
 
     #[test]
     fn test_chatrouille_wrong_public_key() {
-        let (private_key, _, root_node, db_pool, vault_client) = setup_chatrouille();
+        let server = TestServer::new();
         let another_private_key = key_utils::gen_private_key();
         let another_public_key = key_utils::gen_public_key(&another_private_key);
 
         // Valid empty query, but with a wrong public key :-)
-        let query = chatrouille::pack_unsigned_query(&[], &another_public_key).unwrap();
+        let (query, _) = chatrouille::pack_unsigned_query(&[], &another_public_key).unwrap();
 
-        let request = Request::builder().body(Body::from(query.0)).unwrap();
+        let response = server.send(query.clone());
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body_contains(response, "Unknown server key"));
 
-        let response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
-        .unwrap();
+        // Same query, but pretending to target the server key
+        let mut query_with_wrong_key_id = query;
+        query_with_wrong_key_id[5..9].copy_from_slice(&key_utils::key_id(&server.public_key));
+
+        let response = server.send(query_with_wrong_key_id);
         assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
         assert!(body_contains(response, "Unable to decrypt"));
     }
 
     #[test]
     fn test_chatrouille_wrong_graphql() {
-        let (private_key, public_key, root_node, db_pool, vault_client) = setup_chatrouille();
+        let server = TestServer::new();
 
-        let query = chatrouille::pack_unsigned_query(
+        let (query, shared_secret) = chatrouille::pack_unsigned_query(
             &serde_json::to_vec(&json!({
-              "not graphql": true
+                "not graphql": true
             }))
             .unwrap(),
-            &public_key,
+            &server.public_key,
         )
         .unwrap();
-        let request = Request::builder().body(Body::from(query.0)).unwrap();
-        let response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
-        .unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-        assert!(body_contains(response, "missing field"));
+        let (code, message) = read_error(server.send(query), &shared_secret);
+        assert_eq!(code, StatusCode::BAD_REQUEST.as_u16());
+        assert!(message.contains("missing field"));
     }
 
     #[test]
     fn test_chatrouille_valid_unsigned() {
-        let (private_key, public_key, root_node, db_pool, vault_client) = setup_chatrouille();
-        let timestamp = get_timestamp().unwrap();
+        let server = TestServer::new();
 
-        let (query, shared_secret) = chatrouille::pack_unsigned_query(
-      &serde_json::to_vec(&json!({
-        "graphql": {
-          "operationName": "loadCitizenPublicKey",
-          "variables": {
-            "identifier": "abcdef"
-          },
-          "query": "query loadCitizenPublicKey($identifier: String!) { loadCitizenPublicKeys(identifier: $identifier) { publicX25519Dalek publicEd25519Dalek }}"
-        },
-        "exp": timestamp+60,
-      }))
-      .unwrap(),
-      &public_key,
-    )
-    .unwrap();
-        let request = Request::builder().body(Body::from(query)).unwrap();
-        let encrypted_response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
-        .unwrap();
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_unsigned_query(&payload, &server.public_key).unwrap();
+        let encrypted_response = server.send(query);
         assert_eq!(encrypted_response.status(), StatusCode::OK);
         let encrypted_body = read_response_body(encrypted_response);
-        let response = chatrouille::unpack_response(&encrypted_body, &shared_secret).unwrap();
+        // header, nounce, padded response, signature nounce and signature, tag
+        assert_eq!(encrypted_body.len(), 5 + 24 + 1024 + 32 + 64 + 16);
+        let (response, receipt) = chatrouille::unpack_signed_response(
+            &encrypted_body,
+            &shared_secret,
+            &chatrouille::query_hash(&payload),
+            &server.keyring.signing_keypair().public,
+            SETTINGS.max_payload_size,
+        )
+        .unwrap();
+        assert!(receipt
+            .verify(&server.keyring.signing_keypair().public)
+            .is_ok());
 
-        //let response_text = std::str::from_utf8(&response).unwrap();
         assert_eq!(
-            response,
-            serde_json::to_vec(&json!({
-              "data": { "loadCitizenPublicKeys" : null }
-            }))
-            .unwrap()
+            serde_json::from_slice::<serde_json::Value>(&response).unwrap(),
+            json!({ "data": { "loadCitizenPublicKeys": null } })
         );
     }
+
     #[test]
-    fn test_chatrouille_unvalid_unsigned() {
-        let (private_key, public_key, root_node, db_pool, vault_client) = setup_chatrouille();
-        let timestamp = get_timestamp().unwrap();
-
-        let (query, _) = chatrouille::pack_unsigned_query(
-      &serde_json::to_vec(&json!({
-        "graphql": {
-          "operationName": "loadCitizenPublicKey",
-          "variables": {
-            "identifier": "abcdef"
-          },
-          "query": "query loadCitizenPublicKey($identifier: String!) { loadCitizenPublicKeys(identifier: $identifier) { publicX25519Dalek publicEd25519Dalek }}"
-        },
-        "citizenIdentifier": "canard",
-        "exp": timestamp+60,
-      }))
-      .unwrap(),
-      &public_key,
-    )
-    .unwrap();
+    fn test_chatrouille_legacy_duck() {
+        let server = TestServer::new();
 
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_legacy_query(&payload, &server.public_key, None).unwrap();
+        let encrypted_response = server.send(query);
+        assert_eq!(encrypted_response.status(), StatusCode::OK);
+        let encrypted_body = read_response_body(encrypted_response);
+        assert!(chatrouille::packet_mode(&encrypted_body) == chatrouille::Mode::Response);
+        let response = chatrouille::unpack_response(
+            &encrypted_body,
+            &shared_secret,
+            SETTINGS.max_payload_size,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&response).unwrap(),
+            json!({ "data": { "loadCitizenPublicKeys": null } })
+        );
+
+        // The errors are in plain JSON, like before the encrypted errors
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() - 60,
+        );
+        let (query, _) =
+            chatrouille::pack_legacy_query(&payload, &server.public_key, None).unwrap();
+        let response = server.send(query);
+        assert_eq!(response.status(), NorganceChatrouilleError::Expired.status());
+        assert!(body_contains(response, "error"));
+
+        // Refused once the legacy version isn't accepted anymore
+        let (query, _) =
+            chatrouille::pack_legacy_query(&payload, &server.public_key, None).unwrap();
         let request = Request::builder().body(Body::from(query)).unwrap();
         let response = block_on(chatrouille(
             request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
+            Arc::clone(&server.root_node),
+            Arc::clone(&server.db_pool),
+            Arc::clone(&server.vault_client),
+            Arc::clone(&server.sessions),
+            Arc::clone(&server.keyring),
+            ChatrouilleSettings {
+                accept_legacy_version: false,
+                ..SETTINGS
+            },
         ))
         .unwrap();
-        assert_eq!(response.status(), StatusCode::FORBIDDEN);
-        assert!(body_contains(response, "signed"));
+        assert_eq!(
+            response.status(),
+            NorganceChatrouilleError::UnsupportedVersion.status()
+        );
     }
+
     #[test]
-    fn test_chatrouille_unvalid_expired() {
-        let (private_key, public_key, root_node, db_pool, vault_client) = setup_chatrouille();
-        let timestamp = get_timestamp().unwrap();
-
-        let (query, _) = chatrouille::pack_unsigned_query(
-      &serde_json::to_vec(&json!({
-        "graphql": {
-          "operationName": "loadCitizenPublicKey",
-          "variables": {
-            "identifier": "abcdef"
-          },
-          "query": "query loadCitizenPublicKey($identifier: String!) { loadCitizenPublicKeys(identifier: $identifier) { publicX25519Dalek publicEd25519Dalek }}"
-        },
-        "exp": timestamp-60,
-      }))
-      .unwrap(),
-      &public_key,
-    )
-    .unwrap();
+    fn test_chatrouille_valid_hybrid() {
+        let server = TestServer::new();
+        let mlkem_public_key = server
+            .keyring
+            .current_key_at(get_timestamp().unwrap())
+            .unwrap()
+            .mlkem_public_key_base64
+            .as_ref()
+            .and_then(|public_key_base64| {
+                key_utils::mlkem_public_key_from_base64(public_key_base64)
+            })
+            .unwrap();
 
-        let request = Request::builder().body(Body::from(query)).unwrap();
-        let response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) = chatrouille::pack_hybrid_unsigned_query(
+            &payload,
+            &server.public_key,
+            &mlkem_public_key,
+            Codec::default(),
+        )
         .unwrap();
-        assert_eq!(response.status(), StatusCode::GONE);
-        assert!(body_contains(response, "expired"));
+        let encrypted_response = server.send(query);
+        assert_eq!(encrypted_response.status(), StatusCode::OK);
+        let encrypted_body = read_response_body(encrypted_response);
+        assert_eq!(
+            chatrouille::Version::from_packet(&encrypted_body).unwrap(),
+            chatrouille::Version::Owl
+        );
+        let (response, _) = chatrouille::unpack_signed_response(
+            &encrypted_body,
+            &shared_secret,
+            &chatrouille::query_hash(&payload),
+            &server.keyring.signing_keypair().public,
+            SETTINGS.max_payload_size,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&response).unwrap(),
+            json!({ "data": { "loadCitizenPublicKeys": null } })
+        );
     }
+
     #[test]
-    fn test_chatrouille_valid_signed() {
-        let (private_key, public_key, root_node, db_pool, vault_client) = setup_chatrouille();
+    fn test_chatrouille_valid_hpke() {
+        let server = TestServer::new();
 
-        let db = db_pool.get().expect("Database connection failed");
-        let (identifier, access_keypair, keypair) = create_test_citizen_in_db(&db);
-        let timestamp = get_timestamp().unwrap();
-
-        let (query, shared_secret) = chatrouille::pack_signed_query(
-      &serde_json::to_vec(&json!({
-        "graphql": {
-          "operationName": "loadCitizenPublicKey",
-          "variables": {
-            "identifier": identifier
-          },
-          "query": "query loadCitizenPublicKey($identifier: String!) { loadCitizenPublicKeys(identifier: $identifier) { publicEd25519Dalek }}"
-        },
-        "citizenIdentifier": identifier,
-        "exp": timestamp + 60,
-      }))
-      .unwrap(),
-      &public_key,
-      &access_keypair,
-    )
-    .unwrap();
-        let request = Request::builder().body(Body::from(query)).unwrap();
-        let encrypted_response = block_on(chatrouille(
-            request,
-            root_node,
-            db_pool,
-            vault_client,
-            private_key,
-        ))
-        .unwrap();
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_hpke_unsigned_query(&payload, &server.public_key, Codec::default())
+                .unwrap();
+        let encrypted_response = server.send(query);
         assert_eq!(encrypted_response.status(), StatusCode::OK);
         let encrypted_body = read_response_body(encrypted_response);
-        let response = chatrouille::unpack_response(&encrypted_body, &shared_secret).unwrap();
+        assert_eq!(
+            chatrouille::Version::from_packet(&encrypted_body).unwrap(),
+            chatrouille::Version::Flamingo
+        );
+        let (response, _) = chatrouille::unpack_signed_response(
+            &encrypted_body,
+            &shared_secret,
+            &chatrouille::query_hash(&payload),
+            &server.keyring.signing_keypair().public,
+            SETTINGS.max_payload_size,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&response).unwrap(),
+            json!({ "data": { "loadCitizenPublicKeys": null } })
+        );
+    }
 
-        let response_text = std::str::from_utf8(&response).unwrap();
-        let ed25519_dalek_base64 =
-            &base64::encode_config(keypair.public.as_bytes(), base64::STANDARD_NO_PAD);
+    #[test]
+    fn test_chatrouille_unvalid_unsigned() {
+        let server = TestServer::new();
 
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            Some("canard"),
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_unsigned_query(&payload, &server.public_key).unwrap();
+        let (code, message) = read_error(server.send(query), &shared_secret);
+        assert_eq!(code, StatusCode::FORBIDDEN.as_u16());
+        assert!(message.contains("signed"));
+    }
+
+    #[test]
+    fn test_chatrouille_unvalid_expired() {
+        let server = TestServer::new();
+
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() - 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_unsigned_query(&payload, &server.public_key).unwrap();
+        let (code, message) = read_error(server.send(query), &shared_secret);
+        assert_eq!(code, StatusCode::GONE.as_u16());
+        assert!(message.contains("expired"));
+    }
+
+    #[test]
+    fn test_chatrouille_valid_signed() {
+        let server = TestServer::new();
+
+        let db = server.db_pool.get().expect("Database connection failed");
+        let (identifier, access_keypair, keypair) = create_test_citizen_in_db(&db);
+
+        let response = server.signed_graphql(
+            load_citizen_public_keys(&identifier),
+            &identifier,
+            &access_keypair,
+        );
+        let ed25519_dalek_base64 =
+            base64::encode_config(keypair.public.as_bytes(), base64::STANDARD_NO_PAD);
         assert_eq!(
-            response_text,
-            serde_json::to_string(&json!({
-              "data": {
-                "loadCitizenPublicKeys" : {
-                  "publicEd25519Dalek": ed25519_dalek_base64
+            response,
+            json!({
+                "data": {
+                    "loadCitizenPublicKeys": {
+                        "publicEd25519Dalek": ed25519_dalek_base64
+                    }
                 }
-              }
-            }))
+            })
+        );
+    }
+
+    #[test]
+    fn test_chatrouille_update_personal_data() {
+        let server = TestServer::new();
+
+        let db = server.db_pool.get().expect("Database connection failed");
+        let (identifier, access_keypair, _) = create_test_citizen_in_db(&db);
+        let original_aead_data = db::load_citizen_personal_data(&db, &identifier)
             .unwrap()
+            .unwrap();
+
+        let update = |aead_data: &str, expected_version: i32| {
+            server.signed_graphql(
+                json!({
+                    "operationName": "updateCitizenPersonalData",
+                    "variables": {
+                        "aeadData": aead_data,
+                        "expectedVersion": expected_version,
+                    },
+                    "query": "mutation updateCitizenPersonalData($aeadData: String!, $expectedVersion: Int!) { updateCitizenPersonalData(aeadData: $aeadData, expectedVersion: $expectedVersion) { success validAeadData version }}"
+                }),
+                &identifier,
+                &access_keypair,
+            )["data"]["updateCitizenPersonalData"]
+                .clone()
+        };
+
+        let new_aead_data = random_string(64);
+        assert_eq!(
+            update(&new_aead_data, 1),
+            json!({ "success": true, "validAeadData": true, "version": 2 })
+        );
+        // Edited from the first version, after the second one
+        assert_eq!(
+            update(&original_aead_data, 1),
+            json!({ "success": false, "validAeadData": true, "version": 2 })
+        );
+        assert_eq!(
+            update("not base64!", 2),
+            json!({ "success": false, "validAeadData": false, "version": 2 })
+        );
+
+        let personal_data = server.signed_graphql(
+            json!({
+                "operationName": "loadCitizenPersonalDataHistory",
+                "query": "query loadCitizenPersonalDataHistory { loadVersionedCitizenPersonalData { aeadData version } loadCitizenPersonalDataHistory { aeadData version }}"
+            }),
+            &identifier,
+            &access_keypair,
+        );
+        assert_eq!(
+            personal_data["data"],
+            json!({
+                "loadVersionedCitizenPersonalData": { "aeadData": new_aead_data, "version": 2 },
+                "loadCitizenPersonalDataHistory": [{ "aeadData": original_aead_data, "version": 1 }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_chatrouille_several_mutations() {
+        let server = TestServer::new();
+
+        let db = server.db_pool.get().expect("Database connection failed");
+        let (identifier, access_keypair, _) = create_test_citizen_in_db(&db);
+        let new_access_keypair = key_utils::gen_ed25519_keypair();
+        let new_access_key = base64::encode_config(
+            new_access_keypair.public.as_bytes(),
+            base64::STANDARD_NO_PAD,
+        );
+
+        // The receipt of the query is recorded by each mutation
+        let response = server.signed_graphql(
+            json!({
+                "operationName": "severalMutations",
+                "variables": {
+                    "first": random_string(64),
+                    "second": random_string(64),
+                    "third": random_string(64),
+                    "newAccessKey": new_access_key,
+                },
+                "query": "mutation severalMutations($first: String!, $second: String!, $third: String!, $newAccessKey: String!) { first: updateCitizenPersonalData(aeadData: $first, expectedVersion: 1) { success } second: updateCitizenPersonalData(aeadData: $second, expectedVersion: 2) { success } changePassword(newAccessKey: $newAccessKey, aeadData: $third, expectedVersion: 3) { success }}"
+            }),
+            &identifier,
+            &access_keypair,
+        );
+        assert_eq!(
+            response,
+            json!({ "data": {
+                "first": { "success": true },
+                "second": { "success": true },
+                "changePassword": { "success": true },
+            }})
+        );
+
+        let recorded_mutations = {
+            use crate::db::schema::query_receipts::dsl::*;
+            use diesel::prelude::*;
+            query_receipts
+                .filter(citizen_identifier.eq(&identifier))
+                .select(mutation)
+                .order(mutation)
+                .load::<String>(&db)
+                .unwrap()
+        };
+        assert_eq!(
+            recorded_mutations,
+            vec!["changePassword", "updateCitizenPersonalData"]
+        );
+    }
+
+    #[test]
+    fn test_chatrouille_change_password() {
+        let server = TestServer::new();
+
+        let db = server.db_pool.get().expect("Database connection failed");
+        let (identifier, old_access_keypair, _) = create_test_citizen_in_db(&db);
+        let new_access_keypair = key_utils::gen_ed25519_keypair();
+        let new_access_key = base64::encode_config(
+            new_access_keypair.public.as_bytes(),
+            base64::STANDARD_NO_PAD,
+        );
+        let new_aead_data = random_string(64);
+
+        let change_password = |access_key: &str, expected_version: i32| {
+            server.signed_graphql(
+                json!({
+                    "operationName": "changePassword",
+                    "variables": {
+                        "newAccessKey": access_key,
+                        "aeadData": new_aead_data,
+                        "expectedVersion": expected_version,
+                    },
+                    "query": "mutation changePassword($newAccessKey: String!, $aeadData: String!, $expectedVersion: Int!) { changePassword(newAccessKey: $newAccessKey, aeadData: $aeadData, expectedVersion: $expectedVersion) { success validAccessKey validAeadData version }}"
+                }),
+                &identifier,
+                &old_access_keypair,
+            )["data"]["changePassword"]
+                .clone()
+        };
+
+        assert_eq!(
+            change_password("not a key", 1),
+            json!({ "success": false, "validAccessKey": false, "validAeadData": true, "version": 1 })
+        );
+        // Another device updated the personal data sealed with the old password
+        assert_eq!(
+            change_password(&new_access_key, 0),
+            json!({ "success": false, "validAccessKey": true, "validAeadData": true, "version": 1 })
+        );
+        assert_eq!(
+            change_password(&new_access_key, 1),
+            json!({ "success": true, "validAccessKey": true, "validAeadData": true, "version": 2 })
+        );
+        assert_eq!(
+            db::load_citizen_personal_data(&db, &identifier).unwrap(),
+            Some(new_aead_data.clone())
+        );
+
+        // The old password doesn't authorize the queries anymore
+        let load_personal_data = json!({
+            "operationName": "loadCitizenPersonalData",
+            "query": "query loadCitizenPersonalData { loadCitizenPersonalData }"
+        });
+        let (response, shared_secret, _) = server.send_signed_graphql(
+            load_personal_data.clone(),
+            &identifier,
+            &old_access_keypair,
+        );
+        let (code, _) = read_error(response, &shared_secret);
+        assert_eq!(code, StatusCode::FORBIDDEN.as_u16());
+
+        let response = server.signed_graphql(load_personal_data, &identifier, &new_access_keypair);
+        assert_eq!(
+            response["data"]["loadCitizenPersonalData"],
+            json!(new_aead_data)
+        );
+    }
+
+    #[test]
+    fn test_chatrouille_unvalid_too_late() {
+        let server = TestServer::new();
+
+        let payload = graphql_payload(
+            load_citizen_public_keys("abcdef"),
+            None,
+            get_timestamp().unwrap() + 3600,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_unsigned_query(&payload, &server.public_key).unwrap();
+        let (code, message) = read_error(server.send(query), &shared_secret);
+        assert_eq!(code, StatusCode::BAD_REQUEST.as_u16());
+        assert!(message.contains("too late"));
+    }
+
+    #[test]
+    fn test_chatrouille_replay() {
+        let server = TestServer::new();
+
+        let db = server.db_pool.get().expect("Database connection failed");
+        let (identifier, access_keypair, _) = create_test_citizen_in_db(&db);
+
+        let payload = graphql_payload(
+            load_citizen_public_keys(&identifier),
+            Some(&identifier),
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::pack_signed_query(&payload, &server.public_key, &access_keypair).unwrap();
+
+        let response = server.send(query.clone());
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The same query again
+        let (code, message) = read_error(server.send(query), &shared_secret);
+        assert_eq!(code, StatusCode::CONFLICT.as_u16());
+        assert!(message.contains("already been processed"));
+
+        // The same payload signed again in the same second isn't a replay
+        let (query, shared_secret) =
+            chatrouille::pack_signed_query(&payload, &server.public_key, &access_keypair).unwrap();
+        let response = server.read_signed_response(server.send(query), &shared_secret, &payload);
+        assert!(response["data"]["loadCitizenPublicKeys"].is_object());
+    }
+
+    #[test]
+    fn test_chatrouille_valid_chunked() {
+        let server = TestServer::new();
+
+        // Larger than a regular query can be
+        let identifier = random_string(8000);
+        let payload = graphql_payload(
+            load_citizen_public_keys(&identifier),
+            None,
+            get_timestamp().unwrap() + 60,
+        );
+        let (query, shared_secret) =
+            chatrouille::chunked::pack_chunked_query(&payload, &server.public_key, None).unwrap();
+        assert!(query.len() > MAX_PACKED_QUERY_SIZE);
+
+        let encrypted_response = server.send(query);
+        assert_eq!(encrypted_response.status(), StatusCode::OK);
+        let encrypted_body = read_response_body(encrypted_response);
+        let response = chatrouille::chunked::unpack_chunked_response(
+            &encrypted_body,
+            &shared_secret,
+            MAX_CHUNKED_BODY_SIZE,
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&response).unwrap(),
+            json!({ "data": { "loadCitizenPublicKeys": null } })
+        );
+    }
+
+    #[test]
+    fn test_chatrouille_too_large() {
+        let server = TestServer::new();
+
+        // Regular queries are limited
+        let (query, _) =
+            chatrouille::pack_unsigned_query(random_string(10000).as_bytes(), &server.public_key)
+                .unwrap();
+        let response = server.send(query);
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Even once uncompressed
+        let (query, _) = chatrouille::pack_unsigned_query_with_codec(
+            &vec![b'a'; SETTINGS.max_payload_size + 1],
+            &server.public_key,
+            Codec::Deflate,
+        )
+        .unwrap();
+        let response = server.send(query);
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body_contains(response, "larger than"));
+
+        // Chunked queries too, but more
+        let (query, _) = chatrouille::chunked::pack_chunked_query(
+            random_string(MAX_CHUNKED_BODY_SIZE).as_bytes(),
+            &server.public_key,
+            None,
+        )
+        .unwrap();
+        let response = server.send(query);
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_chatrouille_information() {
+        let server = TestServer::new();
+
+        let response = chatrouille_information(&server.keyring).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let information: serde_json::Value =
+            serde_json::from_slice(&read_response_body(response)).unwrap();
+
+        let key_id = key_utils::key_id_to_base64(&key_utils::key_id(&server.public_key));
+        assert_eq!(information["key_id"], json!(key_id));
+        assert_eq!(
+            information["public_key_x448"],
+            json!(key_utils::public_key_to_base64(&server.public_key))
+        );
+        assert!(information["public_key_mlkem768"].is_string());
+        assert!(information["public_key_mlkem768_signature"].is_string());
+        assert_eq!(information["keys"].as_array().unwrap().len(), 1);
+        assert_eq!(information["keys"][0]["key_id"], json!(key_id));
+        assert_eq!(
+            information["keys"][0]["public_key_mlkem768"],
+            information["public_key_mlkem768"]
         );
     }
 }
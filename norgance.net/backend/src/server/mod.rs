@@ -1,6 +1,7 @@
 mod check_password_quality;
 mod graphql;
 mod handlers;
+mod session;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -9,6 +10,7 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Method, Server};
 
 use crate::db;
+use crate::keyring;
 use crate::vault;
 
 #[allow(clippy::expect_used)]
@@ -18,24 +20,36 @@ async fn shutdown_signal() {
         .expect("failed to install CTRL+C signal handler");
 }
 
-fn private_key_to_public_key_base64(public_key: &x448::PublicKey) -> String {
-    base64::encode_config(public_key.as_bytes(), base64::STANDARD_NO_PAD)
-}
-
-fn private_key_sign_base64(public_key: &x448::PublicKey, keypair: &ed25519_dalek::Keypair) -> String {
-    use ed25519_dalek::Signer;
-    let signature = keypair.sign(public_key.as_bytes());
-    base64::encode_config(signature.to_bytes(), base64::STANDARD_NO_PAD)
+/// Limits applied to the chatrouille queries.
+#[derive(Clone, Copy)]
+pub struct ChatrouilleSettings {
+    /// Maximum size of a chunked query body, in bytes.
+    pub max_body_size: usize,
+    /// Maximum size of a regular query payload once uncompressed, in bytes.
+    pub max_payload_size: usize,
+    /// Maximum lifetime of a query, in seconds. Queries expiring later are refused.
+    pub max_lifetime: u64,
+    /// Tolerance for the clock differences between the clients and the server, in seconds.
+    pub clock_skew: u64,
+    /// Whether the regular 🦆 queries, without authenticated headers, are still accepted.
+    pub accept_legacy_version: bool,
+    /// Maximum lifetime of a chatrouille session over a WebSocket, in seconds.
+    pub max_session_lifetime: u64,
+    /// Whether the queries sent directly by the clients, and not by a trusted oblivious relay, are accepted.
+    pub accept_direct_queries: bool,
 }
 
 pub struct ServerData {
     db_pool: Arc<db::DbPool>,
     vault_client: Arc<vault::Client>,
+    // The open chatrouille sessions of the citizens
+    sessions: Arc<session::Sessions>,
     #[cfg(feature = "development")]
     authentication_bearer: Arc<String>,
-    private_key_x448: Arc<x448::Secret>,
-    public_key_x448_base64: Arc<String>,
-    public_key_signature: Arc<String>,
+    keyring: Arc<keyring::Keyring>,
+    chatrouille_settings: ChatrouilleSettings,
+    // Shared with the oblivious relays, they hide the addresses of the clients
+    relay_bearer: Option<String>,
 }
 
 impl ServerData {
@@ -44,22 +58,22 @@ impl ServerData {
         vault_client: Arc<vault::Client>,
         #[cfg(feature = "development")]
         authentication_bearer: String,
-        x448_private_key: x448::Secret,
+        x448_keyring: Vec<keyring::KeyringEntry>,
         ed25519_keypair: ed25519_dalek::Keypair,
+        chatrouille_settings: ChatrouilleSettings,
+        relay_bearer: Option<String>,
     ) -> ServerData {
-
-        let x448_public_key = x448::PublicKey::from(&x448_private_key);
-        let public_key_x448_base64 = private_key_to_public_key_base64(&x448_public_key);
-        let signature_base64 = private_key_sign_base64(&x448_public_key, &ed25519_keypair);
+        let keyring = keyring::Keyring::new(x448_keyring, ed25519_keypair);
 
         ServerData {
             db_pool: Arc::new(db_pool),
             vault_client: Arc::clone(&vault_client),
+            sessions: Arc::new(session::Sessions::default()),
             #[cfg(feature = "development")]
             authentication_bearer: Arc::new(authentication_bearer),
-            private_key_x448: Arc::new(x448_private_key),
-            public_key_x448_base64: Arc::new(public_key_x448_base64),
-            public_key_signature: Arc::new(signature_base64),
+            keyring: Arc::new(keyring),
+            chatrouille_settings,
+            relay_bearer,
         }
     }
 }
@@ -79,18 +93,38 @@ pub async fn server_main(addr: SocketAddr, data: ServerData) {
 
                 async move {
                     match (req.method(), req.uri().path()) {
+                        (&Method::POST, "/chatrouille") | (&Method::GET, "/chatrouille/ws")
+                            if !handlers::is_accepted_origin(
+                                &req,
+                                data.relay_bearer.as_deref(),
+                                data.chatrouille_settings,
+                            ) =>
+                        {
+                            handlers::direct_query_forbidden()
+                        }
                         (&Method::POST, "/chatrouille") => {
                             handlers::chatrouille(
                                 req,
                                 root_node,
                                 Arc::clone(&data.db_pool),
                                 Arc::clone(&data.vault_client),
-                                Arc::clone(&data.private_key_x448),
+                                Arc::clone(&data.sessions),
+                                Arc::clone(&data.keyring),
+                                data.chatrouille_settings,
                             )
                             .await
                         }
+                        (&Method::GET, "/chatrouille/ws") => session::chatrouille_session(
+                            req,
+                            root_node,
+                            Arc::clone(&data.db_pool),
+                            Arc::clone(&data.vault_client),
+                            Arc::clone(&data.sessions),
+                            Arc::clone(&data.keyring),
+                            data.chatrouille_settings,
+                        ),
                         (&Method::GET, "/chatrouille_information") => {
-                            handlers::chatrouille_information(&data.public_key_x448_base64, &data.public_key_signature)
+                            handlers::chatrouille_information(&data.keyring)
                         }
                         (&Method::GET, "/health") => handlers::health(&data.db_pool),
                         #[cfg(feature = "development")]
@@ -100,6 +134,7 @@ pub async fn server_main(addr: SocketAddr, data: ServerData) {
                                 root_node,
                                 Arc::clone(&data.db_pool),
                                 Arc::clone(&data.vault_client),
+                                Arc::clone(&data.sessions),
                                 Arc::clone(&data.authentication_bearer),
                             )
                             .await
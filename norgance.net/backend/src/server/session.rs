@@ -0,0 +1,775 @@
+use crate::db;
+use crate::keyring;
+use crate::server::graphql;
+use crate::server::handlers::{self, NorganceChatrouilleError};
+use crate::server::ChatrouilleSettings;
+use crate::vault;
+
+use chatrouille::envelope::Envelope;
+use chatrouille::padding::PaddingPolicy;
+use chatrouille::session::Session;
+use futures::future::{AbortHandle, Abortable};
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::protocol::{Message, Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
+// https://tools.ietf.org/html/rfc6455#section-1.3
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const MAX_SUBSCRIPTIONS_PER_SESSION: usize = 16;
+
+// The subscriptions wait when the client doesn't read its frames
+const SERVER_FRAMES_BUFFER: usize = 64;
+
+/// The body of the envelope opening a session: `{"exp": ..., "citizenIdentifier": ..., "session": true}`.
+#[derive(serde::Deserialize)]
+struct SessionOpening {
+    session: bool,
+}
+
+/// The frames sent by the clients, in JSON.
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientFrame {
+    /// A query or a mutation, answered by a single data frame.
+    Query {
+        id: String,
+        graphql: juniper::http::GraphQLRequest,
+    },
+    /// A subscription, answered by data frames until the complete frame.
+    Subscribe {
+        id: String,
+        graphql: juniper::http::GraphQLRequest,
+    },
+    Stop {
+        id: String,
+    },
+}
+
+/// A frame to send to the client.
+struct ServerFrame {
+    json: serde_json::Value,
+    padding: PaddingPolicy,
+    // The subscription is over and leaves the session
+    completed_subscription: Option<String>,
+}
+
+fn data_frame(id: &str, payload: serde_json::Value, padding: PaddingPolicy) -> ServerFrame {
+    ServerFrame {
+        json: json!({ "id": id, "type": "data", "payload": payload }),
+        padding,
+        completed_subscription: None,
+    }
+}
+
+fn error_frame(id: Option<&str>, message: &str) -> ServerFrame {
+    ServerFrame {
+        json: json!({ "id": id, "type": "error", "payload": { "message": message } }),
+        padding: PaddingPolicy::default(),
+        completed_subscription: None,
+    }
+}
+
+fn complete_frame(id: String) -> ServerFrame {
+    ServerFrame {
+        json: json!({ "id": id, "type": "complete" }),
+        padding: PaddingPolicy::default(),
+        completed_subscription: Some(id),
+    }
+}
+
+/// The open sessions of the citizens, closed when they change their password.
+#[derive(Default)]
+pub struct Sessions {
+    last_id: AtomicU64,
+    revocations: Mutex<HashMap<String, HashMap<u64, oneshot::Sender<()>>>>,
+}
+
+impl Sessions {
+    /// The receiver completes when the session is revoked.
+    fn register(&self, citizen_identifier: &str) -> (u64, oneshot::Receiver<()>) {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.revocations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(String::from(citizen_identifier))
+            .or_default()
+            .insert(id, sender);
+        (id, receiver)
+    }
+
+    fn unregister(&self, citizen_identifier: &str, id: u64) {
+        let mut revocations = self
+            .revocations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(citizen_sessions) = revocations.get_mut(citizen_identifier) {
+            citizen_sessions.remove(&id);
+            if citizen_sessions.is_empty() {
+                revocations.remove(citizen_identifier);
+            }
+        }
+    }
+
+    /// Closes the open sessions of the citizen.
+    pub fn revoke(&self, citizen_identifier: &str) {
+        let citizen_sessions = self
+            .revocations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(citizen_identifier);
+        for (_, sender) in citizen_sessions.into_iter().flatten() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+fn websocket_accept_key(key: &[u8]) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(key);
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(hasher.digest().bytes())
+}
+
+/// Upgrades the request to a WebSocket carrying a chatrouille session.
+///
+/// The first message is a regular chatrouille query opening the session,
+/// signed when it has a citizen identifier, and the server answers with a signed response.
+/// The next messages are session frames, with JSON frames inside:
+/// client frames: `{"id": "1", "type": "query", "graphql": {...}}`, `{"id": "2", "type": "subscribe", "graphql": {...}}`, `{"id": "2", "type": "stop"}`
+/// server frames: `{"id": "1", "type": "data", "payload": {...}}`, `{"id": "2", "type": "complete"}`, `{"id": "3", "type": "error", "payload": {"message": "..."}}`
+///
+/// The citizen is authenticated once for the whole session, until the session expires or the
+/// citizen changes their password. The mutations keeping the receipt of their query need a
+/// signature of the mutation itself, they go through `/chatrouille`.
+#[allow(clippy::expect_used, clippy::needless_pass_by_value)]
+pub fn chatrouille_session(
+    req: Request<Body>,
+    root_node: Arc<graphql::Schema>,
+    db_pool: Arc<db::DbPool>,
+    vault_client: Arc<vault::Client>,
+    sessions: Arc<Sessions>,
+    keyring: Arc<keyring::Keyring>,
+    settings: ChatrouilleSettings,
+) -> Result<Response<Body>, hyper::Error> {
+    let headers = req.headers();
+    let is_websocket = match headers.get(hyper::header::UPGRADE) {
+        Some(upgrade) => upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"),
+        None => false,
+    };
+    let accept_key = match headers.get(hyper::header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_websocket => websocket_accept_key(key.as_bytes()),
+        _ => {
+            return Ok(handlers::json_error(
+                "Expected a WebSocket upgrade",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+
+    tokio::spawn(async move {
+        // Without the upgrade, the client is already gone
+        if let Ok(upgraded) = req.into_body().on_upgrade().await {
+            let config = WebSocketConfig {
+                max_message_size: Some(settings.max_body_size),
+                ..WebSocketConfig::default()
+            };
+            let socket =
+                WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await;
+            run_session(
+                socket,
+                root_node,
+                db_pool,
+                vault_client,
+                sessions,
+                keyring,
+                settings,
+            )
+            .await;
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(hyper::header::UPGRADE, "websocket")
+        .header(hyper::header::CONNECTION, "Upgrade")
+        .header(hyper::header::SEC_WEBSOCKET_ACCEPT, accept_key)
+        .body(Body::empty())
+        .expect("Unable to build the WebSocket upgrade response"))
+}
+
+/// Returns the citizen identifier of the session, checked like the regular queries.
+fn check_session_opening(
+    unpacked_query: &chatrouille::UnpackedQuery,
+    db_pool: &db::DbPool,
+    settings: ChatrouilleSettings,
+) -> Result<Option<String>, NorganceChatrouilleError> {
+    // The duck version has no session frames
+    if unpacked_query.version == chatrouille::Version::Duck {
+        return Err(NorganceChatrouilleError::UnsupportedVersion);
+    }
+    let (envelope, _): (Envelope<SessionOpening>, _) =
+        handlers::check_chatrouille_query(unpacked_query, db_pool, settings)?;
+    if !envelope.body.session {
+        return Err(NorganceChatrouilleError::InvalidSessionOpening);
+    }
+    Ok(envelope.citizen_identifier)
+}
+
+async fn run_session<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: WebSocketStream<S>,
+    root_node: Arc<graphql::Schema>,
+    db_pool: Arc<db::DbPool>,
+    vault_client: Arc<vault::Client>,
+    sessions: Arc<Sessions>,
+    keyring: Arc<keyring::Keyring>,
+    settings: ChatrouilleSettings,
+) {
+    let (mut sink, mut stream) = socket.split();
+
+    let opening_query = match stream.next().await {
+        Some(Ok(Message::Binary(data))) => data,
+        _ => return,
+    };
+    // Nothing can be encrypted for the client without the shared secret
+    let unpacked_query =
+        match chatrouille::unpack_query(&opening_query, &*keyring, settings.max_payload_size) {
+            Ok(unpacked_query) => unpacked_query,
+            Err(_) => {
+                let _ = sink.close().await;
+                return;
+            }
+        };
+
+    let opening = check_session_opening(&unpacked_query, &db_pool, settings);
+    let opening_response = match &opening {
+        Ok(_) => chatrouille::pack_signed_response_with_codec(
+            json!({ "maxLifetime": settings.max_session_lifetime })
+                .to_string()
+                .as_bytes(),
+            &unpacked_query.shared_secret,
+            &chatrouille::query_hash(&unpacked_query.payload),
+            keyring.signing_keypair(),
+            unpacked_query.version,
+            PaddingPolicy::default(),
+            unpacked_query.codec,
+        ),
+        Err(error) => chatrouille::pack_error(
+            error.status().as_u16(),
+            &error.to_string(),
+            &unpacked_query.shared_secret,
+            unpacked_query.version,
+        ),
+    };
+    let opening_response = match opening_response {
+        Ok(opening_response) => opening_response,
+        Err(_) => {
+            let _ = sink.close().await;
+            return;
+        }
+    };
+    let session = Session::server(&unpacked_query.shared_secret, unpacked_query.version);
+    let sent = sink.send(Message::Binary(opening_response)).await;
+    let (mut session, citizen_identifier) = match (session, opening, sent) {
+        (Ok(session), Ok(citizen_identifier), Ok(_)) => (session, citizen_identifier),
+        _ => {
+            let _ = sink.close().await;
+            return;
+        }
+    };
+
+    // The sessions of a citizen end when the citizen changes their password
+    let (registration, revocation) = match &citizen_identifier {
+        Some(identifier) => {
+            let (id, revocation) = sessions.register(identifier);
+            (Some((identifier.clone(), id)), Some(revocation))
+        }
+        None => (None, None),
+    };
+    let context = Arc::new(graphql::Ctx {
+        db_pool,
+        vault_client,
+        sessions: Arc::clone(&sessions),
+        citizen_identifier,
+        signed_query: None,
+    });
+    let (sender, mut receiver) = mpsc::channel::<ServerFrame>(SERVER_FRAMES_BUFFER);
+    let mut subscriptions: HashMap<String, AbortHandle> = HashMap::new();
+    let mut expiration = tokio::time::delay_for(std::time::Duration::from_secs(
+        settings.max_session_lifetime,
+    ));
+    let revocation = async move {
+        match revocation {
+            Some(revocation) => {
+                let _ = revocation.await;
+            }
+            // The anonymous sessions are never revoked
+            None => futures::future::pending::<()>().await,
+        }
+    };
+    tokio::pin!(revocation);
+
+    loop {
+        let server_frame = tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Binary(frame))) => {
+                    // A frame that can't be opened is an attack or a bug, the session ends
+                    let data = match session.open(&frame, settings.max_payload_size) {
+                        Ok(data) => data,
+                        Err(_) => break,
+                    };
+                    match answer_client_frame(&data, &root_node, &context, &sender, &mut subscriptions).await {
+                        Some(server_frame) => server_frame,
+                        None => continue,
+                    }
+                }
+                // The pings are answered by tungstenite
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                _ => break,
+            },
+            Some(server_frame) = receiver.recv() => server_frame,
+            _ = &mut expiration => break,
+            _ = &mut revocation => break,
+        };
+
+        if let Some(id) = &server_frame.completed_subscription {
+            subscriptions.remove(id);
+        }
+        let data = match serde_json::to_vec(&server_frame.json) {
+            Ok(data) => data,
+            Err(_) => break,
+        };
+        let frame = match session.seal(&data, server_frame.padding, unpacked_query.codec) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        if sink.send(Message::Binary(frame)).await.is_err() {
+            break;
+        }
+    }
+
+    if let Some((identifier, id)) = registration {
+        sessions.unregister(&identifier, id);
+    }
+    for subscription in subscriptions.values() {
+        subscription.abort();
+    }
+    let _ = sink.close().await;
+}
+
+/// Answers the queries right away, and starts or stops the subscriptions.
+async fn answer_client_frame(
+    data: &[u8],
+    root_node: &Arc<graphql::Schema>,
+    context: &Arc<graphql::Ctx>,
+    sender: &mpsc::Sender<ServerFrame>,
+    subscriptions: &mut HashMap<String, AbortHandle>,
+) -> Option<ServerFrame> {
+    let client_frame: ClientFrame = match serde_json::from_slice(data) {
+        Ok(client_frame) => client_frame,
+        Err(e) => return Some(error_frame(None, &e.to_string())),
+    };
+
+    match client_frame {
+        ClientFrame::Query { id, graphql } => {
+            let padding = graphql::response_padding_policy(std::iter::once(&graphql));
+            let response = graphql.execute(&**root_node, &**context).await;
+            Some(match serde_json::to_value(&response) {
+                Ok(payload) => data_frame(&id, payload, padding),
+                Err(e) => error_frame(Some(&id), &e.to_string()),
+            })
+        }
+        ClientFrame::Subscribe { id, graphql } => {
+            if subscriptions.contains_key(&id) {
+                return Some(error_frame(Some(&id), "The subscription already exists"));
+            }
+            if subscriptions.len() >= MAX_SUBSCRIPTIONS_PER_SESSION {
+                return Some(error_frame(Some(&id), "Too many subscriptions"));
+            }
+            // The data frames are padded like the answers of the queries
+            let padding = graphql::response_padding_policy(std::iter::once(&graphql));
+            let (abort_handle, abort_registration) = AbortHandle::new_pair();
+            let subscription = run_subscription(
+                id.clone(),
+                graphql,
+                padding,
+                Arc::clone(root_node),
+                Arc::clone(context),
+                sender.clone(),
+            );
+            tokio::spawn(Abortable::new(subscription, abort_registration));
+            subscriptions.insert(id, abort_handle);
+            None
+        }
+        ClientFrame::Stop { id } => match subscriptions.remove(&id) {
+            Some(abort_handle) => {
+                abort_handle.abort();
+                Some(complete_frame(id))
+            }
+            None => None,
+        },
+    }
+}
+
+async fn run_subscription(
+    id: String,
+    request: juniper::http::GraphQLRequest,
+    padding: PaddingPolicy,
+    root_node: Arc<graphql::Schema>,
+    context: Arc<graphql::Ctx>,
+    mut sender: mpsc::Sender<ServerFrame>,
+) {
+    match juniper::http::resolve_into_stream(&request, &*root_node, &*context).await {
+        Ok((values, errors)) => {
+            let mut responses = juniper_subscriptions::Connection::from_stream(values, errors);
+            while let Some(response) = responses.next().await {
+                let server_frame = match serde_json::to_value(&response) {
+                    Ok(payload) => data_frame(&id, payload, padding),
+                    Err(e) => error_frame(Some(&id), &e.to_string()),
+                };
+                // The session is closed
+                if sender.send(server_frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            let _ = sender.send(error_frame(Some(&id), &e.to_string())).await;
+        }
+    }
+    let _ = sender.send(complete_frame(id)).await;
+}
+
+#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::handlers::tests::{create_test_citizen_in_db, TestServer, SETTINGS};
+    use chatrouille::compressor::Codec;
+    use chatrouille::key_utils::{self, SharedSecret};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_test::block_on;
+
+    type ClientSocket = WebSocketStream<TcpStream>;
+
+    fn timestamp() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Runs a session of the test server over a loopback connection.
+    async fn connect(server: &TestServer) -> ClientSocket {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = TcpStream::connect(address).await.unwrap();
+        let (server_stream, _) = listener.accept().await.unwrap();
+
+        let socket = WebSocketStream::from_raw_socket(server_stream, Role::Server, None).await;
+        tokio::spawn(run_session(
+            socket,
+            Arc::clone(&server.root_node),
+            Arc::clone(&server.db_pool),
+            Arc::clone(&server.vault_client),
+            Arc::clone(&server.sessions),
+            Arc::clone(&server.keyring),
+            SETTINGS,
+        ));
+        WebSocketStream::from_raw_socket(client, Role::Client, None).await
+    }
+
+    async fn receive(socket: &mut ClientSocket) -> Option<Vec<u8>> {
+        match socket.next().await {
+            Some(Ok(Message::Binary(data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Sends the opening query, signed by the citizen if there is one,
+    /// and returns the response with the shared secret of the session.
+    async fn open(
+        server: &TestServer,
+        socket: &mut ClientSocket,
+        session: bool,
+        citizen: Option<(&str, &ed25519_dalek::Keypair)>,
+    ) -> (Result<Vec<u8>, chatrouille::ChatrouilleError>, SharedSecret) {
+        let mut payload = json!({ "session": session, "exp": timestamp() + 60 });
+        let (query, shared_secret) = match citizen {
+            Some((identifier, access_keypair)) => {
+                payload["citizenIdentifier"] = json!(identifier);
+                chatrouille::pack_signed_query(
+                    &serde_json::to_vec(&payload).unwrap(),
+                    &server.public_key,
+                    access_keypair,
+                )
+            }
+            None => chatrouille::pack_unsigned_query(
+                &serde_json::to_vec(&payload).unwrap(),
+                &server.public_key,
+            ),
+        }
+        .unwrap();
+        let payload = serde_json::to_vec(&payload).unwrap();
+        socket.send(Message::Binary(query)).await.unwrap();
+
+        let response = receive(socket).await.unwrap();
+        let response = chatrouille::unpack_signed_response(
+            &response,
+            &shared_secret,
+            &chatrouille::query_hash(&payload),
+            &server.keyring.signing_keypair().public,
+            SETTINGS.max_payload_size,
+        )
+        .map(|(response, _)| response);
+        (response, shared_secret)
+    }
+
+    async fn open_session(
+        server: &TestServer,
+        socket: &mut ClientSocket,
+        citizen: Option<(&str, &ed25519_dalek::Keypair)>,
+    ) -> Session {
+        let (response, shared_secret) = open(server, socket, true, citizen).await;
+        let response: serde_json::Value = serde_json::from_slice(&response.unwrap()).unwrap();
+        assert_eq!(response, json!({ "maxLifetime": SETTINGS.max_session_lifetime }));
+        Session::client(&shared_secret, chatrouille::Version::LATEST).unwrap()
+    }
+
+    async fn send_frame(socket: &mut ClientSocket, session: &mut Session, frame: serde_json::Value) {
+        let data = serde_json::to_vec(&frame).unwrap();
+        let frame = session
+            .seal(&data, PaddingPolicy::default(), Codec::default())
+            .unwrap();
+        socket.send(Message::Binary(frame)).await.unwrap();
+    }
+
+    async fn receive_frame(socket: &mut ClientSocket, session: &mut Session) -> serde_json::Value {
+        let frame = receive(socket).await.expect("Expected a session frame");
+        serde_json::from_slice(&session.open(&frame, SETTINGS.max_payload_size).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_session_opening() {
+        let server = TestServer::new();
+        block_on(async {
+            let mut socket = connect(&server).await;
+            open_session(&server, &mut socket, None).await;
+
+            // Without the session flag, the error is encrypted and the session ends
+            let mut socket = connect(&server).await;
+            let (response, _) = open(&server, &mut socket, false, None).await;
+            assert!(matches!(
+                response,
+                Err(chatrouille::ChatrouilleError::ServerError { .. })
+            ));
+            assert!(receive(&mut socket).await.is_none());
+
+            // A query that can't be unpacked closes the session
+            let mut socket = connect(&server).await;
+            socket
+                .send(Message::Binary(b"not a query".to_vec()))
+                .await
+                .unwrap();
+            assert!(receive(&mut socket).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_session_query() {
+        let server = TestServer::new();
+        block_on(async {
+            let mut socket = connect(&server).await;
+            let mut session = open_session(&server, &mut socket, None).await;
+
+            for id in &["1", "2"] {
+                send_frame(
+                    &mut socket,
+                    &mut session,
+                    json!({
+                        "id": id,
+                        "type": "query",
+                        "graphql": { "query": "{ isIdentifierAvailable(identifier: \"session-test\") }" },
+                    }),
+                )
+                .await;
+                let frame = receive_frame(&mut socket, &mut session).await;
+                assert_eq!(frame["id"], json!(id));
+                assert_eq!(frame["type"], json!("data"));
+                assert!(frame["payload"]["data"]["isIdentifierAvailable"].is_boolean());
+            }
+
+            send_frame(&mut socket, &mut session, json!({ "type": "unknown" })).await;
+            let frame = receive_frame(&mut socket, &mut session).await;
+            assert_eq!(frame["type"], json!("error"));
+
+            // A frame that can't be opened ends the session
+            let mut another_session = open_session(&server, &mut connect(&server).await, None).await;
+            send_frame(&mut socket, &mut another_session, json!({ "type": "stop", "id": "1" })).await;
+            assert!(receive(&mut socket).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_session_subscription() {
+        let server = TestServer::new();
+        block_on(async {
+            let mut socket = connect(&server).await;
+            let mut session = open_session(&server, &mut socket, None).await;
+
+            let subscribe = json!({
+                "id": "time",
+                "type": "subscribe",
+                "graphql": { "query": "subscription { serverTime(interval: 1) }" },
+            });
+            send_frame(&mut socket, &mut session, subscribe.clone()).await;
+            let data_frame = receive(&mut socket).await.unwrap();
+            let frame: serde_json::Value = serde_json::from_slice(
+                &session.open(&data_frame, SETTINGS.max_payload_size).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(frame["id"], json!("time"));
+            assert_eq!(frame["type"], json!("data"));
+            assert!(frame["payload"]["data"]["serverTime"].is_number());
+
+            send_frame(&mut socket, &mut session, subscribe).await;
+            let frame = receive_frame(&mut socket, &mut session).await;
+            assert_eq!(frame["type"], json!("error"));
+
+            // The frames already sent by the subscription may arrive before the complete frame
+            send_frame(&mut socket, &mut session, json!({ "id": "time", "type": "stop" })).await;
+            loop {
+                let frame = receive_frame(&mut socket, &mut session).await;
+                assert_eq!(frame["id"], json!("time"));
+                if frame["type"] == json!("complete") {
+                    break;
+                }
+                assert_eq!(frame["type"], json!("data"));
+            }
+
+            // Stopping again does nothing, the next query is answered
+            send_frame(&mut socket, &mut session, json!({ "id": "time", "type": "stop" })).await;
+            send_frame(
+                &mut socket,
+                &mut session,
+                json!({ "id": "3", "type": "query", "graphql": { "query": "{ isIdentifierAvailable(identifier: \"session-test\") }" } }),
+            )
+            .await;
+            let query_frame = receive(&mut socket).await.unwrap();
+            let frame: serde_json::Value = serde_json::from_slice(
+                &session.open(&query_frame, SETTINGS.max_payload_size).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(frame["id"], json!("3"));
+            assert_eq!(frame["type"], json!("data"));
+
+            // The subscriptions follow the padding policy of the GraphQL operation, like the queries
+            assert_eq!(
+                graphql::response_padding_policy(std::iter::once(
+                    &juniper::http::GraphQLRequest::new(
+                        String::from("subscription { serverTime(interval: 1) }"),
+                        None,
+                        None,
+                    )
+                )),
+                PaddingPolicy::PowerOfTwo { minimum: 512 }
+            );
+            assert_eq!(data_frame.len(), query_frame.len());
+        });
+    }
+
+    #[test]
+    fn test_sessions_revoke() {
+        let sessions = Sessions::default();
+        let (first_id, mut first) = sessions.register("citizen");
+        let (_, mut second) = sessions.register("citizen");
+        let (_, mut another) = sessions.register("another citizen");
+        let (closed_id, mut closed) = sessions.register("citizen");
+        assert_ne!(first_id, closed_id);
+        sessions.unregister("citizen", closed_id);
+
+        sessions.revoke("citizen");
+        assert!(first.try_recv().is_ok());
+        assert!(second.try_recv().is_ok());
+        assert!(closed.try_recv().is_err());
+        assert!(another.try_recv().is_err());
+
+        // Unregistering a revoked session does nothing
+        sessions.unregister("citizen", first_id);
+        sessions.revoke("nobody");
+    }
+
+    #[test]
+    fn test_session_password_change() {
+        let server = TestServer::new();
+        let db = server.db_pool.get().expect("Database connection failed");
+        let (identifier, access_keypair, _) = create_test_citizen_in_db(&db);
+
+        // The sessions keep running while the password changes
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let (mut citizen_socket, mut anonymous_socket, mut anonymous_session) =
+            runtime.block_on(async {
+                let mut citizen_socket = connect(&server).await;
+                open_session(
+                    &server,
+                    &mut citizen_socket,
+                    Some((identifier.as_str(), &access_keypair)),
+                )
+                .await;
+                let mut anonymous_socket = connect(&server).await;
+                let anonymous_session = open_session(&server, &mut anonymous_socket, None).await;
+                (citizen_socket, anonymous_socket, anonymous_session)
+            });
+
+        let new_access_key = base64::encode_config(
+            key_utils::gen_ed25519_keypair().public.as_bytes(),
+            base64::STANDARD_NO_PAD,
+        );
+        let response = server.signed_graphql(
+            json!({
+                "operationName": "changePassword",
+                "variables": {
+                    "newAccessKey": new_access_key,
+                    "aeadData": "c2VjcmV0".repeat(8),
+                    "expectedVersion": 1,
+                },
+                "query": "mutation changePassword($newAccessKey: String!, $aeadData: String!, $expectedVersion: Int!) { changePassword(newAccessKey: $newAccessKey, aeadData: $aeadData, expectedVersion: $expectedVersion) { success }}"
+            }),
+            &identifier,
+            &access_keypair,
+        );
+        assert_eq!(
+            response["data"]["changePassword"],
+            json!({ "success": true })
+        );
+
+        runtime.block_on(async {
+            assert!(receive(&mut citizen_socket).await.is_none());
+
+            // The anonymous sessions don't depend on the passwords
+            send_frame(
+                &mut anonymous_socket,
+                &mut anonymous_session,
+                json!({ "id": "1", "type": "query", "graphql": { "query": "{ isIdentifierAvailable(identifier: \"session-test\") }" } }),
+            )
+            .await;
+            let frame = receive_frame(&mut anonymous_socket, &mut anonymous_session).await;
+            assert_eq!(frame["type"], json!("data"));
+        });
+        assert!(server
+            .sessions
+            .revocations
+            .lock()
+            .unwrap()
+            .get(&identifier)
+            .is_none());
+    }
+}
@@ -3,6 +3,10 @@ use std::collections::HashMap;
 use std::env;
 use std::sync::{Arc, RwLock};
 use reqwest::Method;
+use zeroize::Zeroize;
+use chatrouille::x448;
+
+use crate::keyring;
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Snafu)]
@@ -18,6 +22,8 @@ pub enum VaultError {
         source: base64::DecodeError,
     },
     SecretX448Load,
+    MissingX448Keys,
+    SecretMlKemLoad,
     SecretEd25519Load {
         source: ed25519_dalek::ed25519::Error,
     },
@@ -42,12 +48,48 @@ pub enum VaultError {
 
 pub type Result<T, E = VaultError> = std::result::Result<T, E>;
 
+/// A string wiped when it's dropped, and hidden from the debug output.
+#[derive(serde::Deserialize, Default)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    #[must_use]
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> SecretString {
+        SecretString(String::from(value))
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> SecretString {
+        SecretString(value)
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretString(***)")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 pub struct Client {
     addr: String,
     username: String,
-    password: String,
+    password: SecretString,
     client: reqwest::Client,
-    authentication: Arc<RwLock<String>>,
+    authentication: Arc<RwLock<SecretString>>,
 }
 
 impl Client {
@@ -59,18 +101,19 @@ impl Client {
 
         Ok(Client {
             addr: String::from(addr),
-            authentication: Arc::new(RwLock::new(String::new())),
+            authentication: Arc::new(RwLock::new(SecretString::default())),
             client,
             username: String::from(username),
-            password: String::from(password),
+            password: SecretString::from(password),
         })
     }
 
     pub async fn from_env() -> Result<Client> {
         let vault_addr = env::var("VAULT_ADDR").context(MissingVaultAddr)?;
-        let vault_credentials = env::var("VAULT_CREDENTIALS").context(MissingVaultCredentials)?;
+        let vault_credentials =
+            SecretString::from(env::var("VAULT_CREDENTIALS").context(MissingVaultCredentials)?);
 
-        let op: Vec<&str> = vault_credentials.split(':').collect();
+        let op: Vec<&str> = vault_credentials.expose().split(':').collect();
         let username = op.get(0).context(WrongCredentials)?;
         let password = op.get(1).context(WrongCredentials)?;
 
@@ -93,7 +136,7 @@ impl Client {
                 .to_string(),
             ))
             .json(&LoginPayload {
-                password: String::from(&self.password),
+                password: self.password.expose(),
             })
             .send()
             .await
@@ -104,8 +147,7 @@ impl Client {
             .await
             .context(ResultParsingError)?;
 
-        let token = response.auth.client_token;
-        let bearer = String::from("Bearer ") + &token;
+        let bearer = SecretString::from(String::from("Bearer ") + response.auth.client_token.expose());
 
         {
             let mut authentication = match self.authentication.write() {
@@ -119,18 +161,18 @@ impl Client {
     }
 
     async fn request(&self, method: Method, raw_path: &str) -> Result<reqwest::Response> {
-        let authentication: String;
+        let authentication: SecretString;
         {
             let authentication_rwlock_guard = match self.authentication.read() {
                 Ok(a) => a,
                 Err(_) => return Err(VaultError::AuthenticationLock),
             };
-            authentication = authentication_rwlock_guard.clone();
+            authentication = SecretString::from(authentication_rwlock_guard.expose());
         }
 
         self.client
             .request(method, &format!("{}/v1/{}", &self.addr, raw_path,))
-            .header(reqwest::header::AUTHORIZATION, authentication)
+            .header(reqwest::header::AUTHORIZATION, authentication.expose())
             .send()
             .await
             .context(QueryError)?
@@ -200,9 +242,9 @@ impl Client {
     }
 }
 
-#[derive(serde::Serialize, Debug)]
-struct LoginPayload {
-    password: String,
+#[derive(serde::Serialize)]
+struct LoginPayload<'a> {
+    password: &'a str,
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -212,7 +254,7 @@ struct LoginResponse {
 
 #[derive(serde::Deserialize, Debug)]
 struct LoginAuthResponse {
-    client_token: String,
+    client_token: SecretString,
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -227,20 +269,78 @@ struct SecretDataResponse {
 
 #[derive(serde::Deserialize, Debug)]
 struct SecretDataSecretsPackage {
-    x448_private_key: String,
-    ed25519_private_key: String,
+    // A single key, valid forever, used when there is no keyring
+    #[serde(default)]
+    x448_private_key: Option<SecretString>,
+    // The ML-KEM seed of the single key, for the hybrid owl version
+    #[serde(default)]
+    mlkem768_private_key: Option<SecretString>,
+    #[serde(default)]
+    x448_keyring: Vec<SecretDataKeyringEntry>,
+    ed25519_private_key: SecretString,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct SecretDataKeyringEntry {
+    private_key: SecretString,
+    #[serde(default)]
+    mlkem768_private_key: Option<SecretString>,
+    activation_time: u64,
+    retirement_time: Option<u64>,
+}
+
+fn load_private_key_x448(
+    private_key_base64: &SecretString,
+) -> Result<chatrouille::key_utils::ZeroizingSecret> {
+    let mut secret_bytes = base64::decode(private_key_base64.expose()).context(Base64Decode)?;
+    let secret = x448::Secret::from_bytes(&secret_bytes);
+    secret_bytes.zeroize();
+    Ok(secret.context(SecretX448Load)?.into())
+}
+
+fn load_private_key_mlkem(
+    private_key_base64: Option<&SecretString>,
+) -> Result<Option<chatrouille::mlkem::DecapsulationKey>> {
+    match private_key_base64 {
+        Some(private_key_base64) => {
+            chatrouille::key_utils::mlkem_private_key_from_base64(private_key_base64.expose())
+                .context(SecretMlKemLoad)
+                .map(Some)
+        }
+        None => Ok(None),
+    }
 }
 
 impl SecretDataSecretsPackage {
-    pub fn get_private_key_x448(&self) -> Result<x448::Secret> {
-        let secret_bytes = base64::decode(&self.x448_private_key).context(Base64Decode)?;
-        let secret = x448::Secret::from_bytes(&secret_bytes).context(SecretX448Load)?;
-        Ok(secret)
+    pub fn get_keyring_x448(&self) -> Result<Vec<keyring::KeyringEntry>> {
+        if self.x448_keyring.is_empty() {
+            let private_key_base64 = self.x448_private_key.as_ref().context(MissingX448Keys)?;
+            return Ok(vec![keyring::KeyringEntry {
+                private_key: load_private_key_x448(private_key_base64)?,
+                mlkem_private_key: load_private_key_mlkem(self.mlkem768_private_key.as_ref())?,
+                activation_time: 0,
+                retirement_time: None,
+            }]);
+        }
+
+        self.x448_keyring
+            .iter()
+            .map(|entry| {
+                Ok(keyring::KeyringEntry {
+                    private_key: load_private_key_x448(&entry.private_key)?,
+                    mlkem_private_key: load_private_key_mlkem(entry.mlkem768_private_key.as_ref())?,
+                    activation_time: entry.activation_time,
+                    retirement_time: entry.retirement_time,
+                })
+            })
+            .collect()
     }
     pub fn get_keypair_ed25519(&self) -> Result<ed25519_dalek::Keypair> {
-        let secret_bytes = base64::decode(&self.ed25519_private_key).context(Base64Decode)?;
-        let secret =
-            ed25519_dalek::SecretKey::from_bytes(&secret_bytes).context(SecretEd25519Load)?;
+        let mut secret_bytes =
+            base64::decode(self.ed25519_private_key.expose()).context(Base64Decode)?;
+        let secret = ed25519_dalek::SecretKey::from_bytes(&secret_bytes);
+        secret_bytes.zeroize();
+        let secret = secret.context(SecretEd25519Load)?;
         let public: ed25519_dalek::PublicKey = (&secret).into();
         let keypair = ed25519_dalek::Keypair { public, secret };
         Ok(keypair)
@@ -248,7 +348,7 @@ impl SecretDataSecretsPackage {
 }
 
 pub struct ServerPrivateSecrets {
-    pub x448_private_key: x448::Secret,
+    pub x448_keyring: Vec<keyring::KeyringEntry>,
     pub ed25519_keypair: ed25519_dalek::Keypair,
 }
 
@@ -256,10 +356,10 @@ impl std::convert::TryFrom<SecretDataSecretsPackage> for ServerPrivateSecrets {
     type Error = VaultError;
 
     fn try_from(item: SecretDataSecretsPackage) -> Result<Self, Self::Error> {
-        let x448_private_key = item.get_private_key_x448()?;
+        let x448_keyring = item.get_keyring_x448()?;
         let ed25519_keypair = item.get_keypair_ed25519()?;
         Ok(ServerPrivateSecrets {
-            x448_private_key,
+            x448_keyring,
             ed25519_keypair,
         })
     }
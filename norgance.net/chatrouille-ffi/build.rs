@@ -0,0 +1,18 @@
+//! Generates the C header in the build directory.
+//! The tests compare it with include/chatrouille.h, the committed header.
+
+fn main() {
+  let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is set by cargo");
+  let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+
+  println!("cargo:rerun-if-changed=src/lib.rs");
+  println!("cargo:rerun-if-changed=cbindgen.toml");
+
+  let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).expect("Unable to read cbindgen.toml");
+  cbindgen::Builder::new()
+    .with_crate(&crate_dir)
+    .with_config(config)
+    .generate()
+    .expect("Unable to generate the C header")
+    .write_to_file(format!("{}/chatrouille.h", out_dir));
+}
@@ -0,0 +1,193 @@
+/*
+ * C bindings of the chatrouille client.
+ *
+ * The input pointers are borrowed for the duration of the calls.
+ * The buffers, strings and queries returned by the library belong to the caller,
+ * and are released with chatrouille_buffer_free, chatrouille_string_free
+ * and chatrouille_query_free. The outputs are reset at the start of each call,
+ * so they can be released even after an error.
+ */
+
+#ifndef CHATROUILLE_H
+#define CHATROUILLE_H
+
+/* Generated by cbindgen from src/lib.rs, don't edit it by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef enum ChatrouilleStatus {
+  CHATROUILLE_STATUS_OK = 0,
+  CHATROUILLE_STATUS_NULL_POINTER = 1,
+  CHATROUILLE_STATUS_INVALID_KEY = 2,
+  CHATROUILLE_STATUS_INVALID_BASE64 = 3,
+  CHATROUILLE_STATUS_PACK_ERROR = 4,
+  CHATROUILLE_STATUS_UNPACK_ERROR = 5,
+  CHATROUILLE_STATUS_SERVER_ERROR = 6,
+  CHATROUILLE_STATUS_INVALID_SIGNATURE = 7,
+  CHATROUILLE_STATUS_MISSING_SIGNING_KEY = 8,
+  CHATROUILLE_STATUS_PANIC = 9,
+} ChatrouilleStatus;
+
+/**
+ * What the client keeps to unpack the response of a query.
+ */
+typedef struct ChatrouilleQuery ChatrouilleQuery;
+
+/**
+ * Bytes allocated by the library, to release with `chatrouille_buffer_free`.
+ */
+typedef struct ChatrouilleBuffer {
+  uint8_t *data;
+  size_t len;
+} ChatrouilleBuffer;
+
+/**
+ * Returns a static string describing the status, it must not be freed.
+ */
+const char *chatrouille_status_message(enum ChatrouilleStatus status);
+
+/**
+ * Wipes and releases a buffer returned by the library. Freeing an empty buffer does nothing.
+ *
+ * # Safety
+ * The buffer must come from the library, and must not be used after.
+ */
+void chatrouille_buffer_free(struct ChatrouilleBuffer *buffer);
+
+/**
+ * Releases a string returned by the library.
+ *
+ * # Safety
+ * The string must come from the library, and must not be used after.
+ */
+void chatrouille_string_free(char *string);
+
+/**
+ * Releases a query returned by `chatrouille_pack_signed_query` or `chatrouille_pack_unsigned_query`.
+ *
+ * # Safety
+ * The query must come from the library, and must not be used after.
+ */
+void chatrouille_query_free(struct ChatrouilleQuery *query);
+
+/**
+ * Generates a x448 private key (56 bytes), such as a server key for the tests.
+ *
+ * # Safety
+ * `private_key` must point to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_gen_private_key(struct ChatrouilleBuffer *private_key);
+
+/**
+ * The x448 public key (56 bytes) of a private key.
+ *
+ * # Safety
+ * `private_key` must point to `private_key_len` bytes, and `public_key` to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_public_key(const uint8_t *private_key,
+                                              size_t private_key_len,
+                                              struct ChatrouilleBuffer *public_key);
+
+/**
+ * Generates the ed25519 keypair signing the queries of a client,
+ * the 32 bytes of the secret key followed by the 32 bytes of the public key.
+ *
+ * # Safety
+ * `keypair` must point to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_gen_ed25519_keypair(struct ChatrouilleBuffer *keypair);
+
+/**
+ * Encodes a x448 public key in base64 without padding, like the Norgance server.
+ *
+ * # Safety
+ * `public_key` must point to `public_key_len` bytes, and `base64` to a string pointer.
+ */
+enum ChatrouilleStatus chatrouille_public_key_to_base64(const uint8_t *public_key,
+                                                        size_t public_key_len,
+                                                        char **base64);
+
+/**
+ * Decodes a x448 public key, such as the key given by the chatrouille information endpoint.
+ *
+ * # Safety
+ * `base64` must be a null terminated string, and `public_key` must point to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_public_key_from_base64(const char *base64,
+                                                          struct ChatrouilleBuffer *public_key);
+
+/**
+ * Encodes a x448 private key in base64 without padding.
+ *
+ * # Safety
+ * `private_key` must point to `private_key_len` bytes, and `base64` to a string pointer.
+ */
+enum ChatrouilleStatus chatrouille_private_key_to_base64(const uint8_t *private_key,
+                                                         size_t private_key_len,
+                                                         char **base64);
+
+/**
+ * Decodes a x448 private key.
+ *
+ * # Safety
+ * `base64` must be a null terminated string, and `private_key` must point to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_private_key_from_base64(const char *base64,
+                                                           struct ChatrouilleBuffer *private_key);
+
+/**
+ * Packs a query signed with the client ed25519 keypair (64 bytes) for the x448 public key
+ * of the server (56 bytes). The packed query is sent to the server, and the query is kept
+ * to unpack the response.
+ *
+ * # Safety
+ * The input pointers must point to their length of bytes, `query` to a query pointer
+ * and `packed_query` to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_pack_signed_query(const uint8_t *payload,
+                                                     size_t payload_len,
+                                                     const uint8_t *server_public_key,
+                                                     size_t server_public_key_len,
+                                                     const uint8_t *client_keypair,
+                                                     size_t client_keypair_len,
+                                                     struct ChatrouilleQuery **query,
+                                                     struct ChatrouilleBuffer *packed_query);
+
+/**
+ * Same as `chatrouille_pack_signed_query`, without signature.
+ *
+ * # Safety
+ * The input pointers must point to their length of bytes, `query` to a query pointer
+ * and `packed_query` to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_pack_unsigned_query(const uint8_t *payload,
+                                                       size_t payload_len,
+                                                       const uint8_t *server_public_key,
+                                                       size_t server_public_key_len,
+                                                       struct ChatrouilleQuery **query,
+                                                       struct ChatrouilleBuffer *packed_query);
+
+/**
+ * Unpacks the response of a query. The signed responses are verified with the ed25519
+ * signing public key of the server (32 bytes), it can be null if the responses aren't signed.
+ * When it's given, the responses that aren't signed are refused with
+ * `CHATROUILLE_STATUS_INVALID_SIGNATURE`, except the errors, so the signature can't be stripped.
+ *
+ * With `CHATROUILLE_STATUS_SERVER_ERROR`, the payload is the error message of the server,
+ * and its HTTP status is written in `server_error_code` if it isn't null.
+ *
+ * # Safety
+ * `query` must come from the library, the input pointers must point to their length
+ * of bytes, and `payload` must point to a buffer.
+ */
+enum ChatrouilleStatus chatrouille_unpack_response(const struct ChatrouilleQuery *query,
+                                                   const uint8_t *packed_response,
+                                                   size_t packed_response_len,
+                                                   const uint8_t *server_signing_public_key,
+                                                   size_t server_signing_public_key_len,
+                                                   size_t max_payload_len,
+                                                   struct ChatrouilleBuffer *payload,
+                                                   uint16_t *server_error_code);
+
+#endif /* CHATROUILLE_H */
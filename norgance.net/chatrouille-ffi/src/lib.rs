@@ -0,0 +1,524 @@
+/*!
+ * C bindings of the chatrouille client, so the services written in other languages
+ * can send queries to the Norgance server.
+ *
+ * The header is generated by cbindgen, include/chatrouille.h is checked by the tests.
+ *
+ * Ownership:
+ * - The input pointers are borrowed for the duration of the call, the library doesn't keep them.
+ *   A null data pointer is accepted for an empty input.
+ * - Every buffer, string and query returned through an output pointer belongs to the caller,
+ *   and must be released with `chatrouille_buffer_free`, `chatrouille_string_free`
+ *   or `chatrouille_query_free`. The buffers are wiped when they are freed.
+ * - The outputs are reset before anything else, so they can be freed even after an error.
+ *
+ * Every function returns a status, `CHATROUILLE_STATUS_OK` on success.
+ * A panic in the library is caught and returned as `CHATROUILLE_STATUS_PANIC`.
+ */
+#![warn(clippy::all, clippy::pedantic, clippy::unwrap_used)]
+#![allow(clippy::missing_errors_doc, clippy::module_name_repetitions)]
+
+use chatrouille::key_utils::{self, SharedSecret};
+use chatrouille::{x448, ChatrouilleError, Mode};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic;
+use zeroize::Zeroize;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatrouilleStatus {
+  Ok = 0,
+  NullPointer = 1,
+  InvalidKey = 2,
+  InvalidBase64 = 3,
+  PackError = 4,
+  UnpackError = 5,
+  // The response is an error sent by the server, its message is in the payload
+  ServerError = 6,
+  InvalidSignature = 7,
+  // A signed response can't be unpacked without the server signing public key
+  MissingSigningKey = 8,
+  Panic = 9,
+}
+
+/// Bytes allocated by the library, to release with `chatrouille_buffer_free`.
+#[repr(C)]
+pub struct ChatrouilleBuffer {
+  pub data: *mut u8,
+  pub len: usize,
+}
+
+impl ChatrouilleBuffer {
+  fn empty() -> ChatrouilleBuffer {
+    ChatrouilleBuffer {
+      data: std::ptr::null_mut(),
+      len: 0,
+    }
+  }
+
+  fn from_vec(data: Vec<u8>) -> ChatrouilleBuffer {
+    let data = data.into_boxed_slice();
+    // The length is kept in the buffer, to rebuild the box when it's freed
+    let len = data.len();
+    ChatrouilleBuffer {
+      data: Box::into_raw(data).cast::<u8>(),
+      len,
+    }
+  }
+}
+
+/// What the client keeps to unpack the response of a query.
+pub struct ChatrouilleQuery {
+  shared_secret: SharedSecret,
+  query_hash: Vec<u8>,
+}
+
+fn guard<F: FnOnce() -> ChatrouilleStatus>(function: F) -> ChatrouilleStatus {
+  match panic::catch_unwind(panic::AssertUnwindSafe(function)) {
+    Ok(status) => status,
+    Err(_) => ChatrouilleStatus::Panic,
+  }
+}
+
+/// A null pointer is an empty slice only if the length is zero.
+unsafe fn input_slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+  if data.is_null() {
+    return match len {
+      0 => Some(&[]),
+      _ => None,
+    };
+  }
+  Some(std::slice::from_raw_parts(data, len))
+}
+
+unsafe fn input_str<'a>(data: *const c_char) -> Option<&'a str> {
+  if data.is_null() {
+    return None;
+  }
+  CStr::from_ptr(data).to_str().ok()
+}
+
+/// Returns a static string describing the status, it must not be freed.
+#[no_mangle]
+pub extern "C" fn chatrouille_status_message(status: ChatrouilleStatus) -> *const c_char {
+  let message: &'static [u8] = match status {
+    ChatrouilleStatus::Ok => b"ok\0",
+    ChatrouilleStatus::NullPointer => b"null pointer\0",
+    ChatrouilleStatus::InvalidKey => b"invalid key\0",
+    ChatrouilleStatus::InvalidBase64 => b"invalid base64\0",
+    ChatrouilleStatus::PackError => b"unable to pack the query\0",
+    ChatrouilleStatus::UnpackError => b"unable to unpack the response\0",
+    ChatrouilleStatus::ServerError => b"error from the server\0",
+    ChatrouilleStatus::InvalidSignature => b"invalid signature\0",
+    ChatrouilleStatus::MissingSigningKey => b"missing server signing key\0",
+    ChatrouilleStatus::Panic => b"panic\0",
+  };
+  message.as_ptr().cast::<c_char>()
+}
+
+/// Wipes and releases a buffer returned by the library. Freeing an empty buffer does nothing.
+///
+/// # Safety
+/// The buffer must come from the library, and must not be used after.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_buffer_free(buffer: *mut ChatrouilleBuffer) {
+  if buffer.is_null() || (*buffer).data.is_null() {
+    return;
+  }
+  let mut data = Box::from_raw(std::ptr::slice_from_raw_parts_mut((*buffer).data, (*buffer).len));
+  data.zeroize();
+  *buffer = ChatrouilleBuffer::empty();
+}
+
+/// Releases a string returned by the library.
+///
+/// # Safety
+/// The string must come from the library, and must not be used after.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_string_free(string: *mut c_char) {
+  if string.is_null() {
+    return;
+  }
+  let mut bytes = CString::from_raw(string).into_bytes();
+  bytes.zeroize();
+}
+
+/// Releases a query returned by `chatrouille_pack_signed_query` or `chatrouille_pack_unsigned_query`.
+///
+/// # Safety
+/// The query must come from the library, and must not be used after.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_query_free(query: *mut ChatrouilleQuery) {
+  if !query.is_null() {
+    drop(Box::from_raw(query));
+  }
+}
+
+/// Generates a x448 private key (56 bytes), such as a server key for the tests.
+///
+/// # Safety
+/// `private_key` must point to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_gen_private_key(private_key: *mut ChatrouilleBuffer) -> ChatrouilleStatus {
+  guard(|| {
+    if private_key.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *private_key = ChatrouilleBuffer::empty();
+    let secret = key_utils::ZeroizingSecret::from(key_utils::gen_private_key());
+    *private_key = ChatrouilleBuffer::from_vec(secret.as_bytes().to_vec());
+    ChatrouilleStatus::Ok
+  })
+}
+
+/// The x448 public key (56 bytes) of a private key.
+///
+/// # Safety
+/// `private_key` must point to `private_key_len` bytes, and `public_key` to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_public_key(
+  private_key: *const u8,
+  private_key_len: usize,
+  public_key: *mut ChatrouilleBuffer,
+) -> ChatrouilleStatus {
+  guard(|| {
+    if public_key.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *public_key = ChatrouilleBuffer::empty();
+    let private_key = match input_slice(private_key, private_key_len) {
+      Some(private_key) => private_key,
+      None => return ChatrouilleStatus::NullPointer,
+    };
+    let secret = match x448::Secret::from_bytes(private_key) {
+      Some(secret) => key_utils::ZeroizingSecret::from(secret),
+      None => return ChatrouilleStatus::InvalidKey,
+    };
+    *public_key = ChatrouilleBuffer::from_vec(key_utils::gen_public_key(&secret).as_bytes().to_vec());
+    ChatrouilleStatus::Ok
+  })
+}
+
+/// Generates the ed25519 keypair signing the queries of a client,
+/// the 32 bytes of the secret key followed by the 32 bytes of the public key.
+///
+/// # Safety
+/// `keypair` must point to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_gen_ed25519_keypair(keypair: *mut ChatrouilleBuffer) -> ChatrouilleStatus {
+  guard(|| {
+    if keypair.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *keypair = ChatrouilleBuffer::empty();
+    *keypair = ChatrouilleBuffer::from_vec(key_utils::gen_ed25519_keypair().to_bytes().to_vec());
+    ChatrouilleStatus::Ok
+  })
+}
+
+unsafe fn key_to_base64(key: &[u8], base64: *mut *mut c_char) -> ChatrouilleStatus {
+  match CString::new(base64::encode_config(key, base64::STANDARD_NO_PAD)) {
+    Ok(string) => {
+      *base64 = string.into_raw();
+      ChatrouilleStatus::Ok
+    }
+    Err(_) => ChatrouilleStatus::InvalidBase64,
+  }
+}
+
+/// Encodes a x448 public key in base64 without padding, like the Norgance server.
+///
+/// # Safety
+/// `public_key` must point to `public_key_len` bytes, and `base64` to a string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_public_key_to_base64(
+  public_key: *const u8,
+  public_key_len: usize,
+  base64: *mut *mut c_char,
+) -> ChatrouilleStatus {
+  guard(|| {
+    if base64.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *base64 = std::ptr::null_mut();
+    let public_key = match input_slice(public_key, public_key_len) {
+      Some(public_key) => public_key,
+      None => return ChatrouilleStatus::NullPointer,
+    };
+    match x448::PublicKey::from_bytes(public_key) {
+      Some(public_key) => key_to_base64(public_key.as_bytes(), base64),
+      None => ChatrouilleStatus::InvalidKey,
+    }
+  })
+}
+
+/// Decodes a x448 public key, such as the key given by the chatrouille information endpoint.
+///
+/// # Safety
+/// `base64` must be a null terminated string, and `public_key` must point to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_public_key_from_base64(
+  base64: *const c_char,
+  public_key: *mut ChatrouilleBuffer,
+) -> ChatrouilleStatus {
+  guard(|| {
+    if public_key.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *public_key = ChatrouilleBuffer::empty();
+    let base64 = match input_str(base64) {
+      Some(base64) => base64,
+      None => return ChatrouilleStatus::InvalidBase64,
+    };
+    match key_utils::public_key_from_base64(base64) {
+      Some(key) => {
+        *public_key = ChatrouilleBuffer::from_vec(key.as_bytes().to_vec());
+        ChatrouilleStatus::Ok
+      }
+      None => ChatrouilleStatus::InvalidKey,
+    }
+  })
+}
+
+/// Encodes a x448 private key in base64 without padding.
+///
+/// # Safety
+/// `private_key` must point to `private_key_len` bytes, and `base64` to a string pointer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_private_key_to_base64(
+  private_key: *const u8,
+  private_key_len: usize,
+  base64: *mut *mut c_char,
+) -> ChatrouilleStatus {
+  guard(|| {
+    if base64.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *base64 = std::ptr::null_mut();
+    let private_key = match input_slice(private_key, private_key_len) {
+      Some(private_key) => private_key,
+      None => return ChatrouilleStatus::NullPointer,
+    };
+    match x448::Secret::from_bytes(private_key) {
+      Some(secret) => key_to_base64(key_utils::ZeroizingSecret::from(secret).as_bytes(), base64),
+      None => ChatrouilleStatus::InvalidKey,
+    }
+  })
+}
+
+/// Decodes a x448 private key.
+///
+/// # Safety
+/// `base64` must be a null terminated string, and `private_key` must point to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_private_key_from_base64(
+  base64: *const c_char,
+  private_key: *mut ChatrouilleBuffer,
+) -> ChatrouilleStatus {
+  guard(|| {
+    if private_key.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *private_key = ChatrouilleBuffer::empty();
+    let base64 = match input_str(base64) {
+      Some(base64) => base64,
+      None => return ChatrouilleStatus::InvalidBase64,
+    };
+    match key_utils::private_key_from_base64(base64) {
+      Some(secret) => {
+        let secret = key_utils::ZeroizingSecret::from(secret);
+        *private_key = ChatrouilleBuffer::from_vec(secret.as_bytes().to_vec());
+        ChatrouilleStatus::Ok
+      }
+      None => ChatrouilleStatus::InvalidKey,
+    }
+  })
+}
+
+unsafe fn pack_query(
+  payload: *const u8,
+  payload_len: usize,
+  server_public_key: *const u8,
+  server_public_key_len: usize,
+  client_keypair: Option<(*const u8, usize)>,
+  query: *mut *mut ChatrouilleQuery,
+  packed_query: *mut ChatrouilleBuffer,
+) -> ChatrouilleStatus {
+  if query.is_null() || packed_query.is_null() {
+    return ChatrouilleStatus::NullPointer;
+  }
+  *query = std::ptr::null_mut();
+  *packed_query = ChatrouilleBuffer::empty();
+
+  let (payload, server_public_key) = match (
+    input_slice(payload, payload_len),
+    input_slice(server_public_key, server_public_key_len),
+  ) {
+    (Some(payload), Some(server_public_key)) => (payload, server_public_key),
+    _ => return ChatrouilleStatus::NullPointer,
+  };
+  let server_public_key = match x448::PublicKey::from_bytes(server_public_key) {
+    Some(key) => key,
+    None => return ChatrouilleStatus::InvalidKey,
+  };
+
+  let packed = match client_keypair {
+    Some((client_keypair, client_keypair_len)) => {
+      let client_keypair = match input_slice(client_keypair, client_keypair_len) {
+        Some(client_keypair) => client_keypair,
+        None => return ChatrouilleStatus::NullPointer,
+      };
+      let client_keypair = match ed25519_dalek::Keypair::from_bytes(client_keypair) {
+        Ok(keypair) => keypair,
+        Err(_) => return ChatrouilleStatus::InvalidKey,
+      };
+      chatrouille::pack_signed_query(payload, &server_public_key, &client_keypair)
+    }
+    None => chatrouille::pack_unsigned_query(payload, &server_public_key),
+  };
+  let (packed_data, shared_secret) = match packed {
+    Ok(packed) => packed,
+    Err(_) => return ChatrouilleStatus::PackError,
+  };
+
+  *query = Box::into_raw(Box::new(ChatrouilleQuery {
+    shared_secret,
+    query_hash: chatrouille::query_hash(payload),
+  }));
+  *packed_query = ChatrouilleBuffer::from_vec(packed_data);
+  ChatrouilleStatus::Ok
+}
+
+/// Packs a query signed with the client ed25519 keypair (64 bytes) for the x448 public key
+/// of the server (56 bytes). The packed query is sent to the server, and the query is kept
+/// to unpack the response.
+///
+/// # Safety
+/// The input pointers must point to their length of bytes, `query` to a query pointer
+/// and `packed_query` to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_pack_signed_query(
+  payload: *const u8,
+  payload_len: usize,
+  server_public_key: *const u8,
+  server_public_key_len: usize,
+  client_keypair: *const u8,
+  client_keypair_len: usize,
+  query: *mut *mut ChatrouilleQuery,
+  packed_query: *mut ChatrouilleBuffer,
+) -> ChatrouilleStatus {
+  guard(|| {
+    pack_query(
+      payload,
+      payload_len,
+      server_public_key,
+      server_public_key_len,
+      Some((client_keypair, client_keypair_len)),
+      query,
+      packed_query,
+    )
+  })
+}
+
+/// Same as `chatrouille_pack_signed_query`, without signature.
+///
+/// # Safety
+/// The input pointers must point to their length of bytes, `query` to a query pointer
+/// and `packed_query` to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_pack_unsigned_query(
+  payload: *const u8,
+  payload_len: usize,
+  server_public_key: *const u8,
+  server_public_key_len: usize,
+  query: *mut *mut ChatrouilleQuery,
+  packed_query: *mut ChatrouilleBuffer,
+) -> ChatrouilleStatus {
+  guard(|| {
+    pack_query(
+      payload,
+      payload_len,
+      server_public_key,
+      server_public_key_len,
+      None,
+      query,
+      packed_query,
+    )
+  })
+}
+
+/// Unpacks the response of a query. The signed responses are verified with the ed25519
+/// signing public key of the server (32 bytes), it can be null if the responses aren't signed.
+/// When it's given, the responses that aren't signed are refused with
+/// `CHATROUILLE_STATUS_INVALID_SIGNATURE`, except the errors, so the signature can't be stripped.
+///
+/// With `CHATROUILLE_STATUS_SERVER_ERROR`, the payload is the error message of the server,
+/// and its HTTP status is written in `server_error_code` if it isn't null.
+///
+/// # Safety
+/// `query` must come from the library, the input pointers must point to their length
+/// of bytes, and `payload` must point to a buffer.
+#[no_mangle]
+pub unsafe extern "C" fn chatrouille_unpack_response(
+  query: *const ChatrouilleQuery,
+  packed_response: *const u8,
+  packed_response_len: usize,
+  server_signing_public_key: *const u8,
+  server_signing_public_key_len: usize,
+  max_payload_len: usize,
+  payload: *mut ChatrouilleBuffer,
+  server_error_code: *mut u16,
+) -> ChatrouilleStatus {
+  guard(|| {
+    if query.is_null() || payload.is_null() {
+      return ChatrouilleStatus::NullPointer;
+    }
+    *payload = ChatrouilleBuffer::empty();
+    let query = &*query;
+    let packed_response = match input_slice(packed_response, packed_response_len) {
+      Some(packed_response) => packed_response,
+      None => return ChatrouilleStatus::NullPointer,
+    };
+
+    let unpacked = match chatrouille::packet_mode(packed_response) {
+      Mode::SignedResponse => {
+        if server_signing_public_key.is_null() {
+          return ChatrouilleStatus::MissingSigningKey;
+        }
+        let server_signing_public_key = std::slice::from_raw_parts(server_signing_public_key, server_signing_public_key_len);
+        let server_signing_public_key = match ed25519_dalek::PublicKey::from_bytes(server_signing_public_key) {
+          Ok(key) => key,
+          Err(_) => return ChatrouilleStatus::InvalidKey,
+        };
+        chatrouille::unpack_signed_response(
+          packed_response,
+          &query.shared_secret,
+          &query.query_hash,
+          &server_signing_public_key,
+          max_payload_len,
+        )
+        .map(|(data, _)| data)
+      }
+      Mode::Error => chatrouille::unpack_response(packed_response, &query.shared_secret, max_payload_len),
+      _ if !server_signing_public_key.is_null() => return ChatrouilleStatus::InvalidSignature,
+      _ => chatrouille::unpack_response(packed_response, &query.shared_secret, max_payload_len),
+    };
+
+    match unpacked {
+      Ok(data) => {
+        *payload = ChatrouilleBuffer::from_vec(data);
+        ChatrouilleStatus::Ok
+      }
+      Err(ChatrouilleError::ServerError { code, message }) => {
+        if !server_error_code.is_null() {
+          *server_error_code = code;
+        }
+        *payload = ChatrouilleBuffer::from_vec(message.into_bytes());
+        ChatrouilleStatus::ServerError
+      }
+      Err(ChatrouilleError::VerifySignatureError { .. }) => ChatrouilleStatus::InvalidSignature,
+      Err(_) => ChatrouilleStatus::UnpackError,
+    }
+  })
+}
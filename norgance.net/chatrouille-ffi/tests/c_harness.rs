@@ -0,0 +1,152 @@
+//! Builds tests/harness.c against the shared library, and answers its queries.
+
+use chatrouille::key_utils;
+use chatrouille::padding::PaddingPolicy;
+use chatrouille::VerifyUnpackedQuerySignature;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+const MAX_PAYLOAD_LENGTH: usize = 4096;
+
+/// `cargo test` builds the shared library in the deps directory, next to the test.
+/// The copy in the parent directory is only updated by `cargo build`, it can be outdated.
+fn library_dir() -> PathBuf {
+  let mut dir = std::env::current_exe().expect("test executable path");
+  dir.pop();
+  dir
+}
+
+fn build_harness() -> PathBuf {
+  let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+  let library_dir = library_dir();
+  let harness = library_dir.join("chatrouille_harness");
+  let cc = std::env::var("CC").unwrap_or_else(|_| String::from("cc"));
+
+  let status = Command::new(cc)
+    .arg("-std=c99")
+    .arg("-Wall")
+    .arg("-Wextra")
+    .arg("-Werror")
+    .arg("-I")
+    .arg(manifest_dir.join("include"))
+    .arg(manifest_dir.join("tests/harness.c"))
+    .arg("-o")
+    .arg(&harness)
+    .arg("-L")
+    .arg(&library_dir)
+    .arg(format!("-Wl,-rpath,{}", library_dir.display()))
+    .arg("-lchatrouille_ffi")
+    .status()
+    .expect("Unable to run the C compiler");
+  assert!(status.success(), "Unable to build the harness");
+  harness
+}
+
+fn read_message(reader: &mut impl BufRead, name: &str) -> Vec<u8> {
+  let mut line = String::new();
+  reader.read_line(&mut line).expect("read the harness output");
+  let prefix = format!("{} ", name);
+  assert!(line.starts_with(&prefix), "Unexpected message: {}", line);
+  hex::decode(line[prefix.len()..].trim_end()).expect("hexadecimal message")
+}
+
+/// The header is generated in the build directory, the committed one must be the same.
+/// `CHATROUILLE_WRITE_HEADER=1 cargo test` updates it.
+#[test]
+fn test_header() {
+  let generated = include_str!(concat!(env!("OUT_DIR"), "/chatrouille.h"));
+  let committed_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("include/chatrouille.h");
+  if std::env::var_os("CHATROUILLE_WRITE_HEADER").is_some() {
+    std::fs::write(&committed_path, generated).expect("write the header");
+  }
+  let committed = std::fs::read_to_string(&committed_path).expect("read the header");
+  assert!(
+    committed == generated,
+    "include/chatrouille.h is outdated, run CHATROUILLE_WRITE_HEADER=1 cargo test"
+  );
+}
+
+#[test]
+fn test_c_harness() {
+  let harness = build_harness();
+
+  let server_private_key = key_utils::gen_private_key();
+  let server_public_key = key_utils::gen_public_key(&server_private_key);
+  let server_signing_keypair = key_utils::gen_ed25519_keypair();
+
+  let mut child = Command::new(harness)
+    .arg(key_utils::public_key_to_base64(&server_public_key))
+    .arg(hex::encode(server_signing_keypair.public.as_bytes()))
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .expect("Unable to run the harness");
+  let mut stdin = child.stdin.take().expect("harness stdin");
+  let mut stdout = BufReader::new(child.stdout.take().expect("harness stdout"));
+
+  // Signed query
+  let query = read_message(&mut stdout, "query");
+  let client_public_key =
+    ed25519_dalek::PublicKey::from_bytes(&read_message(&mut stdout, "client")).expect("client public key");
+  let unpacked_query =
+    chatrouille::unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).expect("unpack the signed query");
+  let signature = unpacked_query.signature.as_ref().expect("signed query");
+  assert!(signature.verify(&client_public_key).is_ok());
+  assert_eq!(
+    unpacked_query.payload,
+    br#"{"graphql":{"query":"{ isIdentifierAvailable }"}}"#
+  );
+
+  let response = chatrouille::pack_signed_response(
+    b"{\"data\":{\"isIdentifierAvailable\":true}}",
+    &unpacked_query.shared_secret,
+    signature.query_hash(),
+    &server_signing_keypair,
+    unpacked_query.version,
+    PaddingPolicy::default(),
+  )
+  .expect("pack the signed response");
+  writeln!(stdin, "{}", hex::encode(&response)).expect("write the response");
+  assert_eq!(
+    read_message(&mut stdout, "payload"),
+    b"{\"data\":{\"isIdentifierAvailable\":true}}"
+  );
+
+  // Unsigned query, answered with an error
+  let query = read_message(&mut stdout, "query");
+  let unpacked_query =
+    chatrouille::unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).expect("unpack the unsigned query");
+  assert!(unpacked_query.signature.is_none());
+  let error = chatrouille::pack_error(
+    410,
+    "The request has expired",
+    &unpacked_query.shared_secret,
+    unpacked_query.version,
+  )
+  .expect("pack the error");
+  writeln!(stdin, "{}", hex::encode(&error)).expect("write the error");
+
+  let mut line = String::new();
+  stdout.read_line(&mut line).expect("read the error");
+  assert_eq!(line, "error 410 The request has expired\n");
+
+  // Unsigned query, with an unsigned response
+  let query = read_message(&mut stdout, "query");
+  let unpacked_query =
+    chatrouille::unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).expect("unpack the unsigned query");
+  let response = chatrouille::pack_response(
+    b"{\"data\":{\"isIdentifierAvailable\":false}}",
+    &unpacked_query.shared_secret,
+    unpacked_query.version,
+    PaddingPolicy::default(),
+  )
+  .expect("pack the unsigned response");
+  writeln!(stdin, "{}", hex::encode(&response)).expect("write the response");
+  assert_eq!(
+    read_message(&mut stdout, "payload"),
+    b"{\"data\":{\"isIdentifierAvailable\":false}}"
+  );
+
+  assert!(child.wait().expect("harness exit").success());
+}
@@ -0,0 +1,221 @@
+/*
+ * Small C client of the chatrouille bindings, run by tests/c_harness.rs.
+ *
+ * usage: harness <server public key base64> <server signing public key hexadecimal>
+ *
+ * It checks the key helpers and the errors, then it talks with the test
+ * acting as the server, one hexadecimal line per message:
+ * - it writes the signed query, and the public key of its keypair
+ * - it reads the signed response, and writes its payload
+ * - it writes an unsigned query, reads an error response, and writes the error
+ * - it writes another unsigned query, reads an unsigned response, and writes its payload
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "chatrouille.h"
+
+#define MAX_LINE_LENGTH 65536
+#define MAX_PAYLOAD_LENGTH 4096
+
+#define CHECK(condition)                                                   \
+  do {                                                                     \
+    if (!(condition)) {                                                    \
+      fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #condition); \
+      exit(1);                                                             \
+    }                                                                      \
+  } while (0)
+
+#define CHECK_STATUS(call, expected)                                       \
+  do {                                                                     \
+    ChatrouilleStatus status = (call);                                     \
+    if (status != (expected)) {                                            \
+      fprintf(stderr, "%s:%d: %s returned %s\n", __FILE__, __LINE__, #call, \
+              chatrouille_status_message(status));                         \
+      exit(1);                                                             \
+    }                                                                      \
+  } while (0)
+
+static void write_hex(const char *name, const uint8_t *data, size_t len) {
+  printf("%s ", name);
+  for (size_t i = 0; i < len; i++) {
+    printf("%02x", data[i]);
+  }
+  printf("\n");
+  fflush(stdout);
+}
+
+/* Reads a line of hexadecimal into a buffer allocated with malloc. */
+static uint8_t *read_hex(size_t *len) {
+  static char line[MAX_LINE_LENGTH];
+  CHECK(fgets(line, sizeof(line), stdin) != NULL);
+  size_t line_len = strcspn(line, "\r\n");
+  CHECK(line_len % 2 == 0);
+
+  uint8_t *data = malloc(line_len / 2 + 1);
+  CHECK(data != NULL);
+  for (size_t i = 0; i < line_len / 2; i++) {
+    unsigned int byte;
+    CHECK(sscanf(&line[i * 2], "%2x", &byte) == 1);
+    data[i] = (uint8_t)byte;
+  }
+  *len = line_len / 2;
+  return data;
+}
+
+static void check_keys(void) {
+  ChatrouilleBuffer private_key, public_key, decoded_key, keypair;
+  char *base64;
+
+  CHECK_STATUS(chatrouille_gen_private_key(&private_key), CHATROUILLE_STATUS_OK);
+  CHECK(private_key.len == 56);
+  CHECK_STATUS(chatrouille_public_key(private_key.data, private_key.len, &public_key), CHATROUILLE_STATUS_OK);
+  CHECK(public_key.len == 56);
+
+  CHECK_STATUS(chatrouille_public_key_to_base64(public_key.data, public_key.len, &base64), CHATROUILLE_STATUS_OK);
+  CHECK(strlen(base64) == 75);
+  CHECK_STATUS(chatrouille_public_key_from_base64(base64, &decoded_key), CHATROUILLE_STATUS_OK);
+  CHECK(decoded_key.len == public_key.len && memcmp(decoded_key.data, public_key.data, public_key.len) == 0);
+  chatrouille_string_free(base64);
+  chatrouille_buffer_free(&decoded_key);
+
+  CHECK_STATUS(chatrouille_private_key_to_base64(private_key.data, private_key.len, &base64), CHATROUILLE_STATUS_OK);
+  CHECK_STATUS(chatrouille_private_key_from_base64(base64, &decoded_key), CHATROUILLE_STATUS_OK);
+  CHECK(decoded_key.len == private_key.len && memcmp(decoded_key.data, private_key.data, private_key.len) == 0);
+  chatrouille_string_free(base64);
+  chatrouille_buffer_free(&decoded_key);
+
+  CHECK_STATUS(chatrouille_gen_ed25519_keypair(&keypair), CHATROUILLE_STATUS_OK);
+  CHECK(keypair.len == 64);
+
+  /* The outputs are reset on errors, and can be freed */
+  CHECK_STATUS(chatrouille_public_key_from_base64("coin coin", &decoded_key), CHATROUILLE_STATUS_INVALID_KEY);
+  CHECK(decoded_key.data == NULL && decoded_key.len == 0);
+  chatrouille_buffer_free(&decoded_key);
+  CHECK_STATUS(chatrouille_public_key_from_base64(NULL, &decoded_key), CHATROUILLE_STATUS_INVALID_BASE64);
+  CHECK_STATUS(chatrouille_public_key(private_key.data, 12, &decoded_key), CHATROUILLE_STATUS_INVALID_KEY);
+  CHECK_STATUS(chatrouille_public_key(NULL, 56, &decoded_key), CHATROUILLE_STATUS_NULL_POINTER);
+  CHECK_STATUS(chatrouille_public_key(private_key.data, private_key.len, NULL), CHATROUILLE_STATUS_NULL_POINTER);
+
+  ChatrouilleQuery *query;
+  ChatrouilleBuffer packed_query;
+  CHECK_STATUS(chatrouille_pack_signed_query((const uint8_t *)"coin", 4, public_key.data, public_key.len,
+                                             keypair.data, 12, &query, &packed_query),
+               CHATROUILLE_STATUS_INVALID_KEY);
+  CHECK(query == NULL && packed_query.data == NULL);
+  chatrouille_query_free(query);
+
+  chatrouille_buffer_free(&private_key);
+  CHECK(private_key.data == NULL);
+  chatrouille_buffer_free(&public_key);
+  chatrouille_buffer_free(&keypair);
+}
+
+int main(int argc, char **argv) {
+  CHECK(argc == 3);
+  check_keys();
+
+  ChatrouilleBuffer server_public_key, keypair;
+  CHECK_STATUS(chatrouille_public_key_from_base64(argv[1], &server_public_key), CHATROUILLE_STATUS_OK);
+  /* The signing key isn't a x448 key, the test gives it in hexadecimal */
+  CHECK(strlen(argv[2]) == 64);
+  uint8_t signing_key[32];
+  for (size_t i = 0; i < 32; i++) {
+    unsigned int byte;
+    CHECK(sscanf(&argv[2][i * 2], "%2x", &byte) == 1);
+    signing_key[i] = (uint8_t)byte;
+  }
+  CHECK_STATUS(chatrouille_gen_ed25519_keypair(&keypair), CHATROUILLE_STATUS_OK);
+
+  /* Signed query, with a signed response */
+  const char *payload = "{\"graphql\":{\"query\":\"{ isIdentifierAvailable }\"}}";
+  ChatrouilleQuery *query;
+  ChatrouilleBuffer packed_query, response;
+  CHECK_STATUS(chatrouille_pack_signed_query((const uint8_t *)payload, strlen(payload), server_public_key.data,
+                                             server_public_key.len, keypair.data, keypair.len, &query, &packed_query),
+               CHATROUILLE_STATUS_OK);
+  write_hex("query", packed_query.data, packed_query.len);
+  write_hex("client", keypair.data + 32, 32);
+  chatrouille_buffer_free(&packed_query);
+
+  size_t packed_response_len;
+  uint8_t *packed_response = read_hex(&packed_response_len);
+  CHECK_STATUS(chatrouille_unpack_response(query, packed_response, packed_response_len, NULL, 0, MAX_PAYLOAD_LENGTH,
+                                           &response, NULL),
+               CHATROUILLE_STATUS_MISSING_SIGNING_KEY);
+  CHECK_STATUS(chatrouille_unpack_response(query, packed_response, packed_response_len, signing_key,
+                                           sizeof(signing_key), MAX_PAYLOAD_LENGTH, &response, NULL),
+               CHATROUILLE_STATUS_OK);
+  write_hex("payload", response.data, response.len);
+  chatrouille_buffer_free(&response);
+
+  /* Another key didn't sign the response */
+  signing_key[0] ^= 1;
+  ChatrouilleStatus status = chatrouille_unpack_response(query, packed_response, packed_response_len, signing_key,
+                                                         sizeof(signing_key), MAX_PAYLOAD_LENGTH, &response, NULL);
+  CHECK(status == CHATROUILLE_STATUS_INVALID_SIGNATURE || status == CHATROUILLE_STATUS_INVALID_KEY);
+  free(packed_response);
+  chatrouille_query_free(query);
+
+  /* Unsigned query, with an error response */
+  CHECK_STATUS(chatrouille_pack_unsigned_query((const uint8_t *)payload, strlen(payload), server_public_key.data,
+                                               server_public_key.len, &query, &packed_query),
+               CHATROUILLE_STATUS_OK);
+  write_hex("query", packed_query.data, packed_query.len);
+  chatrouille_buffer_free(&packed_query);
+
+  packed_response = read_hex(&packed_response_len);
+  uint16_t server_error_code = 0;
+  CHECK_STATUS(chatrouille_unpack_response(query, packed_response, packed_response_len, NULL, 0, MAX_PAYLOAD_LENGTH,
+                                           &response, &server_error_code),
+               CHATROUILLE_STATUS_SERVER_ERROR);
+  printf("error %u %.*s\n", server_error_code, (int)response.len, (const char *)response.data);
+  fflush(stdout);
+  chatrouille_buffer_free(&response);
+
+  /* The errors aren't signed, they are accepted with the signing key */
+  signing_key[0] ^= 1;
+  CHECK_STATUS(chatrouille_unpack_response(query, packed_response, packed_response_len, signing_key,
+                                           sizeof(signing_key), MAX_PAYLOAD_LENGTH, &response, NULL),
+               CHATROUILLE_STATUS_SERVER_ERROR);
+  chatrouille_buffer_free(&response);
+
+  /* The response of another query can't be unpacked */
+  ChatrouilleQuery *another_query;
+  CHECK_STATUS(chatrouille_pack_unsigned_query((const uint8_t *)payload, strlen(payload), server_public_key.data,
+                                               server_public_key.len, &another_query, &packed_query),
+               CHATROUILLE_STATUS_OK);
+  CHECK_STATUS(chatrouille_unpack_response(another_query, packed_response, packed_response_len, NULL, 0,
+                                           MAX_PAYLOAD_LENGTH, &response, NULL),
+               CHATROUILLE_STATUS_UNPACK_ERROR);
+  chatrouille_buffer_free(&packed_query);
+  chatrouille_query_free(another_query);
+  free(packed_response);
+  chatrouille_query_free(query);
+
+  /* Unsigned query, with an unsigned response */
+  CHECK_STATUS(chatrouille_pack_unsigned_query((const uint8_t *)payload, strlen(payload), server_public_key.data,
+                                               server_public_key.len, &query, &packed_query),
+               CHATROUILLE_STATUS_OK);
+  write_hex("query", packed_query.data, packed_query.len);
+  chatrouille_buffer_free(&packed_query);
+
+  packed_response = read_hex(&packed_response_len);
+  /* With the signing key, the response without signature is refused */
+  CHECK_STATUS(chatrouille_unpack_response(query, packed_response, packed_response_len, signing_key,
+                                           sizeof(signing_key), MAX_PAYLOAD_LENGTH, &response, NULL),
+               CHATROUILLE_STATUS_INVALID_SIGNATURE);
+  CHECK(response.data == NULL);
+  CHECK_STATUS(chatrouille_unpack_response(query, packed_response, packed_response_len, NULL, 0, MAX_PAYLOAD_LENGTH,
+                                           &response, NULL),
+               CHATROUILLE_STATUS_OK);
+  write_hex("payload", response.data, response.len);
+  chatrouille_buffer_free(&response);
+  free(packed_response);
+  chatrouille_query_free(query);
+
+  chatrouille_buffer_free(&server_public_key);
+  chatrouille_buffer_free(&keypair);
+  return 0;
+}
@@ -0,0 +1,57 @@
+#![warn(
+    clippy::all,
+    //clippy::restriction,
+    clippy::pedantic,
+    clippy::needless_pass_by_value,
+    clippy::unwrap_used,
+    clippy::clone_on_ref_ptr
+)]
+#![allow(
+    clippy::implicit_return,
+    clippy::integer_arithmetic,
+    clippy::missing_docs_in_private_items,
+    clippy::module_name_repetitions,
+    clippy::used_underscore_binding,
+    clippy::wildcard_imports,
+    clippy::else_if_without_else,
+    clippy::single_match_else,
+    clippy::match_wild_err_arm
+)]
+
+mod relay;
+
+use std::env;
+
+#[tokio::main]
+#[allow(clippy::print_stdout, clippy::expect_used)]
+async fn main() {
+    let port = env::var("RELAY_PORT")
+        .unwrap_or_else(|_| String::from("3001"))
+        .parse::<u16>()
+        .expect("Invalid RELAY_PORT");
+    let addr = ([0, 0, 0, 0], port).into();
+
+    let relay = relay::Relay::new(
+        env::var("RELAY_BACKEND_URL").unwrap_or_else(|_| String::from("http://localhost:3000")),
+        // Must be the CHATROUILLE_RELAY_BEARER of the backend
+        env::var("RELAY_BEARER").ok(),
+        env::var("RELAY_ALLOWED_ORIGIN").unwrap_or_else(|_| String::from("https://norgance.net")),
+        // Chunked chatrouille queries can be large
+        env::var("RELAY_MAX_BODY_SIZE")
+            .unwrap_or_else(|_| String::from("1048576"))
+            .parse::<usize>()
+            .expect("Invalid RELAY_MAX_BODY_SIZE"),
+    );
+
+    let (local_addr, server) = relay::bind(&addr, relay, async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install CTRL+C signal handler");
+    });
+
+    println!("Relaying on http://{}", local_addr);
+
+    if let Err(e) = server.await {
+        eprintln!("relay error: {}", e)
+    }
+}
@@ -0,0 +1,445 @@
+//! Oblivious relay of the chatrouille queries, in the spirit of Oblivious HTTP.
+//!
+//! The clients send their chatrouille packets to the relay, and the relay forwards
+//! them to Norgance without anything about the clients: no address, no cookies,
+//! no user agent, only the packet and the bearer of the relay. The responses come back the same way.
+//!
+//! The packets are encrypted for Norgance, the relay can't read them.
+//! Norgance can read them, but doesn't know who sent them.
+//! As long as the relay and Norgance don't collude, nobody knows both.
+
+use futures::future::{self, Future};
+use futures::TryStreamExt;
+use hyper::client::HttpConnector;
+use hyper::header::{
+    HeaderName, CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+use hyper_rustls::HttpsConnector;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// Added to the responses, the clients in relay mode check it
+/// to not send their queries directly to Norgance by mistake.
+const RELAY_HEADER: &str = "Norgance-Relay";
+
+/// Checked by the backend, see its `CHATROUILLE_RELAY_BEARER`.
+const RELAY_AUTHENTICATION_HEADER: &str = "relay-authentication";
+
+const WEBSOCKET_REQUEST_HEADERS: [HeaderName; 4] = [
+    UPGRADE,
+    CONNECTION,
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION,
+];
+const WEBSOCKET_RESPONSE_HEADERS: [HeaderName; 3] = [UPGRADE, CONNECTION, SEC_WEBSOCKET_ACCEPT];
+
+type ResultHandler = Result<Response<Body>, hyper::Error>;
+
+pub struct Relay {
+    backend_url: String,
+    relay_bearer: Option<String>,
+    allowed_origin: String,
+    max_body_size: usize,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Relay {
+    /// `backend_url` is the URL of Norgance without the trailing slash, such as `https://norgance.net`.
+    pub fn new(
+        backend_url: String,
+        relay_bearer: Option<String>,
+        allowed_origin: String,
+        max_body_size: usize,
+    ) -> Relay {
+        Relay {
+            backend_url,
+            relay_bearer,
+            allowed_origin,
+            max_body_size,
+            // The connections to the backend are shared by all the clients
+            client: Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    async fn handle(&self, req: Request<Body>) -> ResultHandler {
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/chatrouille") => self.relay_query(req).await,
+            (&Method::GET, "/chatrouille/ws") => self.relay_session(req).await,
+            (&Method::GET, "/chatrouille_information") => {
+                let request = self.backend_request(Method::GET, "/chatrouille_information");
+                self.forward(request.body(Body::empty())).await
+            }
+            (&Method::OPTIONS, "/chatrouille") => Ok(self.preflight()),
+            (&Method::GET, "/health") => {
+                Ok(self.json_response(&json!({ "available": true }), StatusCode::OK))
+            }
+            _ => Ok(self.json_response(&json!({ "error": "not found" }), StatusCode::NOT_FOUND)),
+        }
+    }
+
+    async fn relay_query(&self, req: Request<Body>) -> ResultHandler {
+        let query = match read_body(req.into_body(), self.max_body_size).await? {
+            Some(query) => query,
+            None => {
+                return Ok(self.json_response(
+                    &json!({ "error": "payload too large" }),
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                ))
+            }
+        };
+        // The relay is not an open proxy
+        if !chatrouille::packet_mode(&query).is_query() {
+            return Ok(self.json_response(
+                &json!({ "error": "Not a chatrouille query" }),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        let request = self
+            .backend_request(Method::POST, "/chatrouille")
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(query));
+        self.forward(request).await
+    }
+
+    /// The WebSocket of a session is spliced to a WebSocket of the backend.
+    /// The session frames are encrypted, the bytes are copied as is.
+    #[allow(clippy::expect_used)]
+    async fn relay_session(&self, req: Request<Body>) -> ResultHandler {
+        let mut request = self.backend_request(Method::GET, "/chatrouille/ws");
+        for name in &WEBSOCKET_REQUEST_HEADERS {
+            if let Some(value) = req.headers().get(name) {
+                request = request.header(name, value);
+            }
+        }
+        let backend_response = match request.body(Body::empty()) {
+            Ok(request) => match self.client.request(request).await {
+                Ok(response) => response,
+                Err(_) => return Ok(self.backend_unreachable()),
+            },
+            Err(_) => return Ok(self.backend_unreachable()),
+        };
+        if backend_response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Ok(self.relay_response(backend_response));
+        }
+
+        let mut response = self.response_builder(StatusCode::SWITCHING_PROTOCOLS);
+        for name in &WEBSOCKET_RESPONSE_HEADERS {
+            if let Some(value) = backend_response.headers().get(name) {
+                response = response.header(name, value);
+            }
+        }
+
+        tokio::spawn(async move {
+            let upgrades = future::try_join(
+                req.into_body().on_upgrade(),
+                backend_response.into_body().on_upgrade(),
+            );
+            let (client, backend) = match upgrades.await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    eprintln!("WebSocket upgrade error: {}", e);
+                    return;
+                }
+            };
+            let (client_reader, client_writer) = tokio::io::split(client);
+            let (backend_reader, backend_writer) = tokio::io::split(backend);
+            let _ = future::try_join(
+                pipe(client_reader, backend_writer),
+                pipe(backend_reader, client_writer),
+            )
+            .await;
+        });
+
+        Ok(response
+            .body(Body::empty())
+            .expect("Unable to build the WebSocket upgrade response"))
+    }
+
+    /// Only the bearer of the relay, nothing comes from the client request.
+    fn backend_request(&self, method: Method, path: &str) -> hyper::http::request::Builder {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.backend_url, path));
+        match &self.relay_bearer {
+            Some(bearer) => request.header(RELAY_AUTHENTICATION_HEADER, bearer.as_str()),
+            None => request,
+        }
+    }
+
+    async fn forward(&self, request: Result<Request<Body>, hyper::http::Error>) -> ResultHandler {
+        let request = match request {
+            Ok(request) => request,
+            Err(_) => return Ok(self.backend_unreachable()),
+        };
+        match self.client.request(request).await {
+            Ok(response) => Ok(self.relay_response(response)),
+            Err(_) => Ok(self.backend_unreachable()),
+        }
+    }
+
+    /// Only the status and the content type of the backend response are kept.
+    #[allow(clippy::expect_used)]
+    fn relay_response(&self, response: Response<Body>) -> Response<Body> {
+        let mut builder = self.response_builder(response.status());
+        if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+            builder = builder.header(CONTENT_TYPE, content_type);
+        }
+        builder
+            .body(response.into_body())
+            .expect("Unable to build the relayed response")
+    }
+
+    fn response_builder(&self, status: StatusCode) -> hyper::http::response::Builder {
+        Response::builder()
+            .status(status)
+            .header(RELAY_HEADER, "1")
+            .header("Access-Control-Allow-Origin", self.allowed_origin.as_str())
+            .header("Access-Control-Expose-Headers", RELAY_HEADER)
+    }
+
+    #[allow(clippy::expect_used)]
+    fn preflight(&self) -> Response<Body> {
+        self.response_builder(StatusCode::NO_CONTENT)
+            .header("Access-Control-Allow-Methods", "POST")
+            .header("Access-Control-Allow-Headers", "Content-Type")
+            .body(Body::empty())
+            .expect("Unable to build the preflight response")
+    }
+
+    #[allow(clippy::expect_used)]
+    fn json_response(&self, json: &serde_json::Value, status: StatusCode) -> Response<Body> {
+        self.response_builder(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json.to_string()))
+            .expect("Unable to build json response")
+    }
+
+    fn backend_unreachable(&self) -> Response<Body> {
+        self.json_response(
+            &json!({ "error": "Norgance is unreachable" }),
+            StatusCode::BAD_GATEWAY,
+        )
+    }
+}
+
+/// Binds the relay, the returned future serves the requests until the shutdown.
+pub fn bind<F>(
+    addr: &SocketAddr,
+    relay: Relay,
+    shutdown: F,
+) -> (SocketAddr, impl Future<Output = Result<(), hyper::Error>>)
+where
+    F: Future<Output = ()>,
+{
+    let relay = Arc::new(relay);
+
+    // The connections are not even looked at, the address of the client is never read
+    let new_service = make_service_fn(move |_| {
+        let relay = Arc::clone(&relay);
+
+        async {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                let relay = Arc::clone(&relay);
+                async move { relay.handle(req).await }
+            }))
+        }
+    });
+
+    let server = Server::bind(addr).serve(new_service);
+    (server.local_addr(), server.with_graceful_shutdown(shutdown))
+}
+
+/// Reads the body, or nothing when it's larger than `max_size`.
+async fn read_body(mut body: Body, max_size: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut data: Vec<u8> = Vec::new();
+    while let Some(chunk) = body.try_next().await? {
+        if data.len() + chunk.len() > max_size {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+async fn pipe<R, W>(mut reader: R, mut writer: W) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    writer.shutdown().await
+}
+
+#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
+/// The tests run the relay against a fake backend on loopback, reversing the bytes of the queries.
+///
+/// `test_relay_real_backend` runs it against the real backend instead, but it's ignored by default:
+/// the backend needs PostgreSQL and Vault, the tests can't start it themselves. Start the backend
+/// on loopback with `CHATROUILLE_RELAY_BEARER=coin` (see the README), then run:
+/// `NORGANCE_BACKEND_URL=http://127.0.0.1:3000 NORGANCE_SIGNING_PUBLIC_KEY=... RELAY_BEARER=coin cargo test -- --ignored`
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatrouille::key_utils;
+    use hyper::HeaderMap;
+    use std::sync::Mutex;
+
+    const MAX_BODY_SIZE: usize = 65536;
+
+    /// Answers the queries with their reversed bytes, and keeps the headers it received.
+    fn fake_backend(received_headers: Arc<Mutex<Vec<HeaderMap>>>) -> SocketAddr {
+        let new_service = make_service_fn(move |_| {
+            let received_headers = Arc::clone(&received_headers);
+            async {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let received_headers = Arc::clone(&received_headers);
+                    async move {
+                        received_headers.lock().unwrap().push(req.headers().clone());
+                        let mut body = hyper::body::to_bytes(req.into_body()).await?.to_vec();
+                        body.reverse();
+                        Ok::<_, hyper::Error>(
+                            Response::builder()
+                                .header(CONTENT_TYPE, "application/octet-stream")
+                                .header("Set-Cookie", "tracker=1")
+                                .body(Body::from(body))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(new_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn relay(backend_addr: SocketAddr) -> SocketAddr {
+        let relay = Relay::new(
+            format!("http://{}", backend_addr),
+            Some(String::from("relay bearer")),
+            String::from("https://norgance.net"),
+            MAX_BODY_SIZE,
+        );
+        let (addr, server) = bind(&([127, 0, 0, 1], 0).into(), relay, future::pending());
+        tokio::spawn(server);
+        addr
+    }
+
+    fn client_request(relay_addr: SocketAddr, body: Vec<u8>) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/chatrouille", relay_addr))
+            .header("User-Agent", "Mozilla/5.0 (Lunix; Marianne's computer)")
+            .header("Cookie", "session=marianne")
+            .header("X-Forwarded-For", "192.0.2.1")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_relay_query() {
+        let received_headers = Arc::new(Mutex::new(Vec::new()));
+        let relay_addr = relay(fake_backend(Arc::clone(&received_headers)));
+
+        let public_key = key_utils::gen_public_key(&key_utils::gen_private_key());
+        let (query, _) = chatrouille::pack_unsigned_query(b"{\"exp\":1}", &public_key).unwrap();
+
+        let client = Client::new();
+        let response = client
+            .request(client_request(relay_addr, query.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[RELAY_HEADER], "1");
+        assert!(response.headers().get("Set-Cookie").is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut expected = query;
+        expected.reverse();
+        assert_eq!(body.to_vec(), expected);
+
+        let received_headers = received_headers.lock().unwrap();
+        assert_eq!(received_headers.len(), 1);
+        let headers = &received_headers[0];
+        assert_eq!(headers[RELAY_AUTHENTICATION_HEADER], "relay bearer");
+        assert!(headers.get("User-Agent").is_none());
+        assert!(headers.get("Cookie").is_none());
+        assert!(headers.get("X-Forwarded-For").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_relay_refuses_other_packets() {
+        let received_headers = Arc::new(Mutex::new(Vec::new()));
+        let relay_addr = relay(fake_backend(Arc::clone(&received_headers)));
+        let client = Client::new();
+
+        let response = client
+            .request(client_request(relay_addr, b"GET / HTTP/1.1".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = client
+            .request(client_request(relay_addr, vec![0; MAX_BODY_SIZE + 1]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        assert!(received_headers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backend_unreachable() {
+        // Nothing listens on the port 1
+        let relay_addr = relay(([127, 0, 0, 1], 1).into());
+        let response = Client::new()
+            .request(
+                Request::builder()
+                    .uri(format!("http://{}/chatrouille_information", relay_addr))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs the real backend on loopback, with PostgreSQL and Vault"]
+    async fn test_relay_real_backend() {
+        let backend_url = std::env::var("NORGANCE_BACKEND_URL").unwrap();
+        let signing_public_key = ed25519_dalek::PublicKey::from_bytes(
+            &base64::decode(std::env::var("NORGANCE_SIGNING_PUBLIC_KEY").unwrap()).unwrap(),
+        )
+        .unwrap();
+        let relay = Relay::new(
+            backend_url,
+            std::env::var("RELAY_BEARER").ok(),
+            String::from("https://norgance.net"),
+            MAX_BODY_SIZE,
+        );
+        let (relay_addr, server) = bind(&([127, 0, 0, 1], 0).into(), relay, future::pending());
+        tokio::spawn(server);
+
+        // The chatrouille client is blocking, it checks the relay header and the server signatures
+        let response = tokio::task::spawn_blocking(move || {
+            let client = chatrouille::client::ChatrouilleClient::connect_through_relay(
+                &format!("http://{relay_addr}"),
+                signing_public_key,
+            )?;
+            client.anonymous_graphql(&json!({
+                "query": "{ isIdentifierAvailable(identifier: \"relay-test\") }"
+            }))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(response.data["isIdentifierAvailable"].is_boolean());
+    }
+}
@@ -0,0 +1,156 @@
+//! Compares the allocations and the time of `unpack_query`, `pack_response` and the query packing
+//! with `unpack_query_into`, `pack_response_into` and `pack_signed_query_into`, reusing their buffers.
+//!
+//! cargo bench --bench allocations
+
+use chatrouille::compressor::Codec;
+use chatrouille::padding::PaddingPolicy;
+use chatrouille::{
+  key_utils, pack_response_into, pack_response_with_codec, pack_signed_query_into, pack_signed_query_with_codec,
+  pack_unsigned_query_with_codec, unpack_query, unpack_query_into, UnpackBuffer, Version,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+// Reallocations are counted as allocations, the vectors growing are part of the churn
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+    System.realloc(ptr, layout, new_size)
+  }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ITERATIONS: usize = 2000;
+const MAX_PAYLOAD_LENGTH: usize = 65536;
+
+struct Measure {
+  allocations: usize,
+  allocated_bytes: usize,
+  nanoseconds: u128,
+}
+
+fn measure<F: FnMut()>(mut function: F) -> Measure {
+  // Warms up the buffers
+  function();
+
+  let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+  let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+  let start = Instant::now();
+  for _ in 0..ITERATIONS {
+    function();
+  }
+  let elapsed = start.elapsed();
+  Measure {
+    allocations: (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / ITERATIONS,
+    allocated_bytes: (ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes) / ITERATIONS,
+    nanoseconds: elapsed.as_nanos() / ITERATIONS as u128,
+  }
+}
+
+fn report(name: &str, current: &Measure, into: &Measure) {
+  println!(
+    "{:<44} {:>4} allocations {:>7} bytes {:>8} ns/iter",
+    name, current.allocations, current.allocated_bytes, current.nanoseconds
+  );
+  println!(
+    "{:<44} {:>4} allocations {:>7} bytes {:>8} ns/iter",
+    format!("{} (into)", name),
+    into.allocations,
+    into.allocated_bytes,
+    into.nanoseconds
+  );
+  assert!(into.allocations < current.allocations);
+}
+
+fn main() {
+  let server_private_key = key_utils::gen_private_key();
+  let server_public_key = key_utils::gen_public_key(&server_private_key);
+  let client_keypair = key_utils::gen_ed25519_keypair();
+  let payload = br#"{"query":"query { citizen(id: \"abc\") { name alias } }"}"#.repeat(16);
+
+  let queries = [
+    (
+      "unsigned query, none",
+      pack_unsigned_query_with_codec(&payload, &server_public_key, Codec::None),
+    ),
+    (
+      "signed query, none",
+      pack_signed_query_with_codec(&payload, &server_public_key, &client_keypair, Codec::None),
+    ),
+    (
+      "signed query, deflate",
+      pack_signed_query_with_codec(&payload, &server_public_key, &client_keypair, Codec::Deflate),
+    ),
+  ];
+
+  let mut packed_query = Vec::new();
+  for codec in &[Codec::None, Codec::Deflate] {
+    let current = measure(|| {
+      pack_signed_query_with_codec(&payload, &server_public_key, &client_keypair, *codec).expect("pack query");
+    });
+    let into = measure(|| {
+      packed_query.clear();
+      pack_signed_query_into(&payload, &server_public_key, &client_keypair, *codec, &mut packed_query)
+        .expect("pack query into");
+    });
+    report(&format!("pack_signed_query, {:?}", codec), &current, &into);
+  }
+
+  let mut buffer = UnpackBuffer::new();
+  for (name, packed) in &queries {
+    let (query, _) = packed.as_ref().expect("pack query");
+    let current = measure(|| {
+      let unpacked_query =
+        unpack_query(query, &server_private_key, MAX_PAYLOAD_LENGTH).expect("unpack query");
+      assert_eq!(unpacked_query.payload.len(), payload.len());
+    });
+    let into = measure(|| {
+      let unpacked_query = unpack_query_into(query, &server_private_key, MAX_PAYLOAD_LENGTH, &mut buffer)
+        .expect("unpack query into");
+      assert_eq!(unpacked_query.payload.len(), payload.len());
+    });
+    report(&format!("unpack_query, {}", name), &current, &into);
+  }
+
+  let (_, shared_secret) = pack_unsigned_query_with_codec(b"", &server_public_key, Codec::None).expect("pack query");
+  let mut packed_data = Vec::new();
+  for codec in &[Codec::None, Codec::Deflate] {
+    let current = measure(|| {
+      pack_response_with_codec(&payload, &shared_secret, Version::Swan, PaddingPolicy::default(), *codec)
+        .expect("pack response");
+    });
+    let into = measure(|| {
+      packed_data.clear();
+      pack_response_into(
+        &payload,
+        &shared_secret,
+        Version::Swan,
+        PaddingPolicy::default(),
+        *codec,
+        &mut packed_data,
+      )
+      .expect("pack response into");
+    });
+    report(&format!("pack_response, {:?}", codec), &current, &into);
+  }
+}
@@ -0,0 +1,458 @@
+/*!
+ * Command line tool to debug the chatrouille packets.
+ *
+ * chatrouille gen-keys
+ * chatrouille pack-query --server-public-key KEY [--server-mlkem-public-key KEY] [--keypair KEYPAIR] [FILE]
+ * chatrouille unpack-query --server-private-key KEY [--server-mlkem-private-key KEY] [--client-signing-public-key KEY] [FILE]
+ * chatrouille unpack-response --shared-secret SECRET [--query-hash HASH --server-signing-public-key KEY] [FILE]
+ * chatrouille inspect [FILE]
+ *
+ * The input is read from the file, or from stdin without a file or with `-`.
+ * The packets are read in binary or in base64, and written in base64.
+ * The payloads and the packets are written on stdout, the secrets and the details on stderr.
+ * The keys are in base64, the ed25519 keypair is the secret key followed by the public key.
+ */
+#![warn(clippy::all, clippy::pedantic)]
+
+use chatrouille::{
+  compressor::Codec, inspect, key_utils, key_utils::SharedSecret, mlkem, query_hash, unpack_query, unpack_response,
+  unpack_signed_response, ChatrouilleError, Mode, PrivateKeyring, VerifyUnpackedQuerySignature, Version, x448,
+};
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const USAGE: &str = "Usage:
+  chatrouille gen-keys
+  chatrouille pack-query --server-public-key KEY [--server-mlkem-public-key KEY] [--keypair KEYPAIR] [FILE]
+  chatrouille unpack-query --server-private-key KEY [--server-mlkem-private-key KEY] [--client-signing-public-key KEY] [FILE]
+  chatrouille unpack-response --shared-secret SECRET [--query-hash HASH --server-signing-public-key KEY] [FILE]
+  chatrouille inspect [FILE]
+
+The input is read from FILE, or from stdin without FILE or with -.";
+
+// Same limit as the server
+const MAX_PAYLOAD_LENGTH: usize = 4 * 1024 * 1024;
+
+// The encrypted fields are only described by their length
+const OPAQUE_FIELDS: [&str; 5] = ["ciphertext", "tag", "segment", "recipient", "ML-KEM ciphertext"];
+
+#[derive(Snafu, Debug)]
+enum CliError {
+  #[snafu(display("{}\n\n{}", message, USAGE))]
+  Usage { message: String },
+
+  #[snafu(display("Unable to read {}: {}", path, source))]
+  ReadError { path: String, source: std::io::Error },
+
+  #[snafu(display("Unable to write the output: {}", source))]
+  WriteError { source: std::io::Error },
+
+  #[snafu(display("Invalid --{}", name))]
+  InvalidKey { name: &'static str },
+
+  #[snafu(display("The input is neither a chatrouille packet nor base64: {}", source))]
+  InvalidInput { source: base64::DecodeError },
+
+  #[snafu(display("The input isn't valid JSON: {}", source))]
+  InvalidJson { source: serde_json::Error },
+
+  #[snafu(display("{}", source))]
+  Chatrouille { source: ChatrouilleError },
+}
+
+type Result<T, E = CliError> = std::result::Result<T, E>;
+
+struct Arguments {
+  options: HashMap<String, String>,
+  input: Option<String>,
+}
+
+impl Arguments {
+  fn parse<I: Iterator<Item = String>>(mut args: I, allowed_options: &[&str]) -> Result<Arguments> {
+    let mut options = HashMap::new();
+    let mut input = None;
+    while let Some(arg) = args.next() {
+      if let Some(name) = arg.strip_prefix("--") {
+        if !allowed_options.contains(&name) {
+          return Usage {
+            message: format!("Unknown option --{}", name),
+          }
+          .fail();
+        }
+        match args.next() {
+          Some(value) => options.insert(name.to_owned(), value),
+          None => {
+            return Usage {
+              message: format!("Missing value for --{}", name),
+            }
+            .fail()
+          }
+        };
+      } else if input.is_none() {
+        input = Some(arg);
+      } else {
+        return Usage {
+          message: format!("Unexpected argument {}", arg),
+        }
+        .fail();
+      }
+    }
+    Ok(Arguments { options, input })
+  }
+
+  fn option(&self, name: &str) -> Option<&str> {
+    self.options.get(name).map(String::as_str)
+  }
+
+  fn required(&self, name: &str) -> Result<&str> {
+    match self.option(name) {
+      Some(value) => Ok(value),
+      None => Usage {
+        message: format!("Missing --{}", name),
+      }
+      .fail(),
+    }
+  }
+}
+
+/// A single x448 key, with its ML-KEM key for the owl queries.
+struct ServerKeys {
+  private_key: key_utils::ZeroizingSecret,
+  mlkem_private_key: Option<mlkem::DecapsulationKey>,
+}
+
+impl PrivateKeyring for ServerKeys {
+  fn private_key(&self, _key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+    Some(&*self.private_key)
+  }
+
+  fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+    vec![&*self.private_key]
+  }
+
+  fn mlkem_private_key(&self, _key_id: &key_utils::KeyId) -> Option<&mlkem::DecapsulationKey> {
+    self.mlkem_private_key.as_ref()
+  }
+}
+
+fn read_input(input: Option<&str>) -> Result<Vec<u8>> {
+  match input {
+    None | Some("-") => {
+      let mut data = Vec::new();
+      std::io::stdin()
+        .read_to_end(&mut data)
+        .context(ReadError { path: "stdin" })?;
+      Ok(data)
+    }
+    Some(path) => std::fs::read(path).context(ReadError { path }),
+  }
+}
+
+/// The packets are binary, or base64 when they are copied from the browser console.
+fn read_packet(input: Option<&str>) -> Result<Vec<u8>> {
+  let data = read_input(input)?;
+  if Version::from_packet(&data).is_some() {
+    return Ok(data);
+  }
+  let text: Vec<u8> = data.into_iter().filter(|byte| !byte.is_ascii_whitespace()).collect();
+  base64::decode(&text).context(InvalidInput)
+}
+
+fn decode_option(arguments: &Arguments, name: &'static str) -> Result<Option<Vec<u8>>> {
+  match arguments.option(name) {
+    Some(value) => match base64::decode(value) {
+      Ok(bytes) => Ok(Some(bytes)),
+      Err(_) => InvalidKey { name }.fail(),
+    },
+    None => Ok(None),
+  }
+}
+
+fn signing_public_key(arguments: &Arguments, name: &'static str) -> Result<Option<ed25519_dalek::PublicKey>> {
+  match decode_option(arguments, name)? {
+    Some(bytes) => match ed25519_dalek::PublicKey::from_bytes(&bytes) {
+      Ok(public_key) => Ok(Some(public_key)),
+      Err(_) => InvalidKey { name }.fail(),
+    },
+    None => Ok(None),
+  }
+}
+
+fn write_stdout(data: &[u8]) -> Result<()> {
+  let mut stdout = std::io::stdout();
+  stdout.write_all(data).context(WriteError)?;
+  stdout.flush().context(WriteError)
+}
+
+fn gen_keys() {
+  let private_key = key_utils::ZeroizingSecret::from(key_utils::gen_private_key());
+  let public_key = key_utils::gen_public_key(&private_key);
+  let mlkem_private_key = key_utils::gen_mlkem_private_key();
+  let keypair = key_utils::gen_ed25519_keypair();
+
+  println!("server private key: {}", key_utils::private_key_to_base64(&private_key));
+  println!("server public key: {}", key_utils::public_key_to_base64(&public_key));
+  println!("server key id: {}", key_utils::key_id_to_base64(&key_utils::key_id(&public_key)));
+  println!(
+    "server mlkem private key: {}",
+    key_utils::mlkem_private_key_to_base64(&mlkem_private_key)
+  );
+  println!(
+    "server mlkem public key: {}",
+    key_utils::mlkem_public_key_to_base64(mlkem_private_key.encapsulation_key())
+  );
+  println!(
+    "signing keypair: {}",
+    base64::encode_config(&zeroize::Zeroizing::new(keypair.to_bytes())[..], base64::STANDARD_NO_PAD)
+  );
+  println!(
+    "signing public key: {}",
+    base64::encode_config(keypair.public.as_bytes(), base64::STANDARD_NO_PAD)
+  );
+}
+
+fn pack_query_command(arguments: &Arguments) -> Result<()> {
+  let server_public_key = match key_utils::public_key_from_base64(arguments.required("server-public-key")?) {
+    Some(public_key) => public_key,
+    None => return InvalidKey { name: "server-public-key" }.fail(),
+  };
+  let server_mlkem_public_key = match arguments.option("server-mlkem-public-key") {
+    Some(value) => match key_utils::mlkem_public_key_from_base64(value) {
+      Some(public_key) => Some(public_key),
+      None => return InvalidKey { name: "server-mlkem-public-key" }.fail(),
+    },
+    None => None,
+  };
+  let keypair = match decode_option(arguments, "keypair")? {
+    Some(bytes) => {
+      let bytes = zeroize::Zeroizing::new(bytes);
+      match ed25519_dalek::Keypair::from_bytes(&bytes) {
+        Ok(keypair) => Some(keypair),
+        Err(_) => return InvalidKey { name: "keypair" }.fail(),
+      }
+    }
+    None => None,
+  };
+
+  let data = read_input(arguments.input.as_deref())?;
+  serde_json::from_slice::<serde_json::Value>(&data).context(InvalidJson)?;
+
+  let codec = Codec::default();
+  let (packed_data, shared_secret) = match (&server_mlkem_public_key, &keypair) {
+    (Some(mlkem_public_key), Some(keypair)) => {
+      chatrouille::pack_hybrid_signed_query(&data, &server_public_key, mlkem_public_key, keypair, codec)
+    }
+    (Some(mlkem_public_key), None) => {
+      chatrouille::pack_hybrid_unsigned_query(&data, &server_public_key, mlkem_public_key, codec)
+    }
+    (None, Some(keypair)) => chatrouille::pack_signed_query_with_codec(&data, &server_public_key, keypair, codec),
+    (None, None) => chatrouille::pack_unsigned_query_with_codec(&data, &server_public_key, codec),
+  }
+  .context(Chatrouille)?;
+
+  eprintln!(
+    "shared secret: {}",
+    base64::encode_config(shared_secret.as_bytes(), base64::STANDARD_NO_PAD)
+  );
+  eprintln!(
+    "query hash: {}",
+    base64::encode_config(&query_hash(&data), base64::STANDARD_NO_PAD)
+  );
+  println!("{}", base64::encode(&packed_data));
+  Ok(())
+}
+
+fn unpack_query_command(arguments: &Arguments) -> Result<()> {
+  let private_key = match key_utils::private_key_from_base64(arguments.required("server-private-key")?) {
+    Some(private_key) => key_utils::ZeroizingSecret::from(private_key),
+    None => return InvalidKey { name: "server-private-key" }.fail(),
+  };
+  let mlkem_private_key = match arguments.option("server-mlkem-private-key") {
+    Some(value) => match key_utils::mlkem_private_key_from_base64(value) {
+      Some(private_key) => Some(private_key),
+      None => return InvalidKey { name: "server-mlkem-private-key" }.fail(),
+    },
+    None => None,
+  };
+  let client_signing_public_key = signing_public_key(arguments, "client-signing-public-key")?;
+  let keys = ServerKeys {
+    private_key,
+    mlkem_private_key,
+  };
+
+  let packed_data = read_packet(arguments.input.as_deref())?;
+  let query = unpack_query(&packed_data, &keys, MAX_PAYLOAD_LENGTH).context(Chatrouille)?;
+
+  eprintln!("version: {:?}", query.version);
+  eprintln!("mode: {:?}", query.mode);
+  eprintln!("codec: {:?}", query.codec);
+  eprintln!(
+    "shared secret: {}",
+    base64::encode_config(query.shared_secret.as_bytes(), base64::STANDARD_NO_PAD)
+  );
+  eprintln!(
+    "query hash: {}",
+    base64::encode_config(&query_hash(&query.payload), base64::STANDARD_NO_PAD)
+  );
+  if let Some(sender_public_key) = &query.sender_public_key {
+    eprintln!("sender public key: {}", key_utils::public_key_to_base64(sender_public_key));
+  }
+  match (&query.signature, &client_signing_public_key) {
+    (Some(signature), Some(public_key)) => {
+      signature.verify(public_key).context(Chatrouille)?;
+      eprintln!("signature: verified");
+    }
+    (Some(_), None) => eprintln!("signature: not verified, without --client-signing-public-key"),
+    (None, _) => eprintln!("signature: none"),
+  }
+  write_stdout(&query.payload)
+}
+
+fn unpack_response_command(arguments: &Arguments) -> Result<()> {
+  let shared_secret = match decode_option(arguments, "shared-secret")? {
+    Some(bytes) => SharedSecret::from_bytes(&zeroize::Zeroizing::new(bytes)),
+    None => {
+      return Usage {
+        message: "Missing --shared-secret",
+      }
+      .fail()
+    }
+  };
+  let query_hash = decode_option(arguments, "query-hash")?;
+  let server_signing_public_key = signing_public_key(arguments, "server-signing-public-key")?;
+
+  let packed_data = read_packet(arguments.input.as_deref())?;
+  let payload = if chatrouille::packet_mode(&packed_data) == Mode::SignedResponse {
+    let (query_hash, server_signing_public_key) = match (query_hash, server_signing_public_key) {
+      (Some(query_hash), Some(public_key)) => (query_hash, public_key),
+      _ => {
+        return Usage {
+          message: "The signed responses need --query-hash and --server-signing-public-key",
+        }
+        .fail()
+      }
+    };
+    let (payload, _) = unpack_signed_response(
+      &packed_data,
+      &shared_secret,
+      &query_hash,
+      &server_signing_public_key,
+      MAX_PAYLOAD_LENGTH,
+    )
+    .context(Chatrouille)?;
+    eprintln!("signature: verified");
+    payload
+  } else {
+    unpack_response(&packed_data, &shared_secret, MAX_PAYLOAD_LENGTH).context(Chatrouille)?
+  };
+  write_stdout(&payload)
+}
+
+fn inspect_command(arguments: &Arguments) -> Result<()> {
+  let packed_data = read_packet(arguments.input.as_deref())?;
+  let layout = inspect::packet_layout(&packed_data).context(Chatrouille)?;
+
+  println!("version: {:?}", layout.version);
+  println!("mode: {:?} ({})", layout.mode, layout.mode.clone() as u8);
+  println!("length: {}", packed_data.len());
+  for field in &layout.fields {
+    let bytes = &packed_data[field.offset..field.offset + field.length];
+    if field.name == "version" || field.name == "mode" || OPAQUE_FIELDS.contains(&field.name) {
+      println!("{:>6} {:>6}  {}", field.offset, field.length, field.name);
+    } else {
+      println!(
+        "{:>6} {:>6}  {}: {}",
+        field.offset,
+        field.length,
+        field.name,
+        base64::encode_config(bytes, base64::STANDARD_NO_PAD)
+      );
+    }
+  }
+  Ok(())
+}
+
+fn run() -> Result<()> {
+  let mut args = std::env::args().skip(1);
+  let command = match args.next() {
+    Some(command) => command,
+    None => {
+      return Usage {
+        message: "Missing command",
+      }
+      .fail()
+    }
+  };
+
+  match command.as_str() {
+    "gen-keys" => {
+      Arguments::parse(args, &[])?;
+      gen_keys();
+      Ok(())
+    }
+    "pack-query" => pack_query_command(&Arguments::parse(
+      args,
+      &["server-public-key", "server-mlkem-public-key", "keypair"],
+    )?),
+    "unpack-query" => unpack_query_command(&Arguments::parse(
+      args,
+      &["server-private-key", "server-mlkem-private-key", "client-signing-public-key"],
+    )?),
+    "unpack-response" => unpack_response_command(&Arguments::parse(
+      args,
+      &["shared-secret", "query-hash", "server-signing-public-key"],
+    )?),
+    "inspect" => inspect_command(&Arguments::parse(args, &[])?),
+    _ => Usage {
+      message: format!("Unknown command {}", command),
+    }
+    .fail(),
+  }
+}
+
+fn main() {
+  if let Err(error) = run() {
+    eprintln!("{}", error);
+    std::process::exit(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str], allowed_options: &[&str]) -> Result<Arguments> {
+    Arguments::parse(args.iter().map(|arg| (*arg).to_owned()), allowed_options)
+  }
+
+  #[test]
+  fn test_parse() {
+    let arguments = parse(&["--shared-secret", "c2VjcmV0", "response.bin"], &["shared-secret", "query-hash"]).unwrap();
+    assert_eq!(arguments.option("shared-secret"), Some("c2VjcmV0"));
+    assert_eq!(arguments.option("query-hash"), None);
+    assert_eq!(arguments.input.as_deref(), Some("response.bin"));
+    assert_eq!(arguments.required("shared-secret").unwrap(), "c2VjcmV0");
+    assert!(matches!(arguments.required("query-hash"), Err(CliError::Usage { .. })));
+
+    // The input can come before the options, - is stdin
+    let arguments = parse(&["-", "--keypair", "a2V5"], &["keypair"]).unwrap();
+    assert_eq!(arguments.input.as_deref(), Some("-"));
+    assert_eq!(arguments.option("keypair"), Some("a2V5"));
+
+    let arguments = parse(&[], &[]).unwrap();
+    assert!(arguments.input.is_none());
+    assert!(arguments.options.is_empty());
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    let message = |result: Result<Arguments>| match result {
+      Err(CliError::Usage { message }) => message,
+      _ => panic!("expected a usage error"),
+    };
+    assert_eq!(message(parse(&["--keypair", "a2V5"], &[])), "Unknown option --keypair");
+    assert_eq!(message(parse(&["--keypair"], &["keypair"])), "Missing value for --keypair");
+    assert_eq!(message(parse(&["query.bin", "response.bin"], &[])), "Unexpected argument response.bin");
+  }
+}
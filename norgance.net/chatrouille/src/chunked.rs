@@ -0,0 +1,658 @@
+/*!
+ * Chunked chatrouille messages, for payloads too large to be packed in one go.
+ *
+ * chunked query: [duck emoji (4 bytes)][mode ChunkedQuery (1 byte)][server key id (4 bytes)][client x448 public key (56 bytes)][stream nounce (24 bytes)][segments]
+ * chunked response: [duck emoji (4 bytes)][mode ChunkedResponse (1 byte)][stream nounce (24 bytes)][segments]
+ * segment: [sealed length (4 bytes, big endian)][data encrypted using the xchacha20poly1305 stream construction (n bytes)][stream tag and poly1305 tag (17 bytes)]
+ *
+ * Every segment is sealed under the same key derived from a single x448 exchange.
+ * The stream construction authenticates the order of the segments, the sealed
+ * length is authenticated as associated data, and the last segment is marked
+ * with the FINISH tag so a truncated message is detected.
+ *
+ * Unlike the other modes, the segments are not compressed. Large payloads are
+ * usually already encrypted or compressed blobs, and it keeps the streaming simple.
+ *
+ * The last segment of a signed query contains the signature nounce and the signature,
+ * computed on the same hash as a signed query.
+ */
+use alloc::{vec, vec::Vec};
+use crate::{
+  compressor::Codec, key_utils, key_utils::SharedSecret, ChatrouilleError, DecryptionError, EncryptionError, KeyDerivationError, Mode,
+  PrivateKeyring, Result, SignatureError, UnpackedQuery, UnpackedQuerySignature, Version,
+  CLIENT_PUBLIC_KEY_LENGTH, KEY_ID_LENGTH, MODE_LENGTH, NOUNCE_LENGTH, PACKET_VERSION, PACKET_VERSION_LENGTH, SIGNATURE_BLAKE2B_HASH_LENGTH,
+  SIGNATURE_BLAKE2B_HASH_SALT, SIGNATURE_LENGTH, SIGNATURE_NOUNCE_LENGTH, x448,
+};
+use orion::hazardous::aead::streaming::{
+  Nonce, SecretKey, StreamTag, StreamXChaCha20Poly1305, ABYTES,
+};
+use rand::{CryptoRng, RngCore};
+use snafu::ResultExt;
+
+pub(crate) const SEGMENT_LENGTH_LENGTH: usize = 4;
+/// Size of the data in each segment produced by the packer.
+pub const SEGMENT_DATA_LENGTH: usize = 4096;
+/// Segments larger than this are refused by the unpacker.
+pub const MAX_SEALED_SEGMENT_LENGTH: usize = 65536 + ABYTES;
+
+fn stream_key(shared_secret: &SharedSecret, mode: &Mode) -> Result<SecretKey> {
+  let symmetric_key =
+    key_utils::derive_shared_secret_to_sym_key(shared_secret, &mode.to_symmetric_key_salt())
+      .context(KeyDerivationError)?;
+  SecretKey::from_slice(symmetric_key.unprotected_as_bytes()).context(KeyDerivationError)
+}
+
+fn signature_hasher() -> blake2_rfc::blake2b::Blake2b {
+  blake2_rfc::blake2b::Blake2b::with_key(
+    SIGNATURE_BLAKE2B_HASH_LENGTH,
+    SIGNATURE_BLAKE2B_HASH_SALT,
+  )
+}
+
+/// Packs a message segment by segment.
+///
+/// Each call to `update` returns the bytes that can be sent right away,
+/// and `finish` returns the last segment.
+pub struct Packer<'a> {
+  mode: Mode,
+  header: Option<Vec<u8>>,
+  stream: StreamXChaCha20Poly1305,
+  client_keypair: Option<&'a ed25519_dalek::Keypair>,
+  hasher: Option<blake2_rfc::blake2b::Blake2b>,
+  // Drawn when the packer is created, so it doesn't have to keep the rng
+  signature_nounce: [u8; SIGNATURE_NOUNCE_LENGTH],
+}
+
+impl<'a> Packer<'a> {
+  #[cfg(feature = "std")]
+  pub fn query(
+    server_public_key: &x448::PublicKey,
+    client_keypair: Option<&'a ed25519_dalek::Keypair>,
+  ) -> Result<(Packer<'a>, SharedSecret)> {
+    Packer::query_with_rng(server_public_key, client_keypair, &mut rand::thread_rng())
+  }
+
+  /// Same as `query`, the client ephemeral key and the nounces come from the rng.
+  pub fn query_with_rng<R: RngCore + CryptoRng>(
+    server_public_key: &x448::PublicKey,
+    client_keypair: Option<&'a ed25519_dalek::Keypair>,
+    rng: &mut R,
+  ) -> Result<(Packer<'a>, SharedSecret)> {
+    let client_secret = key_utils::ZeroizingSecret::from(key_utils::gen_private_key_with_rng(rng));
+    let client_public_key = x448::PublicKey::from(&*client_secret);
+    let shared_secret = match client_secret.as_diffie_hellman(server_public_key) {
+      Some(secret) => SharedSecret::from(secret),
+      None => return Err(ChatrouilleError::DiffieHellmanFail),
+    };
+
+    let mode = match client_keypair {
+      Some(_) => Mode::ChunkedSignedQuery,
+      None => Mode::ChunkedQuery,
+    };
+
+    let key_id = key_utils::key_id(server_public_key);
+    let packer = Packer::new(
+      mode,
+      &[&key_id[..], client_public_key.as_bytes()].concat(),
+      &shared_secret,
+      client_keypair,
+      rng,
+    )?;
+    Ok((packer, shared_secret))
+  }
+
+  #[cfg(feature = "std")]
+  pub fn response(shared_secret: &SharedSecret) -> Result<Packer<'a>> {
+    Packer::response_with_rng(shared_secret, &mut rand::thread_rng())
+  }
+
+  /// Same as `response`, the nounce comes from the rng.
+  pub fn response_with_rng<R: RngCore + CryptoRng>(
+    shared_secret: &SharedSecret,
+    rng: &mut R,
+  ) -> Result<Packer<'a>> {
+    Packer::new(Mode::ChunkedResponse, &[], shared_secret, None, rng)
+  }
+
+  fn new<R: RngCore + CryptoRng>(
+    mode: Mode,
+    query_header_bytes: &[u8],
+    shared_secret: &SharedSecret,
+    client_keypair: Option<&'a ed25519_dalek::Keypair>,
+    rng: &mut R,
+  ) -> Result<Packer<'a>> {
+    let key = stream_key(shared_secret, &mode)?;
+    let mut nounce_bytes = [0_u8; NOUNCE_LENGTH];
+    rng.fill_bytes(&mut nounce_bytes);
+    let nounce = Nonce::from_slice(&nounce_bytes).context(EncryptionError)?;
+    let mut signature_nounce = [0_u8; SIGNATURE_NOUNCE_LENGTH];
+    rng.fill_bytes(&mut signature_nounce);
+    let stream = StreamXChaCha20Poly1305::new(&key, &nounce);
+
+    let mut header = Vec::with_capacity(
+      PACKET_VERSION_LENGTH + MODE_LENGTH + query_header_bytes.len() + NOUNCE_LENGTH,
+    );
+    header.extend(PACKET_VERSION);
+    header.push(mode.clone() as u8);
+    header.extend(query_header_bytes);
+    header.extend(nounce.as_ref());
+
+    let hasher = if mode == Mode::ChunkedSignedQuery {
+      Some(signature_hasher())
+    } else {
+      None
+    };
+
+    Ok(Packer {
+      mode,
+      header: Some(header),
+      stream,
+      client_keypair,
+      hasher,
+      signature_nounce,
+    })
+  }
+
+  fn seal_segment(&mut self, data: &[u8], tag: StreamTag, output: &mut Vec<u8>) -> Result<()> {
+    use core::convert::TryFrom;
+    let sealed_length = data.len() + ABYTES;
+    let sealed_length_bytes = match u32::try_from(sealed_length) {
+      Ok(sealed_length) => sealed_length.to_be_bytes(),
+      Err(_) => return Err(ChatrouilleError::InvalidSegmentLength),
+    };
+    output.extend(&sealed_length_bytes);
+    let start = output.len();
+    output.resize(start + sealed_length, 0);
+    self
+      .stream
+      .seal_chunk(data, Some(&sealed_length_bytes), &mut output[start..], tag)
+      .context(EncryptionError)
+  }
+
+  fn take_header(&mut self) -> Vec<u8> {
+    self.header.take().unwrap_or_default()
+  }
+
+  /// Seals the data and returns the bytes to send.
+  pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+    let mut output = self.take_header();
+    if let Some(hasher) = self.hasher.as_mut() {
+      hasher.update(data);
+    }
+    for segment in data.chunks(SEGMENT_DATA_LENGTH) {
+      self.seal_segment(segment, StreamTag::MESSAGE, &mut output)?;
+    }
+    Ok(output)
+  }
+
+  /// Seals the last segment, containing the signature for signed queries.
+  pub fn finish(mut self) -> Result<Vec<u8>> {
+    let mut output = self.take_header();
+
+    let last_segment = match self.hasher.take() {
+      Some(hasher) => {
+        let keypair = match self.client_keypair {
+          Some(keypair) => keypair,
+          None => return Err(ChatrouilleError::MissingKeyPair),
+        };
+        crate::sign_hash_with_nounce(hasher.finalize().as_bytes(), keypair, &self.signature_nounce)
+      }
+      None => Vec::new(),
+    };
+
+    self.seal_segment(&last_segment, StreamTag::FINISH, &mut output)?;
+    Ok(output)
+  }
+
+  #[must_use]
+  pub fn mode(&self) -> Mode {
+    self.mode.clone()
+  }
+}
+
+enum UnpackerKey<'a> {
+  Query(&'a dyn PrivateKeyring),
+  Response(SecretKey),
+}
+
+/// Unpacks a message as its bytes arrive.
+///
+/// The decrypted payload is kept in memory and can't grow larger
+/// than `max_payload_length`.
+pub struct Unpacker<'a> {
+  key: UnpackerKey<'a>,
+  max_payload_length: usize,
+  buffer: Vec<u8>,
+  mode: Mode,
+  shared_secret: Option<SharedSecret>,
+  stream: Option<StreamXChaCha20Poly1305>,
+  hasher: Option<blake2_rfc::blake2b::Blake2b>,
+  payload: Vec<u8>,
+  last_segment: Option<Vec<u8>>,
+}
+
+impl<'a> Unpacker<'a> {
+  #[must_use]
+  pub fn query(keyring: &'a dyn PrivateKeyring, max_payload_length: usize) -> Unpacker<'a> {
+    Unpacker::new(UnpackerKey::Query(keyring), max_payload_length)
+  }
+
+  pub fn response(
+    shared_secret: &SharedSecret,
+    max_payload_length: usize,
+  ) -> Result<Unpacker<'a>> {
+    let key = stream_key(shared_secret, &Mode::ChunkedResponse)?;
+    Ok(Unpacker::new(UnpackerKey::Response(key), max_payload_length))
+  }
+
+  fn new(key: UnpackerKey<'a>, max_payload_length: usize) -> Unpacker<'a> {
+    Unpacker {
+      key,
+      max_payload_length,
+      buffer: Vec::new(),
+      mode: Mode::Unknown,
+      shared_secret: None,
+      stream: None,
+      hasher: None,
+      payload: Vec::new(),
+      last_segment: None,
+    }
+  }
+
+  fn header_length(&self) -> usize {
+    match self.key {
+      UnpackerKey::Query(_) => {
+        PACKET_VERSION_LENGTH
+          + MODE_LENGTH
+          + KEY_ID_LENGTH
+          + CLIENT_PUBLIC_KEY_LENGTH
+          + NOUNCE_LENGTH
+      }
+      UnpackerKey::Response(_) => PACKET_VERSION_LENGTH + MODE_LENGTH + NOUNCE_LENGTH,
+    }
+  }
+
+  fn read_header(&mut self) -> Result<usize> {
+    if self.buffer[0..PACKET_VERSION_LENGTH] != *PACKET_VERSION {
+      return Err(ChatrouilleError::InvalidDataPrefix);
+    }
+
+    let mode = Mode::from(self.buffer[PACKET_VERSION_LENGTH]);
+    let mut offset = PACKET_VERSION_LENGTH + MODE_LENGTH;
+
+    let key = match &self.key {
+      UnpackerKey::Query(keyring) => {
+        if mode != Mode::ChunkedQuery && mode != Mode::ChunkedSignedQuery {
+          return Err(ChatrouilleError::InvalidModeInData);
+        }
+        let private_key = match crate::query_key_id(&self.buffer) {
+          Some(key_id) => match keyring.private_key(&key_id) {
+            Some(private_key) => private_key,
+            None => return Err(ChatrouilleError::UnknownServerKey),
+          },
+          None => return Err(ChatrouilleError::NotEnoughData),
+        };
+        offset += KEY_ID_LENGTH;
+        let public_key_bytes = &self.buffer[offset..offset + CLIENT_PUBLIC_KEY_LENGTH];
+        offset += CLIENT_PUBLIC_KEY_LENGTH;
+        let public_key = match x448::PublicKey::from_bytes(public_key_bytes) {
+          Some(public_key) => public_key,
+          None => return Err(ChatrouilleError::KeyLoadingError),
+        };
+        let shared_secret = match private_key.as_diffie_hellman(&public_key) {
+          Some(shared_secret) => SharedSecret::from(shared_secret),
+          None => return Err(ChatrouilleError::DiffieHellmanFail),
+        };
+        let key = stream_key(&shared_secret, &mode)?;
+        self.shared_secret = Some(shared_secret);
+        key
+      }
+      UnpackerKey::Response(key) => {
+        if mode != Mode::ChunkedResponse {
+          return Err(ChatrouilleError::InvalidModeInData);
+        }
+        SecretKey::from_slice(key.unprotected_as_bytes()).context(KeyDerivationError)?
+      }
+    };
+
+    let nounce =
+      Nonce::from_slice(&self.buffer[offset..offset + NOUNCE_LENGTH]).context(DecryptionError)?;
+    offset += NOUNCE_LENGTH;
+
+    self.stream = Some(StreamXChaCha20Poly1305::new(&key, &nounce));
+    if mode == Mode::ChunkedSignedQuery {
+      self.hasher = Some(signature_hasher());
+    }
+    self.mode = mode;
+    Ok(offset)
+  }
+
+  /// Reads the next segment from the buffer, if it's complete.
+  fn read_segment(&mut self, offset: usize) -> Result<Option<usize>> {
+    let available = self.buffer.len() - offset;
+    if available < SEGMENT_LENGTH_LENGTH {
+      return Ok(None);
+    }
+
+    let mut sealed_length_bytes = [0_u8; SEGMENT_LENGTH_LENGTH];
+    sealed_length_bytes.copy_from_slice(&self.buffer[offset..offset + SEGMENT_LENGTH_LENGTH]);
+    let sealed_length = u32::from_be_bytes(sealed_length_bytes) as usize;
+    if !(ABYTES..=MAX_SEALED_SEGMENT_LENGTH).contains(&sealed_length) {
+      return Err(ChatrouilleError::InvalidSegmentLength);
+    }
+    if available < SEGMENT_LENGTH_LENGTH + sealed_length {
+      return Ok(None);
+    }
+
+    let data_length = sealed_length - ABYTES;
+    if self.payload.len() + data_length > self.max_payload_length {
+      return Err(ChatrouilleError::PayloadTooLarge);
+    }
+
+    let sealed_start = offset + SEGMENT_LENGTH_LENGTH;
+    let sealed_segment = &self.buffer[sealed_start..sealed_start + sealed_length];
+    let mut segment = vec![0_u8; data_length];
+    let stream = match self.stream.as_mut() {
+      Some(stream) => stream,
+      None => unreachable!(),
+    };
+    let tag = stream
+      .open_chunk(sealed_segment, Some(&sealed_length_bytes), &mut segment)
+      .context(DecryptionError)?;
+
+    if tag == StreamTag::FINISH {
+      self.last_segment = Some(segment);
+    } else if tag == StreamTag::MESSAGE {
+      if let Some(hasher) = self.hasher.as_mut() {
+        hasher.update(&segment);
+      }
+      self.payload.append(&mut segment);
+    } else {
+      return Err(ChatrouilleError::InvalidSegmentLength);
+    }
+
+    Ok(Some(sealed_start + sealed_length))
+  }
+
+  /// Decrypts the segments that are complete.
+  pub fn update(&mut self, bytes: &[u8]) -> Result<()> {
+    if self.last_segment.is_some() {
+      if bytes.is_empty() {
+        return Ok(());
+      }
+      return Err(ChatrouilleError::DataAfterLastSegment);
+    }
+
+    self.buffer.extend_from_slice(bytes);
+
+    let mut offset = 0;
+    if self.stream.is_none() {
+      if self.buffer.len() < self.header_length() {
+        return Ok(());
+      }
+      offset = self.read_header()?;
+    }
+
+    while let Some(next_offset) = self.read_segment(offset)? {
+      offset = next_offset;
+      if self.last_segment.is_some() {
+        if offset != self.buffer.len() {
+          return Err(ChatrouilleError::DataAfterLastSegment);
+        }
+        break;
+      }
+    }
+
+    self.buffer.drain(0..offset);
+    Ok(())
+  }
+
+  /// Returns the query once the last segment has been read.
+  pub fn finish_query(self) -> Result<UnpackedQuery> {
+    let last_segment = match self.last_segment {
+      Some(last_segment) => last_segment,
+      None => return Err(ChatrouilleError::NotEnoughData),
+    };
+    let shared_secret = match self.shared_secret {
+      Some(shared_secret) => shared_secret,
+      None => return Err(ChatrouilleError::InvalidModeInData),
+    };
+
+    let signature = match self.hasher {
+      Some(hasher) => {
+        use ed25519_dalek::Signature;
+        use core::convert::TryFrom;
+
+        if last_segment.len() != SIGNATURE_NOUNCE_LENGTH + SIGNATURE_LENGTH {
+          return Err(ChatrouilleError::NotEnoughData);
+        }
+        let signature = Signature::try_from(&last_segment[SIGNATURE_NOUNCE_LENGTH..])
+          .context(SignatureError)?;
+        Some(UnpackedQuerySignature {
+          query_hash: hasher.finalize().as_bytes().to_vec(),
+          transcript_hash: None,
+          nounce: last_segment[0..SIGNATURE_NOUNCE_LENGTH].to_vec(),
+          signature,
+        })
+      }
+      None => {
+        if !last_segment.is_empty() {
+          return Err(ChatrouilleError::InvalidSegmentLength);
+        }
+        None
+      }
+    };
+
+    Ok(UnpackedQuery {
+      payload: self.payload,
+      version: Version::Duck,
+      // The segments are not compressed
+      codec: Codec::None,
+      mode: self.mode,
+      shared_secret,
+      signature,
+      sender_public_key: None,
+    })
+  }
+
+  /// Returns the response once the last segment has been read.
+  pub fn finish_response(self) -> Result<Vec<u8>> {
+    match self.last_segment {
+      Some(last_segment) if last_segment.is_empty() => Ok(self.payload),
+      Some(_) => Err(ChatrouilleError::InvalidSegmentLength),
+      None => Err(ChatrouilleError::NotEnoughData),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+pub fn pack_chunked_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_chunked_query_with_rng(data, server_public_key, client_keypair, &mut rand::thread_rng())
+}
+
+/// Same as `pack_chunked_query`, the client ephemeral key and the nounces come from the rng.
+pub fn pack_chunked_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  let (mut packer, shared_secret) = Packer::query_with_rng(server_public_key, client_keypair, rng)?;
+  let mut packed_data = packer.update(data)?;
+  packed_data.append(&mut packer.finish()?);
+  Ok((packed_data, shared_secret))
+}
+
+#[cfg(feature = "std")]
+pub fn pack_chunked_response(data: &[u8], shared_secret: &SharedSecret) -> Result<Vec<u8>> {
+  pack_chunked_response_with_rng(data, shared_secret, &mut rand::thread_rng())
+}
+
+/// Same as `pack_chunked_response`, the nounce comes from the rng.
+pub fn pack_chunked_response_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  rng: &mut R,
+) -> Result<Vec<u8>> {
+  let mut packer = Packer::response_with_rng(shared_secret, rng)?;
+  let mut packed_data = packer.update(data)?;
+  packed_data.append(&mut packer.finish()?);
+  Ok(packed_data)
+}
+
+pub fn unpack_chunked_query(
+  packed_data: &[u8],
+  keyring: &dyn PrivateKeyring,
+  max_payload_length: usize,
+) -> Result<UnpackedQuery> {
+  let mut unpacker = Unpacker::query(keyring, max_payload_length);
+  unpacker.update(packed_data)?;
+  unpacker.finish_query()
+}
+
+pub fn unpack_chunked_response(
+  packed_data: &[u8],
+  shared_secret: &SharedSecret,
+  max_payload_length: usize,
+) -> Result<Vec<u8>> {
+  // The errors are never chunked
+  if crate::packet_mode(packed_data) == Mode::Error {
+    return crate::unpack_response(packed_data, shared_secret, max_payload_length);
+  }
+  let mut unpacker = Unpacker::response(shared_secret, max_payload_length)?;
+  unpacker.update(packed_data)?;
+  unpacker.finish_response()
+}
+
+#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use crate::VerifyUnpackedQuerySignature;
+
+  const MAX: usize = 1024 * 1024;
+
+  fn large_payload() -> Vec<u8> {
+    (0..20_000_u32).flat_map(|i| i.to_be_bytes().to_vec()).collect()
+  }
+
+  #[test]
+  fn test_chunked_unsigned() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let data = large_payload();
+
+    let (query, shared_secret) = pack_chunked_query(&data, &server_public_key, None).unwrap();
+    assert!(query.len() > data.len());
+    assert!(crate::packet_mode(&query) == Mode::ChunkedQuery);
+    assert!(crate::packet_mode(&query[0..2]) == Mode::Unknown);
+
+    let unpacked_query = unpack_chunked_query(&query, &server_private_key, MAX).unwrap();
+    assert!(unpacked_query.mode == Mode::ChunkedQuery);
+    assert!(unpacked_query.signature.is_none());
+    assert_eq!(unpacked_query.payload, data);
+    assert_eq!(
+      unpacked_query.shared_secret.as_bytes().to_vec(),
+      shared_secret.as_bytes().to_vec()
+    );
+
+    let response = pack_chunked_response(&data, &shared_secret).unwrap();
+    let unpacked_response = unpack_chunked_response(&response, &shared_secret, MAX).unwrap();
+    assert_eq!(unpacked_response, data);
+  }
+
+  #[test]
+  fn test_chunked_signed() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let keypair = key_utils::gen_ed25519_keypair();
+    let data = large_payload();
+
+    let (query, _) = pack_chunked_query(&data, &server_public_key, Some(&keypair)).unwrap();
+    let unpacked_query = unpack_chunked_query(&query, &server_private_key, MAX).unwrap();
+    assert!(unpacked_query.mode == Mode::ChunkedSignedQuery);
+    assert_eq!(unpacked_query.payload, data);
+    let signature = unpacked_query.signature.unwrap();
+    assert!(signature.verify(&keypair.public).is_ok());
+
+    let another_keypair = key_utils::gen_ed25519_keypair();
+    assert!(signature.verify(&another_keypair.public).is_err());
+  }
+
+  #[test]
+  fn test_incremental() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let data = large_payload();
+
+    let (mut packer, _) = Packer::query(&server_public_key, None).unwrap();
+    let mut query = Vec::new();
+    for part in data.chunks(1000) {
+      query.append(&mut packer.update(part).unwrap());
+    }
+    query.append(&mut packer.finish().unwrap());
+
+    // Feed the unpacker byte ranges that don't match the segments
+    let mut unpacker = Unpacker::query(&server_private_key, MAX);
+    for part in query.chunks(333) {
+      unpacker.update(part).unwrap();
+    }
+    assert_eq!(unpacker.finish_query().unwrap().payload, data);
+  }
+
+  #[test]
+  fn test_chunked_problems() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let data = large_payload();
+
+    let (query, shared_secret) = pack_chunked_query(&data, &server_public_key, None).unwrap();
+
+    // Too large for the limit
+    assert!(matches!(
+      unpack_chunked_query(&query, &server_private_key, 1000),
+      Err(ChatrouilleError::PayloadTooLarge)
+    ));
+
+    // Truncated
+    assert!(unpack_chunked_query(&query[..query.len() - 10], &server_private_key, MAX).is_err());
+    let header_length = PACKET_VERSION_LENGTH
+      + MODE_LENGTH
+      + KEY_ID_LENGTH
+      + CLIENT_PUBLIC_KEY_LENGTH
+      + NOUNCE_LENGTH;
+    let first_segment_length = SEGMENT_LENGTH_LENGTH + SEGMENT_DATA_LENGTH + ABYTES;
+    let mut without_last_segments = query[..header_length + first_segment_length].to_vec();
+    assert!(unpack_chunked_query(&without_last_segments, &server_private_key, MAX).is_err());
+
+    // Reordered segments
+    let second_segment =
+      query[header_length + first_segment_length..header_length + 2 * first_segment_length]
+        .to_vec();
+    let mut reordered = query[..header_length].to_vec();
+    reordered.extend(&second_segment);
+    reordered.extend(&query[header_length..]);
+    assert!(unpack_chunked_query(&reordered, &server_private_key, MAX).is_err());
+
+    // Data after the end
+    let mut with_trailing_data = query.clone();
+    with_trailing_data.push(0);
+    assert!(unpack_chunked_query(&with_trailing_data, &server_private_key, MAX).is_err());
+
+    // Tampered length
+    without_last_segments[header_length] = 0xff;
+    assert!(matches!(
+      unpack_chunked_query(&without_last_segments, &server_private_key, MAX),
+      Err(ChatrouilleError::InvalidSegmentLength)
+    ));
+
+    // Wrong mode for the unpacker
+    assert!(unpack_chunked_response(&query, &shared_secret, MAX).is_err());
+    let (regular_query, _) = crate::pack_unsigned_query(b"hei", &server_public_key).unwrap();
+    assert!(unpack_chunked_query(&regular_query, &server_private_key, MAX).is_err());
+  }
+}
@@ -0,0 +1,621 @@
+/*!
+ * HTTP client of a Norgance chatrouille server, doing what the browser does in chatrouille.js.
+ *
+ * The client fetches `/chatrouille_information`, verifies the server keys with the
+ * hardcoded signing key, and estimates the server time to set the expiration of the queries.
+ * Every response is signed by the server, and comes with its receipt.
+ *
+ * Through an oblivious relay, the server doesn't see the address of the client,
+ * and the relay only sees encrypted packets. The client checks that the information,
+ * loaded when connecting, and every response went through the relay. A misconfigured URL
+ * fails to connect before any query is sent, the server only sees the address in the
+ * request of the information.
+ *
+ * The HTTP calls are blocking.
+ */
+use crate::envelope::{pack_json, Envelope};
+use crate::{
+  compressor::Codec, key_utils, mlkem, packet_mode, unpack_response, unpack_signed_response, ChatrouilleError,
+  HttpError, JsonError, Mode, ResponseReceipt, Result, x448,
+};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::Read;
+
+/// Chatrouille queries expire after 120 seconds, like in the browser.
+pub const DEFAULT_EXPIRATION_DELAY: u64 = 120;
+
+/// Responses larger than this once uncompressed are refused.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Header added by the oblivious relays to their responses.
+pub const RELAY_HEADER: &str = "Norgance-Relay";
+
+// The padding at most doubles the compressed response, plus the header and the signature
+const PACKED_RESPONSE_OVERHEAD: usize = 1024;
+
+#[derive(Deserialize)]
+struct ChatrouilleInformation {
+  public_key_x448: String,
+  public_key_x448_signature: String,
+  #[serde(default)]
+  public_key_mlkem768: Option<String>,
+  #[serde(default)]
+  public_key_mlkem768_signature: Option<String>,
+  time: u64,
+}
+
+#[derive(Serialize)]
+struct GraphqlQuery<'a> {
+  graphql: &'a serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct GraphqlResult {
+  #[serde(default)]
+  data: serde_json::Value,
+  #[serde(default)]
+  errors: Vec<serde_json::Value>,
+}
+
+/// Response of the server, with the proof that the server answered the query.
+pub struct ChatrouilleResponse {
+  pub payload: Vec<u8>,
+  pub receipt: ResponseReceipt,
+}
+
+/// The data of a GraphQL response without errors.
+pub struct GraphqlResponse {
+  pub data: serde_json::Value,
+  pub receipt: ResponseReceipt,
+}
+
+pub struct ChatrouilleClient {
+  agent: ureq::Agent,
+  base_url: String,
+  server_signing_public_key: ed25519_dalek::PublicKey,
+  server_public_key: x448::PublicKey,
+  server_mlkem_public_key: Option<mlkem::EncapsulationKey>,
+  // The server time and the local time when the information was loaded
+  server_time: u64,
+  local_time: u64,
+  expiration_delay: u64,
+  max_response_size: usize,
+  codec: Codec,
+  through_relay: bool,
+}
+
+impl ChatrouilleClient {
+  /// `base_url` is the URL of the server without the trailing slash, such as `https://norgance.net`.
+  pub fn connect(base_url: &str, server_signing_public_key: ed25519_dalek::PublicKey) -> Result<ChatrouilleClient> {
+    ChatrouilleClient::with_agent(ureq::Agent::new(), base_url, server_signing_public_key)
+  }
+
+  /// Same as `connect`, through the oblivious relay at `relay_url`.
+  /// The server keys are loaded through the relay too, they are signed anyway.
+  pub fn connect_through_relay(
+    relay_url: &str,
+    server_signing_public_key: ed25519_dalek::PublicKey,
+  ) -> Result<ChatrouilleClient> {
+    ChatrouilleClient::build(ureq::Agent::new(), relay_url, server_signing_public_key, true)
+  }
+
+  /// Same as `connect`, with an agent configured by the caller (timeouts, proxy, TLS).
+  pub fn with_agent(
+    agent: ureq::Agent,
+    base_url: &str,
+    server_signing_public_key: ed25519_dalek::PublicKey,
+  ) -> Result<ChatrouilleClient> {
+    ChatrouilleClient::build(agent, base_url, server_signing_public_key, false)
+  }
+
+  fn build(
+    agent: ureq::Agent,
+    base_url: &str,
+    server_signing_public_key: ed25519_dalek::PublicKey,
+    through_relay: bool,
+  ) -> Result<ChatrouilleClient> {
+    let information = load_information(&agent, base_url, through_relay)?;
+    let (server_public_key, server_mlkem_public_key) = verify_information(&information, &server_signing_public_key)?;
+
+    Ok(ChatrouilleClient {
+      agent,
+      base_url: String::from(base_url),
+      server_signing_public_key,
+      server_public_key,
+      server_mlkem_public_key,
+      server_time: information.time,
+      local_time: unix_time(),
+      expiration_delay: DEFAULT_EXPIRATION_DELAY,
+      max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+      codec: Codec::default(),
+      through_relay,
+    })
+  }
+
+  /// Loads the server keys and time again, after a key rotation for example.
+  pub fn refresh(&mut self) -> Result<()> {
+    let information = load_information(&self.agent, &self.base_url, self.through_relay)?;
+    let (server_public_key, server_mlkem_public_key) =
+      verify_information(&information, &self.server_signing_public_key)?;
+
+    self.server_public_key = server_public_key;
+    self.server_mlkem_public_key = server_mlkem_public_key;
+    self.server_time = information.time;
+    self.local_time = unix_time();
+    Ok(())
+  }
+
+  pub fn set_expiration_delay(&mut self, expiration_delay: u64) {
+    self.expiration_delay = expiration_delay;
+  }
+
+  pub fn set_max_response_size(&mut self, max_response_size: usize) {
+    self.max_response_size = max_response_size;
+  }
+
+  pub fn set_codec(&mut self, codec: Codec) {
+    self.codec = codec;
+  }
+
+  /// The queries use the hybrid owl version when the server publishes a ML-KEM key.
+  #[must_use]
+  pub fn is_hybrid(&self) -> bool {
+    self.server_mlkem_public_key.is_some()
+  }
+
+  #[must_use]
+  pub fn is_through_relay(&self) -> bool {
+    self.through_relay
+  }
+
+  /// Estimation of the current server time, in seconds.
+  #[must_use]
+  pub fn server_time(&self) -> u64 {
+    self
+      .server_time
+      .saturating_add(unix_time().saturating_sub(self.local_time))
+  }
+
+  /// Sends the body in the chatrouille envelope, and returns the raw response.
+  ///
+  /// The query is signed with the keypair when it's given,
+  /// and a citizen identifier requires a keypair.
+  pub fn query<T: Serialize>(
+    &self,
+    body: T,
+    citizen_identifier: Option<&str>,
+    client_keypair: Option<&ed25519_dalek::Keypair>,
+  ) -> Result<ChatrouilleResponse> {
+    let mut envelope = Envelope::new(body, self.server_time().saturating_add(self.expiration_delay));
+    envelope.citizen_identifier = citizen_identifier.map(String::from);
+
+    let query = pack_json(
+      &envelope,
+      &self.server_public_key,
+      self.server_mlkem_public_key.as_ref(),
+      client_keypair,
+      self.codec,
+    )?;
+
+    let http_response = self
+      .agent
+      .post(&format!("{}/chatrouille", self.base_url))
+      .set("Content-Type", "application/octet-stream")
+      .send_bytes(&query.packed_data)
+      .map_err(Box::new)
+      .context(HttpError)?;
+    check_relay(&http_response, self.through_relay)?;
+    let packed_response = read_body(http_response, self.max_packed_response_size())?;
+
+    if packet_mode(&packed_response) != Mode::SignedResponse {
+      // Most likely an error, but the server signs all its other responses
+      return match unpack_response(&packed_response, &query.shared_secret, self.max_response_size) {
+        Ok(_) => Err(ChatrouilleError::InvalidModeInData),
+        Err(error) => Err(error),
+      };
+    }
+
+    let (payload, receipt) = unpack_signed_response(
+      &packed_response,
+      &query.shared_secret,
+      &query.query_hash,
+      &self.server_signing_public_key,
+      self.max_response_size,
+    )?;
+    Ok(ChatrouilleResponse { payload, receipt })
+  }
+
+  /// The GraphQL query is the JSON object sent to the server, with `query` and `variables`.
+  pub fn anonymous_graphql(&self, graphql: &serde_json::Value) -> Result<GraphqlResponse> {
+    self.graphql(graphql, None, None)
+  }
+
+  /// Same as `anonymous_graphql`, the query is signed for the citizen.
+  pub fn signed_graphql(
+    &self,
+    graphql: &serde_json::Value,
+    citizen_identifier: &str,
+    citizen_keypair: &ed25519_dalek::Keypair,
+  ) -> Result<GraphqlResponse> {
+    self.graphql(graphql, Some(citizen_identifier), Some(citizen_keypair))
+  }
+
+  fn graphql(
+    &self,
+    graphql: &serde_json::Value,
+    citizen_identifier: Option<&str>,
+    client_keypair: Option<&ed25519_dalek::Keypair>,
+  ) -> Result<GraphqlResponse> {
+    let response = self.query(GraphqlQuery { graphql }, citizen_identifier, client_keypair)?;
+    let result: GraphqlResult = serde_json::from_slice(&response.payload).context(JsonError)?;
+    if !result.errors.is_empty() {
+      return Err(ChatrouilleError::GraphqlErrors { errors: result.errors });
+    }
+    Ok(GraphqlResponse {
+      data: result.data,
+      receipt: response.receipt,
+    })
+  }
+
+  fn max_packed_response_size(&self) -> usize {
+    self
+      .max_response_size
+      .saturating_mul(2)
+      .saturating_add(PACKED_RESPONSE_OVERHEAD)
+  }
+}
+
+fn unix_time() -> u64 {
+  match std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH) {
+    Ok(time) => time.as_secs(),
+    Err(_) => 0,
+  }
+}
+
+fn read_body(response: ureq::Response, max_size: usize) -> Result<Vec<u8>> {
+  let mut body = Vec::new();
+  response
+    .into_reader()
+    .take(max_size as u64 + 1)
+    .read_to_end(&mut body)
+    .map_err(|error| Box::new(ureq::Error::from(error)))
+    .context(HttpError)?;
+  if body.len() > max_size {
+    return Err(ChatrouilleError::PayloadTooLarge);
+  }
+  Ok(body)
+}
+
+fn check_relay(response: &ureq::Response, through_relay: bool) -> Result<()> {
+  if through_relay && response.header(RELAY_HEADER).is_none() {
+    return Err(ChatrouilleError::MissingRelay);
+  }
+  Ok(())
+}
+
+fn load_information(agent: &ureq::Agent, base_url: &str, through_relay: bool) -> Result<ChatrouilleInformation> {
+  let response = agent
+    .get(&format!("{base_url}/chatrouille_information"))
+    .call()
+    .map_err(Box::new)
+    .context(HttpError)?;
+  check_relay(&response, through_relay)?;
+  let body = read_body(response, DEFAULT_MAX_RESPONSE_SIZE)?;
+  serde_json::from_slice(&body).context(JsonError)
+}
+
+/// The x448 public key is signed by the server signing key, and the ML-KEM public key
+/// is signed with the x448 public key in front of it.
+fn verify_information(
+  information: &ChatrouilleInformation,
+  server_signing_public_key: &ed25519_dalek::PublicKey,
+) -> Result<(x448::PublicKey, Option<mlkem::EncapsulationKey>)> {
+  let server_public_key = match key_utils::public_key_from_base64(&information.public_key_x448) {
+    Some(key) => key,
+    None => return Err(ChatrouilleError::KeyLoadingError),
+  };
+  verify_key_signature(
+    server_public_key.as_bytes(),
+    &information.public_key_x448_signature,
+    server_signing_public_key,
+  )?;
+
+  let server_mlkem_public_key = match (
+    &information.public_key_mlkem768,
+    &information.public_key_mlkem768_signature,
+  ) {
+    (Some(mlkem_public_key), Some(signature)) => {
+      let mlkem_public_key = match key_utils::mlkem_public_key_from_base64(mlkem_public_key) {
+        Some(key) => key,
+        None => return Err(ChatrouilleError::KeyLoadingError),
+      };
+      let signed_data = [server_public_key.as_bytes(), mlkem_public_key.as_bytes()].concat();
+      verify_key_signature(&signed_data, signature, server_signing_public_key)?;
+      Some(mlkem_public_key)
+    }
+    (Some(_), None) => return Err(ChatrouilleError::InvalidServerKeySignature),
+    (None, _) => None,
+  };
+
+  Ok((server_public_key, server_mlkem_public_key))
+}
+
+fn verify_key_signature(
+  signed_data: &[u8],
+  signature_base64: &str,
+  server_signing_public_key: &ed25519_dalek::PublicKey,
+) -> Result<()> {
+  use core::convert::TryFrom;
+  use ed25519_dalek::Verifier;
+
+  let signature_bytes = match base64::decode(signature_base64) {
+    Ok(bytes) => bytes,
+    Err(_) => return Err(ChatrouilleError::InvalidServerKeySignature),
+  };
+  let signature = match ed25519_dalek::Signature::try_from(&signature_bytes[..]) {
+    Ok(signature) => signature,
+    Err(_) => return Err(ChatrouilleError::InvalidServerKeySignature),
+  };
+  match server_signing_public_key.verify(signed_data, &signature) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(ChatrouilleError::InvalidServerKeySignature),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::envelope::unpack_json;
+  use crate::padding::PaddingPolicy;
+  use crate::{pack_error, pack_signed_response_with_codec, unpack_query, PrivateKeyring, VerifyUnpackedQuerySignature};
+  use ed25519_dalek::Signer;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::TcpListener;
+
+  const MAX: usize = 1024 * 1024;
+
+  #[derive(Deserialize)]
+  struct GraphqlBody {
+    graphql: serde_json::Value,
+  }
+
+  struct FakeServer {
+    private_key: x448::Secret,
+    public_key: x448::PublicKey,
+    mlkem_private_key: Option<mlkem::DecapsulationKey>,
+    signing_keypair: ed25519_dalek::Keypair,
+    client_public_key: Option<ed25519_dalek::PublicKey>,
+    // Answers like an oblivious relay in front of the server
+    relay: bool,
+  }
+
+  impl PrivateKeyring for FakeServer {
+    fn private_key(&self, _key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+      Some(&self.private_key)
+    }
+
+    fn mlkem_private_key(&self, _key_id: &key_utils::KeyId) -> Option<&mlkem::DecapsulationKey> {
+      self.mlkem_private_key.as_ref()
+    }
+  }
+
+  impl FakeServer {
+    fn new(hybrid: bool) -> FakeServer {
+      let private_key = key_utils::gen_private_key();
+      let public_key = key_utils::gen_public_key(&private_key);
+      FakeServer {
+        private_key,
+        public_key,
+        mlkem_private_key: if hybrid {
+          Some(key_utils::gen_mlkem_private_key())
+        } else {
+          None
+        },
+        signing_keypair: key_utils::gen_ed25519_keypair(),
+        client_public_key: None,
+        relay: false,
+      }
+    }
+
+    fn information(&self) -> serde_json::Value {
+      let signature = self.signing_keypair.sign(self.public_key.as_bytes());
+      let mut information = serde_json::json!({
+        "public_key_x448": key_utils::public_key_to_base64(&self.public_key),
+        "public_key_x448_signature": base64::encode(signature.to_bytes()),
+        "time": 1_600_000_000,
+      });
+      if let Some(mlkem_private_key) = &self.mlkem_private_key {
+        let mlkem_public_key = mlkem_private_key.encapsulation_key();
+        let signed_data = [self.public_key.as_bytes(), mlkem_public_key.as_bytes()].concat();
+        information["public_key_mlkem768"] =
+          serde_json::Value::from(key_utils::mlkem_public_key_to_base64(mlkem_public_key));
+        information["public_key_mlkem768_signature"] =
+          serde_json::Value::from(base64::encode(self.signing_keypair.sign(&signed_data).to_bytes()));
+      }
+      information
+    }
+
+    /// Answers the GraphQL queries by echoing them, and the queries asking for an error with an error.
+    fn answer(&self, packed_query: &[u8]) -> Vec<u8> {
+      let unpacked_query = unpack_query(packed_query, self, MAX).unwrap();
+      let envelope: Envelope<GraphqlBody> = unpack_json(&unpacked_query).unwrap();
+      envelope.check_expiration(1_600_000_000, 300, 30).unwrap();
+
+      if let Some(signature) = &unpacked_query.signature {
+        signature.verify(self.client_public_key.as_ref().unwrap()).unwrap();
+      }
+      if envelope.body.graphql["query"] == "error" {
+        return pack_error(410, "The request has expired", &unpacked_query.shared_secret, unpacked_query.version)
+          .unwrap();
+      }
+
+      let response = serde_json::json!({
+        "data": {
+          "query": envelope.body.graphql["query"],
+          "citizenIdentifier": envelope.citizen_identifier,
+        },
+      });
+      let response = if envelope.body.graphql["query"] == "graphql error" {
+        serde_json::json!({ "data": null, "errors": [{ "message": "Coin" }] })
+      } else {
+        response
+      };
+      pack_signed_response_with_codec(
+        &serde_json::to_vec(&response).unwrap(),
+        &unpacked_query.shared_secret,
+        &crate::query_hash(&unpacked_query.payload),
+        &self.signing_keypair,
+        unpacked_query.version,
+        PaddingPolicy::default(),
+        unpacked_query.codec,
+      )
+      .unwrap()
+    }
+
+    /// Serves the HTTP requests on a local port, one request per connection.
+    fn serve(self) -> String {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let base_url = format!("http://{}", listener.local_addr().unwrap());
+      std::thread::spawn(move || {
+        for stream in listener.incoming() {
+          let mut stream = stream.unwrap();
+          let mut reader = BufReader::new(stream.try_clone().unwrap());
+          let mut request_line = String::new();
+          reader.read_line(&mut request_line).unwrap();
+          let mut content_length = 0;
+          loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+              break;
+            }
+            if let Some(length) = header.to_lowercase().strip_prefix("content-length:") {
+              content_length = length.trim().parse().unwrap();
+            }
+          }
+          let mut body = vec![0_u8; content_length];
+          reader.read_exact(&mut body).unwrap();
+
+          let (content_type, response) = if request_line.starts_with("GET /chatrouille_information ") {
+            ("application/json", serde_json::to_vec(&self.information()).unwrap())
+          } else if request_line.starts_with("POST /chatrouille ") {
+            ("application/octet-stream", self.answer(&body))
+          } else {
+            panic!("Unexpected request: {}", request_line);
+          };
+          let relay_header = if self.relay {
+            format!("{}: 1\r\n", RELAY_HEADER)
+          } else {
+            String::new()
+          };
+          write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+            content_type,
+            response.len(),
+            relay_header
+          )
+          .unwrap();
+          stream.write_all(&response).unwrap();
+        }
+      });
+      base_url
+    }
+  }
+
+  #[test]
+  fn test_anonymous_graphql() {
+    for &hybrid in &[false, true] {
+      let server = FakeServer::new(hybrid);
+      let server_signing_public_key = server.signing_keypair.public;
+      let base_url = server.serve();
+
+      let client = ChatrouilleClient::connect(&base_url, server_signing_public_key).unwrap();
+      assert_eq!(client.is_hybrid(), hybrid);
+      assert!(client.server_time() >= 1_600_000_000);
+
+      let response = client
+        .anonymous_graphql(&serde_json::json!({ "query": "{ isIdentifierAvailable }" }))
+        .unwrap();
+      assert_eq!(response.data["query"], "{ isIdentifierAvailable }");
+      assert!(response.data["citizenIdentifier"].is_null());
+      response.receipt.verify(&server_signing_public_key).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_signed_graphql() {
+    let citizen_keypair = key_utils::gen_ed25519_keypair();
+    let mut server = FakeServer::new(true);
+    server.client_public_key = Some(citizen_keypair.public);
+    let server_signing_public_key = server.signing_keypair.public;
+    let base_url = server.serve();
+
+    let client = ChatrouilleClient::connect(&base_url, server_signing_public_key).unwrap();
+    let graphql = serde_json::json!({ "query": "mutation { coin }" });
+    let response = client.signed_graphql(&graphql, "marianne", &citizen_keypair).unwrap();
+    assert_eq!(response.data["citizenIdentifier"], "marianne");
+
+    assert!(matches!(
+      client.query(GraphqlQuery { graphql: &graphql }, Some("marianne"), None),
+      Err(ChatrouilleError::UnsignedCitizenIdentifier)
+    ));
+  }
+
+  #[test]
+  fn test_errors() {
+    let server = FakeServer::new(false);
+    let server_signing_public_key = server.signing_keypair.public;
+    let base_url = server.serve();
+
+    let client = ChatrouilleClient::connect(&base_url, server_signing_public_key).unwrap();
+    match client.anonymous_graphql(&serde_json::json!({ "query": "graphql error" })) {
+      Err(ChatrouilleError::GraphqlErrors { errors }) => assert_eq!(errors[0]["message"], "Coin"),
+      _ => panic!("The GraphQL errors are missing"),
+    }
+    match client.anonymous_graphql(&serde_json::json!({ "query": "error" })) {
+      Err(ChatrouilleError::ServerError { code, message }) => {
+        assert_eq!(code, 410);
+        assert_eq!(message, "The request has expired");
+      }
+      _ => panic!("The server error is missing"),
+    }
+
+    // The server keys must be signed by the hardcoded key
+    let another_key = key_utils::gen_ed25519_keypair().public;
+    assert!(matches!(
+      ChatrouilleClient::connect(&base_url, another_key),
+      Err(ChatrouilleError::InvalidServerKeySignature)
+    ));
+
+    assert!(matches!(
+      ChatrouilleClient::connect("http://127.0.0.1:1", server_signing_public_key),
+      Err(ChatrouilleError::HttpError { .. })
+    ));
+  }
+
+  #[test]
+  fn test_relay() {
+    let mut server = FakeServer::new(false);
+    server.relay = true;
+    let server_signing_public_key = server.signing_keypair.public;
+    let relay_url = server.serve();
+
+    let client = ChatrouilleClient::connect_through_relay(&relay_url, server_signing_public_key).unwrap();
+    assert!(client.is_through_relay());
+    let response = client
+      .anonymous_graphql(&serde_json::json!({ "query": "{ loadCitizenPublicKeys }" }))
+      .unwrap();
+    assert_eq!(response.data["query"], "{ loadCitizenPublicKeys }");
+
+    // The server answering directly must not be mistaken for a relay
+    let server = FakeServer::new(false);
+    let server_signing_public_key = server.signing_keypair.public;
+    let base_url = server.serve();
+    assert!(matches!(
+      ChatrouilleClient::connect_through_relay(&base_url, server_signing_public_key),
+      Err(ChatrouilleError::MissingRelay)
+    ));
+  }
+}
@@ -1,34 +1,180 @@
-use snafu::{ResultExt, Snafu};
+use alloc::vec::Vec;
+use miniz_oxide::inflate::TINFLStatus;
+#[cfg(feature = "zstd-codec")]
+use snafu::ResultExt;
+use snafu::Snafu;
 
 #[derive(Debug, Snafu)]
 pub enum CompressorError {
+  #[cfg(feature = "zstd-codec")]
   #[snafu(display("EncoderError: {}", source))]
   EncoderError { source: std::io::Error },
+  #[cfg(feature = "zstd-codec")]
   #[snafu(display("DecoderError: {}", source))]
   DecoderError { source: std::io::Error },
+  #[snafu(display("Invalid deflate data: {:?}", status))]
+  InflateError { status: TINFLStatus },
+  #[snafu(display("Unknown codec: {}", byte))]
+  UnknownCodec { byte: u8 },
+  #[snafu(display("Unsupported codec: {:?}", codec))]
+  UnsupportedCodec { codec: Codec },
+  #[snafu(display("The uncompressed data is larger than {} bytes", max_size))]
+  OutputTooLarge { max_size: usize },
 }
 
-pub type Result<T, E = CompressorError> = std::result::Result<T, E>;
+pub type Result<T, E = CompressorError> = core::result::Result<T, E>;
 
-pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
-  let mut encoder = libflate::deflate::Encoder::new(Vec::new());
+/**
+ * The codec is stored before the compressed data.
+ *
+ * Compressing secret data with data controlled by someone else in the same packet
+ * can leak the secret data through the compressed size, `Codec::None` avoids it.
+ *
+ * The zstd codecs require the zstd-codec feature, deflate is always available.
+ * The dictionary is part of the codec, it can't change without a new codec.
+ */
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Codec {
+  ZstdDictionary = 90, // Z
+  #[default]
+  Deflate = 100,       // d
+  None = 110,          // n
+  Zstd = 122,          // z
+}
+
+impl Codec {
+  pub fn from_byte(byte: u8) -> Result<Codec> {
+    match byte {
+      90 => Ok(Codec::ZstdDictionary),
+      100 => Ok(Codec::Deflate),
+      110 => Ok(Codec::None),
+      122 => Ok(Codec::Zstd),
+      _ => Err(CompressorError::UnknownCodec { byte }),
+    }
+  }
+
+  /// Whether the codec is available in this build.
+  #[must_use]
+  pub fn is_supported(self) -> bool {
+    match self {
+      Codec::None | Codec::Deflate => true,
+      Codec::Zstd | Codec::ZstdDictionary => cfg!(feature = "zstd-codec"),
+    }
+  }
+}
+
+#[cfg(feature = "zstd-codec")]
+const ZSTD_LEVEL: i32 = 3;
+
+// Raw content dictionary, made of typical Norgance GraphQL queries and responses
+#[cfg(feature = "zstd-codec")]
+const GRAPHQL_DICTIONARY: &[u8] = include_bytes!("graphql_dictionary.txt");
+
+// The default level of zlib, a raw deflate stream without the zlib header
+const DEFLATE_LEVEL: u8 = 6;
+
+pub fn compress_with_codec(codec: Codec, data: &[u8]) -> Result<Vec<u8>> {
+  let mut compressed = Vec::new();
+  compress_into(codec, data, &mut compressed)?;
+  Ok(compressed)
+}
+
+/// Same as `compress_with_codec`, the compressed data is appended to the output.
+pub fn compress_into(codec: Codec, data: &[u8], output: &mut Vec<u8>) -> Result<()> {
+  match codec {
+    Codec::None => {
+      output.extend_from_slice(data);
+      Ok(())
+    }
+    Codec::Deflate => {
+      output.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(data, DEFLATE_LEVEL));
+      Ok(())
+    }
+    #[cfg(feature = "zstd-codec")]
+    Codec::Zstd => zstd::stream::copy_encode(data, output, ZSTD_LEVEL).context(EncoderError),
+    #[cfg(feature = "zstd-codec")]
+    Codec::ZstdDictionary => {
+      use std::io::Write;
+      let mut encoder = zstd::stream::Encoder::with_dictionary(output, ZSTD_LEVEL, GRAPHQL_DICTIONARY)
+        .context(EncoderError)?;
+      encoder.write_all(data).context(EncoderError)?;
+      encoder.finish().context(EncoderError)?;
+      Ok(())
+    }
+    #[cfg(not(feature = "zstd-codec"))]
+    Codec::Zstd | Codec::ZstdDictionary => Err(CompressorError::UnsupportedCodec { codec }),
+  }
+}
+
+/// The uncompressed data can't be larger than `max_size` bytes,
+/// a few bytes of compressed data can otherwise use all the memory.
+pub fn decompress_with_codec(codec: Codec, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+  let mut uncompressed = Vec::new();
+  decompress_into(codec, data, max_size, &mut uncompressed)?;
+  Ok(uncompressed)
+}
 
-  std::io::copy(&mut &data[..], &mut encoder).context(EncoderError)?;
+/// Same as `decompress_with_codec`, the uncompressed data is appended to the output.
+pub fn decompress_into(codec: Codec, data: &[u8], max_size: usize, output: &mut Vec<u8>) -> Result<()> {
+  match codec {
+    Codec::None => {
+      if data.len() > max_size {
+        return Err(CompressorError::OutputTooLarge { max_size });
+      }
+      output.extend_from_slice(data);
+      Ok(())
+    }
+    Codec::Deflate => match miniz_oxide::inflate::decompress_to_vec_with_limit(data, max_size) {
+      Ok(uncompressed) => {
+        output.extend_from_slice(&uncompressed);
+        Ok(())
+      }
+      Err(error) if error.status == TINFLStatus::HasMoreOutput => Err(CompressorError::OutputTooLarge { max_size }),
+      Err(error) => Err(CompressorError::InflateError { status: error.status }),
+    },
+    #[cfg(feature = "zstd-codec")]
+    Codec::Zstd => {
+      let decoder = zstd::stream::Decoder::new(data).context(DecoderError)?;
+      read_to_end_bounded(decoder, max_size, output)
+    }
+    #[cfg(feature = "zstd-codec")]
+    Codec::ZstdDictionary => {
+      let decoder =
+        zstd::stream::Decoder::with_dictionary(data, GRAPHQL_DICTIONARY).context(DecoderError)?;
+      read_to_end_bounded(decoder, max_size, output)
+    }
+    #[cfg(not(feature = "zstd-codec"))]
+    Codec::Zstd | Codec::ZstdDictionary => Err(CompressorError::UnsupportedCodec { codec }),
+  }
+}
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+  compress_with_codec(Codec::Deflate, data)
+}
 
-  let compressed_data = encoder.finish().into_result().context(EncoderError)?;
-  Ok(compressed_data)
+/// Decompresses deflate data, up to `max_size` bytes.
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+  decompress_with_codec(Codec::Deflate, data, max_size)
 }
 
-pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+/// Reads one byte more than the limit, to know whether the limit is exceeded.
+/// The data is appended to the output, the limit doesn't count what was already there.
+#[cfg(feature = "zstd-codec")]
+fn read_to_end_bounded<R: std::io::Read>(reader: R, max_size: usize, output: &mut Vec<u8>) -> Result<()> {
   use std::io::Read;
-  let mut decoder = libflate::deflate::Decoder::new(&data[..]);
 
-  let mut decoded_data = Vec::new();
-  decoder
-    .read_to_end(&mut decoded_data)
+  let initial_length = output.len();
+  reader
+    .take((max_size as u64).saturating_add(1))
+    .read_to_end(output)
     .context(DecoderError)?;
 
-  Ok(decoded_data)
+  if output.len() - initial_length > max_size {
+    output.truncate(initial_length);
+    return Err(CompressorError::OutputTooLarge { max_size });
+  }
+  Ok(())
 }
 
 #[allow(clippy::panic)]
@@ -44,10 +190,77 @@ mod tests {
         Err(_) => panic!("compress fail"),
       };
       assert!(data.len() > compressed.len());
-      let uncompressed = match decompress(&compressed) {
+      let uncompressed = match decompress(&compressed, data.len()) {
         Ok(u) => u,
         Err(_) => panic!("decompress fail"),
       };
       assert_eq!(uncompressed, data);
     }
+
+    #[test]
+    fn codecs() {
+      let data = br#"{"data":{"loadCitizenPublicKeys":{"publicX25519Dalek":"abc","publicEd25519Dalek":"def"}}}"#;
+      for codec in &[Codec::None, Codec::Deflate, Codec::Zstd, Codec::ZstdDictionary] {
+        assert_eq!(Codec::from_byte(*codec as u8).ok(), Some(*codec));
+        if !codec.is_supported() {
+          assert!(compress_with_codec(*codec, data).is_err());
+          continue;
+        }
+        let compressed = match compress_with_codec(*codec, data) {
+          Ok(c) => c,
+          Err(_) => panic!("compress fail"),
+        };
+        let uncompressed = match decompress_with_codec(*codec, &compressed, 1024) {
+          Ok(u) => u,
+          Err(_) => panic!("decompress fail"),
+        };
+        assert_eq!(uncompressed, &data[..]);
+      }
+      assert!(Codec::from_byte(0).is_err());
+    }
+
+    #[test]
+    fn into() {
+      let data = b"data data data data data data";
+      for codec in &[Codec::None, Codec::Deflate, Codec::Zstd, Codec::ZstdDictionary] {
+        if !codec.is_supported() {
+          continue;
+        }
+        let mut compressed = b"prefix".to_vec();
+        assert!(compress_into(*codec, data, &mut compressed).is_ok());
+        assert_eq!(&compressed[..6], b"prefix");
+
+        // The limit doesn't count the data already in the output
+        let mut uncompressed = b"prefix".to_vec();
+        assert!(decompress_into(*codec, &compressed[6..], data.len(), &mut uncompressed).is_ok());
+        assert_eq!(&uncompressed[..6], b"prefix");
+        assert_eq!(&uncompressed[6..], &data[..]);
+
+        let mut uncompressed = b"prefix".to_vec();
+        assert!(decompress_into(*codec, &compressed[6..], data.len() - 1, &mut uncompressed).is_err());
+        assert_eq!(uncompressed, b"prefix");
+      }
+    }
+
+    #[test]
+    fn bounded() {
+      let bomb = vec![0; 1_000_000];
+      for codec in &[Codec::None, Codec::Deflate, Codec::Zstd, Codec::ZstdDictionary] {
+        if !codec.is_supported() {
+          continue;
+        }
+        let compressed = match compress_with_codec(*codec, &bomb) {
+          Ok(c) => c,
+          Err(_) => panic!("compress fail"),
+        };
+        match decompress_with_codec(*codec, &compressed, 1000) {
+          Err(CompressorError::OutputTooLarge { max_size }) => assert_eq!(max_size, 1000),
+          _ => panic!("expected an output too large error"),
+        }
+        match decompress_with_codec(*codec, &compressed, bomb.len()) {
+          Ok(uncompressed) => assert_eq!(uncompressed.len(), bomb.len()),
+          Err(_) => panic!("decompress fail"),
+        }
+      }
+    }
   }
\ No newline at end of file
@@ -0,0 +1,260 @@
+/*!
+ * Typed JSON queries, in the envelope shared by the Norgance server and its clients.
+ *
+ * envelope: {"exp": expiration timestamp, "citizenIdentifier": optional, "requestId": optional, ...body}
+ *
+ * The fields of the body are flattened in the envelope, so a GraphQL query is
+ * {"graphql": {...}, "exp": 1600000000, "citizenIdentifier": "..."}.
+ *
+ * The expiration time is a unix timestamp in seconds. A query with a citizen identifier
+ * must be signed, the packer refuses to pack it without a keypair and the unpacker
+ * refuses it without a signature. It's still up to the server to verify the signature
+ * with the key of the citizen.
+ */
+use alloc::{string::String, vec::Vec};
+use crate::{
+  compressor::Codec, mlkem, pack_query, query_hash, ChatrouilleError, JsonError, Result, UnpackedQuery, Version, x448,
+};
+use crate::key_utils::SharedSecret;
+use rand::{CryptoRng, RngCore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::ResultExt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope<T> {
+  #[serde(rename = "exp")]
+  pub expiration_time: u64,
+
+  #[serde(rename = "citizenIdentifier", default, skip_serializing_if = "Option::is_none")]
+  pub citizen_identifier: Option<String>,
+
+  // Chosen by the client, to match the responses and the queries in its logs
+  #[serde(rename = "requestId", default, skip_serializing_if = "Option::is_none")]
+  pub request_id: Option<String>,
+
+  #[serde(flatten)]
+  pub body: T,
+}
+
+impl<T> Envelope<T> {
+  pub fn new(body: T, expiration_time: u64) -> Envelope<T> {
+    Envelope {
+      expiration_time,
+      citizen_identifier: None,
+      request_id: None,
+      body,
+    }
+  }
+
+  /// The clocks of the clients and the server may differ by `clock_skew` seconds,
+  /// and a query can't live longer than `max_lifetime` seconds.
+  pub fn check_expiration(&self, now: u64, max_lifetime: u64, clock_skew: u64) -> Result<()> {
+    if self.expiration_time.saturating_add(clock_skew) < now {
+      return Err(ChatrouilleError::Expired);
+    }
+    if self.expiration_time > now.saturating_add(max_lifetime).saturating_add(clock_skew) {
+      return Err(ChatrouilleError::ExpiresTooLate);
+    }
+    Ok(())
+  }
+}
+
+/// What the client keeps to unpack the response.
+pub struct JsonQuery {
+  pub packed_data: Vec<u8>,
+  pub shared_secret: SharedSecret,
+  // To verify the signed responses
+  pub query_hash: Vec<u8>,
+}
+
+pub fn pack_json<T: Serialize>(
+  envelope: &Envelope<T>,
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: Option<&mlkem::EncapsulationKey>,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  codec: Codec,
+) -> Result<JsonQuery> {
+  pack_json_with_rng(
+    envelope,
+    server_public_key,
+    server_mlkem_public_key,
+    client_keypair,
+    codec,
+    &mut rand::thread_rng(),
+  )
+}
+
+/// Same as `pack_json`, the ephemeral keys and the nounces come from the rng.
+/// The query is in the owl version with the server ML-KEM public key, in the latest version otherwise.
+pub fn pack_json_with_rng<T: Serialize, R: RngCore + CryptoRng>(
+  envelope: &Envelope<T>,
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: Option<&mlkem::EncapsulationKey>,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<JsonQuery> {
+  if envelope.citizen_identifier.is_some() && client_keypair.is_none() {
+    return Err(ChatrouilleError::UnsignedCitizenIdentifier);
+  }
+
+  let payload = serde_json::to_vec(envelope).context(JsonError)?;
+  let version = match server_mlkem_public_key {
+    Some(_) => Version::Owl,
+    None => Version::LATEST,
+  };
+  let (packed_data, shared_secret) = pack_query(
+    &payload,
+    server_public_key,
+    server_mlkem_public_key,
+    client_keypair,
+    version,
+    codec,
+    rng,
+  )?;
+
+  Ok(JsonQuery {
+    packed_data,
+    shared_secret,
+    query_hash: query_hash(&payload),
+  })
+}
+
+pub fn unpack_json<T: DeserializeOwned>(unpacked_query: &UnpackedQuery) -> Result<Envelope<T>> {
+  let envelope: Envelope<T> = serde_json::from_slice(&unpacked_query.payload).context(JsonError)?;
+  if envelope.citizen_identifier.is_some() && unpacked_query.signature.is_none() {
+    return Err(ChatrouilleError::UnsignedCitizenIdentifier);
+  }
+  Ok(envelope)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{key_utils, unpack_query, unpack_response, VerifyUnpackedQuerySignature};
+
+  const MAX: usize = 1024 * 1024;
+
+  #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+  struct Greeting {
+    message: String,
+  }
+
+  fn greeting() -> Envelope<Greeting> {
+    Envelope::new(
+      Greeting {
+        message: String::from("Liberte, egalite, fraternite"),
+      },
+      1_600_000_000,
+    )
+  }
+
+  #[test]
+  fn test_wire_format() {
+    let mut envelope = greeting();
+    assert_eq!(
+      serde_json::to_string(&envelope).unwrap(),
+      r#"{"exp":1600000000,"message":"Liberte, egalite, fraternite"}"#
+    );
+
+    envelope.citizen_identifier = Some(String::from("marianne"));
+    envelope.request_id = Some(String::from("1789"));
+    assert_eq!(
+      serde_json::to_string(&envelope).unwrap(),
+      r#"{"exp":1600000000,"citizenIdentifier":"marianne","requestId":"1789","message":"Liberte, egalite, fraternite"}"#
+    );
+
+    let parsed: Envelope<Greeting> =
+      serde_json::from_str(r#"{"message":"Liberte, egalite, fraternite","citizenIdentifier":"marianne","exp":1600000000,"requestId":"1789"}"#)
+        .unwrap();
+    assert_eq!(parsed, envelope);
+  }
+
+  #[test]
+  fn test_pack_json() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let envelope = greeting();
+
+    let query = pack_json(&envelope, &server_public_key, None, None, Codec::default()).unwrap();
+    let unpacked_query = unpack_query(&query.packed_data, &server_private_key, MAX).unwrap();
+    assert!(unpacked_query.signature.is_none());
+    assert_eq!(query.query_hash, query_hash(&unpacked_query.payload));
+
+    let unpacked_envelope: Envelope<Greeting> = unpack_json(&unpacked_query).unwrap();
+    assert_eq!(unpacked_envelope, envelope);
+
+    let response = crate::pack_response_with_codec(
+      b"couac",
+      &unpacked_query.shared_secret,
+      unpacked_query.version,
+      crate::padding::PaddingPolicy::default(),
+      unpacked_query.codec,
+    )
+    .unwrap();
+    assert_eq!(unpack_response(&response, &query.shared_secret, MAX).unwrap(), b"couac");
+  }
+
+  #[test]
+  fn test_citizen_identifier() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let client_keypair = key_utils::gen_ed25519_keypair();
+    let mut envelope = greeting();
+    envelope.citizen_identifier = Some(String::from("marianne"));
+
+    assert!(matches!(
+      pack_json(&envelope, &server_public_key, None, None, Codec::default()),
+      Err(ChatrouilleError::UnsignedCitizenIdentifier)
+    ));
+
+    let query = pack_json(&envelope, &server_public_key, None, Some(&client_keypair), Codec::default()).unwrap();
+    let unpacked_query = unpack_query(&query.packed_data, &server_private_key, MAX).unwrap();
+    let signature = unpacked_query.signature.as_ref().unwrap();
+    signature.verify(&client_keypair.public).unwrap();
+    assert_eq!(signature.query_hash(), &query.query_hash[..]);
+    let unpacked_envelope: Envelope<Greeting> = unpack_json(&unpacked_query).unwrap();
+    assert_eq!(unpacked_envelope.citizen_identifier.as_deref(), Some("marianne"));
+
+    // The same envelope in a query without signature
+    let payload = serde_json::to_vec(&envelope).unwrap();
+    let (packed_data, _) = crate::pack_unsigned_query(&payload, &server_public_key).unwrap();
+    let unpacked_query = unpack_query(&packed_data, &server_private_key, MAX).unwrap();
+    assert!(matches!(
+      unpack_json::<Greeting>(&unpacked_query),
+      Err(ChatrouilleError::UnsignedCitizenIdentifier)
+    ));
+  }
+
+  #[test]
+  fn test_invalid_json() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+
+    let (packed_data, _) =
+      crate::pack_unsigned_query(br#"{"exp":1600000000}"#, &server_public_key).unwrap();
+    let unpacked_query = unpack_query(&packed_data, &server_private_key, MAX).unwrap();
+    match unpack_json::<Greeting>(&unpacked_query) {
+      Err(error @ ChatrouilleError::JsonError { .. }) => {
+        assert!(error.to_string().contains("missing field"));
+      }
+      _ => panic!("The message is missing"),
+    }
+  }
+
+  #[test]
+  fn test_check_expiration() {
+    let envelope = greeting();
+    let exp = envelope.expiration_time;
+
+    assert!(envelope.check_expiration(exp, 60, 0).is_ok());
+    assert!(envelope.check_expiration(exp + 10, 60, 10).is_ok());
+    assert!(matches!(envelope.check_expiration(exp + 11, 60, 10), Err(ChatrouilleError::Expired)));
+    assert!(envelope.check_expiration(exp - 70, 60, 10).is_ok());
+    assert!(matches!(
+      envelope.check_expiration(exp - 71, 60, 10),
+      Err(ChatrouilleError::ExpiresTooLate)
+    ));
+    assert!(envelope.check_expiration(u64::MAX, u64::MAX, u64::MAX).is_ok());
+  }
+}
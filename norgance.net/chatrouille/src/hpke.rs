@@ -0,0 +1,539 @@
+/*!
+ * HPKE (RFC 9180), the hybrid public key encryption of the flamingo version.
+ *
+ * Only one suite is implemented: `DHKEM(X448, HKDF-SHA512)`, `HKDF-SHA512`,
+ * and `ChaCha20Poly1305`, in the base and auth modes. The psk modes are not supported.
+ *
+ * The intermediate secrets and the context keys are wiped when they are dropped.
+ */
+use alloc::{vec, vec::Vec};
+use orion::hazardous::aead::chacha20poly1305;
+use orion::hazardous::kdf::hkdf;
+use orion::hazardous::mac::hmac;
+use orion::hazardous::stream::chacha20;
+use rand::{CryptoRng, RngCore};
+use snafu::{ResultExt, Snafu};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{key_utils, x448};
+
+#[derive(Debug, Snafu)]
+pub enum HpkeError {
+  #[snafu(display("Unable to do the diffie Hellman"))]
+  DiffieHellmanFail,
+
+  #[snafu(display("Invalid encapsulated key"))]
+  InvalidEncapsulatedKey,
+
+  #[snafu(display("Unable to derive the keys"))]
+  KeyDerivationError {
+    #[cfg_attr(not(feature = "std"), snafu(source(from(orion::errors::UnknownCryptoError, crate::DependencyError))))]
+    source: crate::CryptoErrorSource,
+  },
+
+  #[snafu(display("Unable to encrypt the data"))]
+  EncryptionError {
+    #[cfg_attr(not(feature = "std"), snafu(source(from(orion::errors::UnknownCryptoError, crate::DependencyError))))]
+    source: crate::CryptoErrorSource,
+  },
+
+  #[snafu(display("Unable to decrypt the data"))]
+  DecryptionError {
+    #[cfg_attr(not(feature = "std"), snafu(source(from(orion::errors::UnknownCryptoError, crate::DependencyError))))]
+    source: crate::CryptoErrorSource,
+  },
+
+  #[snafu(display("The context can't encrypt more messages"))]
+  MessageLimitReached,
+}
+
+pub type Result<T, E = HpkeError> = core::result::Result<T, E>;
+
+pub const KEM_ID: u16 = 0x0021;
+pub const KDF_ID: u16 = 0x0003;
+pub const AEAD_ID: u16 = 0x0003;
+
+/// The encapsulated key is the ephemeral x448 public key.
+pub const ENCAPSULATED_KEY_LENGTH: usize = 56;
+pub const TAG_LENGTH: usize = 16;
+
+const VERSION_LABEL: &[u8] = b"HPKE-v1";
+const MODE_BASE: u8 = 0x00;
+const MODE_AUTH: u8 = 0x02;
+const PRIVATE_KEY_LENGTH: usize = 56;
+// Nsecret and Nh, the SHA512 output length
+const SHARED_SECRET_LENGTH: usize = 64;
+const HASH_LENGTH: usize = 64;
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+
+fn kem_suite_id() -> Vec<u8> {
+  [&b"KEM"[..], &KEM_ID.to_be_bytes()].concat()
+}
+
+fn hpke_suite_id() -> Vec<u8> {
+  [
+    &b"HPKE"[..],
+    &KEM_ID.to_be_bytes(),
+    &KDF_ID.to_be_bytes(),
+    &AEAD_ID.to_be_bytes(),
+  ]
+  .concat()
+}
+
+fn labeled_extract(
+  suite_id: &[u8],
+  salt: &[u8],
+  label: &[u8],
+  ikm: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+  let labeled_ikm = Zeroizing::new([VERSION_LABEL, suite_id, label, ikm].concat());
+  let prk = hkdf::extract(salt, &labeled_ikm).context(KeyDerivationError)?;
+  Ok(Zeroizing::new(prk.unprotected_as_bytes().to_vec()))
+}
+
+fn labeled_expand(
+  suite_id: &[u8],
+  prk: &[u8],
+  label: &[u8],
+  info: &[u8],
+  length: usize,
+) -> Result<Vec<u8>> {
+  use core::convert::TryFrom;
+
+  let length_bytes = match u16::try_from(length) {
+    Ok(length) => length.to_be_bytes(),
+    Err(_) => return Err(orion::errors::UnknownCryptoError).context(KeyDerivationError),
+  };
+  let labeled_info = [&length_bytes[..], VERSION_LABEL, suite_id, label, info].concat();
+  let prk = hmac::Tag::from_slice(prk).context(KeyDerivationError)?;
+  let mut okm = vec![0_u8; length];
+  hkdf::expand(&prk, Some(&labeled_info), &mut okm).context(KeyDerivationError)?;
+  Ok(okm)
+}
+
+/// `DeriveKeyPair` of the RFC, for deterministic keys.
+pub fn derive_key_pair(ikm: &[u8]) -> Result<(x448::Secret, x448::PublicKey)> {
+  let suite_id = kem_suite_id();
+  let dkp_prk = labeled_extract(&suite_id, &[], b"dkp_prk", ikm)?;
+  let secret_bytes = Zeroizing::new(labeled_expand(
+    &suite_id,
+    &dkp_prk,
+    b"sk",
+    &[],
+    PRIVATE_KEY_LENGTH,
+  )?);
+  let secret = match x448::Secret::from_bytes(&secret_bytes) {
+    Some(secret) => secret,
+    None => return Err(HpkeError::DiffieHellmanFail),
+  };
+  let public_key = x448::PublicKey::from(&secret);
+  Ok((secret, public_key))
+}
+
+/// Appends the shared secret to dh, which has the capacity for both shared secrets
+/// of the auth mode so the secrets aren't left behind by a reallocation.
+fn diffie_hellman(
+  secret: &x448::Secret,
+  public_key: &x448::PublicKey,
+  dh: &mut Vec<u8>,
+) -> Result<()> {
+  match secret.as_diffie_hellman(public_key) {
+    Some(mut shared_secret) => {
+      dh.extend_from_slice(shared_secret.as_bytes());
+      key_utils::wipe_x448_shared_secret(&mut shared_secret);
+      Ok(())
+    }
+    None => Err(HpkeError::DiffieHellmanFail),
+  }
+}
+
+fn extract_and_expand(dh: &[u8], kem_context: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+  let suite_id = kem_suite_id();
+  let eae_prk = labeled_extract(&suite_id, &[], b"eae_prk", dh)?;
+  Ok(Zeroizing::new(labeled_expand(
+    &suite_id,
+    &eae_prk,
+    b"shared_secret",
+    kem_context,
+    SHARED_SECRET_LENGTH,
+  )?))
+}
+
+/// `Encap` and `AuthEncap`, returns the encapsulated key and the KEM shared secret.
+fn encapsulate(
+  receiver_public_key: &x448::PublicKey,
+  ephemeral_secret: &x448::Secret,
+  sender_secret: Option<&x448::Secret>,
+) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>)> {
+  let ephemeral_public_key = x448::PublicKey::from(ephemeral_secret);
+  let mut dh = Zeroizing::new(Vec::with_capacity(2 * PRIVATE_KEY_LENGTH));
+  diffie_hellman(ephemeral_secret, receiver_public_key, &mut dh)?;
+  let mut kem_context = [&ephemeral_public_key.as_bytes()[..], receiver_public_key.as_bytes()].concat();
+  if let Some(sender_secret) = sender_secret {
+    diffie_hellman(sender_secret, receiver_public_key, &mut dh)?;
+    kem_context.extend(x448::PublicKey::from(sender_secret).as_bytes());
+  }
+
+  let shared_secret = extract_and_expand(&dh, &kem_context)?;
+  Ok((ephemeral_public_key.as_bytes().to_vec(), shared_secret))
+}
+
+/// `Decap` and `AuthDecap`, returns the KEM shared secret.
+fn decapsulate(
+  encapsulated_key: &[u8],
+  receiver_secret: &x448::Secret,
+  sender_public_key: Option<&x448::PublicKey>,
+) -> Result<Zeroizing<Vec<u8>>> {
+  let ephemeral_public_key = match x448::PublicKey::from_bytes(encapsulated_key) {
+    Some(public_key) => public_key,
+    None => return Err(HpkeError::InvalidEncapsulatedKey),
+  };
+  let receiver_public_key = x448::PublicKey::from(receiver_secret);
+  let mut dh = Zeroizing::new(Vec::with_capacity(2 * PRIVATE_KEY_LENGTH));
+  diffie_hellman(receiver_secret, &ephemeral_public_key, &mut dh)?;
+  let mut kem_context = [encapsulated_key, receiver_public_key.as_bytes()].concat();
+  if let Some(sender_public_key) = sender_public_key {
+    diffie_hellman(receiver_secret, sender_public_key, &mut dh)?;
+    kem_context.extend(sender_public_key.as_bytes());
+  }
+
+  extract_and_expand(&dh, &kem_context)
+}
+
+fn key_schedule(mode: u8, shared_secret: &[u8], info: &[u8]) -> Result<Context> {
+  let suite_id = hpke_suite_id();
+  // Without psk, the psk and its id are empty
+  let psk_id_hash = labeled_extract(&suite_id, &[], b"psk_id_hash", &[])?;
+  let info_hash = labeled_extract(&suite_id, &[], b"info_hash", info)?;
+  let key_schedule_context = [&[mode][..], &psk_id_hash, &info_hash].concat();
+
+  let secret = labeled_extract(&suite_id, shared_secret, b"secret", &[])?;
+  Ok(Context {
+    key: Zeroizing::new(labeled_expand(
+      &suite_id,
+      &secret,
+      b"key",
+      &key_schedule_context,
+      KEY_LENGTH,
+    )?),
+    base_nonce: Zeroizing::new(labeled_expand(
+      &suite_id,
+      &secret,
+      b"base_nonce",
+      &key_schedule_context,
+      NONCE_LENGTH,
+    )?),
+    exporter_secret: Zeroizing::new(labeled_expand(
+      &suite_id,
+      &secret,
+      b"exp",
+      &key_schedule_context,
+      HASH_LENGTH,
+    )?),
+    sequence_number: 0,
+  })
+}
+
+/// The encryption context of a sender or of a receiver.
+///
+/// Each message uses the next nonce, the receiver must open the messages in order.
+pub struct Context {
+  key: Zeroizing<Vec<u8>>,
+  base_nonce: Zeroizing<Vec<u8>>,
+  exporter_secret: Zeroizing<Vec<u8>>,
+  sequence_number: u64,
+}
+
+impl Context {
+  /// The base nonce xored with the sequence number.
+  fn nonce(&self) -> Result<chacha20::Nonce, orion::errors::UnknownCryptoError> {
+    let mut nonce = [0_u8; NONCE_LENGTH];
+    nonce[NONCE_LENGTH - 8..].copy_from_slice(&self.sequence_number.to_be_bytes());
+    for (byte, base_byte) in nonce.iter_mut().zip(self.base_nonce.iter()) {
+      *byte ^= base_byte;
+    }
+    let result = chacha20::Nonce::from_slice(&nonce);
+    nonce.zeroize();
+    result
+  }
+
+  fn increment_sequence_number(&mut self) -> Result<()> {
+    self.sequence_number = match self.sequence_number.checked_add(1) {
+      Some(sequence_number) => sequence_number,
+      None => return Err(HpkeError::MessageLimitReached),
+    };
+    Ok(())
+  }
+
+  pub fn seal(&mut self, associated_data: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut sealed = vec![0_u8; plaintext.len() + TAG_LENGTH];
+    chacha20poly1305::seal(
+      &chacha20::SecretKey::from_slice(&self.key).context(EncryptionError)?,
+      &self.nonce().context(EncryptionError)?,
+      plaintext,
+      Some(associated_data),
+      &mut sealed,
+    )
+    .context(EncryptionError)?;
+    self.increment_sequence_number()?;
+    Ok(sealed)
+  }
+
+  pub fn open(&mut self, associated_data: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let mut opened = vec![0_u8; ciphertext.len().saturating_sub(TAG_LENGTH)];
+    self.open_into(associated_data, ciphertext, &mut opened)?;
+    Ok(opened)
+  }
+
+  /// Same as `open`, the plaintext is written to `opened`,
+  /// which must be `TAG_LENGTH` bytes shorter than the ciphertext.
+  pub fn open_into(&mut self, associated_data: &[u8], ciphertext: &[u8], opened: &mut [u8]) -> Result<()> {
+    chacha20poly1305::open(
+      &chacha20::SecretKey::from_slice(&self.key).context(DecryptionError)?,
+      &self.nonce().context(DecryptionError)?,
+      ciphertext,
+      Some(associated_data),
+      opened,
+    )
+    .context(DecryptionError)?;
+    self.increment_sequence_number()
+  }
+
+  /// Derives a secret from the context, both sides get the same one.
+  pub fn export(&self, exporter_context: &[u8], length: usize) -> Result<Vec<u8>> {
+    labeled_expand(
+      &hpke_suite_id(),
+      &self.exporter_secret,
+      b"sec",
+      exporter_context,
+      length,
+    )
+  }
+}
+
+fn setup_sender(
+  receiver_public_key: &x448::PublicKey,
+  info: &[u8],
+  ephemeral_secret: &x448::Secret,
+  sender_secret: Option<&x448::Secret>,
+) -> Result<(Vec<u8>, Context)> {
+  let (encapsulated_key, shared_secret) =
+    encapsulate(receiver_public_key, ephemeral_secret, sender_secret)?;
+  let mode = match sender_secret {
+    Some(_) => MODE_AUTH,
+    None => MODE_BASE,
+  };
+  let context = key_schedule(mode, &shared_secret, info)?;
+  Ok((encapsulated_key, context))
+}
+
+fn setup_receiver(
+  encapsulated_key: &[u8],
+  receiver_secret: &x448::Secret,
+  info: &[u8],
+  sender_public_key: Option<&x448::PublicKey>,
+) -> Result<Context> {
+  let shared_secret = decapsulate(encapsulated_key, receiver_secret, sender_public_key)?;
+  let mode = match sender_public_key {
+    Some(_) => MODE_AUTH,
+    None => MODE_BASE,
+  };
+  key_schedule(mode, &shared_secret, info)
+}
+
+/// `SetupBaseS`, returns the encapsulated key and the sender context.
+/// The ephemeral key comes from the rng.
+pub fn setup_base_sender<R: RngCore + CryptoRng>(
+  receiver_public_key: &x448::PublicKey,
+  info: &[u8],
+  rng: &mut R,
+) -> Result<(Vec<u8>, Context)> {
+  let ephemeral_secret = key_utils::ZeroizingSecret::from(key_utils::gen_private_key_with_rng(rng));
+  setup_sender(receiver_public_key, info, &ephemeral_secret, None)
+}
+
+/// `SetupBaseR`
+pub fn setup_base_receiver(
+  encapsulated_key: &[u8],
+  receiver_secret: &x448::Secret,
+  info: &[u8],
+) -> Result<Context> {
+  setup_receiver(encapsulated_key, receiver_secret, info, None)
+}
+
+/// `SetupAuthS`, the receiver knows that the sender holds the sender secret.
+pub fn setup_auth_sender<R: RngCore + CryptoRng>(
+  receiver_public_key: &x448::PublicKey,
+  info: &[u8],
+  sender_secret: &x448::Secret,
+  rng: &mut R,
+) -> Result<(Vec<u8>, Context)> {
+  let ephemeral_secret = key_utils::ZeroizingSecret::from(key_utils::gen_private_key_with_rng(rng));
+  setup_sender(
+    receiver_public_key,
+    info,
+    &ephemeral_secret,
+    Some(sender_secret),
+  )
+}
+
+/// `SetupAuthR`, fails if the sender didn't use the secret of the sender public key.
+pub fn setup_auth_receiver(
+  encapsulated_key: &[u8],
+  receiver_secret: &x448::Secret,
+  info: &[u8],
+  sender_public_key: &x448::PublicKey,
+) -> Result<Context> {
+  setup_receiver(
+    encapsulated_key,
+    receiver_secret,
+    info,
+    Some(sender_public_key),
+  )
+}
+
+#[allow(clippy::panic, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  fn check_vector(vector: &HashMap<&str, Vec<&str>>) {
+    let field = |name: &str| from_hex(vector[name][0]);
+    let info = field("info");
+
+    let (ephemeral_secret, ephemeral_public_key) = derive_key_pair(&field("ikmE")).unwrap();
+    let (receiver_secret, receiver_public_key) = derive_key_pair(&field("ikmR")).unwrap();
+    assert_eq!(ephemeral_public_key.as_bytes().to_vec(), field("pkEm"));
+    assert_eq!(receiver_public_key.as_bytes().to_vec(), field("pkRm"));
+
+    let sender_keys = match vector.get("ikmS") {
+      Some(ikm) => {
+        let (sender_secret, sender_public_key) = derive_key_pair(&from_hex(ikm[0])).unwrap();
+        assert_eq!(sender_public_key.as_bytes().to_vec(), field("pkSm"));
+        Some((sender_secret, sender_public_key))
+      }
+      None => None,
+    };
+    let sender_secret = sender_keys.as_ref().map(|(secret, _)| secret);
+    let sender_public_key = sender_keys.as_ref().map(|(_, public_key)| public_key);
+
+    let (encapsulated_key, shared_secret) =
+      encapsulate(&receiver_public_key, &ephemeral_secret, sender_secret).unwrap();
+    assert_eq!(*encapsulated_key, field("enc"));
+    assert_eq!(*shared_secret, field("shared_secret"));
+
+    let (_, mut sender_context) = setup_sender(
+      &receiver_public_key,
+      &info,
+      &ephemeral_secret,
+      sender_secret,
+    )
+    .unwrap();
+    let mut receiver_context = setup_receiver(
+      &encapsulated_key,
+      &receiver_secret,
+      &info,
+      sender_public_key,
+    )
+    .unwrap();
+    assert_eq!(*sender_context.key, field("key"));
+    assert_eq!(*sender_context.base_nonce, field("base_nonce"));
+    assert_eq!(*sender_context.exporter_secret, field("exporter_secret"));
+    assert_eq!(*receiver_context.key, field("key"));
+
+    for encryption in &vector["encryption"] {
+      let parts: Vec<&str> = encryption.split(':').collect();
+      let sequence_number: u64 = parts[0].parse().unwrap();
+      let (associated_data, plaintext, ciphertext) =
+        (from_hex(parts[1]), from_hex(parts[2]), from_hex(parts[3]));
+
+      sender_context.sequence_number = sequence_number;
+      assert_eq!(sender_context.seal(&associated_data, &plaintext).unwrap(), ciphertext);
+      receiver_context.sequence_number = sequence_number;
+      assert_eq!(receiver_context.open(&associated_data, &ciphertext).unwrap(), plaintext);
+    }
+
+    for export in &vector["export"] {
+      let parts: Vec<&str> = export.split(':').collect();
+      let exported = from_hex(parts[2]);
+      let length: usize = parts[1].parse().unwrap();
+      assert_eq!(sender_context.export(&from_hex(parts[0]), length).unwrap(), exported);
+      assert_eq!(receiver_context.export(&from_hex(parts[0]), length).unwrap(), exported);
+    }
+  }
+
+  #[test]
+  fn test_vectors() {
+    let mut checked_modes = vec![];
+    for block in include_str!("../hpke_test_vectors.txt").split("\n\n") {
+      let mut vector: HashMap<&str, Vec<&str>> = HashMap::new();
+      for line in block.lines().filter(|line| !line.starts_with('#')) {
+        let (name, value) = line.split_at(line.find(": ").unwrap());
+        vector.entry(name).or_default().push(&value[2..]);
+      }
+      if vector.is_empty() {
+        continue;
+      }
+      check_vector(&vector);
+      checked_modes.push(vector["mode"][0]);
+    }
+    assert_eq!(checked_modes, vec!["0", "2"]);
+  }
+
+  #[test]
+  fn test_seal_and_open() {
+    let mut rng = rand::thread_rng();
+    let receiver_secret = key_utils::gen_private_key();
+    let receiver_public_key = key_utils::gen_public_key(&receiver_secret);
+    let sender_secret = key_utils::gen_private_key();
+    let sender_public_key = key_utils::gen_public_key(&sender_secret);
+
+    let (encapsulated_key, mut sender_context) =
+      setup_base_sender(&receiver_public_key, b"info", &mut rng).unwrap();
+    assert_eq!(encapsulated_key.len(), ENCAPSULATED_KEY_LENGTH);
+    let first = sender_context.seal(b"aad", b"first").unwrap();
+    let second = sender_context.seal(b"aad", b"second").unwrap();
+
+    let mut receiver_context = setup_base_receiver(&encapsulated_key, &receiver_secret, b"info").unwrap();
+    assert_eq!(receiver_context.open(b"aad", &first).unwrap(), b"first");
+    assert_eq!(receiver_context.open(b"aad", &second).unwrap(), b"second");
+    assert_eq!(
+      sender_context.export(b"context", 32).unwrap(),
+      receiver_context.export(b"context", 32).unwrap()
+    );
+
+    // Another info, another key
+    let mut receiver_context = setup_base_receiver(&encapsulated_key, &receiver_secret, b"other").unwrap();
+    assert!(receiver_context.open(b"aad", &first).is_err());
+
+    // The messages must be opened in order, with the same associated data
+    let mut receiver_context = setup_base_receiver(&encapsulated_key, &receiver_secret, b"info").unwrap();
+    assert!(receiver_context.open(b"aad", &second).is_err());
+    assert!(receiver_context.open(b"other", &first).is_err());
+
+    let (encapsulated_key, mut sender_context) =
+      setup_auth_sender(&receiver_public_key, b"info", &sender_secret, &mut rng).unwrap();
+    let sealed = sender_context.seal(b"aad", b"authenticated").unwrap();
+    let mut receiver_context =
+      setup_auth_receiver(&encapsulated_key, &receiver_secret, b"info", &sender_public_key).unwrap();
+    assert_eq!(receiver_context.open(b"aad", &sealed).unwrap(), b"authenticated");
+
+    // Not the sender key
+    let mut receiver_context =
+      setup_auth_receiver(&encapsulated_key, &receiver_secret, b"info", &receiver_public_key).unwrap();
+    assert!(receiver_context.open(b"aad", &sealed).is_err());
+    let mut receiver_context = setup_base_receiver(&encapsulated_key, &receiver_secret, b"info").unwrap();
+    assert!(receiver_context.open(b"aad", &sealed).is_err());
+
+    assert!(setup_base_receiver(&[0_u8; ENCAPSULATED_KEY_LENGTH], &receiver_secret, b"info").is_err());
+  }
+}
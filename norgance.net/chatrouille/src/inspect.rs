@@ -0,0 +1,268 @@
+/*!
+ * Layout of the chatrouille packets, to debug them without the keys.
+ *
+ * Only the header is read: the fields are located from the version, the mode,
+ * and the lengths in the header (segments of the chunked messages, recipients count).
+ * Nothing is decrypted or verified, a packet with a valid layout can still be invalid.
+ */
+use alloc::vec::Vec;
+use crate::{
+  chunked::SEGMENT_LENGTH_LENGTH, hpke, multi_recipient::RECIPIENTS_COUNT_LENGTH, multi_recipient::RECIPIENT_LENGTH,
+  query_public_keys_length, session::FRAME_HEADER_LENGTH, ChatrouilleError, Mode, Result, Version, CLIENT_PUBLIC_KEY_LENGTH,
+  HPKE_ENCAPSULATED_KEY_LENGTH, KEY_ID_LENGTH, MLKEM_CIPHERTEXT_LENGTH, MODE_LENGTH, NOUNCE_LENGTH,
+  PACKET_VERSION_LENGTH, TAG_LENGTH,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+  pub name: &'static str,
+  pub offset: usize,
+  pub length: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketLayout {
+  pub version: Version,
+  pub mode: Mode,
+  pub fields: Vec<Field>,
+}
+
+impl PacketLayout {
+  #[must_use]
+  pub fn field(&self, name: &str) -> Option<&Field> {
+    self.fields.iter().find(|field| field.name == name)
+  }
+}
+
+struct LayoutBuilder<'a> {
+  packed_data: &'a [u8],
+  fields: Vec<Field>,
+  offset: usize,
+}
+
+impl LayoutBuilder<'_> {
+  fn push(&mut self, name: &'static str, length: usize) -> Result<()> {
+    if self.packed_data.len() < self.offset + length {
+      return Err(ChatrouilleError::NotEnoughData);
+    }
+    self.fields.push(Field {
+      name,
+      offset: self.offset,
+      length,
+    });
+    self.offset += length;
+    Ok(())
+  }
+
+  /// The ciphertext is everything between the current offset and the tag.
+  fn push_sealed(&mut self, tag_length: usize) -> Result<()> {
+    if self.packed_data.len() < self.offset + tag_length {
+      return Err(ChatrouilleError::NotEnoughData);
+    }
+    self.push("ciphertext", self.packed_data.len() - self.offset - tag_length)?;
+    self.push("tag", tag_length)
+  }
+
+  fn push_segments(&mut self) -> Result<()> {
+    while self.offset < self.packed_data.len() {
+      if self.packed_data.len() < self.offset + SEGMENT_LENGTH_LENGTH {
+        return Err(ChatrouilleError::NotEnoughData);
+      }
+      let mut sealed_length_bytes = [0_u8; SEGMENT_LENGTH_LENGTH];
+      sealed_length_bytes.copy_from_slice(&self.packed_data[self.offset..self.offset + SEGMENT_LENGTH_LENGTH]);
+      let sealed_length = u32::from_be_bytes(sealed_length_bytes) as usize;
+      self.push("segment", SEGMENT_LENGTH_LENGTH.saturating_add(sealed_length))?;
+    }
+    Ok(())
+  }
+}
+
+/// Returns the fields of a packet, with their offsets and lengths.
+pub fn packet_layout(packed_data: &[u8]) -> Result<PacketLayout> {
+  let version = match Version::from_packet(packed_data) {
+    Some(version) => version,
+    None => return Err(ChatrouilleError::InvalidDataPrefix),
+  };
+  if packed_data.len() < PACKET_VERSION_LENGTH + MODE_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
+
+  let mut builder = LayoutBuilder {
+    packed_data,
+    fields: Vec::new(),
+    offset: 0,
+  };
+  builder.push("version", PACKET_VERSION_LENGTH)?;
+  builder.push("mode", MODE_LENGTH)?;
+
+  match (version, &mode) {
+    (Version::Flamingo, Mode::Query | Mode::SignedQuery | Mode::AuthQuery) => {
+      builder.push("server key id", KEY_ID_LENGTH)?;
+      builder.push("encapsulated key", HPKE_ENCAPSULATED_KEY_LENGTH)?;
+      if mode == Mode::AuthQuery {
+        builder.push("client static public key", CLIENT_PUBLIC_KEY_LENGTH)?;
+      }
+      builder.push_sealed(hpke::TAG_LENGTH)?;
+    }
+    (_, Mode::Query | Mode::SignedQuery) => {
+      if version != Version::Duck {
+        builder.push("server key id", KEY_ID_LENGTH)?;
+      }
+      builder.push("client public key", CLIENT_PUBLIC_KEY_LENGTH)?;
+      if version == Version::Owl {
+        builder.push("ML-KEM ciphertext", MLKEM_CIPHERTEXT_LENGTH)?;
+      }
+      debug_assert_eq!(builder.offset, PACKET_VERSION_LENGTH + MODE_LENGTH + query_public_keys_length(version));
+      builder.push("nounce", NOUNCE_LENGTH)?;
+      builder.push_sealed(TAG_LENGTH)?;
+    }
+    (_, Mode::Response | Mode::SignedResponse | Mode::Error) => {
+      builder.push("nounce", NOUNCE_LENGTH)?;
+      builder.push_sealed(TAG_LENGTH)?;
+    }
+    (_, Mode::ChunkedQuery | Mode::ChunkedSignedQuery) => {
+      builder.push("server key id", KEY_ID_LENGTH)?;
+      builder.push("client public key", CLIENT_PUBLIC_KEY_LENGTH)?;
+      builder.push("stream nounce", NOUNCE_LENGTH)?;
+      builder.push_segments()?;
+    }
+    (_, Mode::ChunkedResponse) => {
+      builder.push("stream nounce", NOUNCE_LENGTH)?;
+      builder.push_segments()?;
+    }
+    (_, Mode::SessionFrame) => {
+      builder.push("counter", FRAME_HEADER_LENGTH - PACKET_VERSION_LENGTH - MODE_LENGTH)?;
+      builder.push_sealed(TAG_LENGTH)?;
+    }
+    (_, Mode::MultiRecipient) => {
+      builder.push("ephemeral public key", CLIENT_PUBLIC_KEY_LENGTH)?;
+      builder.push("recipients count", RECIPIENTS_COUNT_LENGTH)?;
+      let count_offset = builder.offset - RECIPIENTS_COUNT_LENGTH;
+      let count = u16::from_be_bytes([packed_data[count_offset], packed_data[count_offset + 1]]);
+      for _ in 0..count {
+        builder.push("recipient", RECIPIENT_LENGTH)?;
+      }
+      builder.push("nounce", NOUNCE_LENGTH)?;
+      builder.push_sealed(TAG_LENGTH)?;
+    }
+    _ => return Err(ChatrouilleError::InvalidModeInData),
+  }
+
+  Ok(PacketLayout {
+    version,
+    mode,
+    fields: builder.fields,
+  })
+}
+
+#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use crate::{compressor::Codec, key_utils, padding::PaddingPolicy};
+
+  fn field_names(layout: &PacketLayout) -> Vec<&'static str> {
+    layout.fields.iter().map(|field| field.name).collect()
+  }
+
+  fn assert_covers(layout: &PacketLayout, packed_data: &[u8]) {
+    let mut offset = 0;
+    for field in &layout.fields {
+      assert_eq!(field.offset, offset);
+      offset += field.length;
+    }
+    assert_eq!(offset, packed_data.len());
+  }
+
+  #[test]
+  fn test_query_layouts() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let server_mlkem_private_key = key_utils::gen_mlkem_private_key();
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    let (query, _) = crate::pack_signed_query(b"coin", &server_public_key, &keypair).unwrap();
+    let layout = packet_layout(&query).unwrap();
+    assert_eq!(layout.version, Version::LATEST);
+    assert!(layout.mode == Mode::SignedQuery);
+    assert_eq!(
+      field_names(&layout),
+      ["version", "mode", "server key id", "client public key", "nounce", "ciphertext", "tag"]
+    );
+    let key_id = layout.field("server key id").unwrap();
+    assert_eq!(&query[key_id.offset..key_id.offset + key_id.length], key_utils::key_id(&server_public_key));
+    assert_covers(&layout, &query);
+
+    let (query, _) = crate::pack_query(
+      b"coin",
+      &server_public_key,
+      None,
+      None,
+      Version::Duck,
+      Codec::default(),
+      &mut rand::thread_rng(),
+    )
+    .unwrap();
+    let layout = packet_layout(&query).unwrap();
+    assert_eq!(field_names(&layout), ["version", "mode", "client public key", "nounce", "ciphertext", "tag"]);
+    assert_covers(&layout, &query);
+
+    let (query, _) = crate::pack_hybrid_unsigned_query(
+      b"coin",
+      &server_public_key,
+      server_mlkem_private_key.encapsulation_key(),
+      Codec::default(),
+    )
+    .unwrap();
+    let layout = packet_layout(&query).unwrap();
+    assert_eq!(layout.version, Version::Owl);
+    assert_eq!(layout.field("ML-KEM ciphertext").unwrap().length, MLKEM_CIPHERTEXT_LENGTH);
+    assert_covers(&layout, &query);
+
+    let client_private_key = key_utils::gen_private_key();
+    let (query, _) =
+      crate::pack_hpke_auth_query(b"coin", &server_public_key, &client_private_key, Codec::default()).unwrap();
+    let layout = packet_layout(&query).unwrap();
+    assert_eq!(layout.version, Version::Flamingo);
+    assert_eq!(
+      field_names(&layout),
+      ["version", "mode", "server key id", "encapsulated key", "client static public key", "ciphertext", "tag"]
+    );
+    assert_covers(&layout, &query);
+
+    // Truncated in the header
+    assert!(matches!(packet_layout(&query[0..40]), Err(ChatrouilleError::NotEnoughData)));
+  }
+
+  #[test]
+  fn test_other_layouts() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let (_, shared_secret) = crate::pack_unsigned_query(b"coin", &server_public_key).unwrap();
+
+    let error = crate::pack_error(410, "Expired", &shared_secret, Version::Swan).unwrap();
+    let layout = packet_layout(&error).unwrap();
+    assert!(layout.mode == Mode::Error);
+    assert_eq!(field_names(&layout), ["version", "mode", "nounce", "ciphertext", "tag"]);
+    assert_covers(&layout, &error);
+
+    let data = vec![42_u8; 10_000];
+    let (query, _) = crate::chunked::pack_chunked_query(&data, &server_public_key, None).unwrap();
+    let layout = packet_layout(&query).unwrap();
+    assert!(layout.mode == Mode::ChunkedQuery);
+    // Three segments of data, and the last segment sealed by finish
+    assert_eq!(layout.fields.iter().filter(|field| field.name == "segment").count(), 4);
+    assert_covers(&layout, &query);
+
+    let recipients = [server_public_key, key_utils::gen_public_key(&key_utils::gen_private_key())];
+    let message =
+      crate::multi_recipient::pack_multi_recipient(b"coin", &recipients, PaddingPolicy::default(), Codec::default())
+        .unwrap();
+    let layout = packet_layout(&message).unwrap();
+    assert_eq!(layout.fields.iter().filter(|field| field.name == "recipient").count(), 2);
+    assert_covers(&layout, &message);
+
+    assert!(matches!(packet_layout(b"coin coin"), Err(ChatrouilleError::InvalidDataPrefix)));
+  }
+}
@@ -1,8 +1,24 @@
+use alloc::{string::String, vec::Vec};
+use crate::{mlkem, x448};
+use orion::hazardous::stream::chacha20;
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+#[cfg(feature = "std")]
 #[allow(dead_code)]
 #[must_use]
 pub fn gen_private_key() -> x448::Secret {
-    let mut rng = rand::thread_rng();
-    x448::Secret::new(&mut rng)
+    gen_private_key_with_rng(&mut rand::thread_rng())
+}
+
+#[must_use]
+pub fn gen_private_key_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> x448::Secret {
+    // Without leaving the bytes on the stack
+    let mut bytes = [0_u8; 56];
+    rng.fill_bytes(&mut bytes);
+    let secret = x448::Secret::from(bytes);
+    bytes.zeroize();
+    secret
 }
 
 #[allow(dead_code)]
@@ -14,7 +30,7 @@ pub fn gen_public_key(private_key: &x448::Secret) -> x448::PublicKey {
 #[allow(dead_code)]
 #[must_use]
 pub fn private_key_to_base64(private_key: &x448::Secret) -> String {
-    base64::encode_config(private_key.as_bytes().to_vec(), base64::STANDARD_NO_PAD)
+    base64::encode_config(private_key.as_bytes(), base64::STANDARD_NO_PAD)
 }
 
 #[allow(dead_code)]
@@ -23,28 +39,65 @@ pub fn public_key_to_base64(public_key: &x448::PublicKey) -> String {
     base64::encode_config(public_key.as_bytes().to_vec(), base64::STANDARD_NO_PAD)
 }
 
+pub const KEY_ID_LENGTH: usize = 4;
+pub type KeyId = [u8; KEY_ID_LENGTH];
+
+// Salt for the key ids - https://en.wikipedia.org/wiki/Nothing-up-my-sleeve_number
+const KEY_ID_BLAKE2B_SALT: &[u8; 16] = b"chatrouille-k-id";
+
+/// Identifies a server public key in the queries.
+/// It's not a secret and it doesn't have to be unique,
+/// it only helps the server to pick the right key.
+#[must_use]
+pub fn key_id(public_key: &x448::PublicKey) -> KeyId {
+    let hash =
+        blake2_rfc::blake2b::blake2b(KEY_ID_LENGTH, KEY_ID_BLAKE2B_SALT, public_key.as_bytes());
+    let mut key_id = [0_u8; KEY_ID_LENGTH];
+    key_id.copy_from_slice(hash.as_bytes());
+    key_id
+}
+
+#[must_use]
+pub fn key_id_to_base64(key_id: &KeyId) -> String {
+    base64::encode_config(key_id, base64::STANDARD_NO_PAD)
+}
+
+#[cfg(feature = "std")]
 #[allow(dead_code)]
 #[must_use]
 pub fn gen_ed25519_keypair() -> ed25519_dalek::Keypair {
-    let mut rng = rand::thread_rng();
-    ed25519_dalek::Keypair::generate(&mut rng)
+    gen_ed25519_keypair_with_rng(&mut rand::thread_rng())
 }
 
+#[must_use]
+pub fn gen_ed25519_keypair_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> ed25519_dalek::Keypair {
+    ed25519_dalek::Keypair::generate(rng)
+}
+
+#[cfg(feature = "std")]
 #[allow(dead_code)]
 #[must_use]
 pub fn gen_x25519_static_secret() -> x25519_dalek::StaticSecret {
-    let mut rng = rand::thread_rng();
-    x25519_dalek::StaticSecret::new(&mut rng)
+    gen_x25519_static_secret_with_rng(&mut rand::thread_rng())
+}
+
+#[must_use]
+pub fn gen_x25519_static_secret_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> x25519_dalek::StaticSecret {
+    x25519_dalek::StaticSecret::new(rng)
 }
 
 #[allow(dead_code)]
 #[must_use]
 pub fn private_key_from_base64(private_key_base64: &str) -> Option<x448::Secret> {
-    let bytes = match base64::decode(private_key_base64) {
+    let mut bytes = match base64::decode(private_key_base64) {
         Ok(bytes) => bytes,
         Err(_) => return None,
     };
-    x448::Secret::from_bytes(&bytes)
+    let private_key = x448::Secret::from_bytes(&bytes);
+    bytes.zeroize();
+    private_key
 }
 
 #[allow(dead_code)]
@@ -57,19 +110,158 @@ pub fn public_key_from_base64(public_key_base64: &str) -> Option<x448::PublicKey
     x448::PublicKey::from_bytes(&bytes)
 }
 
+#[cfg(feature = "std")]
+#[allow(dead_code)]
+#[must_use]
+pub fn gen_mlkem_private_key() -> mlkem::DecapsulationKey {
+    gen_mlkem_private_key_with_rng(&mut rand::thread_rng())
+}
+
+#[must_use]
+pub fn gen_mlkem_private_key_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> mlkem::DecapsulationKey {
+    mlkem::DecapsulationKey::generate_with_rng(rng)
+}
+
+/// The ML-KEM private key is stored as its seed.
+#[must_use]
+pub fn mlkem_private_key_to_base64(private_key: &mlkem::DecapsulationKey) -> String {
+    base64::encode_config(private_key.seed(), base64::STANDARD_NO_PAD)
+}
+
+#[must_use]
+pub fn mlkem_private_key_from_base64(private_key_base64: &str) -> Option<mlkem::DecapsulationKey> {
+    let mut bytes = match base64::decode(private_key_base64) {
+        Ok(bytes) => bytes,
+        Err(_) => return None,
+    };
+    let private_key = mlkem::DecapsulationKey::from_seed(&bytes);
+    bytes.zeroize();
+    private_key
+}
+
+#[must_use]
+pub fn mlkem_public_key_to_base64(public_key: &mlkem::EncapsulationKey) -> String {
+    base64::encode_config(public_key.as_bytes(), base64::STANDARD_NO_PAD)
+}
+
+#[must_use]
+pub fn mlkem_public_key_from_base64(public_key_base64: &str) -> Option<mlkem::EncapsulationKey> {
+    let bytes = match base64::decode(public_key_base64) {
+        Ok(bytes) => bytes,
+        Err(_) => return None,
+    };
+    mlkem::EncapsulationKey::from_bytes(&bytes)
+}
+
+/// The secret shared by the client and the server for a query and its responses.
+///
+/// It's the x448 shared secret, followed by the ML-KEM shared secret in the owl version,
+/// so breaking only one of the two key exchanges is not enough.
+/// In the flamingo version, it's exported from the HPKE context of the query.
+///
+/// The bytes are zeroized when it's dropped.
+#[derive(Clone)]
+pub struct SharedSecret {
+    bytes: Vec<u8>,
+}
+
+impl SharedSecret {
+    /// The x448 shared secret is wiped, the caller wipes the ML-KEM one.
+    #[must_use]
+    pub fn hybrid(
+        x448_shared_secret: x448::SharedSecret,
+        mlkem_shared_secret: &[u8; mlkem::SHARED_SECRET_LENGTH],
+    ) -> SharedSecret {
+        let mut x448_shared_secret = x448_shared_secret;
+        let bytes = [&x448_shared_secret.as_bytes()[..], &mlkem_shared_secret[..]].concat();
+        wipe_x448_shared_secret(&mut x448_shared_secret);
+        SharedSecret { bytes }
+    }
+
+    #[must_use]
+    pub fn hpke_exported(exported_secret: Vec<u8>) -> SharedSecret {
+        SharedSecret {
+            bytes: exported_secret,
+        }
+    }
+
+    /// Reloads a secret saved by a client, to unpack the responses later.
+    #[must_use]
+    pub fn from_bytes(bytes: &[u8]) -> SharedSecret {
+        SharedSecret {
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<x448::SharedSecret> for SharedSecret {
+    fn from(shared_secret: x448::SharedSecret) -> Self {
+        let mut shared_secret = shared_secret;
+        let bytes = shared_secret.as_bytes().to_vec();
+        wipe_x448_shared_secret(&mut shared_secret);
+        SharedSecret { bytes }
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
+
+/// A x448 secret wiped when it's dropped.
+pub struct ZeroizingSecret(x448::Secret);
+
+impl From<x448::Secret> for ZeroizingSecret {
+    fn from(secret: x448::Secret) -> Self {
+        ZeroizingSecret(secret)
+    }
+}
+
+impl core::ops::Deref for ZeroizingSecret {
+    type Target = x448::Secret;
+
+    fn deref(&self) -> &x448::Secret {
+        &self.0
+    }
+}
+
+impl Drop for ZeroizingSecret {
+    fn drop(&mut self) {
+        wipe_x448_secret(&mut self.0);
+    }
+}
+
+/// Wipes a x448 secret before it's dropped, it's also wiped when it's dropped.
+pub fn wipe_x448_secret(secret: &mut x448::Secret) {
+    secret.zeroize();
+}
+
+/// Wipes a x448 shared secret before it's dropped.
+pub fn wipe_x448_shared_secret(shared_secret: &mut x448::SharedSecret) {
+    shared_secret.zeroize();
+}
+
 pub fn derive_shared_secret_to_sym_key(
-    shared_secret: &x448::SharedSecret,
+    shared_secret: &SharedSecret,
     key: &[u8],
-) -> Result<orion::aead::SecretKey, orion::errors::UnknownCryptoError> {
+) -> Result<chacha20::SecretKey, orion::errors::UnknownCryptoError> {
     const SYMMETRIC_KEY_SIZE: usize = 32;
     let symmetric_key_bytes =
         blake2_rfc::blake2b::blake2b(SYMMETRIC_KEY_SIZE, key, shared_secret.as_bytes());
-    let symmetric_key = orion::aead::SecretKey::from_slice(symmetric_key_bytes.as_bytes())?;
+    let symmetric_key = chacha20::SecretKey::from_slice(symmetric_key_bytes.as_bytes())?;
     Ok(symmetric_key)
 }
 
 #[allow(clippy::panic, clippy::unwrap_used)]
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
@@ -132,6 +324,7 @@ mod tests {
             shared_secret_ba.as_bytes().to_vec()
         );
 
+        let shared_secret_ab = SharedSecret::from(shared_secret_ab);
         let sym_key = derive_shared_secret_to_sym_key(&shared_secret_ab, &[1, 2, 3]).unwrap();
         assert_eq!(
             sym_key.unprotected_as_bytes(),
@@ -150,6 +343,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hybrid_shared_secret() {
+        let private_key = gen_private_key();
+        let public_key = gen_public_key(&gen_private_key());
+        let x448_shared_secret = || private_key.as_diffie_hellman(&public_key).unwrap();
+        let hybrid_key = derive_shared_secret_to_sym_key(
+            &SharedSecret::hybrid(x448_shared_secret(), &[42; mlkem::SHARED_SECRET_LENGTH]),
+            &[4],
+        )
+        .unwrap();
+        let classic_key =
+            derive_shared_secret_to_sym_key(&SharedSecret::from(x448_shared_secret()), &[4]).unwrap();
+        assert_ne!(classic_key.unprotected_as_bytes(), hybrid_key.unprotected_as_bytes());
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mut private_key = gen_private_key();
+        let public_key = gen_public_key(&private_key);
+        let mut shared_secret = private_key.as_diffie_hellman(&public_key).unwrap();
+
+        wipe_x448_secret(&mut private_key);
+        wipe_x448_shared_secret(&mut shared_secret);
+        assert_eq!(private_key.as_bytes(), &[0_u8; 56]);
+        assert_eq!(shared_secret.as_bytes(), &[0_u8; 56]);
+
+        // The wrapper derefs to the secret
+        let secret = ZeroizingSecret::from(gen_private_key());
+        assert_ne!(secret.as_bytes(), &[0_u8; 56]);
+        assert_eq!(gen_public_key(&secret).as_bytes().len(), 56);
+    }
+
+    #[test]
+    fn test_mlkem_keys() {
+        let private_key = gen_mlkem_private_key();
+        let restored_private_key =
+            mlkem_private_key_from_base64(&mlkem_private_key_to_base64(&private_key)).unwrap();
+        let public_key = restored_private_key.encapsulation_key();
+        assert_eq!(public_key.as_bytes(), private_key.encapsulation_key().as_bytes());
+
+        let restored_public_key =
+            mlkem_public_key_from_base64(&mlkem_public_key_to_base64(public_key)).unwrap();
+        assert_eq!(restored_public_key.as_bytes(), public_key.as_bytes());
+        assert!(mlkem_private_key_from_base64("totally not base64").is_none());
+        assert!(mlkem_public_key_from_base64("AAAA").is_none());
+    }
+
+    #[test]
+    fn test_key_id() {
+        let private_key = gen_private_key();
+        let public_key = gen_public_key(&private_key);
+        let another_public_key = gen_public_key(&gen_private_key());
+
+        assert_eq!(key_id(&public_key), key_id(&public_key));
+        assert_ne!(key_id(&public_key), key_id(&another_public_key));
+        assert_eq!(key_id_to_base64(&key_id(&public_key)).len(), 6);
+    }
+
     #[test]
     fn test_broken_base64() {
         assert!(private_key_from_base64("totally not base64").is_none());
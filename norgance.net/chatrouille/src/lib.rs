@@ -1,3 +1,4 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![warn(
   clippy::all,
   //clippy::restriction,
@@ -23,19 +24,78 @@
   clippy::wildcard_imports
 )]
 
+extern crate alloc;
+
+pub mod chunked;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod compressor;
+#[cfg(feature = "json")]
+pub mod envelope;
+pub mod hpke;
+pub mod inspect;
 pub mod key_utils;
-
+pub mod mlkem;
+pub mod multi_recipient;
+pub mod padding;
+pub mod session;
+pub mod x448;
+
+use alloc::{string::String, vec, vec::Vec};
+use compressor::Codec;
+use key_utils::SharedSecret;
+use orion::hazardous::stream::chacha20;
+use padding::PaddingPolicy;
+use rand::{CryptoRng, RngCore};
 use snafu::{ResultExt, Snafu};
+use zeroize::Zeroize;
 
 /**
  *  The message format is the following:
  *
  * query: [duck emoji (4 bytes)][mode Query (1 byte)][client x448 public key (56 bytes)][nounce (24 bytes)][data compressed with zlib and encrypted using xchacha20poly1305 (n bytes)][tag (16 bytes)]
  * answer: [duck emoji (4 bytes)][mode Answer (1 byte)][nounce (24 bytes)][data compressed with zlib and encrypted using xchacha20poly1305 (n bytes)][tag (16 bytes)]
+ * signed answer: same as answer, with the signature nounce (32 bytes) and the server ed25519 signature (64 bytes) appended to the compressed data before the encryption.
+ * error: same as answer, the data is [error code (2 bytes, big endian)][utf-8 message (n bytes)].
+ *
+ * chunked query, chunked signed query, and chunked response: see the chunked module.
+ * multi recipient message: see the `multi_recipient` module.
+ * session frame: see the session module.
+ *
+ * The duck queries don't say which server key they target, the server tries its legacy keys.
  *
  * The duck emoji 🦆 is used as a chatrouille message version, as a warrant canary, and it's cute.
  *
+ * The swan emoji 🦢 version has the same format, but the packet header
+ * (and the server x448 public key for the queries) is authenticated as associated data,
+ * and the queries contain the server key id (4 bytes) between the mode and the client x448 public key.
+ * The server key id is derived from the server x448 public key, so the server
+ * can keep several keys during a rotation and pick the right one.
+ * The compressed data is also prefixed by its codec byte (see the compressor module),
+ * and then by its length and padded according to a padding policy, see the padding module.
+ * The duck version always uses deflate, and pads the compressed data with zeros.
+ * The signature of the signed queries covers the payload hash and a transcript hash.
+ * The transcript hash covers the packet header, with the client ephemeral x448 public key in it,
+ * and the server x448 public key.
+ * A signed payload can't be re-wrapped under another client ephemeral key or another server key.
+ * The duck version is still accepted during the migration, the chunked modes only exist in the duck version.
+ *
+ * The owl emoji 🦉 version is the swan version with a hybrid key exchange, against the
+ * attackers recording the traffic today to decrypt it once they have a quantum computer.
+ * The query header also contains the ML-KEM-768 ciphertext (1088 bytes) after the client x448 public key,
+ * the server ML-KEM public key is authenticated with the server x448 public key,
+ * and the symmetric keys are derived from both shared secrets.
+ * The clients opt into it when the server publishes an ML-KEM public key.
+ *
+ * The flamingo emoji 🦩 version replaces the bespoke query encryption by the standard HPKE
+ * (RFC 9180) with the `DHKEM(X448, HKDF-SHA512)`, `HKDF-SHA512` and `ChaCha20Poly1305` suite, see the hpke module.
+ * query: [flamingo emoji (4 bytes)][mode Query or SignedQuery (1 byte)][server key id (4 bytes)][HPKE encapsulated key (56 bytes)][HPKE ciphertext (n bytes)][tag (16 bytes)]
+ * auth query: same as query with the mode `AuthQuery`, followed by the client x448 static public key (56 bytes) before the ciphertext.
+ * The base mode of HPKE is used for the queries and the signed queries, the auth mode for the auth queries.
+ * The auth mode proves that the client holds the x448 private key, but the public key is visible in the header.
+ * The header and the server x448 public key are the associated data, and the plaintext is the same as in the swan version.
+ * The responses and the errors have the swan format, with a shared secret exported from the HPKE context.
+ *
  * Unlike JWE and similar, every algorithm is fixed. If the security of one of the parts of the message requires a change, or if better algorithms exist in the future, it will require a new version with something different than the duck emoji.
  */
 
@@ -50,27 +110,38 @@ pub enum ChatrouilleError {
   #[snafu(display("Unable to uncompress the data: {}", source))]
   UncompressionError { source: compressor::CompressorError },
 
+  #[snafu(display("Unable to pad the data: {}", source))]
+  PaddingError { source: padding::PaddingError },
+
+  #[snafu(display("The duck version only supports deflate"))]
+  UnsupportedCodec,
+
   #[snafu(display("Unable to derive the secret to a symmetric key"))]
   KeyDerivationError {
-    source: orion::errors::UnknownCryptoError,
+    #[cfg_attr(not(feature = "std"), snafu(source(from(orion::errors::UnknownCryptoError, DependencyError))))]
+    source: CryptoErrorSource,
   },
 
   #[snafu(display("Unable to encrypt the data"))]
   EncryptionError {
-    source: orion::errors::UnknownCryptoError,
+    #[cfg_attr(not(feature = "std"), snafu(source(from(orion::errors::UnknownCryptoError, DependencyError))))]
+    source: CryptoErrorSource,
   },
 
   #[snafu(display("Unable to decrypt the data"))]
   DecryptionError {
-    source: orion::errors::UnknownCryptoError,
+    #[cfg_attr(not(feature = "std"), snafu(source(from(orion::errors::UnknownCryptoError, DependencyError))))]
+    source: CryptoErrorSource,
   },
   #[snafu(display("Unable to load the signature: {}", source))]
   SignatureError {
-    source: ed25519_dalek::SignatureError,
+    #[cfg_attr(not(feature = "std"), snafu(source(from(ed25519_dalek::SignatureError, DependencyError))))]
+    source: SignatureErrorSource,
   },
   #[snafu(display("Unable to verify the signature: {}", source))]
   VerifySignatureError {
-    source: ed25519_dalek::SignatureError,
+    #[cfg_attr(not(feature = "std"), snafu(source(from(ed25519_dalek::SignatureError, DependencyError))))]
+    source: SignatureErrorSource,
   },
 
   #[snafu(display("Invalid mode"))]
@@ -90,44 +161,242 @@ pub enum ChatrouilleError {
 
   #[snafu(display("Unable to load the encryption key"))]
   KeyLoadingError,
+
+  #[snafu(display("The payload is too large"))]
+  PayloadTooLarge,
+
+  #[snafu(display("Invalid segment length"))]
+  InvalidSegmentLength,
+
+  #[snafu(display("Data after the last segment"))]
+  DataAfterLastSegment,
+
+  #[snafu(display("Unknown server key"))]
+  UnknownServerKey,
+
+  #[snafu(display("The owl version requires a ML-KEM key"))]
+  MissingMlKemKey,
+
+  #[snafu(display("HPKE error: {}", source))]
+  HpkeError { source: hpke::HpkeError },
+
+  #[snafu(display("A message needs at least one recipient"))]
+  NoRecipient,
+
+  #[snafu(display("Too many recipients"))]
+  TooManyRecipients,
+
+  #[snafu(display("The key is not a recipient of the message"))]
+  NotARecipient,
+
+  #[snafu(display("The duck version can't open a session"))]
+  UnsupportedSessionVersion,
+
+  #[snafu(display("Unexpected session frame counter"))]
+  InvalidFrameCounter,
+
+  #[snafu(display("Invalid receipt length"))]
+  InvalidReceiptLength,
+
+  #[snafu(display("Invalid receipt encoding: {}", source))]
+  InvalidReceiptEncoding {
+    #[cfg_attr(not(feature = "std"), snafu(source(from(base64::DecodeError, DependencyError))))]
+    source: DecodeErrorSource,
+  },
+
+  #[snafu(display("The payload doesn't match the receipt"))]
+  ReceiptPayloadMismatch,
+
+  #[snafu(display("Error from the server ({}): {}", code, message))]
+  ServerError { code: u16, message: String },
+
+  #[cfg(feature = "json")]
+  #[snafu(display("Invalid JSON payload: {}", source))]
+  JsonError { source: serde_json::Error },
+
+  #[cfg(feature = "json")]
+  #[snafu(display("The query has expired"))]
+  Expired,
+
+  #[cfg(feature = "json")]
+  #[snafu(display("The query expires too late"))]
+  ExpiresTooLate,
+
+  #[cfg(feature = "json")]
+  #[snafu(display("citizenIdentifier requires a signed query"))]
+  UnsignedCitizenIdentifier,
+
+  #[cfg(feature = "client")]
+  #[snafu(display("HTTP error: {}", source))]
+  HttpError { source: Box<ureq::Error> },
+
+  #[cfg(feature = "client")]
+  #[snafu(display("The server keys are not signed by the server signing key"))]
+  InvalidServerKeySignature,
+
+  #[cfg(feature = "client")]
+  #[snafu(display("Error from the GraphQL server: {} errors", errors.len()))]
+  GraphqlErrors { errors: Vec<serde_json::Value> },
+
+  #[cfg(feature = "client")]
+  #[snafu(display("The response didn't go through the oblivious relay"))]
+  MissingRelay,
+}
+
+pub type Result<T, E = ChatrouilleError> = core::result::Result<T, E>;
+
+/// Without std, the errors of the dependencies don't implement the error trait of snafu,
+/// so they are wrapped. With std, they are the sources of the errors as is.
+#[cfg(not(feature = "std"))]
+#[derive(Debug)]
+pub struct DependencyError<E>(pub E);
+
+#[cfg(not(feature = "std"))]
+impl<E: core::fmt::Display> core::fmt::Display for DependencyError<E> {
+  fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+    self.0.fmt(f)
+  }
 }
 
-pub type Result<T, E = ChatrouilleError> = std::result::Result<T, E>;
+#[cfg(not(feature = "std"))]
+impl<E: core::fmt::Debug + core::fmt::Display> snafu::Error for DependencyError<E> {}
+
+#[cfg(feature = "std")]
+pub type CryptoErrorSource = orion::errors::UnknownCryptoError;
+#[cfg(not(feature = "std"))]
+pub type CryptoErrorSource = DependencyError<orion::errors::UnknownCryptoError>;
+
+#[cfg(feature = "std")]
+pub type SignatureErrorSource = ed25519_dalek::SignatureError;
+#[cfg(not(feature = "std"))]
+pub type SignatureErrorSource = DependencyError<ed25519_dalek::SignatureError>;
+
+#[cfg(feature = "std")]
+pub type DecodeErrorSource = base64::DecodeError;
+#[cfg(not(feature = "std"))]
+pub type DecodeErrorSource = DependencyError<base64::DecodeError>;
 
 #[repr(u8)]
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Mode {
   Unknown = 0,
-  Query = 81,       // Q
-  Response = 82,    // R
-  SignedQuery = 83, // S
+  AuthQuery = 65,          // A
+  Error = 69,              // E
+  SessionFrame = 70,       // F
+  MultiRecipient = 77,     // M
+  Query = 81,              // Q
+  Response = 82,           // R
+  SignedQuery = 83,        // S
+  SignedResponse = 84,     // T
+  ChunkedQuery = 113,      // q
+  ChunkedResponse = 114,   // r
+  ChunkedSignedQuery = 115, // s
 }
 
 impl From<u8> for Mode {
   fn from(item: u8) -> Self {
     match item {
+      65 => Mode::AuthQuery,
+      69 => Mode::Error,
+      70 => Mode::SessionFrame,
+      77 => Mode::MultiRecipient,
       81 => Mode::Query,
       82 => Mode::Response,
       83 => Mode::SignedQuery,
+      84 => Mode::SignedResponse,
+      113 => Mode::ChunkedQuery,
+      114 => Mode::ChunkedResponse,
+      115 => Mode::ChunkedSignedQuery,
       _ => Mode::Unknown,
     }
   }
 }
 
 impl Mode {
+  /// The packets sent by the clients to the server, as opposed to the responses.
+  #[must_use]
+  pub fn is_query(&self) -> bool {
+    matches!(
+      self,
+      Mode::AuthQuery | Mode::Query | Mode::SignedQuery | Mode::ChunkedQuery | Mode::ChunkedSignedQuery
+    )
+  }
+
+  #[must_use]
+  pub fn is_chunked(&self) -> bool {
+    matches!(
+      self,
+      Mode::ChunkedQuery | Mode::ChunkedResponse | Mode::ChunkedSignedQuery
+    )
+  }
+
   // https://en.wikipedia.org/wiki/Nothing-up-my-sleeve_number
   // Fahrenheit 451, Babar, Asterix
-  fn to_symmetric_key_salt(&self) -> Vec<u8> {
+  fn to_symmetric_key_salt(&self) -> [u8; 16] {
     let first_byte = self.clone() as u8;
-    [first_byte, 4, 5, 1, 66, 97, 98, 97, 114, 97, 115, 116, 101, 114, 105, 120].to_vec()
+    [first_byte, 4, 5, 1, 66, 97, 98, 97, 114, 97, 115, 116, 101, 114, 105, 120]
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Version {
+  /// 🦆, the header isn't authenticated
+  Duck,
+  /// 🦢, the header and the public keys are authenticated
+  Swan,
+  /// 🦉, the swan version with a hybrid x448 and ML-KEM-768 key exchange
+  Owl,
+  /// 🦩, the queries are encrypted with HPKE
+  Flamingo,
+}
+
+impl Version {
+  pub const LATEST: Version = Version::Swan;
+
+  #[must_use]
+  pub fn prefix(self) -> &'static [u8] {
+    match self {
+      Version::Duck => PACKET_VERSION,
+      Version::Swan => PACKET_VERSION_SWAN,
+      Version::Owl => PACKET_VERSION_OWL,
+      Version::Flamingo => PACKET_VERSION_FLAMINGO,
+    }
+  }
+
+  #[must_use]
+  pub fn from_packet(packed_data: &[u8]) -> Option<Version> {
+    if packed_data.len() < PACKET_VERSION_LENGTH {
+      return None;
+    }
+    let prefix = &packed_data[0..PACKET_VERSION_LENGTH];
+    if prefix == PACKET_VERSION {
+      Some(Version::Duck)
+    } else if prefix == PACKET_VERSION_SWAN {
+      Some(Version::Swan)
+    } else if prefix == PACKET_VERSION_OWL {
+      Some(Version::Owl)
+    } else if prefix == PACKET_VERSION_FLAMINGO {
+      Some(Version::Flamingo)
+    } else {
+      None
+    }
   }
 }
 
 #[allow(clippy::non_ascii_literal)]
 const PACKET_VERSION: &[u8] = "🦆".as_bytes();
+#[allow(clippy::non_ascii_literal)]
+const PACKET_VERSION_SWAN: &[u8] = "🦢".as_bytes();
+#[allow(clippy::non_ascii_literal)]
+const PACKET_VERSION_OWL: &[u8] = "🦉".as_bytes();
+#[allow(clippy::non_ascii_literal)]
+const PACKET_VERSION_FLAMINGO: &[u8] = "🦩".as_bytes();
 const PACKET_VERSION_LENGTH: usize = PACKET_VERSION.len();
 const MODE_LENGTH: usize = 1;
+const KEY_ID_LENGTH: usize = key_utils::KEY_ID_LENGTH;
 const CLIENT_PUBLIC_KEY_LENGTH: usize = 56;
+const MLKEM_CIPHERTEXT_LENGTH: usize = mlkem::CIPHERTEXT_LENGTH;
+const HPKE_ENCAPSULATED_KEY_LENGTH: usize = hpke::ENCAPSULATED_KEY_LENGTH;
 const NOUNCE_LENGTH: usize = 24;
 const TAG_LENGTH: usize = 16;
 const SIGNATURE_LENGTH: usize = 64;
@@ -139,312 +408,2084 @@ const SIGNATURE_BLAKE2B_HASH_LENGTH: usize = 64;
 const SIGNATURE_NOUNCE_LENGTH: usize = 32;
 // Salt for the signature - French revolution - https://en.wikipedia.org/wiki/Nothing-up-my-sleeve_number
 const SIGNATURE_BLAKE2B_HASH_SALT: &[u8; 16] = b"chatrouille-1789";
+// First French republic
+const RESPONSE_SIGNATURE_BLAKE2B_HASH_SALT: &[u8; 16] = b"chatrouille-1792";
+// Second French republic
+const TRANSCRIPT_BLAKE2B_HASH_SALT: &[u8; 16] = b"chatrouille-1848";
+// Third French republic
+const HPKE_INFO: &[u8; 16] = b"chatrouille-1870";
+// Fourth French republic
+const HPKE_RESPONSE_EXPORTER_CONTEXT: &[u8; 16] = b"chatrouille-1946";
+const HPKE_RESPONSE_SECRET_LENGTH: usize = 64;
+const RECEIPT_LENGTH: usize =
+  SIGNATURE_BLAKE2B_HASH_LENGTH * 2 + SIGNATURE_NOUNCE_LENGTH + SIGNATURE_LENGTH;
+const DUCK_QUERY_RECEIPT_LENGTH: usize =
+  SIGNATURE_BLAKE2B_HASH_LENGTH + SIGNATURE_NOUNCE_LENGTH + SIGNATURE_LENGTH;
+const QUERY_RECEIPT_LENGTH: usize = DUCK_QUERY_RECEIPT_LENGTH + SIGNATURE_BLAKE2B_HASH_LENGTH;
 
 pub struct UnpackedQuery {
   pub payload: Vec<u8>,
+  pub version: Version,
+  // The response should use the same codec
+  pub codec: Codec,
   pub mode: Mode,
-  pub shared_secret: x448::SharedSecret,
+  pub shared_secret: SharedSecret,
   pub signature: Option<UnpackedQuerySignature>,
+  // The client static x448 public key of the flamingo auth queries,
+  // it's up to the caller to check that it belongs to the expected client
+  pub sender_public_key: Option<x448::PublicKey>,
 }
 
 pub struct UnpackedQuerySignature {
   query_hash: Vec<u8>,
+  // Not in the duck version
+  transcript_hash: Option<Vec<u8>>,
   nounce: Vec<u8>,
   signature: ed25519_dalek::Signature,
 }
 
-pub trait VerifyUnpackedQuerySignature {
-  fn verify(&self, public_key: &ed25519_dalek::PublicKey) -> Result<()>;
+impl UnpackedQuerySignature {
+  /// The random nounce of the signature, unique for each signed query.
+  #[must_use]
+  pub fn nounce(&self) -> &[u8] {
+    &self.nounce
+  }
+
+  /// The hash of the signed packet.
+  #[must_use]
+  pub fn query_hash(&self) -> &[u8] {
+    &self.query_hash
+  }
+
+  /// The signature, to keep as a proof that the client sent the query.
+  #[must_use]
+  pub fn receipt(&self) -> QueryReceipt {
+    QueryReceipt {
+      query_hash: self.query_hash.clone(),
+      transcript_hash: self.transcript_hash.clone(),
+      nounce: self.nounce.clone(),
+      signature: self.signature,
+    }
+  }
 }
 
-pub fn pack_signed_query(
-  data: &[u8],
-  server_public_key: &x448::PublicKey,
-  client_keypair: &ed25519_dalek::Keypair,
-) -> Result<(Vec<u8>, x448::SharedSecret)> {
-  pack_query(data, server_public_key, Some(client_keypair))
+/// Same as `UnpackedQuery`, the payload and the signature nounce are borrowed
+/// from the `UnpackBuffer` given to `unpack_query_into`.
+pub struct UnpackedQueryRef<'a> {
+  pub payload: &'a [u8],
+  pub version: Version,
+  pub codec: Codec,
+  pub mode: Mode,
+  pub shared_secret: SharedSecret,
+  pub signature: Option<UnpackedQuerySignatureRef<'a>>,
+  pub sender_public_key: Option<x448::PublicKey>,
 }
 
-#[allow(dead_code)] // TODO
-pub fn pack_unsigned_query(
-  data: &[u8],
-  server_public_key: &x448::PublicKey,
-) -> Result<(Vec<u8>, x448::SharedSecret)> {
-  pack_query(data, server_public_key, None)
+impl UnpackedQueryRef<'_> {
+  /// Copies the borrowed data, to keep the query after the buffer is reused.
+  #[must_use]
+  pub fn into_owned(self) -> UnpackedQuery {
+    UnpackedQuery {
+      payload: self.payload.to_vec(),
+      version: self.version,
+      codec: self.codec,
+      mode: self.mode,
+      shared_secret: self.shared_secret,
+      signature: self.signature.map(UnpackedQuerySignatureRef::into_owned),
+      sender_public_key: self.sender_public_key,
+    }
+  }
 }
 
-fn pack_query(
-  data: &[u8],
-  server_public_key: &x448::PublicKey,
-  client_keypair: Option<&ed25519_dalek::Keypair>,
-) -> Result<(Vec<u8>, x448::SharedSecret)> {
-  let mut rng = rand::thread_rng();
-  let client_secret = x448::Secret::new(&mut rng);
-  let client_public_key = x448::PublicKey::from(&client_secret);
-  let shared_secret = match client_secret.as_diffie_hellman(server_public_key) {
-    Some(secret) => secret,
-    None => return Err(ChatrouilleError::DiffieHellmanFail),
-  };
+pub struct UnpackedQuerySignatureRef<'a> {
+  query_hash: [u8; SIGNATURE_BLAKE2B_HASH_LENGTH],
+  // Not in the duck version
+  transcript_hash: Option<[u8; SIGNATURE_BLAKE2B_HASH_LENGTH]>,
+  nounce: &'a [u8],
+  signature: ed25519_dalek::Signature,
+}
 
-  let public_key_bytes = client_public_key.as_bytes().to_vec();
-  /*assert_eq!(
-    public_key_bytes.len(),
-    CLIENT_PUBLIC_KEY_LENGTH,
-    "The public key length should be {} bytes",
-    CLIENT_PUBLIC_KEY_LENGTH
-  );*/
+impl UnpackedQuerySignatureRef<'_> {
+  /// The random nounce of the signature, unique for each signed query.
+  #[must_use]
+  pub fn nounce(&self) -> &[u8] {
+    self.nounce
+  }
 
-  let mode = match client_keypair {
-    Some(_) => Mode::SignedQuery,
-    None => Mode::Query,
-  };
+  /// The hash of the signed packet.
+  #[must_use]
+  pub fn query_hash(&self) -> &[u8] {
+    &self.query_hash
+  }
 
-  let encrypted_payload = pack(data, mode, public_key_bytes, &shared_secret, client_keypair)?;
+  #[must_use]
+  pub fn receipt(&self) -> QueryReceipt {
+    QueryReceipt {
+      query_hash: self.query_hash.to_vec(),
+      transcript_hash: self.transcript_hash.map(|transcript_hash| transcript_hash.to_vec()),
+      nounce: self.nounce.to_vec(),
+      signature: self.signature,
+    }
+  }
 
-  Ok((encrypted_payload, shared_secret))
+  #[must_use]
+  pub fn into_owned(self) -> UnpackedQuerySignature {
+    UnpackedQuerySignature {
+      query_hash: self.query_hash.to_vec(),
+      transcript_hash: self.transcript_hash.map(|transcript_hash| transcript_hash.to_vec()),
+      nounce: self.nounce.to_vec(),
+      signature: self.signature,
+    }
+  }
 }
 
-pub fn pack_response(data: &[u8], shared_secret: &x448::SharedSecret) -> Result<Vec<u8>> {
-  pack(data, Mode::Response, vec![], &shared_secret, None)
+/// The memory used by `unpack_query_into`. It can be reused from one query to the next,
+/// so the memory isn't allocated again for each query.
+#[derive(Default)]
+pub struct UnpackBuffer {
+  // The associated data, followed by the decrypted bytes
+  decrypted: Vec<u8>,
+  // Only used by the compressed payloads
+  decompressed: Vec<u8>,
 }
 
-#[allow(clippy::needless_pass_by_value)]
-fn pack(
-  data: &[u8],
-  mode: Mode,
-  client_public_key_bytes: Vec<u8>,
-  shared_secret: &x448::SharedSecret,
-  client_keypair: Option<&ed25519_dalek::Keypair>,
-) -> Result<Vec<u8>> {
-  use rand::RngCore;
+impl Drop for UnpackBuffer {
+  fn drop(&mut self) {
+    // The decrypted payloads stay in the buffer between the queries, wiped only when it's dropped
+    self.decrypted.zeroize();
+    self.decompressed.zeroize();
+  }
+}
 
-  let mode_byte = mode.clone() as u8;
+impl UnpackBuffer {
+  #[must_use]
+  pub fn new() -> UnpackBuffer {
+    UnpackBuffer::default()
+  }
 
-  let mut signature_bytes = Vec::with_capacity(0);
-  let mut signature_nounce_bytes = Vec::with_capacity(0);
-  if mode == Mode::SignedQuery {
-    use blake2_rfc::blake2b::blake2b;
-    use ed25519_dalek::Signer;
+  /// Allocates enough memory for most queries up to `capacity` bytes.
+  #[must_use]
+  pub fn with_capacity(capacity: usize) -> UnpackBuffer {
+    UnpackBuffer {
+      decrypted: Vec::with_capacity(capacity),
+      decompressed: Vec::with_capacity(capacity),
+    }
+  }
+}
 
-    let keypair = match client_keypair {
-      Some(ckp) => ckp,
-      None => return Err(ChatrouilleError::MissingKeyPair),
-    };
+/// Finds the server private key targeted by a query, using its key id.
+pub trait PrivateKeyring: Sync {
+  fn private_key(&self, key_id: &key_utils::KeyId) -> Option<&x448::Secret>;
 
-    // We sign on the hash because we can.
-    // It allows us to keep only the hash and not the full data
-    // when we want to verify the signature later.
-    // The whole thing is also a Rube Goldberg machine.
-    let packet_hash = blake2b(
-      SIGNATURE_BLAKE2B_HASH_LENGTH,
-      SIGNATURE_BLAKE2B_HASH_SALT,
-      &data,
-    );
+  /// The keys tried in turn for the duck queries, which don't contain a key id.
+  /// The duck queries are refused when it's empty.
+  fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+    Vec::new()
+  }
 
-    let mut signature_nounce = [0_u8; SIGNATURE_NOUNCE_LENGTH];
-    rand::thread_rng().fill_bytes(&mut signature_nounce);
+  /// The ML-KEM key paired with the x448 key, for the owl version.
+  fn mlkem_private_key(&self, _key_id: &key_utils::KeyId) -> Option<&mlkem::DecapsulationKey> {
+    None
+  }
+}
 
-    let bytes_to_sign = [&signature_nounce, packet_hash.as_bytes()].concat();
-    let signature = keypair.sign(&bytes_to_sign);
-    signature_bytes = signature.to_bytes().to_vec();
-    signature_nounce_bytes = signature_nounce.to_vec();
+/// A single private key is used for every query, whatever the key id.
+/// The decryption fails if the query was packed for another key.
+impl PrivateKeyring for x448::Secret {
+  fn private_key(&self, _key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+    Some(self)
   }
 
-  let mut compressed = compressor::compress(&data).context(CompressionError)?;
+  fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+    vec![self]
+  }
+}
 
-  // To slightly improve the privacy, we pad all
-  // compressed messages with zeros to have a final size which is a multiple of 32.
-  let diff_with_32 = compressed.len() % 32;
-  if diff_with_32 != 0 {
-    compressed.append(&mut vec![0; 32 - diff_with_32]);
+impl PrivateKeyring for key_utils::ZeroizingSecret {
+  fn private_key(&self, _key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+    Some(&**self)
   }
 
-  let symmetric_key = key_utils::derive_shared_secret_to_sym_key(shared_secret, &mode.to_symmetric_key_salt())
-    .context(KeyDerivationError)?;
-  let payload = if mode == Mode::SignedQuery {
-    let mut compressed_and_signed = compressed;
-    compressed_and_signed.reserve(SIGNATURE_LENGTH + SIGNATURE_NOUNCE_LENGTH);
-    compressed_and_signed.append(&mut signature_nounce_bytes);
-    compressed_and_signed.append(&mut signature_bytes);
-    compressed_and_signed
-  } else {
-    compressed
-  };
+  fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+    vec![&**self]
+  }
+}
 
-  let mut encrypted = orion::aead::seal(&symmetric_key, &payload).context(EncryptionError)?;
+pub trait VerifyUnpackedQuerySignature {
+  fn verify(&self, public_key: &ed25519_dalek::PublicKey) -> Result<()>;
+}
 
-  let packed_data_length = PACKET_VERSION_LENGTH
-    + MODE_LENGTH
-    + match mode {
-      Mode::Query | Mode::SignedQuery => CLIENT_PUBLIC_KEY_LENGTH,
-      Mode::Response => 0,
-      Mode::Unknown => return Err(ChatrouilleError::InvalidMode),
-    }
-    + encrypted.len();
+/// Proof that the server answered a query with a response.
+///
+/// It only contains hashes, so it can be stored without the data.
+#[derive(Clone)]
+pub struct ResponseReceipt {
+  query_hash: Vec<u8>,
+  response_hash: Vec<u8>,
+  nounce: Vec<u8>,
+  signature: ed25519_dalek::Signature,
+}
 
-  let mut packed_data: Vec<u8> = Vec::with_capacity(packed_data_length);
+impl ResponseReceipt {
+  #[must_use]
+  pub fn query_hash(&self) -> &[u8] {
+    &self.query_hash
+  }
 
-  packed_data.extend(PACKET_VERSION);
-  packed_data.push(mode_byte);
-  if mode == Mode::Query || mode == Mode::SignedQuery {
-    packed_data.extend(client_public_key_bytes);
+  #[must_use]
+  pub fn response_hash(&self) -> &[u8] {
+    &self.response_hash
   }
-  packed_data.append(&mut encrypted);
-  Ok(packed_data)
-}
 
-pub fn unpack_query(packed_data: &[u8], private_key: &x448::Secret) -> Result<UnpackedQuery> {
-  let data_length = packed_data.len();
-  if data_length < MINIMUM_QUERY_DATA_LENGTH {
-    return Err(ChatrouilleError::NotEnoughData);
+  pub fn verify(&self, server_public_key: &ed25519_dalek::PublicKey) -> Result<()> {
+    let bytes_to_verify = [&self.nounce[..], &self.query_hash[..], &self.response_hash[..]].concat();
+    server_public_key
+      .verify_strict(&bytes_to_verify, &self.signature)
+      .context(VerifySignatureError)?;
+    Ok(())
   }
 
-  // If wrong version
-  if &packed_data[0..PACKET_VERSION_LENGTH] != PACKET_VERSION {
-    return Err(ChatrouilleError::InvalidDataPrefix);
+  /// [query hash (64 bytes)][response hash (64 bytes)][signature nounce (32 bytes)][signature (64 bytes)]
+  #[must_use]
+  pub fn to_bytes(&self) -> Vec<u8> {
+    [
+      &self.query_hash[..],
+      &self.response_hash[..],
+      &self.nounce[..],
+      &self.signature.to_bytes()[..],
+    ]
+    .concat()
   }
 
-  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
-  // If wrong mode
-  if mode != Mode::SignedQuery && mode != Mode::Query {
-    return Err(ChatrouilleError::InvalidModeInData);
+  pub fn from_bytes(bytes: &[u8]) -> Result<ResponseReceipt> {
+    use core::convert::TryFrom;
+
+    if bytes.len() != RECEIPT_LENGTH {
+      return Err(ChatrouilleError::InvalidReceiptLength);
+    }
+    let response_hash_offset = SIGNATURE_BLAKE2B_HASH_LENGTH;
+    let nounce_offset = response_hash_offset + SIGNATURE_BLAKE2B_HASH_LENGTH;
+    let signature_offset = nounce_offset + SIGNATURE_NOUNCE_LENGTH;
+
+    let signature =
+      ed25519_dalek::Signature::try_from(&bytes[signature_offset..]).context(SignatureError)?;
+
+    Ok(ResponseReceipt {
+      query_hash: bytes[0..response_hash_offset].to_vec(),
+      response_hash: bytes[response_hash_offset..nounce_offset].to_vec(),
+      nounce: bytes[nounce_offset..signature_offset].to_vec(),
+      signature,
+    })
   }
+}
 
-  let public_key_bytes = &packed_data[PACKET_VERSION_LENGTH + MODE_LENGTH
-    ..PACKET_VERSION_LENGTH + MODE_LENGTH + CLIENT_PUBLIC_KEY_LENGTH];
+/// Proof that a client signed a query, such as a citizen requesting a mutation.
+///
+/// Like the `ResponseReceipt`, it only contains hashes. The payload of the query
+/// is required to verify it, the receipt alone doesn't prove what was asked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryReceipt {
+  query_hash: Vec<u8>,
+  // Not in the duck version
+  transcript_hash: Option<Vec<u8>>,
+  nounce: Vec<u8>,
+  signature: ed25519_dalek::Signature,
+}
 
-  let public_key = match x448::PublicKey::from_bytes(public_key_bytes) {
-    Some(public_key) => public_key,
-    None => return Err(ChatrouilleError::KeyLoadingError),
-  };
+impl QueryReceipt {
+  #[must_use]
+  pub fn query_hash(&self) -> &[u8] {
+    &self.query_hash
+  }
 
-  let shared_secret = match private_key.as_diffie_hellman(&public_key) {
-    Some(shared_secret) => shared_secret,
-    None => return Err(ChatrouilleError::DiffieHellmanFail),
-  };
+  #[must_use]
+  pub fn nounce(&self) -> &[u8] {
+    &self.nounce
+  }
 
-  let symmetric_key = key_utils::derive_shared_secret_to_sym_key(&shared_secret, &mode.to_symmetric_key_salt())
-    .context(KeyDerivationError)?;
+  /// Checks that the client signed the query with this payload.
+  pub fn verify_receipt(&self, public_key: &ed25519_dalek::PublicKey, payload: &[u8]) -> Result<()> {
+    if query_hash_bytes(payload)[..] != self.query_hash[..] {
+      return Err(ChatrouilleError::ReceiptPayloadMismatch);
+    }
+    verify_query_signature(
+      &self.nounce,
+      &self.query_hash,
+      self.transcript_hash.as_deref(),
+      &self.signature,
+      public_key,
+    )
+  }
 
-  let aead_bytes =
-    &packed_data[PACKET_VERSION_LENGTH + MODE_LENGTH + CLIENT_PUBLIC_KEY_LENGTH..data_length];
-  let decrypted_bytes = orion::aead::open(&symmetric_key, aead_bytes).context(DecryptionError)?;
+  /// [query hash (64 bytes)][transcript hash (64 bytes, not in the duck version)][signature nounce (32 bytes)][signature (64 bytes)]
+  #[must_use]
+  pub fn to_bytes(&self) -> Vec<u8> {
+    [
+      &self.query_hash[..],
+      self.transcript_hash.as_deref().unwrap_or_default(),
+      &self.nounce[..],
+      &self.signature.to_bytes()[..],
+    ]
+    .concat()
+  }
 
-  if mode == Mode::SignedQuery {
-    use blake2_rfc::blake2b::blake2b;
-    use ed25519_dalek::Signature;
-    use std::convert::TryFrom;
+  pub fn from_bytes(bytes: &[u8]) -> Result<QueryReceipt> {
+    use core::convert::TryFrom;
 
-    let decrypted_length = decrypted_bytes.len();
-    if decrypted_length < SIGNATURE_LENGTH + NOUNCE_LENGTH {
-      return Err(ChatrouilleError::NotEnoughData);
-    }
+    let transcript_hash_length = match bytes.len() {
+      QUERY_RECEIPT_LENGTH => SIGNATURE_BLAKE2B_HASH_LENGTH,
+      DUCK_QUERY_RECEIPT_LENGTH => 0,
+      _ => return Err(ChatrouilleError::InvalidReceiptLength),
+    };
+    let transcript_hash_offset = SIGNATURE_BLAKE2B_HASH_LENGTH;
+    let nounce_offset = transcript_hash_offset + transcript_hash_length;
+    let signature_offset = nounce_offset + SIGNATURE_NOUNCE_LENGTH;
+
+    let signature =
+      ed25519_dalek::Signature::try_from(&bytes[signature_offset..]).context(SignatureError)?;
+
+    Ok(QueryReceipt {
+      query_hash: bytes[0..transcript_hash_offset].to_vec(),
+      transcript_hash: match transcript_hash_length {
+        0 => None,
+        _ => Some(bytes[transcript_hash_offset..nounce_offset].to_vec()),
+      },
+      nounce: bytes[nounce_offset..signature_offset].to_vec(),
+      signature,
+    })
+  }
 
-    let signature_nounce_bytes = &decrypted_bytes[decrypted_length-SIGNATURE_LENGTH-SIGNATURE_NOUNCE_LENGTH..decrypted_length-SIGNATURE_LENGTH];
-    let signature_bytes = &decrypted_bytes[decrypted_length - SIGNATURE_LENGTH..decrypted_length];
-    let signature = Signature::try_from(signature_bytes).context(SignatureError)?;
-    let compressed_data = &decrypted_bytes[0..decrypted_length - SIGNATURE_LENGTH];
-    let raw_data = compressor::decompress(&compressed_data).context(UncompressionError)?;
+  /// The bytes in base64 without padding, like the keys.
+  #[must_use]
+  pub fn to_base64(&self) -> String {
+    base64::encode_config(self.to_bytes(), base64::STANDARD_NO_PAD)
+  }
 
-    let packet_hash = blake2b(
-      SIGNATURE_BLAKE2B_HASH_LENGTH,
-      SIGNATURE_BLAKE2B_HASH_SALT,
-      &raw_data,
-    );
-    return Ok(UnpackedQuery {
-      payload: raw_data,
-      mode,
-      shared_secret,
-      signature: Some(UnpackedQuerySignature {
-        query_hash: packet_hash.as_bytes().to_vec(),
-        nounce: signature_nounce_bytes.to_vec(),
-        signature,
-      }),
-    });
+  pub fn from_base64(receipt_base64: &str) -> Result<QueryReceipt> {
+    let bytes = base64::decode_config(receipt_base64, base64::STANDARD_NO_PAD).context(InvalidReceiptEncoding)?;
+    QueryReceipt::from_bytes(&bytes)
   }
-  let raw_data = compressor::decompress(&decrypted_bytes).context(UncompressionError)?;
-  Ok(UnpackedQuery {
-    payload: raw_data,
-    mode,
-    shared_secret,
-    signature: None,
-  })
 }
 
-pub fn unpack_response(packed_data: &[u8], shared_secret: &x448::SharedSecret) -> Result<Vec<u8>> {
-  let data_length = packed_data.len();
-  if data_length < MINIMUM_RESPONSE_DATA_LENGTH {
-    return Err(ChatrouilleError::NotEnoughData);
+/// The receipts are serialized as their base64 string.
+#[cfg(feature = "json")]
+impl serde::Serialize for QueryReceipt {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.to_base64())
   }
+}
 
-  // If wrong version
-  if &packed_data[0..PACKET_VERSION_LENGTH] != PACKET_VERSION {
-    return Err(ChatrouilleError::InvalidDataPrefix);
+#[cfg(feature = "json")]
+impl<'de> serde::Deserialize<'de> for QueryReceipt {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<QueryReceipt, D::Error> {
+    let receipt_base64 = <String as serde::Deserialize>::deserialize(deserializer)?;
+    QueryReceipt::from_base64(&receipt_base64).map_err(serde::de::Error::custom)
   }
+}
 
-  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
-  if mode != Mode::Response {
-    return Err(ChatrouilleError::InvalidModeInData);
-  }
+/// The hash of a query payload, signed in signed queries
+/// and bound to the signed responses.
+#[must_use]
+pub fn query_hash(data: &[u8]) -> Vec<u8> {
+  query_hash_bytes(data).to_vec()
+}
 
-  let symmetric_key = key_utils::derive_shared_secret_to_sym_key(&shared_secret, &mode.to_symmetric_key_salt())
-    .context(KeyDerivationError)?;
+fn query_hash_bytes(data: &[u8]) -> [u8; SIGNATURE_BLAKE2B_HASH_LENGTH] {
+  // We sign on the hash because we can.
+  // It allows us to keep only the hash and not the full data
+  // when we want to verify the signature later.
+  // The whole thing is also a Rube Goldberg machine.
+  signature_hash(SIGNATURE_BLAKE2B_HASH_SALT, data)
+}
 
-  let aead_bytes = &packed_data[PACKET_VERSION_LENGTH + MODE_LENGTH..data_length];
+/// The hash of the authenticated packet header and public keys.
+fn transcript_hash(associated_data: &[u8]) -> Vec<u8> {
+  transcript_hash_bytes(associated_data).to_vec()
+}
 
-  let decrypted = orion::aead::open(&symmetric_key, aead_bytes).context(DecryptionError)?;
-  let raw_data = compressor::decompress(&decrypted).context(UncompressionError)?;
+fn transcript_hash_bytes(associated_data: &[u8]) -> [u8; SIGNATURE_BLAKE2B_HASH_LENGTH] {
+  signature_hash(TRANSCRIPT_BLAKE2B_HASH_SALT, associated_data)
+}
 
-  Ok(raw_data)
+/// The hashes are on the stack, the unpacking of the queries doesn't allocate them.
+fn signature_hash(salt: &[u8; 16], data: &[u8]) -> [u8; SIGNATURE_BLAKE2B_HASH_LENGTH] {
+  let mut hash = [0_u8; SIGNATURE_BLAKE2B_HASH_LENGTH];
+  hash.copy_from_slice(blake2_rfc::blake2b::blake2b(SIGNATURE_BLAKE2B_HASH_LENGTH, salt, data).as_bytes());
+  hash
 }
 
-impl VerifyUnpackedQuerySignature for UnpackedQuerySignature {
-  fn verify(&self, public_key: &ed25519_dalek::PublicKey) -> Result<()> {
-    let bytes_to_verify = [&self.nounce[..], &self.query_hash[..]].concat();
-    public_key
-      .verify_strict(&bytes_to_verify, &self.signature)
-      .context(VerifySignatureError)?;
-    Ok(())
+/// Same as `orion::aead::seal`, with associated data and a nounce from the given rng.
+fn seal_with_associated_data<R: RngCore + CryptoRng>(
+  symmetric_key: &chacha20::SecretKey,
+  plaintext: &[u8],
+  associated_data: &[u8],
+  rng: &mut R,
+) -> Result<Vec<u8>, orion::errors::UnknownCryptoError> {
+  let mut sealed = vec![0_u8; NOUNCE_LENGTH + plaintext.len() + TAG_LENGTH];
+  seal_with_associated_data_into(symmetric_key, plaintext, associated_data, rng, &mut sealed)?;
+  Ok(sealed)
+}
+
+/// Same as `seal_with_associated_data`, into a slice of the length of the sealed data.
+fn seal_with_associated_data_into<R: RngCore + CryptoRng>(
+  symmetric_key: &chacha20::SecretKey,
+  plaintext: &[u8],
+  associated_data: &[u8],
+  rng: &mut R,
+  sealed: &mut [u8],
+) -> Result<(), orion::errors::UnknownCryptoError> {
+  use orion::hazardous::aead::xchacha20poly1305;
+  use orion::hazardous::stream::xchacha20;
+
+  if sealed.len() != NOUNCE_LENGTH + plaintext.len() + TAG_LENGTH {
+    return Err(orion::errors::UnknownCryptoError);
   }
+  rng.fill_bytes(&mut sealed[..NOUNCE_LENGTH]);
+  let nounce = xchacha20::Nonce::from_slice(&sealed[..NOUNCE_LENGTH])?;
+  xchacha20poly1305::seal(
+    symmetric_key,
+    &nounce,
+    plaintext,
+    Some(associated_data),
+    &mut sealed[NOUNCE_LENGTH..],
+  )
 }
 
-#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
-#[cfg(test)]
-mod tests {
-  use super::*;
+/// Same as `orion::aead::open`, with associated data.
+fn open_with_associated_data(
+  symmetric_key: &chacha20::SecretKey,
+  sealed: &[u8],
+  associated_data: &[u8],
+) -> Result<Vec<u8>, orion::errors::UnknownCryptoError> {
+  if sealed.len() <= NOUNCE_LENGTH + TAG_LENGTH {
+    return Err(orion::errors::UnknownCryptoError);
+  }
+  let mut opened = vec![0_u8; sealed.len() - NOUNCE_LENGTH - TAG_LENGTH];
+  open_with_associated_data_into(symmetric_key, sealed, associated_data, &mut opened)?;
+  Ok(opened)
+}
 
-  #[test]
-  fn main_revival() {
-    let bob_secret = x448::Secret::from_bytes(&[
-      0x1c, 0x30, 0x6a, 0x7a, 0xc2, 0xa0, 0xe2, 0xe0, 0x99, 0xb, 0x29, 0x44, 0x70, 0xcb, 0xa3,
-      0x39, 0xe6, 0x45, 0x37, 0x72, 0xb0, 0x75, 0x81, 0x1d, 0x8f, 0xad, 0xd, 0x1d, 0x69, 0x27,
-      0xc1, 0x20, 0xbb, 0x5e, 0xe8, 0x97, 0x2b, 0xd, 0x3e, 0x21, 0x37, 0x4c, 0x9c, 0x92, 0x1b, 0x9,
-      0xd1, 0xb0, 0x36, 0x6f, 0x10, 0xb6, 0x51, 0x73, 0x99, 0x2d,
-    ])
-    .expect("Unwrap bob secret");
-    let bob_public_key = x448::PublicKey::from(&bob_secret);
+/// Same as `open_with_associated_data`, into a slice of the length of the plaintext.
+fn open_with_associated_data_into(
+  symmetric_key: &chacha20::SecretKey,
+  sealed: &[u8],
+  associated_data: &[u8],
+  opened: &mut [u8],
+) -> Result<(), orion::errors::UnknownCryptoError> {
+  use orion::hazardous::aead::xchacha20poly1305;
+  use orion::hazardous::stream::xchacha20;
+
+  if opened.is_empty() || sealed.len() != NOUNCE_LENGTH + opened.len() + TAG_LENGTH {
+    return Err(orion::errors::UnknownCryptoError);
+  }
+  xchacha20poly1305::open(
+    symmetric_key,
+    &xchacha20::Nonce::from_slice(&sealed[..NOUNCE_LENGTH])?,
+    &sealed[NOUNCE_LENGTH..],
+    Some(associated_data),
+    opened,
+  )
+}
 
-    let keypair = ed25519_dalek::Keypair::from_bytes(&[
-      46, 132, 86, 217, 108, 106, 16, 143, 86, 20, 150, 48, 236, 132, 24, 1, 197, 235, 183, 200,
-      148, 75, 24, 203, 228, 31, 166, 18, 122, 29, 90, 151, 176, 102, 32, 203, 59, 181, 83, 5, 128,
-      168, 162, 97, 165, 225, 237, 64, 2, 175, 178, 90, 221, 38, 99, 22, 17, 8, 27, 69, 13, 19, 6,
-      121,
-    ])
-    .expect("Unwrap keypair");
+/// The duck version is the same as `orion::aead::seal` and `orion::aead::open`, without associated data.
+fn seal_into<R: RngCore + CryptoRng>(
+  version: Version,
+  symmetric_key: &chacha20::SecretKey,
+  plaintext: &[u8],
+  associated_data: &[u8],
+  rng: &mut R,
+  sealed: &mut [u8],
+) -> Result<()> {
+  match version {
+    Version::Duck => seal_with_associated_data_into(symmetric_key, plaintext, &[], rng, sealed),
+    Version::Swan | Version::Owl | Version::Flamingo => {
+      seal_with_associated_data_into(symmetric_key, plaintext, associated_data, rng, sealed)
+    }
+  }
+  .context(EncryptionError)
+}
 
-    let (canard, _canard_secret) =
-      pack_signed_query(b"Bonjour le monde.", &bob_public_key, &keypair)
-        .expect("pack signed query");
+fn open(
+  version: Version,
+  symmetric_key: &chacha20::SecretKey,
+  sealed: &[u8],
+  associated_data: &[u8],
+) -> Result<Vec<u8>> {
+  let mut opened = vec![0_u8; sealed.len().saturating_sub(NOUNCE_LENGTH + TAG_LENGTH)];
+  open_into(version, symmetric_key, sealed, associated_data, &mut opened)?;
+  Ok(opened)
+}
 
-    println!(
-      "packed: {}\nlen: {}",
-      base64::encode_config(canard.clone(), base64::STANDARD_NO_PAD),
-      canard.len(),
-    );
+fn open_into(
+  version: Version,
+  symmetric_key: &chacha20::SecretKey,
+  sealed: &[u8],
+  associated_data: &[u8],
+  opened: &mut [u8],
+) -> Result<()> {
+  match version {
+    Version::Duck => open_with_associated_data_into(symmetric_key, sealed, &[], opened),
+    Version::Swan | Version::Owl | Version::Flamingo => {
+      open_with_associated_data_into(symmetric_key, sealed, associated_data, opened)
+    }
+  }
+  .context(DecryptionError)
+}
 
-    let payload = unpack_query(&canard, &bob_secret);
-    match payload {
-      Ok(unpacked_query) => {
+fn response_hash(data: &[u8]) -> Vec<u8> {
+  use blake2_rfc::blake2b::blake2b;
+
+  blake2b(
+    SIGNATURE_BLAKE2B_HASH_LENGTH,
+    RESPONSE_SIGNATURE_BLAKE2B_HASH_SALT,
+    data,
+  )
+  .as_bytes()
+  .to_vec()
+}
+
+/// The payload is compressed with the default codec.
+#[cfg(feature = "std")]
+pub fn pack_signed_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_signed_query_with_codec(data, server_public_key, client_keypair, Codec::default())
+}
+
+/// Same as `pack_signed_query`, the payload is compressed with the codec.
+#[cfg(feature = "std")]
+pub fn pack_signed_query_with_codec(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_signed_query_with_rng(data, server_public_key, client_keypair, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_signed_query`, the client ephemeral key and the nounces come from the rng.
+pub fn pack_signed_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_query(data, server_public_key, None, Some(client_keypair), Version::LATEST, codec, rng)
+}
+
+/// The payload is compressed with the default codec.
+#[cfg(feature = "std")]
+#[allow(dead_code)] // TODO
+pub fn pack_unsigned_query(data: &[u8], server_public_key: &x448::PublicKey) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_unsigned_query_with_codec(data, server_public_key, Codec::default())
+}
+
+/// Same as `pack_unsigned_query`, the payload is compressed with the codec.
+#[cfg(feature = "std")]
+pub fn pack_unsigned_query_with_codec(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_unsigned_query_with_rng(data, server_public_key, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_unsigned_query`, the client ephemeral key and the nounce come from the rng.
+pub fn pack_unsigned_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_query(data, server_public_key, None, None, Version::LATEST, codec, rng)
+}
+
+/// Same as `pack_signed_query_with_codec`, the query is appended to `packed_data`.
+/// Reusing the same vector for the queries avoids allocating memory for each of them.
+#[cfg(feature = "std")]
+pub fn pack_signed_query_into(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+  packed_data: &mut Vec<u8>,
+) -> Result<SharedSecret> {
+  pack_signed_query_into_with_rng(data, server_public_key, client_keypair, codec, &mut rand::thread_rng(), packed_data)
+}
+
+/// Same as `pack_signed_query_into`, the client ephemeral key and the nounces come from the rng.
+pub fn pack_signed_query_into_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+  rng: &mut R,
+  packed_data: &mut Vec<u8>,
+) -> Result<SharedSecret> {
+  pack_query_into(data, server_public_key, None, Some(client_keypair), Version::LATEST, codec, rng, packed_data)
+}
+
+/// Same as `pack_unsigned_query_with_codec`, the query is appended to `packed_data`.
+#[cfg(feature = "std")]
+pub fn pack_unsigned_query_into(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  codec: Codec,
+  packed_data: &mut Vec<u8>,
+) -> Result<SharedSecret> {
+  pack_unsigned_query_into_with_rng(data, server_public_key, codec, &mut rand::thread_rng(), packed_data)
+}
+
+/// Same as `pack_unsigned_query_into`, the client ephemeral key and the nounce come from the rng.
+pub fn pack_unsigned_query_into_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  codec: Codec,
+  rng: &mut R,
+  packed_data: &mut Vec<u8>,
+) -> Result<SharedSecret> {
+  pack_query_into(data, server_public_key, None, None, Version::LATEST, codec, rng, packed_data)
+}
+
+/// Same as `pack_signed_query`, in the owl version with the server ML-KEM public key.
+#[cfg(feature = "std")]
+pub fn pack_hybrid_signed_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: &mlkem::EncapsulationKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hybrid_signed_query_with_rng(
+    data,
+    server_public_key,
+    server_mlkem_public_key,
+    client_keypair,
+    codec,
+    &mut rand::thread_rng(),
+  )
+}
+
+/// Same as `pack_hybrid_signed_query`, the ephemeral keys and the nounces come from the rng.
+pub fn pack_hybrid_signed_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: &mlkem::EncapsulationKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_query(
+    data,
+    server_public_key,
+    Some(server_mlkem_public_key),
+    Some(client_keypair),
+    Version::Owl,
+    codec,
+    rng,
+  )
+}
+
+/// Same as `pack_unsigned_query`, in the owl version with the server ML-KEM public key.
+#[cfg(feature = "std")]
+pub fn pack_hybrid_unsigned_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: &mlkem::EncapsulationKey,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hybrid_unsigned_query_with_rng(
+    data,
+    server_public_key,
+    server_mlkem_public_key,
+    codec,
+    &mut rand::thread_rng(),
+  )
+}
+
+/// Same as `pack_hybrid_unsigned_query`, the ephemeral keys and the nounce come from the rng.
+pub fn pack_hybrid_unsigned_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: &mlkem::EncapsulationKey,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_query(data, server_public_key, Some(server_mlkem_public_key), None, Version::Owl, codec, rng)
+}
+
+/// Packs a 🦆 query, like the clients from before the swan version, signed if there is a keypair.
+/// The servers still accepting the legacy version answer it with a regular response.
+#[cfg(feature = "std")]
+pub fn pack_legacy_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_query(
+    data,
+    server_public_key,
+    None,
+    client_keypair,
+    Version::Duck,
+    Codec::Deflate,
+    &mut rand::thread_rng(),
+  )
+}
+
+/// The server ML-KEM public key is only used, and required, in the owl version.
+fn pack_query<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: Option<&mlkem::EncapsulationKey>,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  version: Version,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  let mut packed_data = Vec::new();
+  let shared_secret = pack_query_into(
+    data,
+    server_public_key,
+    server_mlkem_public_key,
+    client_keypair,
+    version,
+    codec,
+    rng,
+    &mut packed_data,
+  )?;
+  Ok((packed_data, shared_secret))
+}
+
+/// Same as `pack_query`, the query is appended to the given vector.
+/// Nothing is appended if it fails.
+#[allow(clippy::too_many_arguments)]
+fn pack_query_into<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  server_mlkem_public_key: Option<&mlkem::EncapsulationKey>,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  version: Version,
+  codec: Codec,
+  rng: &mut R,
+  packed_data: &mut Vec<u8>,
+) -> Result<SharedSecret> {
+  if version == Version::Flamingo {
+    let (packet, shared_secret) = pack_hpke_query(data, server_public_key, client_keypair, None, codec, rng)?;
+    packed_data.extend_from_slice(&packet);
+    return Ok(shared_secret);
+  }
+
+  let client_secret = key_utils::ZeroizingSecret::from(key_utils::gen_private_key_with_rng(rng));
+  let client_public_key = x448::PublicKey::from(&*client_secret);
+  let x448_shared_secret = match client_secret.as_diffie_hellman(server_public_key) {
+    Some(secret) => secret,
+    None => return Err(ChatrouilleError::DiffieHellmanFail),
+  };
+
+  let (mlkem_ciphertext, shared_secret, server_public_keys_bytes) = match version {
+    Version::Owl => {
+      let server_mlkem_public_key = match server_mlkem_public_key {
+        Some(key) => key,
+        None => return Err(ChatrouilleError::MissingMlKemKey),
+      };
+      let (ciphertext, mut mlkem_shared_secret) =
+        server_mlkem_public_key.encapsulate_with_rng(rng);
+      let shared_secret = SharedSecret::hybrid(x448_shared_secret, &mlkem_shared_secret);
+      mlkem_shared_secret.zeroize();
+      (
+        ciphertext,
+        shared_secret,
+        [server_public_key.as_bytes(), server_mlkem_public_key.as_bytes()].concat(),
+      )
+    }
+    Version::Duck | Version::Swan | Version::Flamingo => (
+      vec![],
+      SharedSecret::from(x448_shared_secret),
+      server_public_key.as_bytes().to_vec(),
+    ),
+  };
+
+  // The duck queries keep the layout of the first clients, without the key id
+  let public_key_bytes = match version {
+    Version::Duck => client_public_key.as_bytes().to_vec(),
+    Version::Swan | Version::Owl | Version::Flamingo => {
+      let key_id = key_utils::key_id(server_public_key);
+      [&key_id[..], client_public_key.as_bytes(), &mlkem_ciphertext].concat()
+    }
+  };
+  /*assert_eq!(
+    public_key_bytes.len(),
+    CLIENT_PUBLIC_KEY_LENGTH,
+    "The public key length should be {} bytes",
+    CLIENT_PUBLIC_KEY_LENGTH
+  );*/
+
+  let mode = match client_keypair {
+    Some(_) => Mode::SignedQuery,
+    None => Mode::Query,
+  };
+
+  let header = packet_header(version, &mode, &public_key_bytes);
+  let associated_data = [&header[..], &server_public_keys_bytes].concat();
+
+  let signature_nounce_and_signature_bytes =
+    sign_query(data, &associated_data, version, client_keypair, rng);
+
+  pack_into(
+    data,
+    &mode,
+    &header,
+    &associated_data,
+    &shared_secret,
+    &signature_nounce_and_signature_bytes,
+    PaddingPolicy::default(),
+    codec,
+    rng,
+    packed_data,
+  )?;
+
+  Ok(shared_secret)
+}
+
+/// Same as `pack_unsigned_query`, in the flamingo version.
+#[cfg(feature = "std")]
+pub fn pack_hpke_unsigned_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hpke_unsigned_query_with_rng(data, server_public_key, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_hpke_unsigned_query`, the HPKE ephemeral key comes from the rng.
+pub fn pack_hpke_unsigned_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hpke_query(data, server_public_key, None, None, codec, rng)
+}
+
+/// Same as `pack_signed_query`, in the flamingo version.
+#[cfg(feature = "std")]
+pub fn pack_hpke_signed_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hpke_signed_query_with_rng(data, server_public_key, client_keypair, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_hpke_signed_query`, the HPKE ephemeral key and the signature nounce come from the rng.
+pub fn pack_hpke_signed_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: &ed25519_dalek::Keypair,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hpke_query(data, server_public_key, Some(client_keypair), None, codec, rng)
+}
+
+/// Packs a query in the HPKE auth mode, authenticated with the client x448 static key.
+/// The server finds the client public key in `UnpackedQuery::sender_public_key`.
+#[cfg(feature = "std")]
+pub fn pack_hpke_auth_query(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_secret: &x448::Secret,
+  codec: Codec,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hpke_auth_query_with_rng(data, server_public_key, client_secret, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_hpke_auth_query`, the HPKE ephemeral key comes from the rng.
+pub fn pack_hpke_auth_query_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_secret: &x448::Secret,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  pack_hpke_query(data, server_public_key, None, Some(client_secret), codec, rng)
+}
+
+/// A query is either signed with an ed25519 key, or authenticated with a x448 key, not both.
+fn pack_hpke_query<R: RngCore + CryptoRng>(
+  data: &[u8],
+  server_public_key: &x448::PublicKey,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  client_secret: Option<&x448::Secret>,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<(Vec<u8>, SharedSecret)> {
+  let (mode, encapsulated_key, mut context, client_public_key_bytes) = match (client_keypair, client_secret) {
+    (Some(_), Some(_)) => return Err(ChatrouilleError::InvalidMode),
+    (_, None) => {
+      let (encapsulated_key, context) =
+        hpke::setup_base_sender(server_public_key, HPKE_INFO, rng).context(HpkeError)?;
+      let mode = match client_keypair {
+        Some(_) => Mode::SignedQuery,
+        None => Mode::Query,
+      };
+      (mode, encapsulated_key, context, vec![])
+    }
+    (None, Some(client_secret)) => {
+      let (encapsulated_key, context) =
+        hpke::setup_auth_sender(server_public_key, HPKE_INFO, client_secret, rng).context(HpkeError)?;
+      let client_public_key = x448::PublicKey::from(client_secret);
+      (Mode::AuthQuery, encapsulated_key, context, client_public_key.as_bytes().to_vec())
+    }
+  };
+
+  let key_id = key_utils::key_id(server_public_key);
+  let header_bytes = [&key_id[..], &encapsulated_key, &client_public_key_bytes].concat();
+  let header = packet_header(Version::Flamingo, &mode, &header_bytes);
+  let associated_data = [&header[..], server_public_key.as_bytes()].concat();
+
+  let signature_nounce_and_signature_bytes =
+    sign_query(data, &associated_data, Version::Flamingo, client_keypair, rng);
+  let payload = pack_payload(
+    data,
+    Version::Flamingo,
+    &mode,
+    &signature_nounce_and_signature_bytes,
+    PaddingPolicy::default(),
+    codec,
+  )?;
+  let mut encrypted = context.seal(&associated_data, &payload).context(HpkeError)?;
+
+  let shared_secret = SharedSecret::hpke_exported(
+    context
+      .export(HPKE_RESPONSE_EXPORTER_CONTEXT, HPKE_RESPONSE_SECRET_LENGTH)
+      .context(HpkeError)?,
+  );
+
+  let mut packed_data = header;
+  packed_data.append(&mut encrypted);
+  Ok((packed_data, shared_secret))
+}
+
+/// The response should use the same version as the query.
+/// It's compressed with the default codec, see `pack_response_with_codec`.
+#[cfg(feature = "std")]
+pub fn pack_response(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  version: Version,
+  padding: PaddingPolicy,
+) -> Result<Vec<u8>> {
+  pack_response_with_codec(data, shared_secret, version, padding, Codec::default())
+}
+
+/// The response should use the same version and codec as the query.
+#[cfg(feature = "std")]
+pub fn pack_response_with_codec(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+) -> Result<Vec<u8>> {
+  pack_response_with_rng(data, shared_secret, version, padding, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_response`, the nounce comes from the rng.
+pub fn pack_response_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<Vec<u8>> {
+  let mut packed_data = Vec::new();
+  pack_response_into_with_rng(data, shared_secret, version, padding, codec, rng, &mut packed_data)?;
+  Ok(packed_data)
+}
+
+/// Same as `pack_response`, the response is appended to `packed_data`.
+/// Reusing the same vector for the responses avoids allocating memory for each of them.
+#[cfg(feature = "std")]
+pub fn pack_response_into(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+  packed_data: &mut Vec<u8>,
+) -> Result<()> {
+  pack_response_into_with_rng(data, shared_secret, version, padding, codec, &mut rand::thread_rng(), packed_data)
+}
+
+/// Same as `pack_response_into`, the nounce comes from the rng.
+pub fn pack_response_into_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+  packed_data: &mut Vec<u8>,
+) -> Result<()> {
+  let header = packet_header_bytes(version, &Mode::Response);
+  pack_into(
+    data,
+    &Mode::Response,
+    &header,
+    &header,
+    shared_secret,
+    &[],
+    padding,
+    codec,
+    rng,
+    packed_data,
+  )
+}
+
+/// Packs a response signed by the server, bound to the query using its hash.
+/// It's compressed with the default codec, see `pack_signed_response_with_codec`.
+#[cfg(feature = "std")]
+pub fn pack_signed_response(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  query_hash: &[u8],
+  server_keypair: &ed25519_dalek::Keypair,
+  version: Version,
+  padding: PaddingPolicy,
+) -> Result<Vec<u8>> {
+  pack_signed_response_with_codec(
+    data,
+    shared_secret,
+    query_hash,
+    server_keypair,
+    version,
+    padding,
+    Codec::default(),
+  )
+}
+
+/// Same as `pack_signed_response`, with the codec of the query.
+#[cfg(feature = "std")]
+pub fn pack_signed_response_with_codec(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  query_hash: &[u8],
+  server_keypair: &ed25519_dalek::Keypair,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+) -> Result<Vec<u8>> {
+  pack_signed_response_with_rng(
+    data,
+    shared_secret,
+    query_hash,
+    server_keypair,
+    version,
+    padding,
+    codec,
+    &mut rand::thread_rng(),
+  )
+}
+
+/// Same as `pack_signed_response`, the nounces come from the rng.
+#[allow(clippy::too_many_arguments)]
+pub fn pack_signed_response_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  query_hash: &[u8],
+  server_keypair: &ed25519_dalek::Keypair,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<Vec<u8>> {
+  let mut packed_data = Vec::new();
+  pack_signed_response_into_with_rng(
+    data,
+    shared_secret,
+    query_hash,
+    server_keypair,
+    version,
+    padding,
+    codec,
+    rng,
+    &mut packed_data,
+  )?;
+  Ok(packed_data)
+}
+
+/// Same as `pack_signed_response`, the response is appended to `packed_data`.
+#[cfg(feature = "std")]
+#[allow(clippy::too_many_arguments)]
+pub fn pack_signed_response_into(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  query_hash: &[u8],
+  server_keypair: &ed25519_dalek::Keypair,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+  packed_data: &mut Vec<u8>,
+) -> Result<()> {
+  pack_signed_response_into_with_rng(
+    data,
+    shared_secret,
+    query_hash,
+    server_keypair,
+    version,
+    padding,
+    codec,
+    &mut rand::thread_rng(),
+    packed_data,
+  )
+}
+
+/// Same as `pack_signed_response_into`, the nounces come from the rng.
+#[allow(clippy::too_many_arguments)]
+pub fn pack_signed_response_into_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  shared_secret: &SharedSecret,
+  query_hash: &[u8],
+  server_keypair: &ed25519_dalek::Keypair,
+  version: Version,
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+  packed_data: &mut Vec<u8>,
+) -> Result<()> {
+  let hashes = [query_hash, &response_hash(data)[..]].concat();
+  let signature_nounce_and_signature_bytes = sign_hash(&hashes, server_keypair, rng);
+  let header = packet_header_bytes(version, &Mode::SignedResponse);
+  pack_into(
+    data,
+    &Mode::SignedResponse,
+    &header,
+    &header,
+    shared_secret,
+    &signature_nounce_and_signature_bytes,
+    padding,
+    codec,
+    rng,
+    packed_data,
+  )
+}
+
+/// Packs an error, for the queries that were decrypted but couldn't be answered.
+/// Nobody else than the client can read it or forge it.
+///
+/// The error messages are short and may contain data from the query,
+/// they are not compressed in the swan version.
+#[cfg(feature = "std")]
+pub fn pack_error(
+  code: u16,
+  message: &str,
+  shared_secret: &SharedSecret,
+  version: Version,
+) -> Result<Vec<u8>> {
+  pack_error_with_rng(code, message, shared_secret, version, &mut rand::thread_rng())
+}
+
+/// Same as `pack_error`, the nounce comes from the rng.
+pub fn pack_error_with_rng<R: RngCore + CryptoRng>(
+  code: u16,
+  message: &str,
+  shared_secret: &SharedSecret,
+  version: Version,
+  rng: &mut R,
+) -> Result<Vec<u8>> {
+  let data = [&code.to_be_bytes()[..], message.as_bytes()].concat();
+  let header = packet_header_bytes(version, &Mode::Error);
+  pack(
+    &data,
+    &Mode::Error,
+    &header,
+    &header,
+    shared_secret,
+    &[],
+    PaddingPolicy::default(),
+    match version {
+      Version::Duck => Codec::Deflate,
+      Version::Swan | Version::Owl | Version::Flamingo => Codec::None,
+    },
+    rng,
+  )
+}
+
+/// [version][mode][query header bytes]
+fn packet_header(version: Version, mode: &Mode, query_header_bytes: &[u8]) -> Vec<u8> {
+  let mut header =
+    Vec::with_capacity(PACKET_VERSION_LENGTH + MODE_LENGTH + query_header_bytes.len());
+  header.extend(version.prefix());
+  header.push(mode.clone() as u8);
+  header.extend(query_header_bytes);
+  header
+}
+
+/// [version][mode], the header of the responses, without allocating it.
+fn packet_header_bytes(version: Version, mode: &Mode) -> [u8; PACKET_VERSION_LENGTH + MODE_LENGTH] {
+  let mut header = [0_u8; PACKET_VERSION_LENGTH + MODE_LENGTH];
+  header[..PACKET_VERSION_LENGTH].copy_from_slice(version.prefix());
+  header[PACKET_VERSION_LENGTH] = mode.clone() as u8;
+  header
+}
+
+/// Signs the query hash, and the transcript hash after the duck version.
+/// Returns nothing for the unsigned queries.
+fn sign_query<R: RngCore + CryptoRng>(
+  data: &[u8],
+  associated_data: &[u8],
+  version: Version,
+  client_keypair: Option<&ed25519_dalek::Keypair>,
+  rng: &mut R,
+) -> Vec<u8> {
+  match client_keypair {
+    Some(keypair) => match version {
+      Version::Duck => sign_hash(&query_hash(data), keypair, rng),
+      Version::Swan | Version::Owl | Version::Flamingo => sign_hash(
+        &[query_hash(data), transcript_hash(associated_data)].concat(),
+        keypair,
+        rng,
+      ),
+    },
+    None => vec![],
+  }
+}
+
+/// Signs a hash with a random nounce.
+/// Returns the nounce followed by the signature.
+fn sign_hash<R: RngCore + CryptoRng>(hash: &[u8], keypair: &ed25519_dalek::Keypair, rng: &mut R) -> Vec<u8> {
+  let mut signature_nounce = [0_u8; SIGNATURE_NOUNCE_LENGTH];
+  rng.fill_bytes(&mut signature_nounce);
+  sign_hash_with_nounce(hash, keypair, &signature_nounce)
+}
+
+/// The chunked packer draws its nounce when it's created.
+fn sign_hash_with_nounce(
+  hash: &[u8],
+  keypair: &ed25519_dalek::Keypair,
+  signature_nounce: &[u8; SIGNATURE_NOUNCE_LENGTH],
+) -> Vec<u8> {
+  use ed25519_dalek::Signer;
+
+  let bytes_to_sign = [&signature_nounce[..], hash].concat();
+  let signature = keypair.sign(&bytes_to_sign);
+  [&signature_nounce[..], &signature.to_bytes()[..]].concat()
+}
+
+/// Compresses and pads the data, and appends the signature nounce and signature bytes,
+/// they must be present for the signed modes.
+///
+/// The padding doesn't include the signature, which has a fixed length.
+fn pack_payload(
+  data: &[u8],
+  version: Version,
+  mode: &Mode,
+  signature_nounce_and_signature_bytes: &[u8],
+  padding: PaddingPolicy,
+  codec: Codec,
+) -> Result<Vec<u8>> {
+  let mut payload = Vec::new();
+  pack_payload_into(
+    data,
+    version,
+    mode,
+    signature_nounce_and_signature_bytes,
+    padding,
+    codec,
+    &mut payload,
+  )?;
+  Ok(payload)
+}
+
+/// Same as `pack_payload`, the payload is appended to the given vector.
+fn pack_payload_into(
+  data: &[u8],
+  version: Version,
+  mode: &Mode,
+  signature_nounce_and_signature_bytes: &[u8],
+  padding: PaddingPolicy,
+  codec: Codec,
+  payload: &mut Vec<u8>,
+) -> Result<()> {
+  let is_signed = *mode == Mode::SignedQuery || *mode == Mode::SignedResponse;
+  if is_signed && signature_nounce_and_signature_bytes.len() != SIGNATURE_NOUNCE_LENGTH + SIGNATURE_LENGTH {
+    return Err(ChatrouilleError::MissingKeyPair);
+  }
+
+  // To improve the privacy, the compressed messages are padded.
+  // The duck version relies on deflate ignoring the trailing zeros.
+  let offset = payload.len();
+  match version {
+    Version::Duck => {
+      if codec != Codec::Deflate {
+        return Err(ChatrouilleError::UnsupportedCodec);
+      }
+      compressor::compress_into(codec, data, payload).context(CompressionError)?;
+      let padded_length = padding.padded_length(payload.len() - offset);
+      payload.resize(offset + padded_length, 0);
+    }
+    Version::Swan | Version::Owl | Version::Flamingo => {
+      payload.extend(&[0_u8; padding::LENGTH_PREFIX_LENGTH]);
+      payload.push(codec as u8);
+      compressor::compress_into(codec, data, payload).context(CompressionError)?;
+      padding::pad_in_place(payload, offset, padding).context(PaddingError)?;
+    }
+  }
+
+  if is_signed {
+    payload.extend_from_slice(signature_nounce_and_signature_bytes);
+  }
+  Ok(())
+}
+
+/// See `pack_payload` for the signature nounce and signature bytes.
+///
+/// The associated data is not authenticated in the duck version,
+/// the version is read from the header.
+#[allow(clippy::too_many_arguments)]
+fn pack<R: RngCore + CryptoRng>(
+  data: &[u8],
+  mode: &Mode,
+  header: &[u8],
+  associated_data: &[u8],
+  shared_secret: &SharedSecret,
+  signature_nounce_and_signature_bytes: &[u8],
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<Vec<u8>> {
+  let mut packed_data = Vec::new();
+  pack_into(
+    data,
+    mode,
+    header,
+    associated_data,
+    shared_secret,
+    signature_nounce_and_signature_bytes,
+    padding,
+    codec,
+    rng,
+    &mut packed_data,
+  )?;
+  Ok(packed_data)
+}
+
+/// Same as `pack`, the packet is appended to the given vector.
+/// Nothing is appended if it fails.
+#[allow(clippy::too_many_arguments)]
+fn pack_into<R: RngCore + CryptoRng>(
+  data: &[u8],
+  mode: &Mode,
+  header: &[u8],
+  associated_data: &[u8],
+  shared_secret: &SharedSecret,
+  signature_nounce_and_signature_bytes: &[u8],
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+  packed_data: &mut Vec<u8>,
+) -> Result<()> {
+  let version = match Version::from_packet(header) {
+    Some(version) => version,
+    None => return Err(ChatrouilleError::InvalidDataPrefix),
+  };
+
+  // The payload is written after the header, and sealed after itself.
+  // The sealed payload then replaces it, so the packet needs no other memory.
+  let payload_length = padding.padded_length(padding::LENGTH_PREFIX_LENGTH + 1 + data.len())
+    + signature_nounce_and_signature_bytes.len();
+  packed_data.reserve(header.len() + payload_length * 2 + NOUNCE_LENGTH + TAG_LENGTH);
+
+  let initial_length = packed_data.len();
+  packed_data.extend_from_slice(header);
+  let result = (|| {
+    let payload_offset = packed_data.len();
+    pack_payload_into(
+      data,
+      version,
+      mode,
+      signature_nounce_and_signature_bytes,
+      padding,
+      codec,
+      packed_data,
+    )?;
+    let symmetric_key = key_utils::derive_shared_secret_to_sym_key(shared_secret, &mode.to_symmetric_key_salt())
+      .context(KeyDerivationError)?;
+
+    let expected_header_length = PACKET_VERSION_LENGTH
+      + MODE_LENGTH
+      + match mode {
+        Mode::Query | Mode::SignedQuery => query_public_keys_length(version),
+        Mode::Response | Mode::SignedResponse | Mode::Error => 0,
+        _ => return Err(ChatrouilleError::InvalidMode),
+      };
+    if header.len() != expected_header_length {
+      return Err(ChatrouilleError::InvalidMode);
+    }
+
+    let payload_length = packed_data.len() - payload_offset;
+    let sealed_length = NOUNCE_LENGTH + payload_length + TAG_LENGTH;
+    packed_data.resize(payload_offset + payload_length + sealed_length, 0);
+    let (payload, sealed) = packed_data[payload_offset..].split_at_mut(payload_length);
+    seal_into(version, &symmetric_key, payload, associated_data, rng, sealed)?;
+    packed_data.copy_within(payload_offset + payload_length.., payload_offset);
+    packed_data.truncate(payload_offset + sealed_length);
+    Ok(())
+  })();
+
+  if result.is_err() {
+    packed_data.truncate(initial_length);
+  }
+  result
+}
+
+/// Removes the padding, authenticated in the swan version,
+/// and decompresses the data with its codec, up to `max_payload_length` bytes.
+fn decompress(
+  version: Version,
+  padded_data: &[u8],
+  max_payload_length: usize,
+) -> Result<(Vec<u8>, Codec)> {
+  let (codec, compressed_data) = unpad_compressed(version, padded_data)?;
+  let raw_data = compressor::decompress_with_codec(codec, compressed_data, max_payload_length)
+    .context(UncompressionError)?;
+  Ok((raw_data, codec))
+}
+
+/// Same as `decompress`, the data is decompressed into the output.
+/// The uncompressed data is borrowed from the padded data, without copying it.
+fn decompress_into<'a>(
+  version: Version,
+  padded_data: &'a [u8],
+  max_payload_length: usize,
+  output: &'a mut Vec<u8>,
+) -> Result<(&'a [u8], Codec)> {
+  let (codec, compressed_data) = unpad_compressed(version, padded_data)?;
+  if codec == Codec::None {
+    if compressed_data.len() > max_payload_length {
+      return Err(ChatrouilleError::UncompressionError {
+        source: compressor::CompressorError::OutputTooLarge {
+          max_size: max_payload_length,
+        },
+      });
+    }
+    return Ok((compressed_data, codec));
+  }
+
+  output.clear();
+  compressor::decompress_into(codec, compressed_data, max_payload_length, output)
+    .context(UncompressionError)?;
+  Ok((output, codec))
+}
+
+/// Returns the codec and the compressed data of a padded payload.
+fn unpad_compressed(version: Version, padded_data: &[u8]) -> Result<(Codec, &[u8])> {
+  match version {
+    Version::Duck => Ok((Codec::Deflate, padded_data)),
+    Version::Swan | Version::Owl | Version::Flamingo => match padding::unpad(padded_data).context(PaddingError)?.split_first() {
+      Some((codec_byte, compressed_data)) => Ok((
+        Codec::from_byte(*codec_byte).context(UncompressionError)?,
+        compressed_data,
+      )),
+      None => Err(ChatrouilleError::NotEnoughData),
+    },
+  }
+}
+
+/// Length of the query header after the mode: the server key id (except in the duck version),
+/// the client x448 public key, and the ML-KEM ciphertext in the owl version.
+/// In the flamingo version, the HPKE encapsulated key replaces the client public key,
+/// and the auth queries also contain the client static public key.
+fn query_public_keys_length(version: Version) -> usize {
+  match version {
+    Version::Duck => CLIENT_PUBLIC_KEY_LENGTH,
+    Version::Swan => KEY_ID_LENGTH + CLIENT_PUBLIC_KEY_LENGTH,
+    Version::Owl => KEY_ID_LENGTH + CLIENT_PUBLIC_KEY_LENGTH + MLKEM_CIPHERTEXT_LENGTH,
+    Version::Flamingo => KEY_ID_LENGTH + HPKE_ENCAPSULATED_KEY_LENGTH,
+  }
+}
+
+/// Returns the mode of a packet from its first bytes,
+/// or `Mode::Unknown` if they are not enough or the version is invalid.
+#[must_use]
+pub fn packet_mode(packed_data: &[u8]) -> Mode {
+  if packed_data.len() < PACKET_VERSION_LENGTH + MODE_LENGTH
+    || Version::from_packet(packed_data).is_none()
+  {
+    return Mode::Unknown;
+  }
+  Mode::from(packed_data[PACKET_VERSION_LENGTH])
+}
+
+/// Length of the prefix required by `packet_mode`.
+pub const PACKET_MODE_PREFIX_LENGTH: usize = PACKET_VERSION_LENGTH + MODE_LENGTH;
+
+/// Returns the server key id of a query, if the data is long enough.
+///
+/// The duck queries don't have one, except the chunked queries.
+#[must_use]
+pub fn query_key_id(packed_data: &[u8]) -> Option<key_utils::KeyId> {
+  if Version::from_packet(packed_data) == Some(Version::Duck) && !packet_mode(packed_data).is_chunked() {
+    return None;
+  }
+  let offset = PACKET_VERSION_LENGTH + MODE_LENGTH;
+  if packed_data.len() < offset + KEY_ID_LENGTH {
+    return None;
+  }
+  let mut key_id = [0_u8; KEY_ID_LENGTH];
+  key_id.copy_from_slice(&packed_data[offset..offset + KEY_ID_LENGTH]);
+  Some(key_id)
+}
+
+/// Returns the shared secret of a query, and appends the server public keys
+/// authenticated with its header to the associated data. The length of the header has been checked.
+fn query_shared_secret<K: PrivateKeyring + ?Sized>(
+  packed_data: &[u8],
+  keyring: &K,
+  version: Version,
+  associated_data: &mut Vec<u8>,
+) -> Result<SharedSecret> {
+  let key_id = match query_key_id(packed_data) {
+    Some(key_id) => key_id,
+    None => return Err(ChatrouilleError::NotEnoughData),
+  };
+  let private_key = match keyring.private_key(&key_id) {
+    Some(private_key) => private_key,
+    None => return Err(ChatrouilleError::UnknownServerKey),
+  };
+
+  let public_key_offset = PACKET_VERSION_LENGTH + MODE_LENGTH + KEY_ID_LENGTH;
+  let public_key_bytes =
+    &packed_data[public_key_offset..public_key_offset + CLIENT_PUBLIC_KEY_LENGTH];
+
+  let public_key = match x448::PublicKey::from_bytes(public_key_bytes) {
+    Some(public_key) => public_key,
+    None => return Err(ChatrouilleError::KeyLoadingError),
+  };
+
+  let x448_shared_secret = match private_key.as_diffie_hellman(&public_key) {
+    Some(shared_secret) => shared_secret,
+    None => return Err(ChatrouilleError::DiffieHellmanFail),
+  };
+  let server_public_key = x448::PublicKey::from(private_key);
+
+  match version {
+    Version::Owl => {
+      let mlkem_private_key = match keyring.mlkem_private_key(&key_id) {
+        Some(mlkem_private_key) => mlkem_private_key,
+        None => return Err(ChatrouilleError::MissingMlKemKey),
+      };
+      let ciphertext_offset = public_key_offset + CLIENT_PUBLIC_KEY_LENGTH;
+      let mut mlkem_shared_secret = match mlkem_private_key
+        .decapsulate(&packed_data[ciphertext_offset..ciphertext_offset + MLKEM_CIPHERTEXT_LENGTH])
+      {
+        Some(mlkem_shared_secret) => mlkem_shared_secret,
+        None => return Err(ChatrouilleError::NotEnoughData),
+      };
+      let shared_secret = SharedSecret::hybrid(x448_shared_secret, &mlkem_shared_secret);
+      mlkem_shared_secret.zeroize();
+      associated_data.extend_from_slice(server_public_key.as_bytes());
+      associated_data.extend_from_slice(mlkem_private_key.encapsulation_key().as_bytes());
+      Ok(shared_secret)
+    }
+    Version::Swan => {
+      associated_data.extend_from_slice(server_public_key.as_bytes());
+      Ok(SharedSecret::from(x448_shared_secret))
+    }
+    // See open_duck_query_into and unpack_hpke_query_into
+    Version::Duck | Version::Flamingo => Err(ChatrouilleError::InvalidDataPrefix),
+  }
+}
+
+/// Decrypts the query after the associated data at the start of the buffer.
+fn open_query_into(
+  buffer: &mut UnpackBuffer,
+  version: Version,
+  mode: &Mode,
+  shared_secret: &SharedSecret,
+  aead_bytes: &[u8],
+) -> Result<()> {
+  let symmetric_key = key_utils::derive_shared_secret_to_sym_key(shared_secret, &mode.to_symmetric_key_salt())
+    .context(KeyDerivationError)?;
+
+  let associated_data_length = buffer.decrypted.len();
+  buffer
+    .decrypted
+    .resize(associated_data_length + aead_bytes.len() - NOUNCE_LENGTH - TAG_LENGTH, 0);
+  let (associated_data, decrypted_bytes) = buffer.decrypted.split_at_mut(associated_data_length);
+  open_into(version, &symmetric_key, aead_bytes, associated_data, decrypted_bytes)
+}
+
+/// The duck queries don't contain the server key id, so the legacy keys are tried in turn
+/// until one decrypts the query. Returns its shared secret, the buffer contains the header,
+/// the server public key as in the other versions, and the decrypted bytes.
+fn open_duck_query_into<K: PrivateKeyring + ?Sized>(
+  packed_data: &[u8],
+  keyring: &K,
+  mode: &Mode,
+  aead_bytes: &[u8],
+  buffer: &mut UnpackBuffer,
+) -> Result<SharedSecret> {
+  let public_key_offset = PACKET_VERSION_LENGTH + MODE_LENGTH;
+  let public_key = match x448::PublicKey::from_bytes(
+    &packed_data[public_key_offset..public_key_offset + CLIENT_PUBLIC_KEY_LENGTH],
+  ) {
+    Some(public_key) => public_key,
+    None => return Err(ChatrouilleError::KeyLoadingError),
+  };
+
+  let header_length = buffer.decrypted.len();
+  let mut result = Err(ChatrouilleError::UnknownServerKey);
+  for private_key in keyring.legacy_private_keys() {
+    let shared_secret = match private_key.as_diffie_hellman(&public_key) {
+      Some(x448_shared_secret) => SharedSecret::from(x448_shared_secret),
+      None => return Err(ChatrouilleError::DiffieHellmanFail),
+    };
+    buffer.decrypted.truncate(header_length);
+    buffer
+      .decrypted
+      .extend_from_slice(x448::PublicKey::from(private_key).as_bytes());
+    result = open_query_into(buffer, Version::Duck, mode, &shared_secret, aead_bytes).map(|()| shared_secret);
+    if result.is_ok() {
+      break;
+    }
+  }
+  result
+}
+
+/// The payload can't be larger than `max_payload_length` bytes once uncompressed.
+///
+/// The flamingo queries are decrypted with HPKE, see `unpack_hpke_query_into`.
+pub fn unpack_query<K: PrivateKeyring + ?Sized>(
+  packed_data: &[u8],
+  keyring: &K,
+  max_payload_length: usize,
+) -> Result<UnpackedQuery> {
+  let mut buffer = UnpackBuffer::new();
+  Ok(unpack_query_into(packed_data, keyring, max_payload_length, &mut buffer)?.into_owned())
+}
+
+/// Same as `unpack_query`, the query is decrypted into the buffer,
+/// and the payload is borrowed from it instead of being copied.
+///
+/// With a buffer reused for every query, the uncompressed queries
+/// are unpacked without allocating memory for the data.
+pub fn unpack_query_into<'a, K: PrivateKeyring + ?Sized>(
+  packed_data: &[u8],
+  keyring: &K,
+  max_payload_length: usize,
+  buffer: &'a mut UnpackBuffer,
+) -> Result<UnpackedQueryRef<'a>> {
+  buffer.decrypted.clear();
+  if Version::from_packet(packed_data) == Some(Version::Flamingo) {
+    return unpack_hpke_query_into(packed_data, keyring, max_payload_length, buffer);
+  }
+
+  let data_length = packed_data.len();
+  if data_length < MINIMUM_QUERY_DATA_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  let version = match Version::from_packet(packed_data) {
+    Some(version) => version,
+    None => return Err(ChatrouilleError::InvalidDataPrefix),
+  };
+
+  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
+  // If wrong mode
+  if mode != Mode::SignedQuery && mode != Mode::Query {
+    return Err(ChatrouilleError::InvalidModeInData);
+  }
+
+  let header_length = PACKET_VERSION_LENGTH + MODE_LENGTH + query_public_keys_length(version);
+  if data_length < header_length + NOUNCE_LENGTH + TAG_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  buffer.decrypted.extend_from_slice(&packed_data[0..header_length]);
+  let aead_bytes = &packed_data[header_length..data_length];
+  let shared_secret = if version == Version::Duck {
+    open_duck_query_into(packed_data, keyring, &mode, aead_bytes, buffer)?
+  } else {
+    let shared_secret = query_shared_secret(packed_data, keyring, version, &mut buffer.decrypted)?;
+    open_query_into(buffer, version, &mode, &shared_secret, aead_bytes)?;
+    shared_secret
+  };
+  let associated_data_length = buffer.decrypted.len() - (aead_bytes.len() - NOUNCE_LENGTH - TAG_LENGTH);
+
+  unpack_query_payload(
+    buffer,
+    associated_data_length,
+    version,
+    mode,
+    shared_secret,
+    None,
+    max_payload_length,
+  )
+}
+
+/// Decrypts a flamingo query with HPKE, in the base mode or in the auth mode.
+fn unpack_hpke_query_into<'a, K: PrivateKeyring + ?Sized>(
+  packed_data: &[u8],
+  keyring: &K,
+  max_payload_length: usize,
+  buffer: &'a mut UnpackBuffer,
+) -> Result<UnpackedQueryRef<'a>> {
+  let data_length = packed_data.len();
+  if data_length < PACKET_VERSION_LENGTH + MODE_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
+  let sender_public_key_length = match mode {
+    Mode::Query | Mode::SignedQuery => 0,
+    Mode::AuthQuery => CLIENT_PUBLIC_KEY_LENGTH,
+    _ => return Err(ChatrouilleError::InvalidModeInData),
+  };
+
+  let encapsulated_key_offset = PACKET_VERSION_LENGTH + MODE_LENGTH + KEY_ID_LENGTH;
+  let sender_public_key_offset = encapsulated_key_offset + HPKE_ENCAPSULATED_KEY_LENGTH;
+  let header_length = sender_public_key_offset + sender_public_key_length;
+  if data_length < header_length + hpke::TAG_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  let key_id = match query_key_id(packed_data) {
+    Some(key_id) => key_id,
+    None => return Err(ChatrouilleError::NotEnoughData),
+  };
+  let private_key = match keyring.private_key(&key_id) {
+    Some(private_key) => private_key,
+    None => return Err(ChatrouilleError::UnknownServerKey),
+  };
+
+  let encapsulated_key = &packed_data[encapsulated_key_offset..sender_public_key_offset];
+  let (mut context, sender_public_key) = match mode {
+    Mode::AuthQuery => {
+      let sender_public_key = match x448::PublicKey::from_bytes(&packed_data[sender_public_key_offset..header_length]) {
+        Some(public_key) => public_key,
+        None => return Err(ChatrouilleError::KeyLoadingError),
+      };
+      let context = hpke::setup_auth_receiver(encapsulated_key, private_key, HPKE_INFO, &sender_public_key)
+        .context(HpkeError)?;
+      (context, Some(sender_public_key))
+    }
+    _ => (
+      hpke::setup_base_receiver(encapsulated_key, private_key, HPKE_INFO).context(HpkeError)?,
+      None,
+    ),
+  };
+
+  let server_public_key = x448::PublicKey::from(private_key);
+  buffer.decrypted.extend_from_slice(&packed_data[0..header_length]);
+  buffer.decrypted.extend_from_slice(server_public_key.as_bytes());
+
+  let ciphertext = &packed_data[header_length..data_length];
+  let associated_data_length = buffer.decrypted.len();
+  buffer
+    .decrypted
+    .resize(associated_data_length + ciphertext.len() - hpke::TAG_LENGTH, 0);
+  let (associated_data, decrypted_bytes) = buffer.decrypted.split_at_mut(associated_data_length);
+  context
+    .open_into(associated_data, ciphertext, decrypted_bytes)
+    .context(HpkeError)?;
+
+  let shared_secret = SharedSecret::hpke_exported(
+    context
+      .export(HPKE_RESPONSE_EXPORTER_CONTEXT, HPKE_RESPONSE_SECRET_LENGTH)
+      .context(HpkeError)?,
+  );
+
+  unpack_query_payload(
+    buffer,
+    associated_data_length,
+    Version::Flamingo,
+    mode,
+    shared_secret,
+    sender_public_key,
+    max_payload_length,
+  )
+}
+
+/// Extracts the signature of the signed queries, and decompresses the payload.
+/// The decrypted buffer contains the associated data, followed by the decrypted bytes.
+fn unpack_query_payload(
+  buffer: &mut UnpackBuffer,
+  associated_data_length: usize,
+  version: Version,
+  mode: Mode,
+  shared_secret: SharedSecret,
+  sender_public_key: Option<x448::PublicKey>,
+  max_payload_length: usize,
+) -> Result<UnpackedQueryRef<'_>> {
+  let UnpackBuffer {
+    decrypted,
+    decompressed,
+  } = buffer;
+  let (associated_data, decrypted_bytes) = decrypted.split_at(associated_data_length);
+
+  if mode == Mode::SignedQuery {
+    use ed25519_dalek::Signature;
+    use core::convert::TryFrom;
+
+    let decrypted_length = decrypted_bytes.len();
+    if decrypted_length < SIGNATURE_LENGTH + SIGNATURE_NOUNCE_LENGTH {
+      return Err(ChatrouilleError::NotEnoughData);
+    }
+
+    let signature_nounce_bytes = &decrypted_bytes[decrypted_length-SIGNATURE_LENGTH-SIGNATURE_NOUNCE_LENGTH..decrypted_length-SIGNATURE_LENGTH];
+    let signature_bytes = &decrypted_bytes[decrypted_length - SIGNATURE_LENGTH..decrypted_length];
+    let signature = Signature::try_from(signature_bytes).context(SignatureError)?;
+    let compressed_data =
+      &decrypted_bytes[0..decrypted_length - SIGNATURE_LENGTH - SIGNATURE_NOUNCE_LENGTH];
+    let (raw_data, codec) = decompress_into(version, compressed_data, max_payload_length, decompressed)?;
+
+    return Ok(UnpackedQueryRef {
+      payload: raw_data,
+      version,
+      codec,
+      mode,
+      shared_secret,
+      signature: Some(UnpackedQuerySignatureRef {
+        query_hash: query_hash_bytes(raw_data),
+        transcript_hash: match version {
+          Version::Duck => None,
+          Version::Swan | Version::Owl | Version::Flamingo => Some(transcript_hash_bytes(associated_data)),
+        },
+        nounce: signature_nounce_bytes,
+        signature,
+      }),
+      sender_public_key,
+    });
+  }
+  let (raw_data, codec) = decompress_into(version, decrypted_bytes, max_payload_length, decompressed)?;
+  Ok(UnpackedQueryRef {
+    payload: raw_data,
+    version,
+    codec,
+    mode,
+    shared_secret,
+    signature: None,
+    sender_public_key,
+  })
+}
+
+/// The payload can't be larger than `max_payload_length` bytes once uncompressed.
+pub fn unpack_response(
+  packed_data: &[u8],
+  shared_secret: &SharedSecret,
+  max_payload_length: usize,
+) -> Result<Vec<u8>> {
+  let data_length = packed_data.len();
+  if data_length < MINIMUM_RESPONSE_DATA_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  let version = match Version::from_packet(packed_data) {
+    Some(version) => version,
+    None => return Err(ChatrouilleError::InvalidDataPrefix),
+  };
+
+  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
+  if mode == Mode::Error {
+    return Err(unpack_error(packed_data, shared_secret, version, max_payload_length));
+  }
+  if mode != Mode::Response {
+    return Err(ChatrouilleError::InvalidModeInData);
+  }
+
+  let symmetric_key = key_utils::derive_shared_secret_to_sym_key(&shared_secret, &mode.to_symmetric_key_salt())
+    .context(KeyDerivationError)?;
+
+  let (header, aead_bytes) = packed_data.split_at(PACKET_VERSION_LENGTH + MODE_LENGTH);
+
+  let decrypted = open(version, &symmetric_key, aead_bytes, header)?;
+  let (raw_data, _) = decompress(version, &decrypted, max_payload_length)?;
+  Ok(raw_data)
+}
+
+/// Returns the `ServerError` carried by an error packet,
+/// or the error preventing to read it.
+fn unpack_error(
+  packed_data: &[u8],
+  shared_secret: &SharedSecret,
+  version: Version,
+  max_payload_length: usize,
+) -> ChatrouilleError {
+  const CODE_LENGTH: usize = 2;
+
+  let symmetric_key =
+    match key_utils::derive_shared_secret_to_sym_key(shared_secret, &Mode::Error.to_symmetric_key_salt())
+      .context(KeyDerivationError)
+    {
+      Ok(symmetric_key) => symmetric_key,
+      Err(error) => return error,
+    };
+
+  let (header, aead_bytes) = packed_data.split_at(PACKET_VERSION_LENGTH + MODE_LENGTH);
+  let decrypted = match open(version, &symmetric_key, aead_bytes, header) {
+    Ok(decrypted) => decrypted,
+    Err(error) => return error,
+  };
+  let raw_data = match decompress(version, &decrypted, max_payload_length) {
+    Ok((raw_data, _)) => raw_data,
+    Err(error) => return error,
+  };
+  if raw_data.len() < CODE_LENGTH {
+    return ChatrouilleError::NotEnoughData;
+  }
+
+  ChatrouilleError::ServerError {
+    code: u16::from_be_bytes([raw_data[0], raw_data[1]]),
+    message: String::from_utf8_lossy(&raw_data[CODE_LENGTH..]).into_owned(),
+  }
+}
+
+/// Unpacks a signed response and verifies the server signature
+/// against the hash of the query it answers.
+pub fn unpack_signed_response(
+  packed_data: &[u8],
+  shared_secret: &SharedSecret,
+  query_hash: &[u8],
+  server_public_key: &ed25519_dalek::PublicKey,
+  max_payload_length: usize,
+) -> Result<(Vec<u8>, ResponseReceipt)> {
+  use ed25519_dalek::Signature;
+  use core::convert::TryFrom;
+
+  let data_length = packed_data.len();
+  if data_length < MINIMUM_RESPONSE_DATA_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  let version = match Version::from_packet(packed_data) {
+    Some(version) => version,
+    None => return Err(ChatrouilleError::InvalidDataPrefix),
+  };
+
+  let mode = Mode::from(packed_data[PACKET_VERSION_LENGTH]);
+  if mode == Mode::Error {
+    return Err(unpack_error(packed_data, shared_secret, version, max_payload_length));
+  }
+  if mode != Mode::SignedResponse {
+    return Err(ChatrouilleError::InvalidModeInData);
+  }
+
+  let symmetric_key = key_utils::derive_shared_secret_to_sym_key(shared_secret, &mode.to_symmetric_key_salt())
+    .context(KeyDerivationError)?;
+
+  let (header, aead_bytes) = packed_data.split_at(PACKET_VERSION_LENGTH + MODE_LENGTH);
+  let decrypted_bytes = open(version, &symmetric_key, aead_bytes, header)?;
+
+  let decrypted_length = decrypted_bytes.len();
+  if decrypted_length < SIGNATURE_LENGTH + SIGNATURE_NOUNCE_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+  let signature_offset = decrypted_length - SIGNATURE_LENGTH;
+  let nounce_offset = signature_offset - SIGNATURE_NOUNCE_LENGTH;
+  let signature = Signature::try_from(&decrypted_bytes[signature_offset..]).context(SignatureError)?;
+
+  let (raw_data, _) = decompress(version, &decrypted_bytes[0..nounce_offset], max_payload_length)?;
+
+  let receipt = ResponseReceipt {
+    query_hash: query_hash.to_vec(),
+    response_hash: response_hash(&raw_data),
+    nounce: decrypted_bytes[nounce_offset..signature_offset].to_vec(),
+    signature,
+  };
+  receipt.verify(server_public_key)?;
+
+  Ok((raw_data, receipt))
+}
+
+impl VerifyUnpackedQuerySignature for UnpackedQuerySignature {
+  fn verify(&self, public_key: &ed25519_dalek::PublicKey) -> Result<()> {
+    verify_query_signature(
+      &self.nounce,
+      &self.query_hash,
+      self.transcript_hash.as_deref(),
+      &self.signature,
+      public_key,
+    )
+  }
+}
+
+impl VerifyUnpackedQuerySignature for UnpackedQuerySignatureRef<'_> {
+  fn verify(&self, public_key: &ed25519_dalek::PublicKey) -> Result<()> {
+    verify_query_signature(
+      self.nounce,
+      &self.query_hash,
+      self.transcript_hash.as_ref().map(|transcript_hash| &transcript_hash[..]),
+      &self.signature,
+      public_key,
+    )
+  }
+}
+
+fn verify_query_signature(
+  nounce: &[u8],
+  query_hash: &[u8],
+  transcript_hash: Option<&[u8]>,
+  signature: &ed25519_dalek::Signature,
+  public_key: &ed25519_dalek::PublicKey,
+) -> Result<()> {
+  let bytes_to_verify = match transcript_hash {
+    Some(transcript_hash) => [nounce, query_hash, transcript_hash].concat(),
+    None => [nounce, query_hash].concat(),
+  };
+  public_key
+    .verify_strict(&bytes_to_verify, signature)
+    .context(VerifySignatureError)?;
+  Ok(())
+}
+
+// Most tests use the thread rng, they need std
+#[allow(clippy::panic, clippy::expect_used, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+
+  const MAX_PAYLOAD_LENGTH: usize = 4096;
+
+  #[test]
+  fn main_revival() {
+    let bob_secret = x448::Secret::from_bytes(&[
+      0x1c, 0x30, 0x6a, 0x7a, 0xc2, 0xa0, 0xe2, 0xe0, 0x99, 0xb, 0x29, 0x44, 0x70, 0xcb, 0xa3,
+      0x39, 0xe6, 0x45, 0x37, 0x72, 0xb0, 0x75, 0x81, 0x1d, 0x8f, 0xad, 0xd, 0x1d, 0x69, 0x27,
+      0xc1, 0x20, 0xbb, 0x5e, 0xe8, 0x97, 0x2b, 0xd, 0x3e, 0x21, 0x37, 0x4c, 0x9c, 0x92, 0x1b, 0x9,
+      0xd1, 0xb0, 0x36, 0x6f, 0x10, 0xb6, 0x51, 0x73, 0x99, 0x2d,
+    ])
+    .expect("Unwrap bob secret");
+    let bob_public_key = x448::PublicKey::from(&bob_secret);
+
+    let keypair = ed25519_dalek::Keypair::from_bytes(&[
+      46, 132, 86, 217, 108, 106, 16, 143, 86, 20, 150, 48, 236, 132, 24, 1, 197, 235, 183, 200,
+      148, 75, 24, 203, 228, 31, 166, 18, 122, 29, 90, 151, 176, 102, 32, 203, 59, 181, 83, 5, 128,
+      168, 162, 97, 165, 225, 237, 64, 2, 175, 178, 90, 221, 38, 99, 22, 17, 8, 27, 69, 13, 19, 6,
+      121,
+    ])
+    .expect("Unwrap keypair");
+
+    let (canard, _canard_secret) =
+      pack_signed_query(b"Bonjour le monde.", &bob_public_key, &keypair)
+        .expect("pack signed query");
+
+    println!(
+      "packed: {}\nlen: {}",
+      base64::encode_config(canard.clone(), base64::STANDARD_NO_PAD),
+      canard.len(),
+    );
+
+    let payload = unpack_query(&canard, &bob_secret, MAX_PAYLOAD_LENGTH);
+    match payload {
+      Ok(unpacked_query) => {
         println!(
           "payload: {} | mode: {:?}",
           std::str::from_utf8(&unpacked_query.payload).unwrap_or("prout"),
@@ -456,9 +2497,9 @@ mod tests {
         };
         assert!(signed);
         let canard_response =
-          pack_response(b"Bien le bonjour aussi", &unpacked_query.shared_secret)
+          pack_response(b"Bien le bonjour aussi", &unpacked_query.shared_secret, unpacked_query.version, PaddingPolicy::default())
             .expect("pack response");
-        let payload_response = unpack_response(&canard_response, &unpacked_query.shared_secret);
+        let payload_response = unpack_response(&canard_response, &unpacked_query.shared_secret, MAX_PAYLOAD_LENGTH);
         match payload_response {
           Ok(payload) => {
             println!(
@@ -473,6 +2514,38 @@ mod tests {
     };
   }
 
+  /// A duck query packed by the first version of the library, before the key ids.
+  #[test]
+  fn test_legacy_duck_query() {
+    let bob_secret = x448::Secret::from_bytes(&[
+      0x1c, 0x30, 0x6a, 0x7a, 0xc2, 0xa0, 0xe2, 0xe0, 0x99, 0xb, 0x29, 0x44, 0x70, 0xcb, 0xa3,
+      0x39, 0xe6, 0x45, 0x37, 0x72, 0xb0, 0x75, 0x81, 0x1d, 0x8f, 0xad, 0xd, 0x1d, 0x69, 0x27,
+      0xc1, 0x20, 0xbb, 0x5e, 0xe8, 0x97, 0x2b, 0xd, 0x3e, 0x21, 0x37, 0x4c, 0x9c, 0x92, 0x1b, 0x9,
+      0xd1, 0xb0, 0x36, 0x6f, 0x10, 0xb6, 0x51, 0x73, 0x99, 0x2d,
+    ])
+    .expect("Unwrap bob secret");
+    let keypair = ed25519_dalek::Keypair::from_bytes(&[
+      46, 132, 86, 217, 108, 106, 16, 143, 86, 20, 150, 48, 236, 132, 24, 1, 197, 235, 183, 200,
+      148, 75, 24, 203, 228, 31, 166, 18, 122, 29, 90, 151, 176, 102, 32, 203, 59, 181, 83, 5, 128,
+      168, 162, 97, 165, 225, 237, 64, 2, 175, 178, 90, 221, 38, 99, 22, 17, 8, 27, 69, 13, 19, 6,
+      121,
+    ])
+    .expect("Unwrap keypair");
+
+    let canard = base64::decode_config(
+      "8J+mhlNl/mTECCglHkyQ2gEzC0BwHN3/CRWC6xnbXxmTV48BZzrpNQk+LIIC88xm/B/dYgMvC1jG2rWoMfWe/inksoKHfTvHMH0K3gztXefwv945SGkhNUUBPlY2VhrxIPmKL3G2I7K5gFAx3wjwDTVW32SAahDpxrXS6ne1FJmR1jL3E8hRHNASL2gBB4OEVKFP7DIFoVCQOgPoezxrUallLKmkQnjSNnD4fLqEK2sK7GM/htT9Zl3cRS2MCF58Fq3CShtI6b3t2T3XQ1SUJrHpLiGC11Vc5VzN9qbKYhvXRr8iYQ",
+      base64::STANDARD_NO_PAD,
+    )
+    .unwrap();
+    assert_eq!(query_key_id(&canard), None);
+
+    let unpacked_query = unpack_query(&canard, &bob_secret, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.version == Version::Duck);
+    assert!(unpacked_query.mode == Mode::SignedQuery);
+    assert_eq!(unpacked_query.payload, b"Bonjour le monde.");
+    assert!(unpacked_query.signature.unwrap().verify(&keypair.public).is_ok());
+  }
+
   #[test]
   fn test_unsigned() {
     let server_private_key = key_utils::gen_private_key();
@@ -484,8 +2557,8 @@ mod tests {
     // Check that each query has a different secret
     assert_ne!(query_a.1.as_bytes().to_vec(), query_b.1.as_bytes().to_vec());
 
-    let unpack_query_a = unpack_query(&query_a.0, &server_private_key).unwrap();
-    let unpack_query_b = unpack_query(&query_b.0, &server_private_key).unwrap();
+    let unpack_query_a = unpack_query(&query_a.0, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    let unpack_query_b = unpack_query(&query_b.0, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
 
     assert!(unpack_query_a.mode == Mode::Query);
     assert_eq!(unpack_query_a.payload, b"top secret data");
@@ -499,17 +2572,833 @@ mod tests {
       shared_secret_b.as_bytes().to_vec()
     );
 
-    let response_a = pack_response(b"indeed it's secret", &shared_secret_a).unwrap();
-    let response_b = pack_response(b"indeed it's secret", &shared_secret_b).unwrap();
+    let response_a =
+      pack_response(b"indeed it's secret", &shared_secret_a, unpack_query_a.version, PaddingPolicy::default()).unwrap();
+    let response_b =
+      pack_response(b"indeed it's secret", &shared_secret_b, unpack_query_b.version, PaddingPolicy::default()).unwrap();
     assert_ne!(response_a, response_b);
 
-    let unpack_response_a = unpack_response(&response_a, &shared_secret_a).unwrap();
-    let unpack_response_b = unpack_response(&response_b, &shared_secret_b).unwrap();
+    let unpack_response_a = unpack_response(&response_a, &shared_secret_a, MAX_PAYLOAD_LENGTH).unwrap();
+    let unpack_response_b = unpack_response(&response_b, &shared_secret_b, MAX_PAYLOAD_LENGTH).unwrap();
 
     assert_eq!(unpack_response_a, b"indeed it's secret");
     assert_eq!(unpack_response_b, b"indeed it's secret");
   }
 
+  #[test]
+  fn test_signed_response() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let server_keypair = key_utils::gen_ed25519_keypair();
+
+    let (query, client_shared_secret) =
+      pack_unsigned_query(b"am I a citizen?", &server_public_key).unwrap();
+    let client_query_hash = query_hash(b"am I a citizen?");
+
+    let unpacked_query = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    let response = pack_signed_response(
+      b"yes you are",
+      &unpacked_query.shared_secret,
+      &query_hash(&unpacked_query.payload),
+      &server_keypair,
+      unpacked_query.version,
+      PaddingPolicy::FixedSize(1024),
+    )
+    .unwrap();
+    assert!(packet_mode(&response) == Mode::SignedResponse);
+
+    let (payload, receipt) = unpack_signed_response(
+      &response,
+      &client_shared_secret,
+      &client_query_hash,
+      &server_keypair.public,
+      MAX_PAYLOAD_LENGTH,
+    )
+    .unwrap();
+    assert_eq!(payload, b"yes you are");
+    assert_eq!(receipt.query_hash(), &client_query_hash[..]);
+
+    // The receipt can be stored and verified later
+    let stored_receipt = ResponseReceipt::from_bytes(&receipt.to_bytes()).unwrap();
+    assert!(stored_receipt.verify(&server_keypair.public).is_ok());
+    assert!(ResponseReceipt::from_bytes(&receipt.to_bytes()[1..]).is_err());
+
+    // Bound to the query
+    assert!(unpack_signed_response(
+      &response,
+      &client_shared_secret,
+      &query_hash(b"am I a duck?"),
+      &server_keypair.public,
+      MAX_PAYLOAD_LENGTH,
+    )
+    .is_err());
+
+    // Signed by the server
+    let another_keypair = key_utils::gen_ed25519_keypair();
+    assert!(unpack_signed_response(
+      &response,
+      &client_shared_secret,
+      &client_query_hash,
+      &another_keypair.public,
+      MAX_PAYLOAD_LENGTH,
+    )
+    .is_err());
+    assert!(stored_receipt.verify(&another_keypair.public).is_err());
+
+    // Not a regular response
+    assert!(unpack_response(&response, &client_shared_secret, MAX_PAYLOAD_LENGTH).is_err());
+  }
+
+  struct HybridKeyring {
+    private_key: x448::Secret,
+    mlkem_private_key: mlkem::DecapsulationKey,
+  }
+
+  impl PrivateKeyring for HybridKeyring {
+    fn private_key(&self, _key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+      Some(&self.private_key)
+    }
+
+    fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+      vec![&self.private_key]
+    }
+
+    fn mlkem_private_key(&self, _key_id: &key_utils::KeyId) -> Option<&mlkem::DecapsulationKey> {
+      Some(&self.mlkem_private_key)
+    }
+  }
+
+  fn gen_hybrid_keyring() -> HybridKeyring {
+    HybridKeyring {
+      private_key: key_utils::gen_private_key(),
+      mlkem_private_key: key_utils::gen_mlkem_private_key(),
+    }
+  }
+
+  #[test]
+  fn test_versions() {
+    let keyring = gen_hybrid_keyring();
+    let server_public_key = key_utils::gen_public_key(&keyring.private_key);
+    let server_mlkem_public_key = keyring.mlkem_private_key.encapsulation_key();
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    for version in &[Version::Duck, Version::Swan, Version::Owl, Version::Flamingo] {
+      let (query, shared_secret) = pack_query(
+        b"coin coin",
+        &server_public_key,
+        Some(server_mlkem_public_key),
+        Some(&keypair),
+        *version,
+        Codec::default(),
+        &mut rand::thread_rng(),
+      )
+      .unwrap();
+      assert!(Version::from_packet(&query) == Some(*version));
+
+      let unpacked_query = unpack_query(&query, &keyring, MAX_PAYLOAD_LENGTH).unwrap();
+      assert!(unpacked_query.version == *version);
+      assert_eq!(unpacked_query.payload, b"coin coin");
+      assert!(unpacked_query.signature.as_ref().unwrap().verify(&keypair.public).is_ok());
+
+      let response =
+        pack_response(b"couac", &unpacked_query.shared_secret, unpacked_query.version, PaddingPolicy::default()).unwrap();
+      assert!(Version::from_packet(&response) == Some(*version));
+      assert_eq!(unpack_response(&response, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(), b"couac");
+
+      // The version is authenticated in the swan version, and the
+      // duck version has no associated data, so changing it breaks the decryption
+      let mut response_with_another_version = response.clone();
+      let another_version = match version {
+        Version::Duck | Version::Owl | Version::Flamingo => Version::Swan,
+        Version::Swan => Version::Duck,
+      };
+      response_with_another_version[0..PACKET_VERSION_LENGTH]
+        .copy_from_slice(another_version.prefix());
+      assert!(unpack_response(&response_with_another_version, &shared_secret, MAX_PAYLOAD_LENGTH).is_err());
+    }
+  }
+
+  #[test]
+  fn test_query_receipt() {
+    let keyring = gen_hybrid_keyring();
+    let server_public_key = key_utils::gen_public_key(&keyring.private_key);
+    let server_mlkem_public_key = keyring.mlkem_private_key.encapsulation_key();
+    let keypair = key_utils::gen_ed25519_keypair();
+    let another_keypair = key_utils::gen_ed25519_keypair();
+    let mut buffer = UnpackBuffer::new();
+
+    for version in &[Version::Duck, Version::Swan, Version::Owl, Version::Flamingo] {
+      let (query, _) = pack_query(
+        b"I want to change my name",
+        &server_public_key,
+        Some(server_mlkem_public_key),
+        Some(&keypair),
+        *version,
+        Codec::default(),
+        &mut rand::thread_rng(),
+      )
+      .unwrap();
+      let unpacked_query = unpack_query(&query, &keyring, MAX_PAYLOAD_LENGTH).unwrap();
+      let receipt = unpacked_query.signature.as_ref().unwrap().receipt();
+      assert_eq!(receipt.query_hash(), &query_hash(b"I want to change my name")[..]);
+      assert!(receipt.verify_receipt(&keypair.public, b"I want to change my name").is_ok());
+
+      let unpacked_query_ref = unpack_query_into(&query, &keyring, MAX_PAYLOAD_LENGTH, &mut buffer).unwrap();
+      assert_eq!(unpacked_query_ref.signature.as_ref().unwrap().receipt(), receipt);
+
+      // The receipt can be stored and verified later
+      let stored_receipt = QueryReceipt::from_base64(&receipt.to_base64()).unwrap();
+      assert_eq!(stored_receipt, receipt);
+      assert_eq!(QueryReceipt::from_bytes(&receipt.to_bytes()).unwrap(), receipt);
+      assert!(stored_receipt.verify_receipt(&keypair.public, b"I want to change my name").is_ok());
+      assert_eq!(
+        receipt.to_bytes().len(),
+        match version {
+          Version::Duck => DUCK_QUERY_RECEIPT_LENGTH,
+          Version::Swan | Version::Owl | Version::Flamingo => QUERY_RECEIPT_LENGTH,
+        }
+      );
+
+      assert!(matches!(
+        stored_receipt.verify_receipt(&keypair.public, b"I want to change my duck"),
+        Err(ChatrouilleError::ReceiptPayloadMismatch)
+      ));
+      assert!(matches!(
+        stored_receipt.verify_receipt(&another_keypair.public, b"I want to change my name"),
+        Err(ChatrouilleError::VerifySignatureError { .. })
+      ));
+      assert!(matches!(
+        QueryReceipt::from_bytes(&receipt.to_bytes()[1..]),
+        Err(ChatrouilleError::InvalidReceiptLength)
+      ));
+    }
+
+    assert!(matches!(
+      QueryReceipt::from_base64("not a receipt"),
+      Err(ChatrouilleError::InvalidReceiptEncoding { .. })
+    ));
+  }
+
+  #[cfg(feature = "json")]
+  #[test]
+  fn test_query_receipt_serde() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    let (query, _) = pack_signed_query(b"coin coin", &server_public_key, &keypair).unwrap();
+    let unpacked_query = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    let receipt = unpacked_query.signature.unwrap().receipt();
+
+    let json = serde_json::to_string(&receipt).unwrap();
+    assert_eq!(json, format!("\"{}\"", receipt.to_base64()));
+    assert_eq!(serde_json::from_str::<QueryReceipt>(&json).unwrap(), receipt);
+    assert!(serde_json::from_str::<QueryReceipt>("\"Y29pbg\"").is_err());
+  }
+
+  #[test]
+  fn test_unpack_query_into() {
+    let keyring = gen_hybrid_keyring();
+    let server_public_key = key_utils::gen_public_key(&keyring.private_key);
+    let server_mlkem_public_key = keyring.mlkem_private_key.encapsulation_key();
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    // The same buffers are used for every query and response
+    let mut buffer = UnpackBuffer::new();
+    let mut packed_response = b"previous response".to_vec();
+
+    for version in &[Version::Duck, Version::Swan, Version::Owl, Version::Flamingo] {
+      for codec in &[Codec::Deflate, Codec::None] {
+        for client_keypair in &[Some(&keypair), None] {
+          if *version == Version::Duck && *codec == Codec::None {
+            continue;
+          }
+          let (query, shared_secret) = pack_query(
+            b"coin coin",
+            &server_public_key,
+            Some(server_mlkem_public_key),
+            *client_keypair,
+            *version,
+            *codec,
+            &mut rand::thread_rng(),
+          )
+          .unwrap();
+
+          let unpacked_query = unpack_query_into(&query, &keyring, MAX_PAYLOAD_LENGTH, &mut buffer).unwrap();
+          assert!(unpacked_query.version == *version);
+          assert!(unpacked_query.codec == *codec);
+          assert_eq!(unpacked_query.payload, b"coin coin");
+          assert_eq!(unpacked_query.shared_secret.as_bytes(), shared_secret.as_bytes());
+          match client_keypair {
+            Some(client_keypair) => {
+              let signature = unpacked_query.signature.as_ref().unwrap();
+              assert!(signature.verify(&client_keypair.public).is_ok());
+              assert_eq!(signature.query_hash(), &query_hash(b"coin coin")[..]);
+              assert!(signature.verify(&key_utils::gen_ed25519_keypair().public).is_err());
+            }
+            None => assert!(unpacked_query.signature.is_none()),
+          }
+
+          // The uncompressed payload is not copied out of the decrypted bytes
+          let payload_pointer = unpacked_query.payload.as_ptr();
+          let unpacked_query = unpacked_query.into_owned();
+          assert_eq!(
+            buffer.decrypted.as_ptr_range().contains(&payload_pointer),
+            *codec == Codec::None
+          );
+
+          let owned_query = unpack_query(&query, &keyring, MAX_PAYLOAD_LENGTH).unwrap();
+          assert_eq!(unpacked_query.payload, owned_query.payload);
+          assert_eq!(
+            unpacked_query.signature.map(|signature| signature.nounce().to_vec()),
+            owned_query.signature.map(|signature| signature.nounce().to_vec()),
+          );
+
+          // The response is appended after the previous one
+          let previous_length = packed_response.len();
+          match client_keypair {
+            Some(_) => pack_signed_response_into(
+              b"couac",
+              &unpacked_query.shared_secret,
+              &query_hash(b"coin coin"),
+              &keypair,
+              *version,
+              PaddingPolicy::default(),
+              *codec,
+              &mut packed_response,
+            )
+            .unwrap(),
+            None => pack_response_into(
+              b"couac",
+              &unpacked_query.shared_secret,
+              *version,
+              PaddingPolicy::default(),
+              *codec,
+              &mut packed_response,
+            )
+            .unwrap(),
+          }
+          assert_eq!(&packed_response[..17], b"previous response");
+          let response = &packed_response[previous_length..];
+          let payload = match client_keypair {
+            Some(_) => {
+              unpack_signed_response(response, &shared_secret, &query_hash(b"coin coin"), &keypair.public, MAX_PAYLOAD_LENGTH)
+                .unwrap()
+                .0
+            }
+            None => unpack_response(response, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(),
+          };
+          assert_eq!(payload, b"couac");
+          packed_response.truncate(previous_length);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_pack_query_into() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let keypair = key_utils::gen_ed25519_keypair();
+    let mut packed_query = b"previous query".to_vec();
+
+    for client_keypair in &[Some(&keypair), None] {
+      let shared_secret = match client_keypair {
+        Some(client_keypair) => {
+          pack_signed_query_into(b"coin coin", &server_public_key, client_keypair, Codec::Deflate, &mut packed_query)
+        }
+        None => pack_unsigned_query_into(b"coin coin", &server_public_key, Codec::Deflate, &mut packed_query),
+      }
+      .unwrap();
+      assert_eq!(&packed_query[..14], b"previous query");
+
+      let unpacked_query = unpack_query(&packed_query[14..], &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+      assert!(unpacked_query.version == Version::LATEST);
+      assert_eq!(unpacked_query.payload, b"coin coin");
+      assert_eq!(unpacked_query.shared_secret.as_bytes(), shared_secret.as_bytes());
+      assert_eq!(unpacked_query.signature.is_some(), client_keypair.is_some());
+      packed_query.truncate(14);
+    }
+
+    // Nothing is appended when the packing fails
+    assert!(pack_query_into(
+      b"coin coin",
+      &server_public_key,
+      None,
+      None,
+      Version::Duck,
+      Codec::None,
+      &mut rand::thread_rng(),
+      &mut packed_query,
+    )
+    .is_err());
+    assert_eq!(packed_query, b"previous query");
+  }
+
+  #[test]
+  fn test_into_after_error() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let mut buffer = UnpackBuffer::new();
+    let mut packed_response = b"previous response".to_vec();
+
+    // The buffer can still be used after an error
+    let (query, _) = pack_unsigned_query_with_codec(b"coin coin", &server_public_key, Codec::None).unwrap();
+    assert!(unpack_query_into(&query[..query.len() - 1], &server_private_key, MAX_PAYLOAD_LENGTH, &mut buffer).is_err());
+    assert!(matches!(
+      unpack_query_into(&query, &server_private_key, 4, &mut buffer),
+      Err(ChatrouilleError::UncompressionError { .. })
+    ));
+    let unpacked_query = unpack_query_into(&query, &server_private_key, MAX_PAYLOAD_LENGTH, &mut buffer).unwrap();
+    assert_eq!(unpacked_query.payload, b"coin coin");
+
+    // Nothing is appended when the packing fails
+    let shared_secret = unpacked_query.shared_secret;
+    assert!(pack_response_into(
+      b"couac",
+      &shared_secret,
+      Version::Duck,
+      PaddingPolicy::default(),
+      Codec::None,
+      &mut packed_response,
+    )
+    .is_err());
+    assert_eq!(packed_response, b"previous response");
+  }
+
+  #[test]
+  fn test_legacy_query() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    let (query, shared_secret) = pack_legacy_query(b"coin coin", &server_public_key, Some(&keypair)).unwrap();
+    assert_eq!(query_key_id(&query), None);
+    let unpacked_query = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.version == Version::Duck);
+    assert!(unpacked_query.mode == Mode::SignedQuery);
+    assert_eq!(unpacked_query.payload, b"coin coin");
+    assert!(unpacked_query.signature.unwrap().verify(&keypair.public).is_ok());
+
+    let response = pack_response(b"couac", &unpacked_query.shared_secret, Version::Duck, PaddingPolicy::default()).unwrap();
+    assert!(packet_mode(&response) == Mode::Response);
+    assert_eq!(unpack_response(&response, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(), b"couac");
+  }
+
+  #[test]
+  fn test_rewrapped_signature() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    for version in &[Version::Duck, Version::Swan] {
+      let (query, _) =
+        pack_query(b"coin coin", &server_public_key, None, Some(&keypair), *version, Codec::default(), &mut rand::thread_rng()).unwrap();
+      let signature = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH)
+        .unwrap()
+        .signature
+        .unwrap();
+      let signature_nounce_and_signature_bytes =
+        [&signature.nounce[..], &signature.signature.to_bytes()[..]].concat();
+
+      // Someone knowing the signed payload re-wraps it under another ephemeral key
+      let another_client_secret = key_utils::gen_private_key();
+      let another_client_public_key = key_utils::gen_public_key(&another_client_secret);
+      let shared_secret = SharedSecret::from(
+        another_client_secret
+          .as_diffie_hellman(&server_public_key)
+          .unwrap(),
+      );
+      let key_id = match version {
+        Version::Duck => vec![],
+        _ => key_utils::key_id(&server_public_key).to_vec(),
+      };
+      let header = packet_header(
+        *version,
+        &Mode::SignedQuery,
+        &[&key_id[..], another_client_public_key.as_bytes()].concat(),
+      );
+      let associated_data = [&header[..], server_public_key.as_bytes()].concat();
+      let rewrapped_query = pack(
+        b"coin coin",
+        &Mode::SignedQuery,
+        &header,
+        &associated_data,
+        &shared_secret,
+        &signature_nounce_and_signature_bytes,
+        PaddingPolicy::default(),
+        Codec::default(),
+        &mut rand::thread_rng(),
+      )
+      .unwrap();
+
+      let rewrapped_signature = unpack_query(&rewrapped_query, &server_private_key, MAX_PAYLOAD_LENGTH)
+        .unwrap()
+        .signature
+        .unwrap();
+      let verified = rewrapped_signature.verify(&keypair.public).is_ok();
+      match version {
+        // The reason of the swan version
+        Version::Duck => assert!(verified),
+        Version::Swan | Version::Owl | Version::Flamingo => assert!(!verified),
+      }
+    }
+  }
+
+  #[test]
+  fn test_hybrid() {
+    let keyring = gen_hybrid_keyring();
+    let server_public_key = key_utils::gen_public_key(&keyring.private_key);
+    let server_mlkem_public_key = keyring.mlkem_private_key.encapsulation_key();
+    let keypair = key_utils::gen_ed25519_keypair();
+
+    let (query, shared_secret) = pack_hybrid_signed_query(
+      b"coin coin",
+      &server_public_key,
+      server_mlkem_public_key,
+      &keypair,
+      Codec::default(),
+    )
+    .unwrap();
+    assert!(Version::from_packet(&query) == Some(Version::Owl));
+    assert_eq!(query_key_id(&query), Some(key_utils::key_id(&server_public_key)));
+
+    let unpacked_query = unpack_query(&query, &keyring, MAX_PAYLOAD_LENGTH).unwrap();
+    assert_eq!(unpacked_query.payload, b"coin coin");
+    assert!(unpacked_query.signature.as_ref().unwrap().verify(&keypair.public).is_ok());
+    assert_eq!(unpacked_query.shared_secret.as_bytes(), shared_secret.as_bytes());
+    assert_eq!(
+      shared_secret.as_bytes().len(),
+      CLIENT_PUBLIC_KEY_LENGTH + mlkem::SHARED_SECRET_LENGTH
+    );
+
+    // The server needs its ML-KEM key
+    assert!(matches!(
+      unpack_query(&query, &keyring.private_key, MAX_PAYLOAD_LENGTH),
+      Err(ChatrouilleError::MissingMlKemKey)
+    ));
+
+    // The ML-KEM ciphertext is authenticated
+    let mut modified_query = query.clone();
+    modified_query[PACKET_VERSION_LENGTH + MODE_LENGTH + KEY_ID_LENGTH + CLIENT_PUBLIC_KEY_LENGTH + 42] ^= 1;
+    assert!(unpack_query(&modified_query, &keyring, MAX_PAYLOAD_LENGTH).is_err());
+    assert!(unpack_query(&query[..query.len() - 1200], &keyring, MAX_PAYLOAD_LENGTH).is_err());
+
+    // So is the server ML-KEM public key
+    let another_keyring = HybridKeyring {
+      private_key: x448::Secret::from_bytes(keyring.private_key.as_bytes()).unwrap(),
+      mlkem_private_key: key_utils::gen_mlkem_private_key(),
+    };
+    assert!(unpack_query(&query, &another_keyring, MAX_PAYLOAD_LENGTH).is_err());
+
+    let (unsigned_query, unsigned_shared_secret) =
+      pack_hybrid_unsigned_query(b"hello?", &server_public_key, server_mlkem_public_key, Codec::None).unwrap();
+    let unpacked_query = unpack_query(&unsigned_query, &keyring, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.signature.is_none());
+    let response = pack_response_with_codec(
+      b"couac",
+      &unpacked_query.shared_secret,
+      unpacked_query.version,
+      PaddingPolicy::default(),
+      unpacked_query.codec,
+    )
+    .unwrap();
+    assert!(Version::from_packet(&response) == Some(Version::Owl));
+    assert_eq!(unpack_response(&response, &unsigned_shared_secret, MAX_PAYLOAD_LENGTH).unwrap(), b"couac");
+    assert!(unpack_response(&response, &shared_secret, MAX_PAYLOAD_LENGTH).is_err());
+
+    // The owl version can't be packed without the ML-KEM key
+    assert!(matches!(
+      pack_query(b"coin coin", &server_public_key, None, None, Version::Owl, Codec::default(), &mut rand::thread_rng()),
+      Err(ChatrouilleError::MissingMlKemKey)
+    ));
+  }
+
+  #[test]
+  fn test_hpke() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let keypair = key_utils::gen_ed25519_keypair();
+    let client_secret = key_utils::gen_private_key();
+    let client_public_key = key_utils::gen_public_key(&client_secret);
+
+    let (query, shared_secret) =
+      pack_hpke_unsigned_query(b"coin coin", &server_public_key, Codec::default()).unwrap();
+    assert!(Version::from_packet(&query) == Some(Version::Flamingo));
+    assert!(packet_mode(&query) == Mode::Query);
+    assert_eq!(query_key_id(&query), Some(key_utils::key_id(&server_public_key)));
+    let unpacked_query = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert_eq!(unpacked_query.payload, b"coin coin");
+    assert!(unpacked_query.signature.is_none());
+    assert!(unpacked_query.sender_public_key.is_none());
+    assert_eq!(unpacked_query.shared_secret.as_bytes(), shared_secret.as_bytes());
+    assert_eq!(shared_secret.as_bytes().len(), HPKE_RESPONSE_SECRET_LENGTH);
+
+    let response = pack_response_with_codec(
+      b"couac",
+      &unpacked_query.shared_secret,
+      unpacked_query.version,
+      PaddingPolicy::default(),
+      unpacked_query.codec,
+    )
+    .unwrap();
+    assert!(Version::from_packet(&response) == Some(Version::Flamingo));
+    assert_eq!(unpack_response(&response, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(), b"couac");
+
+    let (signed_query, _) =
+      pack_hpke_signed_query(b"coin coin", &server_public_key, &keypair, Codec::default()).unwrap();
+    assert!(packet_mode(&signed_query) == Mode::SignedQuery);
+    let unpacked_query = unpack_query(&signed_query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.signature.unwrap().verify(&keypair.public).is_ok());
+
+    let (auth_query, auth_shared_secret) =
+      pack_hpke_auth_query(b"coin coin", &server_public_key, &client_secret, Codec::default()).unwrap();
+    assert!(packet_mode(&auth_query) == Mode::AuthQuery);
+    let unpacked_query = unpack_query(&auth_query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert_eq!(unpacked_query.payload, b"coin coin");
+    assert!(unpacked_query.mode == Mode::AuthQuery);
+    assert_eq!(
+      unpacked_query.sender_public_key.unwrap().as_bytes()[..],
+      client_public_key.as_bytes()[..]
+    );
+    assert_eq!(unpacked_query.shared_secret.as_bytes(), auth_shared_secret.as_bytes());
+
+    // The client public key is authenticated by the auth mode
+    let another_client_public_key = key_utils::gen_public_key(&key_utils::gen_private_key());
+    let sender_public_key_offset = PACKET_VERSION_LENGTH + MODE_LENGTH + KEY_ID_LENGTH + HPKE_ENCAPSULATED_KEY_LENGTH;
+    let mut query_with_another_sender = auth_query.clone();
+    query_with_another_sender[sender_public_key_offset..sender_public_key_offset + CLIENT_PUBLIC_KEY_LENGTH]
+      .copy_from_slice(another_client_public_key.as_bytes());
+    assert!(matches!(
+      unpack_query(&query_with_another_sender, &server_private_key, MAX_PAYLOAD_LENGTH),
+      Err(ChatrouilleError::HpkeError { .. })
+    ));
+
+    // An auth query can't be downgraded to the base mode
+    let mut query_with_another_mode = auth_query.clone();
+    query_with_another_mode[PACKET_VERSION_LENGTH] = Mode::Query as u8;
+    assert!(unpack_query(&query_with_another_mode, &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
+
+    // The auth mode only exists in the flamingo version
+    let mut query_with_another_version = auth_query.clone();
+    query_with_another_version[0..PACKET_VERSION_LENGTH].copy_from_slice(Version::Swan.prefix());
+    assert!(matches!(
+      unpack_query(&query_with_another_version, &server_private_key, MAX_PAYLOAD_LENGTH),
+      Err(ChatrouilleError::InvalidModeInData)
+    ));
+
+    // Another server key
+    let another_server_private_key = key_utils::gen_private_key();
+    assert!(unpack_query(&query, &another_server_private_key, MAX_PAYLOAD_LENGTH).is_err());
+    assert!(unpack_query(&auth_query[..100], &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
+
+    assert!(matches!(
+      pack_hpke_query(
+        b"coin coin",
+        &server_public_key,
+        Some(&keypair),
+        Some(&client_secret),
+        Codec::default(),
+        &mut rand::thread_rng()
+      ),
+      Err(ChatrouilleError::InvalidMode)
+    ));
+  }
+
+  #[test]
+  fn test_error() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let server_keypair = key_utils::gen_ed25519_keypair();
+
+    let (query, shared_secret) = pack_unsigned_query(b"hello?", &server_public_key).unwrap();
+    let unpacked_query = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+
+    let error = pack_error(
+      410,
+      "The request has expired",
+      &unpacked_query.shared_secret,
+      unpacked_query.version,
+    )
+    .unwrap();
+    assert!(packet_mode(&error) == Mode::Error);
+
+    match unpack_response(&error, &shared_secret, MAX_PAYLOAD_LENGTH) {
+      Err(ChatrouilleError::ServerError { code, message }) => {
+        assert_eq!(code, 410);
+        assert_eq!(message, "The request has expired");
+      }
+      _ => panic!("Expected a server error"),
+    }
+
+    match unpack_signed_response(
+      &error,
+      &shared_secret,
+      &query_hash(b"hello?"),
+      &server_keypair.public,
+      MAX_PAYLOAD_LENGTH,
+    ) {
+      Err(ChatrouilleError::ServerError { code, .. }) => assert_eq!(code, 410),
+      _ => panic!("Expected a server error"),
+    }
+
+    // Only the client can read it
+    let (_, another_shared_secret) = pack_unsigned_query(b"hello?", &server_public_key).unwrap();
+    match unpack_response(&error, &another_shared_secret, MAX_PAYLOAD_LENGTH) {
+      Err(ChatrouilleError::DecryptionError { .. }) => (),
+      _ => panic!("Expected a decryption error"),
+    }
+  }
+
+  #[test]
+  fn test_padding() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+
+    let (query, shared_secret) = pack_unsigned_query(b"short", &server_public_key).unwrap();
+    let (another_query, _) =
+      pack_unsigned_query(b"a bit longer but still short", &server_public_key).unwrap();
+    assert_eq!(query.len(), another_query.len());
+    assert_eq!(unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap().payload, b"short");
+
+    // The fixed size hides the length of the response
+    let short = pack_response(b"Jo", &shared_secret, Version::LATEST, PaddingPolicy::FixedSize(512)).unwrap();
+    let long = pack_response(
+      b"Jean-Baptiste Emmanuel Zorg",
+      &shared_secret,
+      Version::LATEST,
+      PaddingPolicy::FixedSize(512),
+    )
+    .unwrap();
+    assert_eq!(short.len(), long.len());
+    assert_eq!(short.len(), PACKET_VERSION_LENGTH + MODE_LENGTH + NOUNCE_LENGTH + 512 + TAG_LENGTH);
+    assert_eq!(unpack_response(&short, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(), b"Jo");
+
+    // The duck version pads without the length
+    let duck = pack_response(b"Jo", &shared_secret, Version::Duck, PaddingPolicy::FixedMultiple(32)).unwrap();
+    assert_eq!(duck.len(), PACKET_VERSION_LENGTH + MODE_LENGTH + NOUNCE_LENGTH + 32 + TAG_LENGTH);
+    assert_eq!(unpack_response(&duck, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(), b"Jo");
+  }
+
+  #[test]
+  fn test_codecs() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+
+    for codec in &[Codec::None, Codec::Deflate, Codec::Zstd, Codec::ZstdDictionary] {
+      if !codec.is_supported() {
+        assert!(pack_unsigned_query_with_codec(b"{}", &server_public_key, *codec).is_err());
+        continue;
+      }
+      let (query, shared_secret) = pack_unsigned_query_with_codec(b"{}", &server_public_key, *codec).unwrap();
+      let unpacked_query = unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+      assert_eq!(unpacked_query.codec, *codec);
+      assert_eq!(unpacked_query.payload, b"{}");
+
+      let response = pack_response_with_codec(
+        br#"{"data":{"isIdentifierAvailable":true}}"#,
+        &unpacked_query.shared_secret,
+        unpacked_query.version,
+        PaddingPolicy::default(),
+        unpacked_query.codec,
+      )
+      .unwrap();
+      assert_eq!(
+        unpack_response(&response, &shared_secret, MAX_PAYLOAD_LENGTH).unwrap(),
+        &br#"{"data":{"isIdentifierAvailable":true}}"#[..]
+      );
+    }
+
+    let (_, shared_secret) = pack_unsigned_query_with_codec(b"{}", &server_public_key, Codec::None).unwrap();
+    assert!(pack_response_with_codec(b"{}", &shared_secret, Version::Duck, PaddingPolicy::default(), Codec::None).is_err());
+  }
+
+  #[test]
+  fn test_max_payload_length() {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+
+    // Small once compressed, but not once uncompressed
+    let large_payload = vec![b'a'; MAX_PAYLOAD_LENGTH + 1];
+    let (query, shared_secret) =
+      pack_unsigned_query_with_codec(&large_payload, &server_public_key, Codec::Deflate).unwrap();
+    assert!(query.len() < MAX_PAYLOAD_LENGTH);
+    match unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH) {
+      Err(ChatrouilleError::UncompressionError {
+        source: compressor::CompressorError::OutputTooLarge { .. },
+      }) => (),
+      _ => panic!("Expected an output too large error"),
+    }
+    let unpacked_query = unpack_query(&query, &server_private_key, large_payload.len()).unwrap();
+
+    let response = pack_response_with_codec(
+      &large_payload,
+      &unpacked_query.shared_secret,
+      unpacked_query.version,
+      PaddingPolicy::default(),
+      Codec::None,
+    )
+    .unwrap();
+    assert!(unpack_response(&response, &shared_secret, MAX_PAYLOAD_LENGTH).is_err());
+    assert!(unpack_response(&response, &shared_secret, large_payload.len()).is_ok());
+  }
+
+  struct TestKeyring {
+    keys: Vec<x448::Secret>,
+  }
+
+  impl PrivateKeyring for TestKeyring {
+    fn private_key(&self, key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+      self
+        .keys
+        .iter()
+        .find(|key| key_utils::key_id(&key_utils::gen_public_key(key)) == *key_id)
+    }
+
+    fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+      self.keys.iter().collect()
+    }
+  }
+
+  #[test]
+  fn test_keyring() {
+    let keyring = TestKeyring {
+      keys: vec![key_utils::gen_private_key(), key_utils::gen_private_key()],
+    };
+    let old_public_key = key_utils::gen_public_key(&keyring.keys[0]);
+    let new_public_key = key_utils::gen_public_key(&keyring.keys[1]);
+
+    let (old_query, _) = pack_unsigned_query(b"old", &old_public_key).unwrap();
+    let (new_query, _) = pack_unsigned_query(b"new", &new_public_key).unwrap();
+
+    assert_eq!(
+      query_key_id(&old_query),
+      Some(key_utils::key_id(&old_public_key))
+    );
+    assert_eq!(unpack_query(&old_query, &keyring, MAX_PAYLOAD_LENGTH).unwrap().payload, b"old");
+    assert_eq!(unpack_query(&new_query, &keyring, MAX_PAYLOAD_LENGTH).unwrap().payload, b"new");
+
+    let unknown_public_key = key_utils::gen_public_key(&key_utils::gen_private_key());
+    let (unknown_query, _) = pack_unsigned_query(b"unknown", &unknown_public_key).unwrap();
+    assert!(matches!(
+      unpack_query(&unknown_query, &keyring, MAX_PAYLOAD_LENGTH),
+      Err(ChatrouilleError::UnknownServerKey)
+    ));
+
+    // The duck queries don't have a key id, every legacy key is tried
+    let mut rng = rand::thread_rng();
+    let (duck_query, _) =
+      pack_query(b"duck", &new_public_key, None, None, Version::Duck, Codec::default(), &mut rng).unwrap();
+    assert_eq!(query_key_id(&duck_query), None);
+    assert_eq!(unpack_query(&duck_query, &keyring, MAX_PAYLOAD_LENGTH).unwrap().payload, b"duck");
+    let (unknown_duck_query, _) =
+      pack_query(b"duck", &unknown_public_key, None, None, Version::Duck, Codec::default(), &mut rng).unwrap();
+    assert!(matches!(
+      unpack_query(&unknown_duck_query, &keyring, MAX_PAYLOAD_LENGTH),
+      Err(ChatrouilleError::DecryptionError { .. })
+    ));
+  }
+
   #[test]
   fn test_problems() {
     let server_private_key = key_utils::gen_private_key();
@@ -519,39 +3408,284 @@ mod tests {
     pack_unsigned_query(&[], &server_public_key).unwrap();
 
     // Unpacking empty data should not work
-    assert!(unpack_query(&[], &server_private_key).is_err());
+    assert!(unpack_query(&[], &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
 
     // Building a valid query to modify it later
     let (query, shared_secret) = pack_unsigned_query(b"hei", &server_public_key).unwrap();
-    assert!(unpack_query(&query, &server_private_key).is_ok());
+    assert!(unpack_query(&query, &server_private_key, MAX_PAYLOAD_LENGTH).is_ok());
 
     let mut query_with_wrong_version = query.clone();
     query_with_wrong_version[0] = 128;
-    assert!(unpack_query(&query_with_wrong_version, &server_private_key).is_err());
+    assert!(unpack_query(&query_with_wrong_version, &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
 
     let mut query_with_wrong_mode = query.clone();
     query_with_wrong_mode[PACKET_VERSION_LENGTH] = 128;
-    assert!(unpack_query(&query_with_wrong_mode, &server_private_key).is_err());
+    assert!(unpack_query(&query_with_wrong_mode, &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
 
     // Query that is not supposed to be signed
     query_with_wrong_mode[PACKET_VERSION_LENGTH] = Mode::SignedQuery as u8;
-    assert!(unpack_query(&query_with_wrong_mode, &server_private_key).is_err());
+    assert!(unpack_query(&query_with_wrong_mode, &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
 
     // Wrong aead data - invalid tag
     let mut query_with_weird_aead_data = query.clone();
     query_with_weird_aead_data[query.len() - 1] = 128;
-    assert!(unpack_query(&query_with_weird_aead_data, &server_private_key).is_err());
+    assert!(unpack_query(&query_with_weird_aead_data, &server_private_key, MAX_PAYLOAD_LENGTH).is_err());
 
-    assert!(unpack_response(&[], &shared_secret).is_err());
+    assert!(unpack_response(&[], &shared_secret, MAX_PAYLOAD_LENGTH).is_err());
     // Building a valid response to modify it later
-    let response = pack_response(b"hei hei", &shared_secret).unwrap();
+    let response = pack_response(b"hei hei", &shared_secret, Version::LATEST, PaddingPolicy::default()).unwrap();
 
     let mut response_with_wrong_version = response.clone();
     response_with_wrong_version[0] = 128;
-    assert!(unpack_response(&response_with_wrong_version, &shared_secret).is_err());
+    assert!(unpack_response(&response_with_wrong_version, &shared_secret, MAX_PAYLOAD_LENGTH).is_err());
 
     let mut response_with_wrong_mode = response; //.clone();
     response_with_wrong_mode[PACKET_VERSION_LENGTH] = 128;
-    assert!(unpack_response(&response_with_wrong_mode, &shared_secret).is_err());
+    assert!(unpack_response(&response_with_wrong_mode, &shared_secret, MAX_PAYLOAD_LENGTH).is_err());
+  }
+
+  /// Everything is drawn from a seeded rng, so the packets are known answers.
+  /// Set `CHATROUILLE_WRITE_TEST_VECTORS` to regenerate the file after a format change.
+  fn generate_test_vectors() -> String {
+    use rand::SeedableRng;
+
+    let mut rng = rand_chacha::ChaCha20Rng::from_seed([42; 32]);
+    let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::STANDARD_NO_PAD);
+
+    let server_private_key = key_utils::gen_private_key_with_rng(&mut rng);
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let server_keypair = key_utils::gen_ed25519_keypair_with_rng(&mut rng);
+    let client_keypair = key_utils::gen_ed25519_keypair_with_rng(&mut rng);
+
+    let query = b"{\"query\":\"{ coin }\"}";
+    let response = b"{\"data\":{\"coin\":\"couac\"}}";
+
+    let (unsigned_query, unsigned_shared_secret) =
+      pack_unsigned_query_with_rng(query, &server_public_key, Codec::default(), &mut rng).unwrap();
+    let packed_response = pack_response_with_rng(
+      response,
+      &unsigned_shared_secret,
+      Version::LATEST,
+      PaddingPolicy::default(),
+      Codec::default(),
+      &mut rng,
+    )
+    .unwrap();
+    let error = pack_error_with_rng(410, "The request has expired", &unsigned_shared_secret, Version::LATEST, &mut rng)
+      .unwrap();
+
+    let (signed_query, signed_shared_secret) =
+      pack_signed_query_with_rng(query, &server_public_key, &client_keypair, Codec::default(), &mut rng).unwrap();
+    let signed_response = pack_signed_response_with_rng(
+      response,
+      &signed_shared_secret,
+      &query_hash(query),
+      &server_keypair,
+      Version::LATEST,
+      PaddingPolicy::default(),
+      Codec::default(),
+      &mut rng,
+    )
+    .unwrap();
+
+    let server_mlkem_private_key = key_utils::gen_mlkem_private_key_with_rng(&mut rng);
+    let (hybrid_query, hybrid_shared_secret) = pack_hybrid_signed_query_with_rng(
+      query,
+      &server_public_key,
+      server_mlkem_private_key.encapsulation_key(),
+      &client_keypair,
+      Codec::default(),
+      &mut rng,
+    )
+    .unwrap();
+    let hybrid_response = pack_response_with_rng(
+      response,
+      &hybrid_shared_secret,
+      Version::Owl,
+      PaddingPolicy::default(),
+      Codec::default(),
+      &mut rng,
+    )
+    .unwrap();
+
+    let client_secret = key_utils::gen_private_key_with_rng(&mut rng);
+    let (hpke_query, hpke_shared_secret) =
+      pack_hpke_auth_query_with_rng(query, &server_public_key, &client_secret, Codec::default(), &mut rng).unwrap();
+    let hpke_response = pack_response_with_rng(
+      response,
+      &hpke_shared_secret,
+      Version::Flamingo,
+      PaddingPolicy::default(),
+      Codec::default(),
+      &mut rng,
+    )
+    .unwrap();
+
+    [
+      ("server_private_key", encode(server_private_key.as_bytes())),
+      ("server_public_key", encode(server_public_key.as_bytes())),
+      ("server_ed25519_secret_key", encode(server_keypair.secret.as_bytes())),
+      ("client_ed25519_secret_key", encode(client_keypair.secret.as_bytes())),
+      ("query", encode(query)),
+      ("response", encode(response)),
+      ("unsigned_query", encode(&unsigned_query)),
+      ("unsigned_query_response", encode(&packed_response)),
+      ("unsigned_query_error", encode(&error)),
+      ("signed_query", encode(&signed_query)),
+      ("signed_query_signed_response", encode(&signed_response)),
+      ("server_mlkem768_private_key", encode(server_mlkem_private_key.seed())),
+      ("hybrid_signed_query", encode(&hybrid_query)),
+      ("hybrid_signed_query_response", encode(&hybrid_response)),
+      ("client_x448_private_key", encode(client_secret.as_bytes())),
+      ("hpke_auth_query", encode(&hpke_query)),
+      ("hpke_auth_query_response", encode(&hpke_response)),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{}: {}\n", name, value))
+    .collect::<Vec<String>>()
+    .concat()
+  }
+
+  #[test]
+  fn test_vectors() {
+    let vectors = generate_test_vectors();
+    if std::env::var_os("CHATROUILLE_WRITE_TEST_VECTORS").is_some() {
+      std::fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/test_vectors.txt"), &vectors).unwrap();
+    }
+    assert_eq!(vectors, include_str!("../test_vectors.txt"));
+
+    let vector = |name: &str| {
+      let line = vectors.lines().find(|line| line.starts_with(&format!("{}: ", name))).unwrap();
+      base64::decode_config(&line[name.len() + 2..], base64::STANDARD_NO_PAD).unwrap()
+    };
+    let server_private_key = x448::Secret::from_bytes(&vector("server_private_key")).unwrap();
+    let server_keypair = {
+      let secret = ed25519_dalek::SecretKey::from_bytes(&vector("server_ed25519_secret_key")).unwrap();
+      let public = ed25519_dalek::PublicKey::from(&secret);
+      ed25519_dalek::Keypair { secret, public }
+    };
+    let client_public_key = ed25519_dalek::PublicKey::from(
+      &ed25519_dalek::SecretKey::from_bytes(&vector("client_ed25519_secret_key")).unwrap(),
+    );
+
+    let unpacked_query = unpack_query(&vector("unsigned_query"), &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert_eq!(unpacked_query.payload, vector("query"));
+    assert_eq!(
+      unpack_response(&vector("unsigned_query_response"), &unpacked_query.shared_secret, MAX_PAYLOAD_LENGTH).unwrap(),
+      vector("response")
+    );
+    match unpack_response(&vector("unsigned_query_error"), &unpacked_query.shared_secret, MAX_PAYLOAD_LENGTH) {
+      Err(ChatrouilleError::ServerError { code, .. }) => assert_eq!(code, 410),
+      _ => panic!("Expected a server error"),
+    }
+
+    let unpacked_query = unpack_query(&vector("signed_query"), &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.signature.unwrap().verify(&client_public_key).is_ok());
+    let (response, _) = unpack_signed_response(
+      &vector("signed_query_signed_response"),
+      &unpacked_query.shared_secret,
+      &query_hash(&vector("query")),
+      &server_keypair.public,
+      MAX_PAYLOAD_LENGTH,
+    )
+    .unwrap();
+    assert_eq!(response, vector("response"));
+
+    let client_x448_public_key =
+      key_utils::gen_public_key(&x448::Secret::from_bytes(&vector("client_x448_private_key")).unwrap());
+    let unpacked_query = unpack_query(&vector("hpke_auth_query"), &server_private_key, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.version == Version::Flamingo);
+    assert_eq!(
+      unpacked_query.sender_public_key.unwrap().as_bytes()[..],
+      client_x448_public_key.as_bytes()[..]
+    );
+    assert_eq!(
+      unpack_response(&vector("hpke_auth_query_response"), &unpacked_query.shared_secret, MAX_PAYLOAD_LENGTH).unwrap(),
+      vector("response")
+    );
+
+    let keyring = HybridKeyring {
+      private_key: server_private_key,
+      mlkem_private_key: mlkem::DecapsulationKey::from_seed(&vector("server_mlkem768_private_key")).unwrap(),
+    };
+    let unpacked_query = unpack_query(&vector("hybrid_signed_query"), &keyring, MAX_PAYLOAD_LENGTH).unwrap();
+    assert!(unpacked_query.version == Version::Owl);
+    assert!(unpacked_query.signature.unwrap().verify(&client_public_key).is_ok());
+    assert_eq!(
+      unpack_response(&vector("hybrid_signed_query_response"), &unpacked_query.shared_secret, MAX_PAYLOAD_LENGTH)
+        .unwrap(),
+      vector("response")
+    );
+  }
+}
+
+// Run with `cargo test --no-default-features`
+#[allow(clippy::unwrap_used)]
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests {
+  use super::*;
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_query_and_response_without_std() {
+    let mut rng = rand_chacha::ChaCha20Rng::from_seed([42; 32]);
+    let server_private_key = key_utils::gen_private_key_with_rng(&mut rng);
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    assert_eq!(Codec::default(), Codec::Deflate);
+    assert!(Codec::Deflate.is_supported());
+
+    for version in &[Version::Duck, Version::Swan, Version::Owl, Version::Flamingo] {
+      let server_mlkem_private_key = key_utils::gen_mlkem_private_key_with_rng(&mut rng);
+      let (query, shared_secret) = match version {
+        Version::Owl => pack_hybrid_unsigned_query_with_rng(
+          b"coin coin",
+          &server_public_key,
+          server_mlkem_private_key.encapsulation_key(),
+          Codec::default(),
+          &mut rng,
+        ),
+        Version::Flamingo => pack_hpke_unsigned_query_with_rng(b"coin coin", &server_public_key, Codec::default(), &mut rng),
+        Version::Duck => pack_query(b"coin coin", &server_public_key, None, None, Version::Duck, Codec::default(), &mut rng),
+        Version::Swan => pack_unsigned_query_with_rng(b"coin coin", &server_public_key, Codec::default(), &mut rng),
+      }
+      .unwrap();
+
+      let keyring = TestKeyring {
+        private_key: &server_private_key,
+        mlkem_private_key: &server_mlkem_private_key,
+      };
+      let unpacked_query = unpack_query(&query, &keyring, 1024).unwrap();
+      assert_eq!(unpacked_query.payload, b"coin coin");
+
+      let response = pack_response_with_rng(
+        b"pouet",
+        &unpacked_query.shared_secret,
+        *version,
+        PaddingPolicy::default(),
+        Codec::default(),
+        &mut rng,
+      )
+      .unwrap();
+      assert_eq!(unpack_response(&response, &shared_secret, 1024).unwrap(), b"pouet");
+    }
+  }
+
+  struct TestKeyring<'a> {
+    private_key: &'a x448::Secret,
+    mlkem_private_key: &'a mlkem::DecapsulationKey,
+  }
+
+  impl PrivateKeyring for TestKeyring<'_> {
+    fn private_key(&self, _key_id: &key_utils::KeyId) -> Option<&x448::Secret> {
+      Some(self.private_key)
+    }
+
+    fn legacy_private_keys(&self) -> Vec<&x448::Secret> {
+      vec![self.private_key]
+    }
+
+    fn mlkem_private_key(&self, _key_id: &key_utils::KeyId) -> Option<&mlkem::DecapsulationKey> {
+      Some(self.mlkem_private_key)
+    }
   }
 }
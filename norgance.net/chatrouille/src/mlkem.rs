@@ -0,0 +1,276 @@
+/*!
+ * ML-KEM-768 (FIPS 203), the post-quantum key encapsulation of the owl version.
+ *
+ * The lattice arithmetic is done by the `ml-kem` crate, with its constant-time
+ * Barrett reductions and compressions. This module only keeps the chatrouille side:
+ * the lengths, the checks of the encoded keys, and the storage of the private key.
+ *
+ * The decapsulation key is stored as its 64 bytes seed (d and z),
+ * the expanded form is recomputed when the key is loaded.
+ * Both are wiped when the key is dropped.
+ */
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use ml_kem::array::Array;
+use ml_kem::kem::{Decapsulate, DecapsulationKey as MlKemDecapsulationKey, EncapsulationKey as MlKemEncapsulationKey};
+use ml_kem::{EncapsulateDeterministic, EncodedSizeUser, KemCore, MlKem768, MlKem768Params};
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+const SYMMETRIC_LENGTH: usize = 32;
+pub const SEED_LENGTH: usize = 2 * SYMMETRIC_LENGTH;
+pub const PUBLIC_KEY_LENGTH: usize = 1184;
+pub const CIPHERTEXT_LENGTH: usize = 1088;
+pub const SHARED_SECRET_LENGTH: usize = 32;
+
+/// The public part, sent to the clients.
+#[derive(Clone)]
+pub struct EncapsulationKey {
+  bytes: Vec<u8>,
+  key: MlKemEncapsulationKey<MlKem768Params>,
+}
+
+impl EncapsulationKey {
+  /// Returns None if the length is wrong or a coefficient isn't reduced modulo q.
+  #[must_use]
+  pub fn from_bytes(bytes: &[u8]) -> Option<EncapsulationKey> {
+    let encoded = Array::try_from(bytes).ok()?;
+    let key = MlKemEncapsulationKey::<MlKem768Params>::from_bytes(&encoded);
+    // The decoding reduces the coefficients modulo q, the modulus check of FIPS 203
+    if key.as_bytes()[..] != bytes[..] {
+      return None;
+    }
+    Some(EncapsulationKey {
+      bytes: bytes.to_vec(),
+      key,
+    })
+  }
+
+  fn from_key(key: MlKemEncapsulationKey<MlKem768Params>) -> EncapsulationKey {
+    EncapsulationKey {
+      bytes: key.as_bytes().to_vec(),
+      key,
+    }
+  }
+
+  #[must_use]
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  /// Returns the ciphertext and the shared secret.
+  pub fn encapsulate_with_rng<R: RngCore + CryptoRng>(
+    &self,
+    rng: &mut R,
+  ) -> (Vec<u8>, [u8; SHARED_SECRET_LENGTH]) {
+    let mut message = [0_u8; SYMMETRIC_LENGTH];
+    rng.fill_bytes(&mut message);
+    let result = self.encapsulate_with_message(&message);
+    message.zeroize();
+    result
+  }
+
+  fn encapsulate_with_message(&self, message: &[u8; SYMMETRIC_LENGTH]) -> (Vec<u8>, [u8; SHARED_SECRET_LENGTH]) {
+    let mut message = Array::from(*message);
+    // The encapsulation can't fail, the error type is only there for the kem traits
+    let (ciphertext, mut shared_secret) = match self.key.encapsulate_deterministic(&message) {
+      Ok(encapsulated) => encapsulated,
+      Err(()) => unreachable!(),
+    };
+    message.zeroize();
+    let mut result = [0_u8; SHARED_SECRET_LENGTH];
+    result.copy_from_slice(&shared_secret);
+    shared_secret.zeroize();
+    (ciphertext.to_vec(), result)
+  }
+}
+
+/// The private part, kept by the server.
+pub struct DecapsulationKey {
+  seed: [u8; SEED_LENGTH],
+  key: MlKemDecapsulationKey<MlKem768Params>,
+  encapsulation_key: EncapsulationKey,
+}
+
+impl DecapsulationKey {
+  pub fn generate_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> DecapsulationKey {
+    let mut seed = [0_u8; SEED_LENGTH];
+    rng.fill_bytes(&mut seed);
+    let key = DecapsulationKey::from_seed_bytes(&seed);
+    seed.zeroize();
+    key
+  }
+
+  /// Returns None if the seed isn't 64 bytes long.
+  #[must_use]
+  pub fn from_seed(seed: &[u8]) -> Option<DecapsulationKey> {
+    if seed.len() != SEED_LENGTH {
+      return None;
+    }
+    let mut seed_bytes = [0_u8; SEED_LENGTH];
+    seed_bytes.copy_from_slice(seed);
+    let key = DecapsulationKey::from_seed_bytes(&seed_bytes);
+    seed_bytes.zeroize();
+    Some(key)
+  }
+
+  fn from_seed_bytes(seed: &[u8; SEED_LENGTH]) -> DecapsulationKey {
+    let mut d = Array::try_from(&seed[..SYMMETRIC_LENGTH]).unwrap_or_default();
+    let mut z = Array::try_from(&seed[SYMMETRIC_LENGTH..]).unwrap_or_default();
+    let (key, encapsulation_key) = MlKem768::generate_deterministic(&d, &z);
+    d.zeroize();
+    z.zeroize();
+    DecapsulationKey {
+      seed: *seed,
+      key,
+      encapsulation_key: EncapsulationKey::from_key(encapsulation_key),
+    }
+  }
+
+  /// The seed is the serialised form of the key.
+  #[must_use]
+  pub fn seed(&self) -> &[u8] {
+    &self.seed
+  }
+
+  #[must_use]
+  pub fn encapsulation_key(&self) -> &EncapsulationKey {
+    &self.encapsulation_key
+  }
+
+  /// Returns None if the ciphertext length is wrong.
+  ///
+  /// An invalid ciphertext gives a pseudo random shared secret (implicit rejection),
+  /// the decryption of the packet fails later.
+  #[must_use]
+  pub fn decapsulate(&self, ciphertext: &[u8]) -> Option<[u8; SHARED_SECRET_LENGTH]> {
+    let ciphertext = Array::try_from(ciphertext).ok()?;
+    let mut shared_secret = self.key.decapsulate(&ciphertext).ok()?;
+    let mut result = [0_u8; SHARED_SECRET_LENGTH];
+    result.copy_from_slice(&shared_secret);
+    shared_secret.zeroize();
+    Some(result)
+  }
+}
+
+impl Drop for DecapsulationKey {
+  fn drop(&mut self) {
+    // The expanded key is wiped by ml-kem, with its zeroize feature
+    self.seed.zeroize();
+  }
+}
+
+#[allow(clippy::panic, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_encapsulation() {
+    let mut rng = rand::thread_rng();
+    let decapsulation_key = DecapsulationKey::generate_with_rng(&mut rng);
+    let encapsulation_key =
+      EncapsulationKey::from_bytes(decapsulation_key.encapsulation_key().as_bytes()).unwrap();
+    assert_eq!(encapsulation_key.as_bytes().len(), PUBLIC_KEY_LENGTH);
+
+    let (ciphertext, shared_secret) = encapsulation_key.encapsulate_with_rng(&mut rng);
+    assert_eq!(ciphertext.len(), CIPHERTEXT_LENGTH);
+    assert_eq!(decapsulation_key.decapsulate(&ciphertext).unwrap(), shared_secret);
+
+    // Implicit rejection
+    let mut wrong_ciphertext = ciphertext.clone();
+    wrong_ciphertext[42] ^= 1;
+    let rejection_secret = decapsulation_key.decapsulate(&wrong_ciphertext).unwrap();
+    assert_ne!(rejection_secret, shared_secret);
+    assert_eq!(decapsulation_key.decapsulate(&wrong_ciphertext).unwrap(), rejection_secret);
+    assert!(decapsulation_key.decapsulate(&ciphertext[1..]).is_none());
+
+    // The seed is enough to restore the key
+    let restored_key = DecapsulationKey::from_seed(decapsulation_key.seed()).unwrap();
+    assert_eq!(restored_key.decapsulate(&ciphertext).unwrap(), shared_secret);
+    assert!(DecapsulationKey::from_seed(&[0; 32]).is_none());
+  }
+
+  #[test]
+  fn test_encapsulation_key_checks() {
+    let decapsulation_key = DecapsulationKey::from_seed(&[1; SEED_LENGTH]).unwrap();
+    let bytes = decapsulation_key.encapsulation_key().as_bytes();
+    assert!(EncapsulationKey::from_bytes(&bytes[1..]).is_none());
+
+    // The first coefficient is 4095, larger than q
+    let mut not_reduced = bytes.to_vec();
+    not_reduced[0] = 0xff;
+    not_reduced[1] |= 0x0f;
+    assert!(EncapsulationKey::from_bytes(&not_reduced).is_none());
+  }
+
+  fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  fn check_vector(vector: &HashMap<&str, &str>) {
+    let field = |name: &str| from_hex(vector[name]);
+    match vector["function"] {
+      "keyGen" => {
+        let seed = [field("d"), field("z")].concat();
+        let decapsulation_key = DecapsulationKey::from_seed(&seed).unwrap();
+        assert_eq!(decapsulation_key.encapsulation_key().as_bytes(), &field("ek")[..]);
+        assert_eq!(decapsulation_key.key.as_bytes()[..], field("dk")[..]);
+      }
+      "encapsulation" => {
+        let encapsulation_key = EncapsulationKey::from_bytes(&field("ek")).unwrap();
+        let mut message = [0_u8; SYMMETRIC_LENGTH];
+        message.copy_from_slice(&field("m"));
+        let (ciphertext, shared_secret) = encapsulation_key.encapsulate_with_message(&message);
+        assert_eq!(ciphertext, field("c"));
+        assert_eq!(shared_secret[..], field("k")[..]);
+      }
+      "decapsulation" => {
+        // The vectors give the expanded key, without its seed
+        let key = MlKemDecapsulationKey::<MlKem768Params>::from_bytes(&Array::try_from(&field("dk")[..]).unwrap());
+        let shared_secret = key.decapsulate(&Array::try_from(&field("c")[..]).unwrap()).unwrap();
+        assert_eq!(shared_secret[..], field("k")[..]);
+      }
+      function => panic!("Unknown function {}", function),
+    }
+  }
+
+  #[test]
+  fn test_vectors() {
+    let mut checked_functions = vec![];
+    for block in include_str!("../mlkem_test_vectors.txt").split("\n\n") {
+      let vector: HashMap<&str, &str> = block
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+          let (name, value) = line.split_at(line.find(": ").unwrap());
+          (name, &value[2..])
+        })
+        .collect();
+      if vector.is_empty() {
+        continue;
+      }
+      check_vector(&vector);
+      checked_functions.push(vector["function"]);
+    }
+    assert_eq!(
+      checked_functions,
+      vec![
+        "keyGen",
+        "keyGen",
+        "keyGen",
+        "encapsulation",
+        "encapsulation",
+        "encapsulation",
+        "decapsulation",
+        "decapsulation",
+        "decapsulation",
+        "decapsulation"
+      ]
+    );
+  }
+}
@@ -0,0 +1,329 @@
+/*!
+ * Multi recipient chatrouille messages, to encrypt one payload once for several x448 keys,
+ * such as several Norgance server keys or the keys of several citizens.
+ *
+ * message: [swan emoji (4 bytes)][mode MultiRecipient (1 byte)][ephemeral x448 public key (56 bytes)][recipients count (2 bytes, big endian)][recipients][nounce (24 bytes)][payload encrypted using xchacha20poly1305 (n bytes)][tag (16 bytes)]
+ * recipient: [hint (8 bytes)][nounce (24 bytes)][content key encrypted using xchacha20poly1305 (32 bytes)][tag (16 bytes)]
+ *
+ * The payload is compressed and padded like in the swan version, and encrypted once
+ * with a random content key. The content key is wrapped for each recipient with a key
+ * derived from the x448 exchange between the ephemeral key and the recipient key.
+ *
+ * The hint lets a recipient find its entry without trying all of them. It's derived
+ * from the same exchange, so it's different in every message and only the sender
+ * and the recipient can link it to the recipient key, unlike the server key id of the queries.
+ *
+ * The whole header, including every recipient, is authenticated with the payload.
+ * Nothing authenticates the sender: every recipient knows the content key,
+ * and can forge a message for the other recipients.
+ *
+ * The ephemeral key, the content key and the exchanged secrets are wiped after use.
+ */
+use alloc::vec::Vec;
+use crate::{
+  compressor::Codec, decompress, open_with_associated_data, pack_payload, packet_header, padding::PaddingPolicy,
+  seal_with_associated_data, ChatrouilleError, DecryptionError, EncryptionError, KeyDerivationError, Mode, Result,
+  Version, CLIENT_PUBLIC_KEY_LENGTH, MODE_LENGTH, NOUNCE_LENGTH, PACKET_VERSION_LENGTH, TAG_LENGTH, x448,
+};
+use orion::hazardous::stream::chacha20;
+use rand::{CryptoRng, RngCore};
+use snafu::ResultExt;
+use zeroize::Zeroizing;
+
+/// Messages with more recipients are refused by the packer and the unpacker.
+pub const MAX_RECIPIENTS: usize = 256;
+
+pub(crate) const RECIPIENTS_COUNT_LENGTH: usize = 2;
+const HINT_LENGTH: usize = 8;
+const CONTENT_KEY_LENGTH: usize = 32;
+pub(crate) const RECIPIENT_LENGTH: usize = HINT_LENGTH + NOUNCE_LENGTH + CONTENT_KEY_LENGTH + TAG_LENGTH;
+const RECIPIENTS_OFFSET: usize =
+  PACKET_VERSION_LENGTH + MODE_LENGTH + CLIENT_PUBLIC_KEY_LENGTH + RECIPIENTS_COUNT_LENGTH;
+// Fifth French republic
+const HINT_BLAKE2B_HASH_SALT: &[u8; 16] = b"chatrouille-1958";
+
+/// The x448 shared secret of `secret` and `public_key`, followed by both public keys of the exchange.
+fn recipient_secret(
+  secret: &x448::Secret,
+  public_key: &x448::PublicKey,
+  ephemeral_public_key: &x448::PublicKey,
+  recipient_public_key: &x448::PublicKey,
+) -> Result<Zeroizing<Vec<u8>>> {
+  let mut shared_secret = match secret.as_diffie_hellman(public_key) {
+    Some(shared_secret) => shared_secret,
+    None => return Err(ChatrouilleError::DiffieHellmanFail),
+  };
+  let recipient_secret = [
+    &shared_secret.as_bytes()[..],
+    ephemeral_public_key.as_bytes(),
+    recipient_public_key.as_bytes(),
+  ]
+  .concat();
+  crate::key_utils::wipe_x448_shared_secret(&mut shared_secret);
+  Ok(Zeroizing::new(recipient_secret))
+}
+
+fn recipient_hint(recipient_secret: &[u8]) -> Vec<u8> {
+  blake2_rfc::blake2b::blake2b(HINT_LENGTH, HINT_BLAKE2B_HASH_SALT, recipient_secret)
+    .as_bytes()
+    .to_vec()
+}
+
+fn wrapping_key(recipient_secret: &[u8]) -> Result<chacha20::SecretKey> {
+  let key_bytes = blake2_rfc::blake2b::blake2b(
+    CONTENT_KEY_LENGTH,
+    &Mode::MultiRecipient.to_symmetric_key_salt(),
+    recipient_secret,
+  );
+  chacha20::SecretKey::from_slice(key_bytes.as_bytes()).context(KeyDerivationError)
+}
+
+#[cfg(feature = "std")]
+pub fn pack_multi_recipient(
+  data: &[u8],
+  recipient_public_keys: &[x448::PublicKey],
+  padding: PaddingPolicy,
+  codec: Codec,
+) -> Result<Vec<u8>> {
+  pack_multi_recipient_with_rng(data, recipient_public_keys, padding, codec, &mut rand::thread_rng())
+}
+
+/// Same as `pack_multi_recipient`, the ephemeral key, the content key and the nounces come from the rng.
+pub fn pack_multi_recipient_with_rng<R: RngCore + CryptoRng>(
+  data: &[u8],
+  recipient_public_keys: &[x448::PublicKey],
+  padding: PaddingPolicy,
+  codec: Codec,
+  rng: &mut R,
+) -> Result<Vec<u8>> {
+  use core::convert::TryFrom;
+
+  let recipients_count = match u16::try_from(recipient_public_keys.len()) {
+    Ok(0) => return Err(ChatrouilleError::NoRecipient),
+    Ok(count) if count as usize <= MAX_RECIPIENTS => count,
+    _ => return Err(ChatrouilleError::TooManyRecipients),
+  };
+
+  let ephemeral_secret =
+    crate::key_utils::ZeroizingSecret::from(crate::key_utils::gen_private_key_with_rng(rng));
+  let ephemeral_public_key = x448::PublicKey::from(&*ephemeral_secret);
+  let mut content_key_bytes = Zeroizing::new([0_u8; CONTENT_KEY_LENGTH]);
+  rng.fill_bytes(&mut *content_key_bytes);
+  let content_key = chacha20::SecretKey::from_slice(&*content_key_bytes).context(KeyDerivationError)?;
+
+  let mut header = packet_header(
+    Version::Swan,
+    &Mode::MultiRecipient,
+    &[ephemeral_public_key.as_bytes(), &recipients_count.to_be_bytes()[..]].concat(),
+  );
+  let recipients_prefix_length = header.len();
+  header.reserve(recipient_public_keys.len() * RECIPIENT_LENGTH);
+
+  for recipient_public_key in recipient_public_keys {
+    let secret = recipient_secret(
+      &ephemeral_secret,
+      recipient_public_key,
+      &ephemeral_public_key,
+      recipient_public_key,
+    )?;
+    let associated_data = [&header[..recipients_prefix_length], recipient_public_key.as_bytes()].concat();
+    let mut wrapped_content_key =
+      seal_with_associated_data(&wrapping_key(&secret)?, &*content_key_bytes, &associated_data, rng)
+        .context(EncryptionError)?;
+    header.append(&mut recipient_hint(&secret));
+    header.append(&mut wrapped_content_key);
+  }
+
+  let payload = pack_payload(data, Version::Swan, &Mode::MultiRecipient, &[], padding, codec)?;
+  let mut encrypted = seal_with_associated_data(&content_key, &payload, &header, rng).context(EncryptionError)?;
+
+  let mut packed_data = header;
+  packed_data.append(&mut encrypted);
+  Ok(packed_data)
+}
+
+/// Returns the number of recipients of a message, if its header is valid.
+#[must_use]
+pub fn recipients_count(packed_data: &[u8]) -> Option<usize> {
+  if packed_data.len() < RECIPIENTS_OFFSET
+    || Version::from_packet(packed_data) != Some(Version::Swan)
+    || Mode::from(packed_data[PACKET_VERSION_LENGTH]) != Mode::MultiRecipient
+  {
+    return None;
+  }
+  let count = u16::from_be_bytes([packed_data[RECIPIENTS_OFFSET - 2], packed_data[RECIPIENTS_OFFSET - 1]]) as usize;
+  if count == 0 || count > MAX_RECIPIENTS {
+    return None;
+  }
+  Some(count)
+}
+
+/// The payload can't be larger than `max_payload_length` bytes once uncompressed.
+pub fn unpack_for_recipient(
+  packed_data: &[u8],
+  recipient_secret_key: &x448::Secret,
+  max_payload_length: usize,
+) -> Result<Vec<u8>> {
+  let count = match recipients_count(packed_data) {
+    Some(count) => count,
+    None => return Err(ChatrouilleError::InvalidModeInData),
+  };
+  let header_length = RECIPIENTS_OFFSET + count * RECIPIENT_LENGTH;
+  if packed_data.len() < header_length + NOUNCE_LENGTH + TAG_LENGTH {
+    return Err(ChatrouilleError::NotEnoughData);
+  }
+
+  let ephemeral_public_key_offset = PACKET_VERSION_LENGTH + MODE_LENGTH;
+  let ephemeral_public_key = match x448::PublicKey::from_bytes(
+    &packed_data[ephemeral_public_key_offset..ephemeral_public_key_offset + CLIENT_PUBLIC_KEY_LENGTH],
+  ) {
+    Some(public_key) => public_key,
+    None => return Err(ChatrouilleError::KeyLoadingError),
+  };
+  let recipient_public_key = x448::PublicKey::from(recipient_secret_key);
+  let secret = recipient_secret(
+    recipient_secret_key,
+    &ephemeral_public_key,
+    &ephemeral_public_key,
+    &recipient_public_key,
+  )?;
+  let hint = recipient_hint(&secret);
+  let associated_data = [&packed_data[..RECIPIENTS_OFFSET], recipient_public_key.as_bytes()].concat();
+
+  // The hints may collide, so every matching entry is tried
+  let content_key_bytes = packed_data[RECIPIENTS_OFFSET..header_length]
+    .chunks(RECIPIENT_LENGTH)
+    .filter(|recipient| recipient[..HINT_LENGTH] == hint[..])
+    .find_map(|recipient| {
+      open_with_associated_data(&wrapping_key(&secret).ok()?, &recipient[HINT_LENGTH..], &associated_data).ok()
+    })
+    .map(Zeroizing::new);
+  let content_key = match content_key_bytes {
+    Some(content_key_bytes) => chacha20::SecretKey::from_slice(&content_key_bytes).context(KeyDerivationError)?,
+    None => return Err(ChatrouilleError::NotARecipient),
+  };
+
+  let padded_data = open_with_associated_data(&content_key, &packed_data[header_length..], &packed_data[..header_length])
+    .context(DecryptionError)?;
+  let (raw_data, _) = decompress(Version::Swan, &padded_data, max_payload_length)?;
+  Ok(raw_data)
+}
+
+#[allow(clippy::panic, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+  use crate::key_utils;
+  use core::convert::TryFrom;
+
+  const MAX: usize = 1024 * 1024;
+
+  #[test]
+  fn test_multi_recipient() {
+    let secrets: Vec<x448::Secret> = (0..3).map(|_| key_utils::gen_private_key()).collect();
+    let public_keys: Vec<x448::PublicKey> = secrets.iter().map(key_utils::gen_public_key).collect();
+    let data = b"Liberte, egalite, fraternite";
+
+    let packed_data = pack_multi_recipient(data, &public_keys, PaddingPolicy::default(), Codec::default()).unwrap();
+    assert!(crate::packet_mode(&packed_data) == Mode::MultiRecipient);
+    assert_eq!(recipients_count(&packed_data), Some(3));
+    for secret in &secrets {
+      assert_eq!(unpack_for_recipient(&packed_data, secret, MAX).unwrap(), data);
+    }
+
+    let another_secret = key_utils::gen_private_key();
+    assert!(matches!(
+      unpack_for_recipient(&packed_data, &another_secret, MAX),
+      Err(ChatrouilleError::NotARecipient)
+    ));
+
+    // The hints change in every message, and don't contain the key ids
+    let another_packed_data =
+      pack_multi_recipient(data, &public_keys, PaddingPolicy::default(), Codec::default()).unwrap();
+    let hints = |packed_data: &[u8]| -> Vec<Vec<u8>> {
+      packed_data[RECIPIENTS_OFFSET..RECIPIENTS_OFFSET + 3 * RECIPIENT_LENGTH]
+        .chunks(RECIPIENT_LENGTH)
+        .map(|recipient| recipient[..HINT_LENGTH].to_vec())
+        .collect()
+    };
+    for (hint, another_hint) in hints(&packed_data).iter().zip(hints(&another_packed_data).iter()) {
+      assert_ne!(hint, another_hint);
+    }
+    for public_key in &public_keys {
+      let key_id = key_utils::key_id(public_key);
+      assert!(!packed_data.windows(key_id.len()).any(|window| window == key_id));
+    }
+  }
+
+  #[test]
+  fn test_recipients_bounds() {
+    let secret = key_utils::gen_private_key();
+    let public_key = key_utils::gen_public_key(&secret);
+
+    assert!(matches!(
+      pack_multi_recipient(b"personne", &[], PaddingPolicy::default(), Codec::default()),
+      Err(ChatrouilleError::NoRecipient)
+    ));
+
+    let mut public_keys: Vec<x448::PublicKey> =
+      (1..MAX_RECIPIENTS).map(|_| key_utils::gen_public_key(&key_utils::gen_private_key())).collect();
+    public_keys.push(x448::PublicKey::from_bytes(public_key.as_bytes()).unwrap());
+    let packed_data =
+      pack_multi_recipient(b"tout le monde", &public_keys, PaddingPolicy::default(), Codec::default()).unwrap();
+    assert_eq!(recipients_count(&packed_data), Some(MAX_RECIPIENTS));
+    assert_eq!(unpack_for_recipient(&packed_data, &secret, MAX).unwrap(), b"tout le monde");
+
+    public_keys.push(key_utils::gen_public_key(&key_utils::gen_private_key()));
+    assert!(matches!(
+      pack_multi_recipient(b"trop de monde", &public_keys, PaddingPolicy::default(), Codec::default()),
+      Err(ChatrouilleError::TooManyRecipients)
+    ));
+
+    // A forged count is refused before reading the recipients
+    let mut packed_data = packed_data;
+    packed_data[RECIPIENTS_OFFSET - 2..RECIPIENTS_OFFSET].copy_from_slice(&u16::try_from(MAX_RECIPIENTS + 1).unwrap().to_be_bytes());
+    assert!(recipients_count(&packed_data).is_none());
+    assert!(unpack_for_recipient(&packed_data, &secret, MAX).is_err());
+  }
+
+  #[test]
+  fn test_multi_recipient_problems() {
+    let secrets: Vec<x448::Secret> = (0..2).map(|_| key_utils::gen_private_key()).collect();
+    let public_keys: Vec<x448::PublicKey> = secrets.iter().map(key_utils::gen_public_key).collect();
+    let packed_data =
+      pack_multi_recipient(b"coin coin", &public_keys, PaddingPolicy::default(), Codec::default()).unwrap();
+
+    // Removing a recipient breaks the payload authentication
+    let mut without_second_recipient = packed_data[..RECIPIENTS_OFFSET + RECIPIENT_LENGTH].to_vec();
+    without_second_recipient[RECIPIENTS_OFFSET - 2..RECIPIENTS_OFFSET].copy_from_slice(&1_u16.to_be_bytes());
+    without_second_recipient.extend(&packed_data[RECIPIENTS_OFFSET + 2 * RECIPIENT_LENGTH..]);
+    assert!(unpack_for_recipient(&without_second_recipient, &secrets[0], MAX).is_err());
+
+    // A modified entry breaks the decryption for every recipient
+    let mut modified = packed_data.clone();
+    modified[RECIPIENTS_OFFSET + HINT_LENGTH + 30] ^= 1;
+    assert!(matches!(
+      unpack_for_recipient(&modified, &secrets[0], MAX),
+      Err(ChatrouilleError::NotARecipient)
+    ));
+    assert!(matches!(
+      unpack_for_recipient(&modified, &secrets[1], MAX),
+      Err(ChatrouilleError::DecryptionError { .. })
+    ));
+
+    let mut modified = packed_data.clone();
+    let last = modified.len() - 1;
+    modified[last] ^= 1;
+    assert!(unpack_for_recipient(&modified, &secrets[1], MAX).is_err());
+
+    assert!(unpack_for_recipient(&packed_data[..RECIPIENTS_OFFSET + 10], &secrets[0], MAX).is_err());
+    assert!(unpack_for_recipient(&[], &secrets[0], MAX).is_err());
+
+    let mut another_mode = packed_data;
+    another_mode[PACKET_VERSION_LENGTH] = Mode::Query as u8;
+    assert!(matches!(
+      unpack_for_recipient(&another_mode, &secrets[0], MAX),
+      Err(ChatrouilleError::InvalidModeInData)
+    ));
+  }
+}
@@ -0,0 +1,174 @@
+use alloc::vec::Vec;
+use snafu::Snafu;
+
+/**
+ * Padding hides the precise size of the messages.
+ *
+ * The padded format is: [data length (4 bytes, big endian)][data (n bytes)][zeros]
+ *
+ * The length is part of the encrypted plaintext, so it's authenticated with the rest
+ * of the message, and the data can be anything, not only something ignoring trailing zeros.
+ */
+
+#[derive(Debug, Snafu)]
+pub enum PaddingError {
+  #[snafu(display("The padded data is too short"))]
+  NotEnoughData,
+
+  #[snafu(display("The data length is larger than the padded data"))]
+  InvalidLength,
+
+  #[snafu(display("The padding is not only zeros"))]
+  InvalidPadding,
+
+  #[snafu(display("The data is too large to be padded"))]
+  DataTooLarge,
+}
+
+pub type Result<T, E = PaddingError> = core::result::Result<T, E>;
+
+pub const LENGTH_PREFIX_LENGTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingPolicy {
+  /// Pads to a multiple of the given size. It was the only policy before the swan version, with 32.
+  FixedMultiple(usize),
+
+  /// Pads to the next power of two, and at least to the minimum.
+  /// Leaks only the order of magnitude of the size.
+  PowerOfTwo { minimum: usize },
+
+  /// Pads to the given size, for operations with a known maximum size.
+  /// Larger data is padded to a multiple of this size.
+  FixedSize(usize),
+}
+
+impl Default for PaddingPolicy {
+  fn default() -> Self {
+    PaddingPolicy::PowerOfTwo { minimum: 256 }
+  }
+}
+
+impl PaddingPolicy {
+  /// Returns the length once padded, never smaller than the length.
+  #[must_use]
+  pub fn padded_length(self, length: usize) -> usize {
+    match self {
+      PaddingPolicy::FixedMultiple(0) | PaddingPolicy::FixedSize(0) => length,
+      PaddingPolicy::FixedMultiple(multiple) | PaddingPolicy::FixedSize(multiple) => {
+        let remainder = length % multiple;
+        if length > 0 && remainder == 0 {
+          length
+        } else {
+          length - remainder + multiple
+        }
+      }
+      PaddingPolicy::PowerOfTwo { minimum } => {
+        if length <= minimum {
+          minimum
+        } else {
+          length.checked_next_power_of_two().unwrap_or(length)
+        }
+      }
+    }
+  }
+}
+
+/// Prefixes the data with its length, and pads it according to the policy.
+pub fn pad(data: &[u8], policy: PaddingPolicy) -> Result<Vec<u8>> {
+  let mut padded = Vec::with_capacity(policy.padded_length(LENGTH_PREFIX_LENGTH + data.len()));
+  padded.extend(&[0_u8; LENGTH_PREFIX_LENGTH]);
+  padded.extend(data);
+  pad_in_place(&mut padded, 0, policy)?;
+  Ok(padded)
+}
+
+/// Same as `pad`, for data already in the buffer.
+///
+/// The buffer contains `LENGTH_PREFIX_LENGTH` bytes at the offset, overwritten by the length,
+/// followed by the data until its end.
+pub fn pad_in_place(buffer: &mut Vec<u8>, offset: usize, policy: PaddingPolicy) -> Result<()> {
+  use core::convert::TryFrom;
+
+  let data_offset = offset + LENGTH_PREFIX_LENGTH;
+  if buffer.len() < data_offset {
+    return Err(PaddingError::NotEnoughData);
+  }
+  let length = match u32::try_from(buffer.len() - data_offset) {
+    Ok(length) => length,
+    Err(_) => return Err(PaddingError::DataTooLarge),
+  };
+  let padded_length = policy.padded_length(buffer.len() - offset);
+
+  buffer[offset..data_offset].copy_from_slice(&length.to_be_bytes());
+  buffer.resize(offset + padded_length, 0);
+  Ok(())
+}
+
+/// Returns the data of a padded message.
+pub fn unpad(padded: &[u8]) -> Result<&[u8]> {
+  if padded.len() < LENGTH_PREFIX_LENGTH {
+    return Err(PaddingError::NotEnoughData);
+  }
+  let (length_bytes, rest) = padded.split_at(LENGTH_PREFIX_LENGTH);
+  let length = u32::from_be_bytes([length_bytes[0], length_bytes[1], length_bytes[2], length_bytes[3]]) as usize;
+  if length > rest.len() {
+    return Err(PaddingError::InvalidLength);
+  }
+
+  let (data, padding) = rest.split_at(length);
+  if padding.iter().any(|byte| *byte != 0) {
+    return Err(PaddingError::InvalidPadding);
+  }
+  Ok(data)
+}
+
+#[allow(clippy::panic, clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_padded_length() {
+    assert_eq!(PaddingPolicy::FixedMultiple(32).padded_length(0), 32);
+    assert_eq!(PaddingPolicy::FixedMultiple(32).padded_length(32), 32);
+    assert_eq!(PaddingPolicy::FixedMultiple(32).padded_length(33), 64);
+    assert_eq!(PaddingPolicy::FixedMultiple(0).padded_length(33), 33);
+    assert_eq!(PaddingPolicy::PowerOfTwo { minimum: 256 }.padded_length(12), 256);
+    assert_eq!(PaddingPolicy::PowerOfTwo { minimum: 256 }.padded_length(257), 512);
+    assert_eq!(PaddingPolicy::PowerOfTwo { minimum: 256 }.padded_length(1024), 1024);
+    assert_eq!(PaddingPolicy::FixedSize(4096).padded_length(100), 4096);
+    assert_eq!(PaddingPolicy::FixedSize(4096).padded_length(4097), 8192);
+  }
+
+  #[test]
+  fn test_pad_and_unpad() {
+    let data = b"Perfection is achieved, not when there is nothing more to add, but when there is nothing left to take away.";
+    let padded = pad(data, PaddingPolicy::FixedSize(1000)).unwrap();
+    assert_eq!(padded.len(), 1000);
+    assert_eq!(unpad(&padded).unwrap(), &data[..]);
+
+    // Trailing zeros in the data are kept
+    let padded = pad(&[1, 0, 0], PaddingPolicy::PowerOfTwo { minimum: 16 }).unwrap();
+    assert_eq!(padded.len(), 16);
+    assert_eq!(unpad(&padded).unwrap(), &[1, 0, 0]);
+
+    assert!(unpad(&padded[..6]).is_err());
+    assert!(unpad(&[0, 0]).is_err());
+    let mut not_zeros = padded;
+    not_zeros[12] = 1;
+    assert!(unpad(&not_zeros).is_err());
+  }
+
+  #[test]
+  fn test_pad_in_place() {
+    let mut buffer = vec![42, 42, 0, 0, 0, 0, 1, 2, 3];
+    pad_in_place(&mut buffer, 2, PaddingPolicy::FixedSize(16)).unwrap();
+    assert_eq!(buffer.len(), 18);
+    assert_eq!(&buffer[..2], &[42, 42]);
+    assert_eq!(&buffer[2..], &pad(&[1, 2, 3], PaddingPolicy::FixedSize(16)).unwrap()[..]);
+    assert_eq!(unpad(&buffer[2..]).unwrap(), &[1, 2, 3]);
+
+    assert!(pad_in_place(&mut vec![0, 0], 0, PaddingPolicy::default()).is_err());
+  }
+}
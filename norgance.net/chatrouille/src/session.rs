@@ -0,0 +1,265 @@
+/*!
+ * Chatrouille sessions, to exchange many frames after a single key exchange, such as over a WebSocket.
+ *
+ * The session is opened by a regular query and its response. The client and the server derive
+ * a chain key for each direction from the shared secret of this query.
+ *
+ * frame: [version emoji (4 bytes)][mode SessionFrame (1 byte)][counter (8 bytes, big endian)][data compressed and encrypted using xchacha20poly1305 (n bytes)][tag (16 bytes)]
+ *
+ * Each frame is encrypted with its own key derived from the chain key, and the chain key
+ * is replaced by the next one (a symmetric ratchet), so the keys of a session at some point
+ * don't decrypt the previous frames. Since each key encrypts a single frame, the nounce is the counter.
+ *
+ * The header and the counter are authenticated as associated data. The transport is expected
+ * to be reliable and ordered, so the frames must arrive in order: a missing, replayed or
+ * reordered frame is refused, and the session should be closed.
+ *
+ * The plaintext is the same as in the swan version, the data is compressed with its codec and padded.
+ * The frames have the version of the query opening the session, the duck version can't open a session.
+ */
+use alloc::{vec, vec::Vec};
+use crate::{
+  compressor::Codec, decompress, key_utils, key_utils::SharedSecret, pack_payload, padding::PaddingPolicy,
+  ChatrouilleError, DecryptionError, EncryptionError, KeyDerivationError, Mode, Result, Version, MODE_LENGTH,
+  NOUNCE_LENGTH, PACKET_VERSION_LENGTH, TAG_LENGTH,
+};
+use orion::hazardous::aead::xchacha20poly1305;
+use orion::hazardous::stream::{chacha20, xchacha20};
+use snafu::ResultExt;
+use zeroize::Zeroizing;
+
+const COUNTER_LENGTH: usize = 8;
+const CHAIN_KEY_LENGTH: usize = 32;
+/// Length of the frame header, authenticated as associated data.
+pub const FRAME_HEADER_LENGTH: usize = PACKET_VERSION_LENGTH + MODE_LENGTH + COUNTER_LENGTH;
+// May 68
+const CLIENT_CHAIN_SALT: &[u8; 16] = b"chatrouille-1968";
+// Abolition of the death penalty
+const SERVER_CHAIN_SALT: &[u8; 16] = b"chatrouille-1981";
+// Same constants as the symmetric ratchet of the Signal protocol
+const FRAME_KEY_CONSTANT: &[u8] = &[1];
+const NEXT_CHAIN_KEY_CONSTANT: &[u8] = &[2];
+
+/// The keys of one direction of the session.
+struct Chain {
+  key: Zeroizing<[u8; CHAIN_KEY_LENGTH]>,
+  // Counter of the next frame
+  counter: u64,
+}
+
+impl Chain {
+  fn new(shared_secret: &SharedSecret, salt: &[u8; 16]) -> Result<Chain> {
+    let symmetric_key = key_utils::derive_shared_secret_to_sym_key(shared_secret, salt).context(KeyDerivationError)?;
+    let mut key = Zeroizing::new([0_u8; CHAIN_KEY_LENGTH]);
+    key.copy_from_slice(symmetric_key.unprotected_as_bytes());
+    Ok(Chain { key, counter: 0 })
+  }
+
+  fn frame_key(&self) -> Result<chacha20::SecretKey> {
+    let frame_key = blake2_rfc::blake2b::blake2b(CHAIN_KEY_LENGTH, &self.key[..], FRAME_KEY_CONSTANT);
+    chacha20::SecretKey::from_slice(frame_key.as_bytes()).context(KeyDerivationError)
+  }
+
+  /// Replaces the chain key once the frame is sealed or opened.
+  fn advance(&mut self) -> Result<()> {
+    let next_chain_key = blake2_rfc::blake2b::blake2b(CHAIN_KEY_LENGTH, &self.key[..], NEXT_CHAIN_KEY_CONSTANT);
+    self.key.copy_from_slice(next_chain_key.as_bytes());
+    self.counter = match self.counter.checked_add(1) {
+      Some(counter) => counter,
+      None => return Err(ChatrouilleError::InvalidFrameCounter),
+    };
+    Ok(())
+  }
+}
+
+fn counter_nounce(counter: u64) -> Result<xchacha20::Nonce> {
+  let mut nounce = [0_u8; NOUNCE_LENGTH];
+  nounce[NOUNCE_LENGTH - COUNTER_LENGTH..].copy_from_slice(&counter.to_be_bytes());
+  xchacha20::Nonce::from_slice(&nounce).context(KeyDerivationError)
+}
+
+/// One end of a session, the client or the server.
+pub struct Session {
+  version: Version,
+  sending: Chain,
+  receiving: Chain,
+}
+
+impl Session {
+  /// The session of the client, with the shared secret of the query opening it.
+  pub fn client(shared_secret: &SharedSecret, version: Version) -> Result<Session> {
+    Session::new(shared_secret, version, CLIENT_CHAIN_SALT, SERVER_CHAIN_SALT)
+  }
+
+  /// The session of the server, with the shared secret of the unpacked query opening it.
+  pub fn server(shared_secret: &SharedSecret, version: Version) -> Result<Session> {
+    Session::new(shared_secret, version, SERVER_CHAIN_SALT, CLIENT_CHAIN_SALT)
+  }
+
+  fn new(
+    shared_secret: &SharedSecret,
+    version: Version,
+    sending_salt: &[u8; 16],
+    receiving_salt: &[u8; 16],
+  ) -> Result<Session> {
+    if version == Version::Duck {
+      return Err(ChatrouilleError::UnsupportedSessionVersion);
+    }
+    Ok(Session {
+      version,
+      sending: Chain::new(shared_secret, sending_salt)?,
+      receiving: Chain::new(shared_secret, receiving_salt)?,
+    })
+  }
+
+  #[must_use]
+  pub fn version(&self) -> Version {
+    self.version
+  }
+
+  /// Compresses, pads and encrypts the data in the next frame.
+  pub fn seal(&mut self, data: &[u8], padding: PaddingPolicy, codec: Codec) -> Result<Vec<u8>> {
+    let payload = Zeroizing::new(pack_payload(data, self.version, &Mode::SessionFrame, &[], padding, codec)?);
+    let counter = self.sending.counter;
+
+    let mut frame = vec![0_u8; FRAME_HEADER_LENGTH + payload.len() + TAG_LENGTH];
+    frame[..PACKET_VERSION_LENGTH].copy_from_slice(self.version.prefix());
+    frame[PACKET_VERSION_LENGTH] = Mode::SessionFrame as u8;
+    frame[PACKET_VERSION_LENGTH + MODE_LENGTH..FRAME_HEADER_LENGTH].copy_from_slice(&counter.to_be_bytes());
+
+    let (header, sealed) = frame.split_at_mut(FRAME_HEADER_LENGTH);
+    xchacha20poly1305::seal(
+      &self.sending.frame_key()?,
+      &counter_nounce(counter)?,
+      &payload,
+      Some(header),
+      sealed,
+    )
+    .context(EncryptionError)?;
+    self.sending.advance()?;
+    Ok(frame)
+  }
+
+  /// Decrypts the next frame. The data can't be larger than `max_payload_length` bytes once uncompressed.
+  ///
+  /// The chain moves forward only when the frame is authentic.
+  pub fn open(&mut self, frame: &[u8], max_payload_length: usize) -> Result<Vec<u8>> {
+    if frame.len() <= FRAME_HEADER_LENGTH + TAG_LENGTH {
+      return Err(ChatrouilleError::NotEnoughData);
+    }
+    if Version::from_packet(frame) != Some(self.version) {
+      return Err(ChatrouilleError::InvalidDataPrefix);
+    }
+    if Mode::from(frame[PACKET_VERSION_LENGTH]) != Mode::SessionFrame {
+      return Err(ChatrouilleError::InvalidModeInData);
+    }
+    let mut counter_bytes = [0_u8; COUNTER_LENGTH];
+    counter_bytes.copy_from_slice(&frame[PACKET_VERSION_LENGTH + MODE_LENGTH..FRAME_HEADER_LENGTH]);
+    let counter = u64::from_be_bytes(counter_bytes);
+    if counter != self.receiving.counter {
+      return Err(ChatrouilleError::InvalidFrameCounter);
+    }
+
+    let (header, sealed) = frame.split_at(FRAME_HEADER_LENGTH);
+    let mut padded_data = Zeroizing::new(vec![0_u8; sealed.len() - TAG_LENGTH]);
+    xchacha20poly1305::open(
+      &self.receiving.frame_key()?,
+      &counter_nounce(counter)?,
+      sealed,
+      Some(header),
+      &mut padded_data,
+    )
+    .context(DecryptionError)?;
+    self.receiving.advance()?;
+
+    let (data, _) = decompress(self.version, &padded_data, max_payload_length)?;
+    Ok(data)
+  }
+}
+
+#[allow(clippy::panic, clippy::unwrap_used)]
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use super::*;
+
+  const MAX: usize = 1024 * 1024;
+
+  fn open_sessions(version: Version) -> (Session, Session) {
+    let server_private_key = key_utils::gen_private_key();
+    let server_public_key = key_utils::gen_public_key(&server_private_key);
+    let (query, client_shared_secret) =
+      crate::pack_unsigned_query(b"{\"session\":true}", &server_public_key).unwrap();
+    let unpacked_query = crate::unpack_query(&query, &server_private_key, MAX).unwrap();
+    (
+      Session::client(&client_shared_secret, version).unwrap(),
+      Session::server(&unpacked_query.shared_secret, version).unwrap(),
+    )
+  }
+
+  #[test]
+  fn test_session() {
+    let (mut client, mut server) = open_sessions(Version::Swan);
+
+    for i in 0..5 {
+      let data = format!("Frame {} from the client", i);
+      let frame = client.seal(data.as_bytes(), PaddingPolicy::default(), Codec::default()).unwrap();
+      assert!(crate::packet_mode(&frame) == Mode::SessionFrame);
+      assert_eq!(server.open(&frame, MAX).unwrap(), data.as_bytes());
+
+      let data = format!("Frame {} from the server", i);
+      let frame = server.seal(data.as_bytes(), PaddingPolicy::default(), Codec::None).unwrap();
+      assert_eq!(client.open(&frame, MAX).unwrap(), data.as_bytes());
+    }
+
+    // Every frame has its own key
+    let frame = client.seal(b"coin", PaddingPolicy::default(), Codec::None).unwrap();
+    let another_frame = client.seal(b"coin", PaddingPolicy::default(), Codec::None).unwrap();
+    assert_ne!(frame[FRAME_HEADER_LENGTH..], another_frame[FRAME_HEADER_LENGTH..]);
+    server.open(&frame, MAX).unwrap();
+    server.open(&another_frame, MAX).unwrap();
+
+    // A client can't open its own frames, with fresh sessions whose counters are in step
+    let (mut client, _) = open_sessions(Version::Swan);
+    let frame = client.seal(b"coin", PaddingPolicy::default(), Codec::default()).unwrap();
+    assert!(matches!(client.open(&frame, MAX), Err(ChatrouilleError::DecryptionError { .. })));
+  }
+
+  #[test]
+  fn test_session_order() {
+    let (mut client, mut server) = open_sessions(Version::Owl);
+
+    let first_frame = client.seal(b"first", PaddingPolicy::default(), Codec::default()).unwrap();
+    let second_frame = client.seal(b"second", PaddingPolicy::default(), Codec::default()).unwrap();
+
+    assert!(matches!(server.open(&second_frame, MAX), Err(ChatrouilleError::InvalidFrameCounter)));
+    assert_eq!(server.open(&first_frame, MAX).unwrap(), b"first");
+    assert!(matches!(server.open(&first_frame, MAX), Err(ChatrouilleError::InvalidFrameCounter)));
+
+    // A frame with a rewritten counter doesn't decrypt, and the chain doesn't move
+    let mut forged_frame = first_frame.clone();
+    forged_frame[FRAME_HEADER_LENGTH - 1] = 1;
+    assert!(matches!(server.open(&forged_frame, MAX), Err(ChatrouilleError::DecryptionError { .. })));
+    assert_eq!(server.open(&second_frame, MAX).unwrap(), b"second");
+
+    // The version of the session is authenticated
+    let mut frame = client.seal(b"third", PaddingPolicy::default(), Codec::default()).unwrap();
+    frame[..PACKET_VERSION_LENGTH].copy_from_slice(Version::Swan.prefix());
+    assert!(matches!(server.open(&frame, MAX), Err(ChatrouilleError::InvalidDataPrefix)));
+  }
+
+  #[test]
+  fn test_session_keys() {
+    let (mut client, _) = open_sessions(Version::Swan);
+    let (_, mut another_server) = open_sessions(Version::Swan);
+
+    let frame = client.seal(b"coin", PaddingPolicy::default(), Codec::default()).unwrap();
+    assert!(matches!(another_server.open(&frame, MAX), Err(ChatrouilleError::DecryptionError { .. })));
+
+    let (_, shared_secret) =
+      crate::pack_unsigned_query(b"coin", &key_utils::gen_public_key(&key_utils::gen_private_key())).unwrap();
+    assert!(matches!(
+      Session::client(&shared_secret, Version::Duck),
+      Err(ChatrouilleError::UnsupportedSessionVersion)
+    ));
+  }
+}
@@ -0,0 +1,214 @@
+/*!
+ * X448 (RFC 7748), the key exchange of the packets.
+ *
+ * It keeps the interface of the x448 crate, whose curve implementation links std,
+ * over the Montgomery ladder of ed448-goldilocks-plus, which builds without std.
+ *
+ * Like the x448 crate, the secrets are clamped when they are created
+ * and the public keys of low order are refused.
+ * The secrets and the shared secrets are wiped when they are dropped.
+ */
+use ed448_goldilocks_plus::x448 as curve;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+pub const KEY_LENGTH: usize = curve::X448_LENGTH;
+
+// The points of low order of the curve and its twist: 0, 1 and p - 1
+const LOW_ORDER_POINTS: [[u8; KEY_LENGTH]; 3] = [
+  [0; KEY_LENGTH],
+  [
+    1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  ],
+  [
+    0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+  ],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PublicKey(curve::PublicKey);
+
+/// A private key, clamped.
+pub struct Secret(curve::StaticSecret);
+
+/// The result of the key exchange.
+pub struct SharedSecret(curve::SharedSecret);
+
+impl PublicKey {
+  /// Returns None if the length is wrong or if the point has a low order.
+  #[must_use]
+  pub fn from_bytes(bytes: &[u8]) -> Option<PublicKey> {
+    let public_key = PublicKey::from_bytes_unchecked(bytes)?;
+    if public_key.is_low_order() {
+      return None;
+    }
+    Some(public_key)
+  }
+
+  /// Only checks the length.
+  #[must_use]
+  pub fn from_bytes_unchecked(bytes: &[u8]) -> Option<PublicKey> {
+    if bytes.len() != KEY_LENGTH {
+      return None;
+    }
+    let mut array = [0_u8; KEY_LENGTH];
+    array.copy_from_slice(bytes);
+    Some(PublicKey(curve::PublicKey::from(array)))
+  }
+
+  #[must_use]
+  pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+    self.0.as_bytes()
+  }
+
+  fn is_low_order(&self) -> bool {
+    LOW_ORDER_POINTS
+      .iter()
+      .any(|point| bool::from(self.as_bytes().ct_eq(point)))
+  }
+}
+
+impl From<&Secret> for PublicKey {
+  fn from(secret: &Secret) -> PublicKey {
+    PublicKey(curve::PublicKey::from(&secret.0))
+  }
+}
+
+impl Secret {
+  /// Returns None if the length is wrong.
+  #[must_use]
+  pub fn from_bytes(bytes: &[u8]) -> Option<Secret> {
+    if bytes.len() != KEY_LENGTH {
+      return None;
+    }
+    let mut array = [0_u8; KEY_LENGTH];
+    array.copy_from_slice(bytes);
+    let secret = Secret::from(array);
+    array.zeroize();
+    Some(secret)
+  }
+
+  #[must_use]
+  pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+    self.0.as_bytes()
+  }
+
+  /// Returns None if the public key has a low order,
+  /// or if the shared secret is zero and doesn't depend on the secret.
+  #[must_use]
+  pub fn as_diffie_hellman(&self, public_key: &PublicKey) -> Option<SharedSecret> {
+    if public_key.is_low_order() {
+      return None;
+    }
+    let shared_secret = self.0.diffie_hellman(&public_key.0);
+    if !shared_secret.was_contributory() {
+      return None;
+    }
+    Some(SharedSecret(shared_secret))
+  }
+}
+
+impl From<[u8; KEY_LENGTH]> for Secret {
+  fn from(bytes: [u8; KEY_LENGTH]) -> Secret {
+    let mut bytes = bytes;
+    bytes[0] &= 0xfc;
+    bytes[55] |= 0x80;
+    let secret = Secret(curve::StaticSecret::from(bytes));
+    bytes.zeroize();
+    secret
+  }
+}
+
+impl Zeroize for Secret {
+  fn zeroize(&mut self) {
+    self.0.zeroize();
+  }
+}
+
+impl SharedSecret {
+  #[must_use]
+  pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+    self.0.as_bytes()
+  }
+}
+
+impl Zeroize for SharedSecret {
+  fn zeroize(&mut self) {
+    self.0.zeroize();
+  }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn from_hex(hex: &str) -> [u8; KEY_LENGTH] {
+    let mut bytes = [0_u8; KEY_LENGTH];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    bytes
+  }
+
+  #[test]
+  fn test_rfc7748() {
+    // RFC 7748, section 6.2
+    let alice = Secret::from(from_hex(
+      "9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf574a9419744897391006382a6f127ab1d9ac2d8c0a598726b",
+    ));
+    let bob = Secret::from(from_hex(
+      "1c306a7ac2a0e2e0990b294470cba339e6453772b075811d8fad0d1d6927c120bb5ee8972b0d3e21374c9c921b09d1b0366f10b65173992d",
+    ));
+    let alice_public = PublicKey::from(&alice);
+    let bob_public = PublicKey::from(&bob);
+    assert_eq!(
+      alice_public.as_bytes(),
+      &from_hex("9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bbc836647241d953d40c5b12da88120d53177f80e532c41fa0")
+    );
+    assert_eq!(
+      bob_public.as_bytes(),
+      &from_hex("3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609")
+    );
+
+    let shared_secret = from_hex(
+      "07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56fd2464c335543936521c24403085d59a449a5037514a879d",
+    );
+    assert_eq!(alice.as_diffie_hellman(&bob_public).unwrap().as_bytes(), &shared_secret);
+    assert_eq!(bob.as_diffie_hellman(&alice_public).unwrap().as_bytes(), &shared_secret);
+  }
+
+  #[test]
+  fn test_low_order() {
+    let secret = Secret::from([42; KEY_LENGTH]);
+    for point in &LOW_ORDER_POINTS {
+      assert!(PublicKey::from_bytes(point).is_none());
+      let public_key = PublicKey::from_bytes_unchecked(point).unwrap();
+      assert!(secret.as_diffie_hellman(&public_key).is_none());
+    }
+
+    // p, a non canonical encoding of 0
+    let mut p = LOW_ORDER_POINTS[2];
+    p[0] = 0xff;
+    let public_key = PublicKey::from_bytes(&p).unwrap();
+    assert!(secret.as_diffie_hellman(&public_key).is_none());
+
+    assert!(PublicKey::from_bytes(&[5; 55]).is_none());
+    assert!(Secret::from_bytes(&[5; 57]).is_none());
+  }
+
+  #[test]
+  fn test_clamp_and_zeroize() {
+    let mut secret = Secret::from([0xff; KEY_LENGTH]);
+    assert_eq!(secret.as_bytes()[0], 0xfc);
+    assert_eq!(secret.as_bytes()[55], 0xff);
+    assert_eq!(Secret::from_bytes(&[0; KEY_LENGTH]).unwrap().as_bytes()[55], 0x80);
+
+    secret.zeroize();
+    assert_eq!(secret.as_bytes(), &[0; KEY_LENGTH]);
+  }
+}
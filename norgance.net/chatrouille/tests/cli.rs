@@ -0,0 +1,114 @@
+/*!
+ * Runs the chatrouille command line tool through a query and its response.
+ */
+#![allow(clippy::unwrap_used)]
+
+use chatrouille::{key_utils::SharedSecret, padding::PaddingPolicy, Version};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn chatrouille(args: &[&str], input: &[u8]) -> Output {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_chatrouille"))
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child.stdin.take().unwrap().write_all(input).unwrap();
+  child.wait_with_output().unwrap()
+}
+
+fn success(args: &[&str], input: &[u8]) -> (Vec<u8>, String) {
+  let output = chatrouille(args, input);
+  let stderr = String::from_utf8(output.stderr).unwrap();
+  assert!(output.status.success(), "{:?} failed: {}", args, stderr);
+  (output.stdout, stderr)
+}
+
+// The value of a "name: value" line
+fn value<'a>(lines: &'a str, name: &str) -> &'a str {
+  let prefix = format!("{}: ", name);
+  lines
+    .lines()
+    .find_map(|line| line.strip_prefix(prefix.as_str()))
+    .unwrap_or_else(|| panic!("no {} in {}", name, lines))
+}
+
+#[test]
+fn test_round_trip() {
+  let (keys, _) = success(&["gen-keys"], b"");
+  let keys = String::from_utf8(keys).unwrap();
+  let server_private_key = value(&keys, "server private key");
+  let server_public_key = value(&keys, "server public key");
+  let keypair = value(&keys, "signing keypair");
+  let signing_public_key = value(&keys, "signing public key");
+
+  let payload = br#"{"hello":"world"}"#;
+  let (packet, pack_log) = success(
+    &["pack-query", "--server-public-key", server_public_key, "--keypair", keypair],
+    payload,
+  );
+  let shared_secret = value(&pack_log, "shared secret");
+  let query_hash = value(&pack_log, "query hash");
+
+  let (unpacked, unpack_log) = success(
+    &[
+      "unpack-query",
+      "--server-private-key",
+      server_private_key,
+      "--client-signing-public-key",
+      signing_public_key,
+    ],
+    &packet,
+  );
+  assert_eq!(unpacked, payload);
+  assert_eq!(value(&unpack_log, "shared secret"), shared_secret);
+  assert_eq!(value(&unpack_log, "query hash"), query_hash);
+  assert_eq!(value(&unpack_log, "signature"), "verified");
+
+  // The base64 packet is read from a file too
+  let path = std::env::temp_dir().join(format!("chatrouille-cli-{}.txt", std::process::id()));
+  std::fs::write(&path, &packet).unwrap();
+  let (unpacked, _) = success(
+    &["unpack-query", "--server-private-key", server_private_key, path.to_str().unwrap()],
+    b"",
+  );
+  std::fs::remove_file(&path).unwrap();
+  assert_eq!(unpacked, payload);
+
+  let (layout, _) = success(&["inspect"], &packet);
+  let layout = String::from_utf8(layout).unwrap();
+  assert_eq!(value(&layout, "length"), base64::decode(packet.trim_ascii_end()).unwrap().len().to_string());
+
+  // The response is packed by the library with the shared secret printed by the tool
+  let response = chatrouille::pack_response(
+    b"{}",
+    &SharedSecret::from_bytes(&base64::decode(shared_secret).unwrap()),
+    Version::LATEST,
+    PaddingPolicy::default(),
+  )
+  .unwrap();
+  let (unpacked, _) = success(&["unpack-response", "--shared-secret", shared_secret], &response);
+  assert_eq!(unpacked, b"{}");
+
+  let wrong_secret = base64::encode_config([0_u8; 32], base64::STANDARD_NO_PAD);
+  assert!(!chatrouille(&["unpack-response", "--shared-secret", &wrong_secret], &response)
+    .status
+    .success());
+}
+
+#[test]
+fn test_usage() {
+  let output = chatrouille(&["pack-query", "--keypair"], b"");
+  assert!(!output.status.success());
+  assert!(String::from_utf8(output.stderr)
+    .unwrap()
+    .starts_with("Missing value for --keypair"));
+
+  let output = chatrouille(&["pack-query", "--server-public-key", "AAAA"], b"{}");
+  assert!(!output.status.success());
+  assert!(String::from_utf8(output.stderr)
+    .unwrap()
+    .starts_with("Invalid --server-public-key"));
+}
@@ -22,11 +22,16 @@
 )]
 mod utils;
 
+use chatrouille::compressor::Codec;
+use chatrouille::envelope::Envelope;
+use chatrouille::padding::{self, PaddingPolicy};
+use chatrouille::x448;
 use once_cell::sync::OnceCell;
 use snafu::{ResultExt, Snafu};
 use std::collections::HashMap;
 use std::sync::Mutex;
 use wasm_bindgen::prelude::*;
+use zeroize::Zeroize;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -56,8 +61,16 @@ pub enum NorganceError {
     AccessKeyError,
     Argon2,
     ChatrouilleMissingKeypair,
+    ChatrouilleMissingServerSigningKey,
     ChatrouillePack,
     ChatrouilleUnpack,
+    ChatrouilleResponseSignature,
+    InvalidChatrouilleSession,
+    #[snafu(display("{}", message))]
+    ChatrouilleServerError {
+        code: u16,
+        message: String,
+    },
     Generic,
     HashError {
         source: std::array::TryFromSliceError,
@@ -65,6 +78,7 @@ pub enum NorganceError {
     InvalidEd25519DalekPrivateKey,
     InvalidEd25519DalekPublicKey,
     InvalidUTF8,
+    InvalidJson,
     InvalidX25519DalekPrivateKey,
     InvalidX25519DalekPublicKey,
     InvalidVault,
@@ -84,7 +98,21 @@ pub enum NorganceError {
 
 impl From<NorganceError> for wasm_bindgen::JsValue {
     fn from(err: NorganceError) -> wasm_bindgen::JsValue {
-        JsValue::from_str(&format!("NorganceError: {}", err))
+        match err {
+            // A plain object, so the code survives the worker messages
+            NorganceError::ChatrouilleServerError { code, message } => {
+                let error = js_sys::Object::new();
+                for (key, value) in &[
+                    ("name", JsValue::from_str("ChatrouilleServerError")),
+                    ("code", JsValue::from(code)),
+                    ("message", JsValue::from_str(&message)),
+                ] {
+                    let _ = js_sys::Reflect::set(&error, &JsValue::from_str(key), value);
+                }
+                error.into()
+            }
+            _ => JsValue::from_str(&format!("NorganceError: {}", err)),
+        }
     }
 }
 
@@ -156,6 +184,7 @@ fn norgance_argon2id(identifier: &str, password: &str, mode: &[u8]) -> Result<Ve
     }
 }
 
+/// The key is wiped when it's dropped, calling `free()` from javascript drops it.
 #[wasm_bindgen]
 pub struct NorganceVaultKey {
     key: orion::aead::SecretKey,
@@ -164,9 +193,11 @@ pub struct NorganceVaultKey {
 #[wasm_bindgen]
 impl NorganceVaultKey {
     pub fn derive(identifier: &str, password: &str) -> Result<NorganceVaultKey> {
-        let raw_key = norgance_argon2id(identifier, password, b"vault_key")?;
+        let mut raw_key = norgance_argon2id(identifier, password, b"vault_key")?;
 
-        let key = match orion::aead::SecretKey::from_slice(&raw_key) {
+        let key = orion::aead::SecretKey::from_slice(&raw_key);
+        raw_key.zeroize();
+        let key = match key {
             Ok(key) => key,
             Err(_) => return Err(NorganceError::VaultKeyError.into()),
         };
@@ -175,6 +206,7 @@ impl NorganceVaultKey {
     }
 }
 
+/// The key is wiped when it's dropped, calling `free()` from javascript drops it.
 #[wasm_bindgen]
 pub struct NorganceAccessKey {
     key: NorganceEd25519DalekPrivateKey,
@@ -183,9 +215,11 @@ pub struct NorganceAccessKey {
 #[wasm_bindgen]
 impl NorganceAccessKey {
     pub fn derive(identifier: &str, password: &str) -> Result<NorganceAccessKey> {
-        let raw_key = norgance_argon2id(identifier, password, b"access_key")?;
+        let mut raw_key = norgance_argon2id(identifier, password, b"access_key")?;
 
-        let key = NorganceEd25519DalekPrivateKey::from_bytes(&raw_key)?;
+        let key = NorganceEd25519DalekPrivateKey::from_bytes(&raw_key);
+        raw_key.zeroize();
+        let key = key?;
 
         Ok(NorganceAccessKey { key })
     }
@@ -198,9 +232,8 @@ impl NorganceAccessKey {
 impl NorganceAccessKey {
     pub fn to_keypair(&self) -> Result<ed25519_dalek::Keypair> {
         // ed25519_dalek::Keypair do not implement the copy trait
-        // So we do it manually from the bytes
-        let private_key_bytes = self.key.key.to_bytes();
-        let copied_private_key = match ed25519_dalek::SecretKey::from_bytes(&private_key_bytes) {
+        // So we do it manually from the bytes, without an intermediate copy
+        let copied_private_key = match ed25519_dalek::SecretKey::from_bytes(self.key.key.as_bytes()) {
             Ok(key) => key,
             Err(_) => return Err(NorganceError::KeypairError.into()),
         };
@@ -212,43 +245,73 @@ impl NorganceAccessKey {
     }
 }
 
+// The deflate block type 0b11 is reserved, so a deflate stream never starts with this byte.
+// It marks the vaults padded with an explicit length.
+const PADDED_VAULT_MARKER: u8 = 0b0000_0111;
+const VAULT_PADDING: PaddingPolicy = PaddingPolicy::PowerOfTwo { minimum: 1024 };
+
 #[wasm_bindgen]
 pub struct NorganceVault {}
 
 #[wasm_bindgen]
 impl NorganceVault {
-    pub fn open(key: &NorganceVaultKey, encrypted_data_base64: &[u8]) -> Result<String> {
+    /// The vault can't be larger than `max_size` bytes once uncompressed.
+    pub fn open(
+        key: &NorganceVaultKey,
+        encrypted_data_base64: &[u8],
+        max_size: usize,
+    ) -> Result<String> {
         let encrypted_data = match base64::decode(encrypted_data_base64) {
             Ok(bytes) => bytes,
             Err(_) => return Err(NorganceError::InvalidVault.into()),
         };
 
-        let decrypted_data = match orion::aead::open(&key.key, &encrypted_data) {
+        // The vault contains the citizen private keys, the plaintext copies are wiped
+        let mut decrypted_data = match orion::aead::open(&key.key, &encrypted_data) {
             Ok(bytes) => bytes,
             Err(_) => return Err(NorganceError::InvalidVault.into()),
         };
 
-        let uncompressed_data =
-            chatrouille::compressor::decompress(&decrypted_data).context(CompressorError)?;
+        // The older vaults are only padded with zeros after the deflate stream
+        let compressed_data = match decrypted_data.split_first() {
+            Some((&PADDED_VAULT_MARKER, padded_data)) => match padding::unpad(padded_data) {
+                Ok(compressed_data) => compressed_data,
+                Err(_) => return Err(NorganceError::InvalidVault.into()),
+            },
+            _ => &decrypted_data,
+        };
+
+        let uncompressed_data = chatrouille::compressor::decompress(compressed_data, max_size);
+        decrypted_data.zeroize();
+        let mut uncompressed_data = uncompressed_data.context(CompressorError)?;
 
-        match std::str::from_utf8(&uncompressed_data) {
+        let result = match std::str::from_utf8(&uncompressed_data) {
             Ok(r) => Ok(String::from(r)),
             Err(_) => Err(NorganceError::InvalidUTF8.into()),
-        }
+        };
+        uncompressed_data.zeroize();
+        result
     }
 
     pub fn seal(key: &NorganceVaultKey, data_string: &str) -> Result<String> {
         let mut compressed =
             chatrouille::compressor::compress(data_string.as_bytes()).context(CompressorError)?;
 
-        // To slightly improve the privacy, we pad all
-        // compressed messages with zeros to have a final size which is a multiple of 32.
-        let diff_with_32 = compressed.len() % 32;
-        if diff_with_32 != 0 {
-            compressed.append(&mut vec![0; 32 - diff_with_32]);
-        }
+        // To improve the privacy, the compressed data is padded with its length
+        let padded_data = padding::pad(&compressed, VAULT_PADDING);
+        compressed.zeroize();
+        let mut padded = match padded_data {
+            Ok(mut padded_data) => {
+                let padded = [&[PADDED_VAULT_MARKER][..], &padded_data].concat();
+                padded_data.zeroize();
+                padded
+            }
+            Err(_) => return Err(NorganceError::InvalidVault.into()),
+        };
 
-        let encrypted_data = match orion::aead::seal(&key.key, &compressed) {
+        let encrypted_data = orion::aead::seal(&key.key, &padded);
+        padded.zeroize();
+        let encrypted_data = match encrypted_data {
             Ok(bytes) => bytes,
             Err(_) => return Err(NorganceError::InvalidVault.into()),
         };
@@ -259,6 +322,68 @@ impl NorganceVault {
     }
 }
 
+/// The keys derived from a new password, with the vault sealed again with the new vault key.
+///
+/// The access key and the vault key are taken once the server accepted the change,
+/// the old ones don't work anymore.
+#[wasm_bindgen]
+pub struct NorgancePasswordChange {
+    access_key: Option<NorganceAccessKey>,
+    vault_key: Option<NorganceVaultKey>,
+    aead_data: String,
+}
+
+#[wasm_bindgen]
+impl NorgancePasswordChange {
+    pub fn derive(
+        identifier: &str,
+        new_password: &str,
+        old_vault_key: &NorganceVaultKey,
+        encrypted_data_base64: &[u8],
+        max_size: usize,
+    ) -> Result<NorgancePasswordChange> {
+        let access_key = NorganceAccessKey::derive(identifier, new_password)?;
+        let vault_key = NorganceVaultKey::derive(identifier, new_password)?;
+
+        // The plaintext never leaves the wasm memory
+        let mut data_string = NorganceVault::open(old_vault_key, encrypted_data_base64, max_size)?;
+        let aead_data = NorganceVault::seal(&vault_key, &data_string);
+        data_string.zeroize();
+        let aead_data = aead_data?;
+
+        Ok(NorgancePasswordChange {
+            access_key: Some(access_key),
+            vault_key: Some(vault_key),
+            aead_data,
+        })
+    }
+
+    pub fn get_access_key_base64(&self) -> Result<String> {
+        match &self.access_key {
+            Some(access_key) => Ok(access_key.get_public_key_base64()),
+            None => Err(NorganceError::AccessKeyError.into()),
+        }
+    }
+
+    pub fn get_aead_data(&self) -> String {
+        self.aead_data.clone()
+    }
+
+    pub fn take_access_key(&mut self) -> Result<NorganceAccessKey> {
+        match self.access_key.take() {
+            Some(access_key) => Ok(access_key),
+            None => Err(NorganceError::AccessKeyError.into()),
+        }
+    }
+
+    pub fn take_vault_key(&mut self) -> Result<NorganceVaultKey> {
+        match self.vault_key.take() {
+            Some(vault_key) => Ok(vault_key),
+            None => Err(NorganceError::VaultKeyError.into()),
+        }
+    }
+}
+
 #[must_use]
 #[wasm_bindgen]
 pub fn norgance_hibp_password_hash(password: &str, size: usize) -> String {
@@ -277,10 +402,16 @@ pub fn norgance_hibp_password_hash(password: &str, size: usize) -> String {
     hex::encode(hash)
 }
 
+// Responses larger than this once uncompressed are refused
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 4 * 1024 * 1024;
+
 #[wasm_bindgen]
 pub struct Chatrouille {
     server_public_key: x448::PublicKey,
+    server_mlkem_public_key: Option<chatrouille::mlkem::EncapsulationKey>,
+    server_signing_public_key: Option<ed25519_dalek::PublicKey>,
     client_keypair: Option<ed25519_dalek::Keypair>,
+    max_response_size: usize,
 }
 
 #[wasm_bindgen]
@@ -293,7 +424,10 @@ impl Chatrouille {
 
         Ok(Chatrouille {
             server_public_key,
+            server_mlkem_public_key: None,
+            server_signing_public_key: None,
             client_keypair: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
         })
     }
 
@@ -340,7 +474,57 @@ impl Chatrouille {
             Err(_) => return Err(NorganceError::PublicKeySignature.into()),
         };
 
-        Chatrouille::with_public_key(&server_public_key_bytes)
+        // The same key signs the responses
+        let mut chatrouille = Chatrouille::with_public_key(&server_public_key_bytes)?;
+        chatrouille.server_signing_public_key = Some(hardcoded_public_key_instance);
+        Ok(chatrouille)
+    }
+
+    /// Opts into the hybrid owl version, when the server publishes a ML-KEM key.
+    ///
+    /// The signature covers the x448 public key followed by the ML-KEM public key,
+    /// and is verified with the key given to `with_public_key_and_signature_base64`.
+    pub fn enable_hybrid_with_public_key_and_signature_base64(
+        &mut self,
+        server_mlkem_public_key: &str,
+        signature: &str,
+    ) -> Result<()> {
+        use ed25519_dalek::Verifier;
+        use std::convert::TryFrom;
+        let server_signing_public_key = match &self.server_signing_public_key {
+            Some(key) => key,
+            None => return Err(NorganceError::ChatrouilleMissingServerSigningKey.into()),
+        };
+        let server_mlkem_public_key =
+            match chatrouille::key_utils::mlkem_public_key_from_base64(server_mlkem_public_key) {
+                Some(key) => key,
+                None => return Err(NorganceError::PublicKey.into()),
+            };
+        let signature_bytes = match base64::decode(signature) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(NorganceError::PublicKeySignature.into()),
+        };
+        let signature_instance = match ed25519_dalek::Signature::try_from(&signature_bytes[..]) {
+            Ok(signature) => signature,
+            Err(_) => return Err(NorganceError::PublicKeySignature.into()),
+        };
+
+        let signed_data = [
+            self.server_public_key.as_bytes(),
+            server_mlkem_public_key.as_bytes(),
+        ]
+        .concat();
+        match server_signing_public_key.verify(&signed_data, &signature_instance) {
+            Ok(_) => (),
+            Err(_) => return Err(NorganceError::PublicKeySignature.into()),
+        };
+
+        self.server_mlkem_public_key = Some(server_mlkem_public_key);
+        Ok(())
+    }
+
+    pub fn set_max_response_size(&mut self, max_response_size: usize) {
+        self.max_response_size = max_response_size;
     }
 
     pub fn set_client_keypair(&mut self, access_key: &NorganceAccessKey) -> Result<()> {
@@ -351,55 +535,365 @@ impl Chatrouille {
     }
 
     pub fn pack_unsigned_query(&self, payload: &str) -> Result<ChatrouilleQuery> {
-        let (query, shared_secret) =
-            match chatrouille::pack_unsigned_query(payload.as_bytes(), &self.server_public_key) {
-                Ok(o) => o,
-                Err(_) => return Err(NorganceError::ChatrouillePack.into()),
-            };
+        self.unsigned_query(payload, &mut rand::thread_rng())
+    }
+
+    /// The ephemeral key and the nounce come from the seeded rng.
+    pub fn pack_unsigned_query_with_rng(
+        &self,
+        payload: &str,
+        rng: &mut NorganceRng,
+    ) -> Result<ChatrouilleQuery> {
+        self.unsigned_query(payload, &mut rng.rng)
+    }
+
+    pub fn pack_signed_query(&self, payload: &str) -> Result<ChatrouilleQuery> {
+        self.signed_query(payload, &mut rand::thread_rng())
+    }
+
+    /// The ephemeral key and the nounces come from the seeded rng.
+    pub fn pack_signed_query_with_rng(
+        &self,
+        payload: &str,
+        rng: &mut NorganceRng,
+    ) -> Result<ChatrouilleQuery> {
+        self.signed_query(payload, &mut rng.rng)
+    }
+
+    /// The GraphQL query is given as JSON, and packed in the chatrouille envelope
+    /// with its expiration timestamp.
+    pub fn pack_unsigned_graphql(
+        &self,
+        graphql: &str,
+        expiration_time: u32,
+    ) -> Result<ChatrouilleQuery> {
+        self.graphql_query(graphql, expiration_time, None, &mut rand::thread_rng())
+    }
+
+    /// Same as `pack_unsigned_graphql`, the query is signed for the citizen.
+    pub fn pack_signed_graphql(
+        &self,
+        graphql: &str,
+        expiration_time: u32,
+        citizen_identifier: &str,
+    ) -> Result<ChatrouilleQuery> {
+        self.graphql_query(
+            graphql,
+            expiration_time,
+            Some(citizen_identifier),
+            &mut rand::thread_rng(),
+        )
+    }
+
+    /// Same as `pack_unsigned_graphql`, the ephemeral key and the nounces come from the seeded rng.
+    pub fn pack_unsigned_graphql_with_rng(
+        &self,
+        graphql: &str,
+        expiration_time: u32,
+        rng: &mut NorganceRng,
+    ) -> Result<ChatrouilleQuery> {
+        self.graphql_query(graphql, expiration_time, None, &mut rng.rng)
+    }
+
+    /// Same as `pack_signed_graphql`, the ephemeral key and the nounces come from the seeded rng.
+    pub fn pack_signed_graphql_with_rng(
+        &self,
+        graphql: &str,
+        expiration_time: u32,
+        citizen_identifier: &str,
+        rng: &mut NorganceRng,
+    ) -> Result<ChatrouilleQuery> {
+        self.graphql_query(
+            graphql,
+            expiration_time,
+            Some(citizen_identifier),
+            &mut rng.rng,
+        )
+    }
+
+    /// Opens a session over a WebSocket, see `ChatrouilleSession`.
+    pub fn pack_unsigned_session(&self, expiration_time: u32) -> Result<ChatrouilleQuery> {
+        self.envelope_query(
+            SessionOpening { session: true },
+            expiration_time,
+            None,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    /// Same as `pack_unsigned_session`, the citizen is authenticated for the whole session.
+    pub fn pack_signed_session(
+        &self,
+        expiration_time: u32,
+        citizen_identifier: &str,
+    ) -> Result<ChatrouilleQuery> {
+        self.envelope_query(
+            SessionOpening { session: true },
+            expiration_time,
+            Some(citizen_identifier),
+            &mut rand::thread_rng(),
+        )
+    }
+
+    /// Same as `pack_unsigned_session`, the ephemeral key and the nounces come from the seeded rng.
+    pub fn pack_unsigned_session_with_rng(
+        &self,
+        expiration_time: u32,
+        rng: &mut NorganceRng,
+    ) -> Result<ChatrouilleQuery> {
+        self.envelope_query(
+            SessionOpening { session: true },
+            expiration_time,
+            None,
+            &mut rng.rng,
+        )
+    }
+
+    /// Same as `pack_signed_session`, the ephemeral key and the nounces come from the seeded rng.
+    pub fn pack_signed_session_with_rng(
+        &self,
+        expiration_time: u32,
+        citizen_identifier: &str,
+        rng: &mut NorganceRng,
+    ) -> Result<ChatrouilleQuery> {
+        self.envelope_query(
+            SessionOpening { session: true },
+            expiration_time,
+            Some(citizen_identifier),
+            &mut rng.rng,
+        )
+    }
+
+    /// The server accepts the session with a signed response.
+    pub fn open_session(
+        packed_data: &[u8],
+        query: &ChatrouilleQuery,
+    ) -> Result<ChatrouilleSession> {
+        let response = Chatrouille::unpack_response(packed_data, query)?;
+        if response.receipt.is_none() {
+            return Err(NorganceError::ChatrouilleResponseSignature.into());
+        }
+        let session = chatrouille::Version::from_packet(&query.query).and_then(|version| {
+            chatrouille::session::Session::client(&query.shared_secret, version).ok()
+        });
+        match session {
+            Some(session) => Ok(ChatrouilleSession {
+                session,
+                max_response_size: query.max_response_size,
+            }),
+            None => Err(NorganceError::InvalidChatrouilleSession.into()),
+        }
+    }
+
+    /// Signed responses are verified with the hardcoded server key,
+    /// and come with a receipt that can be stored as a proof.
+    ///
+    /// Once the hardcoded server key is known, the unsigned responses are refused,
+    /// except the errors, so the signature can't be stripped.
+    pub fn unpack_response(
+        packed_data: &[u8],
+        query: &ChatrouilleQuery,
+    ) -> Result<ChatrouilleResponse> {
+        let (raw_response, receipt) = match chatrouille::packet_mode(packed_data) {
+            chatrouille::Mode::SignedResponse => {
+                let server_signing_public_key = match &query.server_signing_public_key {
+                    Some(key) => key,
+                    None => return Err(NorganceError::ChatrouilleMissingServerSigningKey.into()),
+                };
+                match chatrouille::unpack_signed_response(
+                    packed_data,
+                    &query.shared_secret,
+                    &query.query_hash,
+                    server_signing_public_key,
+                    query.max_response_size,
+                ) {
+                    Ok((r, receipt)) => (r, Some(receipt)),
+                    Err(chatrouille::ChatrouilleError::VerifySignatureError { .. }) => {
+                        return Err(NorganceError::ChatrouilleResponseSignature.into())
+                    }
+                    Err(_) => return Err(NorganceError::ChatrouilleUnpack.into()),
+                }
+            }
+            mode if mode != chatrouille::Mode::Error
+                && query.server_signing_public_key.is_some() =>
+            {
+                return Err(NorganceError::ChatrouilleResponseSignature.into())
+            }
+            _ => match chatrouille::unpack_response(
+                packed_data,
+                &query.shared_secret,
+                query.max_response_size,
+            ) {
+                Ok(r) => (r, None),
+                Err(chatrouille::ChatrouilleError::ServerError { code, message }) => {
+                    return Err(NorganceError::ChatrouilleServerError { code, message }.into())
+                }
+                Err(_) => return Err(NorganceError::ChatrouilleUnpack.into()),
+            },
+        };
+        let payload = match std::str::from_utf8(&raw_response) {
+            Ok(r) => String::from(r),
+            Err(_) => return Err(NorganceError::InvalidUTF8.into()),
+        };
+        Ok(ChatrouilleResponse { payload, receipt })
+    }
+}
+
+impl Chatrouille {
+    fn unsigned_query<R: rand::RngCore + rand::CryptoRng>(
+        &self,
+        payload: &str,
+        rng: &mut R,
+    ) -> Result<ChatrouilleQuery> {
+        let packed = match &self.server_mlkem_public_key {
+            Some(server_mlkem_public_key) => chatrouille::pack_hybrid_unsigned_query_with_rng(
+                payload.as_bytes(),
+                &self.server_public_key,
+                server_mlkem_public_key,
+                Codec::default(),
+                rng,
+            ),
+            None => chatrouille::pack_unsigned_query_with_rng(
+                payload.as_bytes(),
+                &self.server_public_key,
+                Codec::default(),
+                rng,
+            ),
+        };
+        let (query, shared_secret) = match packed {
+            Ok(o) => o,
+            Err(_) => return Err(NorganceError::ChatrouillePack.into()),
+        };
 
         Ok(ChatrouilleQuery {
             query,
+            query_hash: chatrouille::query_hash(payload.as_bytes()),
             shared_secret,
+            server_signing_public_key: self.server_signing_public_key,
+            max_response_size: self.max_response_size,
         })
     }
 
-    pub fn pack_signed_query(&self, payload: &str) -> Result<ChatrouilleQuery> {
+    fn signed_query<R: rand::RngCore + rand::CryptoRng>(
+        &self,
+        payload: &str,
+        rng: &mut R,
+    ) -> Result<ChatrouilleQuery> {
         let keypair = match &self.client_keypair {
             Some(keypair) => keypair,
             None => return Err(NorganceError::ChatrouilleMissingKeypair.into()),
         };
 
-        let (query, shared_secret) = match chatrouille::pack_signed_query(
-            payload.as_bytes(),
-            &self.server_public_key,
-            keypair,
-        ) {
+        let packed = match &self.server_mlkem_public_key {
+            Some(server_mlkem_public_key) => chatrouille::pack_hybrid_signed_query_with_rng(
+                payload.as_bytes(),
+                &self.server_public_key,
+                server_mlkem_public_key,
+                keypair,
+                Codec::default(),
+                rng,
+            ),
+            None => chatrouille::pack_signed_query_with_rng(
+                payload.as_bytes(),
+                &self.server_public_key,
+                keypair,
+                Codec::default(),
+                rng,
+            ),
+        };
+        let (query, shared_secret) = match packed {
             Ok(o) => o,
             Err(_) => return Err(NorganceError::ChatrouillePack.into()),
         };
 
         Ok(ChatrouilleQuery {
             query,
+            query_hash: chatrouille::query_hash(payload.as_bytes()),
             shared_secret,
+            server_signing_public_key: self.server_signing_public_key,
+            max_response_size: self.max_response_size,
         })
     }
+}
 
-    pub fn unpack_response(packed_data: &[u8], query: &ChatrouilleQuery) -> Result<String> {
-        let raw_response = match chatrouille::unpack_response(packed_data, &query.shared_secret) {
-            Ok(r) => r,
-            Err(_) => return Err(NorganceError::ChatrouilleUnpack.into()),
+#[derive(serde::Serialize)]
+struct GraphqlQuery {
+    graphql: serde_json::Value,
+}
+
+#[derive(serde::Serialize)]
+struct SessionOpening {
+    session: bool,
+}
+
+impl Chatrouille {
+    fn graphql_query<R: rand::RngCore + rand::CryptoRng>(
+        &self,
+        graphql: &str,
+        expiration_time: u32,
+        citizen_identifier: Option<&str>,
+        rng: &mut R,
+    ) -> Result<ChatrouilleQuery> {
+        let graphql = match serde_json::from_str(graphql) {
+            Ok(graphql) => graphql,
+            Err(_) => return Err(NorganceError::InvalidJson.into()),
         };
-        match std::str::from_utf8(&raw_response) {
-            Ok(r) => Ok(String::from(r)),
-            Err(_) => Err(NorganceError::InvalidUTF8.into()),
-        }
+        self.envelope_query(
+            GraphqlQuery { graphql },
+            expiration_time,
+            citizen_identifier,
+            rng,
+        )
+    }
+
+    fn envelope_query<T: serde::Serialize, R: rand::RngCore + rand::CryptoRng>(
+        &self,
+        body: T,
+        expiration_time: u32,
+        citizen_identifier: Option<&str>,
+        rng: &mut R,
+    ) -> Result<ChatrouilleQuery> {
+        let mut envelope = Envelope::new(body, u64::from(expiration_time));
+        let client_keypair = match citizen_identifier {
+            Some(identifier) => {
+                envelope.citizen_identifier = Some(String::from(identifier));
+                match &self.client_keypair {
+                    Some(keypair) => Some(keypair),
+                    None => return Err(NorganceError::ChatrouilleMissingKeypair.into()),
+                }
+            }
+            None => None,
+        };
+
+        let query = match chatrouille::envelope::pack_json_with_rng(
+            &envelope,
+            &self.server_public_key,
+            self.server_mlkem_public_key.as_ref(),
+            client_keypair,
+            Codec::default(),
+            rng,
+        ) {
+            Ok(query) => query,
+            Err(_) => return Err(NorganceError::ChatrouillePack.into()),
+        };
+
+        Ok(ChatrouilleQuery {
+            query: query.packed_data,
+            query_hash: query.query_hash,
+            shared_secret: query.shared_secret,
+            server_signing_public_key: self.server_signing_public_key,
+            max_response_size: self.max_response_size,
+        })
     }
 }
 
 #[wasm_bindgen]
 pub struct ChatrouilleQuery {
     query: Vec<u8>,
-    shared_secret: x448::SharedSecret,
+    query_hash: Vec<u8>,
+    shared_secret: chatrouille::key_utils::SharedSecret,
+    server_signing_public_key: Option<ed25519_dalek::PublicKey>,
+    max_response_size: usize,
 }
 
 #[wasm_bindgen]
@@ -410,6 +904,61 @@ impl ChatrouilleQuery {
     }
 }
 
+#[wasm_bindgen]
+pub struct ChatrouilleResponse {
+    payload: String,
+    receipt: Option<chatrouille::ResponseReceipt>,
+}
+
+#[wasm_bindgen]
+impl ChatrouilleResponse {
+    #[must_use]
+    pub fn get_payload(&self) -> String {
+        self.payload.clone()
+    }
+
+    /// The receipt of a signed response, encoded in base64.
+    #[must_use]
+    pub fn get_receipt(&self) -> Option<String> {
+        self.receipt
+            .as_ref()
+            .map(|receipt| base64::encode_config(receipt.to_bytes(), base64::STANDARD_NO_PAD))
+    }
+}
+
+/// The frames of a session, after the opening query.
+#[wasm_bindgen]
+pub struct ChatrouilleSession {
+    session: chatrouille::session::Session,
+    max_response_size: usize,
+}
+
+#[wasm_bindgen]
+impl ChatrouilleSession {
+    pub fn seal(&mut self, payload: &str) -> Result<Box<[u8]>> {
+        match self.session.seal(
+            payload.as_bytes(),
+            PaddingPolicy::default(),
+            Codec::default(),
+        ) {
+            Ok(frame) => Ok(frame.into_boxed_slice()),
+            Err(_) => Err(NorganceError::ChatrouillePack.into()),
+        }
+    }
+
+    /// The frames must be opened in the order they were received.
+    pub fn open(&mut self, frame: &[u8]) -> Result<String> {
+        let data = match self.session.open(frame, self.max_response_size) {
+            Ok(data) => data,
+            Err(_) => return Err(NorganceError::ChatrouilleUnpack.into()),
+        };
+        match String::from_utf8(data) {
+            Ok(payload) => Ok(payload),
+            Err(_) => Err(NorganceError::InvalidUTF8.into()),
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub struct NorganceRng {
     rng: Box<rand::rngs::StdRng>,
@@ -440,18 +989,21 @@ impl NorganceRng {
         seed_hasher.update(entropy);
         seed_hasher.update(&arr);
 
-        let seed: [u8; 32] = seed_hasher
+        let mut seed: [u8; 32] = seed_hasher
             .finalize()
             .as_bytes()
             .try_into()
             .context(HashError)?;
 
         let mut rng = StdRng::from_seed(seed);
+        seed.zeroize();
 
         // Consume 1024 bytes for no good reasons.
         // Only to check that it works, and to make it
         // a bit more difficult to guess the next bytes.
-        rng.try_fill_bytes(&mut arr[..]).context(RandomError)?;
+        let filled = rng.try_fill_bytes(&mut arr[..]);
+        arr.zeroize();
+        filled.context(RandomError)?;
 
         Ok(NorganceRng { rng: Box::new(rng) })
     }
@@ -477,18 +1029,25 @@ impl NorganceX25519DalekPrivateKey {
             Err(_) => return Err(NorganceError::InvalidX25519DalekPrivateKey.into()),
         };
 
-        let bytes: Box<[u8; 32]> = match bytes.try_into() {
+        let mut bytes: Box<[u8; 32]> = match bytes.try_into() {
             Ok(bytes) => bytes,
-            Err(_) => return Err(NorganceError::InvalidX25519DalekPrivateKey.into()),
+            Err(mut bytes) => {
+                bytes.zeroize();
+                return Err(NorganceError::InvalidX25519DalekPrivateKey.into());
+            }
         };
 
         let key = x25519_dalek::StaticSecret::from(*bytes);
+        bytes.zeroize();
         Ok(NorganceX25519DalekPrivateKey { key })
     }
 
     #[must_use]
     pub fn to_base64(&self) -> String {
-        base64::encode_config(&self.key.to_bytes().to_vec(), base64::STANDARD_NO_PAD)
+        let mut bytes = self.key.to_bytes();
+        let private_key_base64 = base64::encode_config(&bytes, base64::STANDARD_NO_PAD);
+        bytes.zeroize();
+        private_key_base64
     }
 
     #[must_use]
@@ -550,17 +1109,19 @@ impl NorganceEd25519DalekPrivateKey {
     }
 
     pub fn from_base64(private_key_base64: &str) -> Result<NorganceEd25519DalekPrivateKey> {
-        let bytes = match base64::decode(private_key_base64) {
-            Ok(bytes) => bytes.into_boxed_slice(),
+        let mut bytes = match base64::decode(private_key_base64) {
+            Ok(bytes) => bytes,
             Err(_) => return Err(NorganceError::InvalidEd25519DalekPrivateKey.into()),
         };
 
-        NorganceEd25519DalekPrivateKey::from_bytes(&bytes)
+        let key = NorganceEd25519DalekPrivateKey::from_bytes(&bytes);
+        bytes.zeroize();
+        key
     }
 
     #[must_use]
     pub fn to_base64(&self) -> String {
-        base64::encode_config(&self.key.to_bytes().to_vec(), base64::STANDARD_NO_PAD)
+        base64::encode_config(self.key.as_bytes(), base64::STANDARD_NO_PAD)
     }
 
     #[must_use]
@@ -11,3 +11,46 @@ wasm_bindgen_test_configure!(run_in_browser);
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+#[wasm_bindgen_test]
+fn password_change() {
+    use norgance::{NorganceAccessKey, NorgancePasswordChange, NorganceVault, NorganceVaultKey};
+
+    const MAX_SIZE: usize = 1024;
+    let data = r#"{"name":"Citizen"}"#;
+    let old_vault_key = NorganceVaultKey::derive("citizen", "old password").unwrap();
+    let old_aead_data = NorganceVault::seal(&old_vault_key, data).unwrap();
+
+    let mut change = NorgancePasswordChange::derive(
+        "citizen",
+        "new password",
+        &old_vault_key,
+        old_aead_data.as_bytes(),
+        MAX_SIZE,
+    )
+    .unwrap();
+    let aead_data = change.get_aead_data();
+
+    // The vault opens with the key derived again from the new password, not with the old key
+    let new_vault_key = NorganceVaultKey::derive("citizen", "new password").unwrap();
+    assert_eq!(
+        NorganceVault::open(&new_vault_key, aead_data.as_bytes(), MAX_SIZE).unwrap(),
+        data
+    );
+    assert!(NorganceVault::open(&old_vault_key, aead_data.as_bytes(), MAX_SIZE).is_err());
+    assert_eq!(
+        change.get_access_key_base64().unwrap(),
+        NorganceAccessKey::derive("citizen", "new password")
+            .unwrap()
+            .get_public_key_base64()
+    );
+
+    let vault_key = change.take_vault_key().unwrap();
+    assert_eq!(
+        NorganceVault::open(&vault_key, aead_data.as_bytes(), MAX_SIZE).unwrap(),
+        data
+    );
+    change.take_access_key().unwrap();
+    assert!(change.take_vault_key().is_err());
+    assert!(change.take_access_key().is_err());
+}
@@ -2,6 +2,7 @@ import ky from 'ky';
 
 import entropy from './entropy';
 import { Chatrouille } from './rustyglue/rustyChatrouille';
+import { NorganceRng } from './rustyglue/classes';
 
 class GraphqlError extends Error {
   constructor(errors) {
@@ -11,10 +12,41 @@ class GraphqlError extends Error {
   }
 }
 
+// Encrypted error sent by the server, the code is an HTTP status code
+export class ChatrouilleServerError extends Error {
+  constructor({ code, message }) {
+    super(message);
+    this.name = this.constructor.name;
+    this.code = code;
+  }
+}
+
+function wrapServerError(error) {
+  if (error && error.name === 'ChatrouilleServerError') {
+    return new ChatrouilleServerError(error);
+  }
+  return error;
+}
+
+async function unpackResponse(packedData, query) {
+  try {
+    return await Chatrouille.unpackResponse(packedData, query);
+  } catch (error) {
+    throw wrapServerError(error);
+  }
+}
+
 let CHATROUILLE_DEBUG_MODE = process.env.VUE_APP_CHATROUILLE_DEBUG_MODE === 'true';
-const CHATROUILLE_PATH = process.env.VUE_APP_CHATROUILLE_PATH || 'http://localhost:3000/chatrouille';
-const CHATROUILLE_INFORMATION_PATH = process.env.VUE_APP_CHATROUILLE_INFORMATION_PATH || `${CHATROUILLE_PATH}_information`;
+// Through an oblivious relay, Norgance doesn't see the addresses of the citizens
+const CHATROUILLE_RELAY_PATH = process.env.VUE_APP_CHATROUILLE_RELAY_PATH;
+const CHATROUILLE_PATH = CHATROUILLE_RELAY_PATH || process.env.VUE_APP_CHATROUILLE_PATH || 'http://localhost:3000/chatrouille';
+// In relay mode, the information always goes through the relay, to check it before the first query
+const CHATROUILLE_INFORMATION_PATH = (!CHATROUILLE_RELAY_PATH && process.env.VUE_APP_CHATROUILLE_INFORMATION_PATH) || `${CHATROUILLE_PATH}_information`;
+const CHATROUILLE_SESSION_PATH = process.env.VUE_APP_CHATROUILLE_SESSION_PATH || `${CHATROUILLE_PATH.replace(/^http/, 'ws')}/ws`;
 const CHATROUILLE_HARCODED_PUBLIC_KEY = process.env.VUE_APP_CHATROUILLE_HARCODED_PUBLIC_KEY;
+// Without the hybrid post-quantum key, the queries fall back to x448 only
+const CHATROUILLE_REQUIRE_HYBRID = process.env.VUE_APP_CHATROUILLE_REQUIRE_HYBRID === 'true';
+const CHATROUILLE_HYBRID_PIN_KEY = 'chatrouilleHybridPinned';
 
 window.enableChatrouilleDebug = () => {
   CHATROUILLE_DEBUG_MODE = true;
@@ -23,8 +55,18 @@ if (!CHATROUILLE_DEBUG_MODE) {
   console.info('Run enableChatrouilleDebug() to see the network exchanges.');
 }
 
+// The relays add this header. The information is loaded before any query,
+// so a wrong relay path fails before the first query reaches Norgance,
+// which only sees the address in the request of the information.
+function checkRelay(response) {
+  if (CHATROUILLE_RELAY_PATH && !response.headers.has('Norgance-Relay')) {
+    throw new Error('The response didn\'t go through the oblivious relay');
+  }
+}
+
 async function loadChatrouilleInformation() {
   const response = await ky.get(CHATROUILLE_INFORMATION_PATH);
+  checkRelay(response);
   return response.json();
 }
 
@@ -41,12 +83,51 @@ async function buildChatrouilleInstance() {
     information.public_key_x448_signature,
     CHATROUILLE_HARCODED_PUBLIC_KEY,
   );
+  // Servers with a ML-KEM key support the hybrid post-quantum version.
+  // Once required or seen, the hybrid key is pinned, so the information
+  // can't be stripped of it by someone in the middle.
+  const hybridPinned = window.localStorage.getItem(CHATROUILLE_HYBRID_PIN_KEY) === 'true';
+  if (information.public_key_mlkem768) {
+    await instance.enableHybridWithPublicKeyAndSignatureBase64(
+      information.public_key_mlkem768,
+      information.public_key_mlkem768_signature,
+    );
+    window.localStorage.setItem(CHATROUILLE_HYBRID_PIN_KEY, 'true');
+  } else if (CHATROUILLE_REQUIRE_HYBRID || hybridPinned) {
+    instance.free();
+    instance = undefined;
+    throw new Error('The chatrouille server information has no hybrid post-quantum key');
+  }
   serverTimeDiff = information.time - Math.ceil(+new Date() / 1000);
   instanceBuildingPromise = undefined;
   entropyInstance.ping();
 }
 instanceBuildingPromise = buildChatrouilleInstance();
 
+function graphqlData(jsonResponse) {
+  if (jsonResponse.errors && Array.isArray(jsonResponse.errors) && jsonResponse.errors.length > 0) {
+    throw new GraphqlError(jsonResponse.errors);
+  }
+
+  const data = jsonResponse.data;
+  const dataEntries = Object.entries(data);
+  if (dataEntries.length === 1) {
+    return dataEntries[0][1];
+  }
+  return data;
+}
+
+// The ephemeral keys and the nounces of the queries come from a rng
+// seeded with the user entropy, freed once the query is packed
+async function packWithSeededRng(entropyInstance, pack) {
+  const rng = await NorganceRng.fromEntropy(entropyInstance);
+  try {
+    return await pack(rng);
+  } finally {
+    rng.free();
+  }
+}
+
 export async function anonymousGraphql(graphql) {
   if (CHATROUILLE_DEBUG_MODE) {
     console.info('Chatrouille query', graphql);
@@ -60,38 +141,173 @@ export async function anonymousGraphql(graphql) {
   entropyInstance.ping(); // Ping before processing
 
   const exp = Math.ceil(+new Date() / 1000) + serverTimeDiff + MAX_SERVER_DIFF;
-  const payload = JSON.stringify({
-    graphql,
-    exp,
-  });
-  const query = await instance.packUnsignedQuery(payload);
-  let decoded;
+  const query = await packWithSeededRng(
+    entropyInstance,
+    (rng) => instance.packUnsignedGraphql(JSON.stringify(graphql), exp, rng),
+  );
+  let response;
   try {
     entropyInstance.ping(); // Ping after processing
-    const response = await ky.post(CHATROUILLE_PATH, {
+    const httpResponse = await ky.post(CHATROUILLE_PATH, {
       body: query.query,
     });
     entropyInstance.ping(); // Ping after response
-    const responseBody = await response.arrayBuffer();
-    decoded = await Chatrouille.unpackResponse(new Uint8Array(responseBody), query);
+    checkRelay(httpResponse);
+    const responseBody = await httpResponse.arrayBuffer();
+    response = await unpackResponse(new Uint8Array(responseBody), query);
   } finally {
     query.free();
   }
-  const jsonResponse = JSON.parse(decoded);
+  const jsonResponse = JSON.parse(response.payload);
   entropyInstance.ping(); // Ping after response processing
   if (CHATROUILLE_DEBUG_MODE) {
-    console.info('Chatrouille response', jsonResponse);
+    console.info('Chatrouille response', jsonResponse, 'receipt', response.receipt);
   }
-  if (jsonResponse.errors && Array.isArray(jsonResponse.errors) && jsonResponse.errors.length > 0) {
-    throw new GraphqlError(jsonResponse.errors);
+  return graphqlData(jsonResponse);
+}
+
+// A session over a WebSocket, opened with a single chatrouille query.
+// The GraphQL subscriptions are only available in a session.
+export async function openChatrouilleSession() {
+  if (instanceBuildingPromise) {
+    await instanceBuildingPromise;
   }
 
-  const data = jsonResponse.data;
-  const dataEntries = Object.entries(data);
-  if (dataEntries.length === 1) {
-    return dataEntries[0][1];
+  const entropyInstance = entropy();
+  entropyInstance.ping();
+
+  const exp = Math.ceil(+new Date() / 1000) + serverTimeDiff + MAX_SERVER_DIFF;
+  const query = await packWithSeededRng(
+    entropyInstance,
+    (rng) => instance.packUnsignedSession(exp, rng),
+  );
+  const socket = new WebSocket(CHATROUILLE_SESSION_PATH);
+  socket.binaryType = 'arraybuffer';
+
+  let session;
+  try {
+    session = await new Promise((resolve, reject) => {
+      socket.onopen = () => socket.send(query.query);
+      socket.onclose = () => reject(new Error('The chatrouille session is closed'));
+      socket.onmessage = (event) => {
+        Chatrouille.openSession(new Uint8Array(event.data), query)
+          .then(resolve, (error) => reject(wrapServerError(error)));
+      };
+    });
+  } catch (error) {
+    socket.close();
+    throw error;
+  } finally {
+    query.free();
   }
-  return data;
+
+  let lastId = 0;
+  const pendingQueries = new Map();
+  const subscriptions = new Map();
+
+  // The first failure ends the session, the chains of frames can't skip a frame
+  let failure;
+  const fail = (error) => {
+    if (failure) {
+      return;
+    }
+    failure = error;
+    pendingQueries.forEach(({ reject }) => reject(error));
+    pendingQueries.clear();
+    subscriptions.clear();
+    socket.close();
+  };
+
+  // The frames are sealed and opened in order, one at a time
+  let sealing = Promise.resolve();
+  let opening = Promise.resolve();
+  const send = (frame) => {
+    if (CHATROUILLE_DEBUG_MODE) {
+      console.info('Chatrouille session frame', frame);
+    }
+    const sent = sealing.then(async () => {
+      if (failure) {
+        throw failure;
+      }
+      socket.send(await session.seal(JSON.stringify(frame)));
+    });
+    sealing = sent.catch(fail);
+    return sent;
+  };
+
+  const receive = (frame) => {
+    if (CHATROUILLE_DEBUG_MODE) {
+      console.info('Chatrouille session frame', frame);
+    }
+    const pendingQuery = pendingQueries.get(frame.id);
+    if (pendingQuery) {
+      pendingQueries.delete(frame.id);
+      if (frame.type === 'data') {
+        try {
+          pendingQuery.resolve(graphqlData(frame.payload));
+        } catch (error) {
+          pendingQuery.reject(error);
+        }
+      } else {
+        pendingQuery.reject(new Error(frame.payload ? frame.payload.message : frame.type));
+      }
+      return;
+    }
+    const subscription = subscriptions.get(frame.id);
+    if (subscription) {
+      if (frame.type === 'data') {
+        subscription(frame.payload);
+      } else {
+        subscriptions.delete(frame.id);
+      }
+    }
+  };
+
+  socket.onmessage = (event) => {
+    const frame = new Uint8Array(event.data);
+    opening = opening
+      .then(async () => {
+        if (!failure) {
+          receive(JSON.parse(await session.open(frame)));
+        }
+      })
+      .catch(fail);
+  };
+  socket.onclose = () => {
+    fail(new Error('The chatrouille session is closed'));
+    // The frames being sealed or opened still use the session
+    Promise.all([sealing, opening]).then(() => session.free());
+  };
+
+  return {
+    query(graphql) {
+      lastId += 1;
+      const id = `${lastId}`;
+      return new Promise((resolve, reject) => {
+        pendingQueries.set(id, { resolve, reject });
+        send({ id, type: 'query', graphql }).catch((error) => {
+          pendingQueries.delete(id);
+          reject(error);
+        });
+      });
+    },
+    // The callback receives the GraphQL responses, until the returned stop function is called
+    subscribe(graphql, callback) {
+      lastId += 1;
+      const id = `${lastId}`;
+      subscriptions.set(id, callback);
+      // A failure to send ends the session, and the subscription with it
+      send({ id, type: 'subscribe', graphql }).catch(() => {});
+      return () => {
+        if (subscriptions.delete(id)) {
+          send({ id, type: 'stop' }).catch(() => {});
+        }
+      };
+    },
+    close() {
+      socket.close();
+    },
+  };
 }
 
 export async function authenticatedQuery(/* graphql, citizenIdentifier, citizenPrivateKey */) {
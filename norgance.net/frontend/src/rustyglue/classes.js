@@ -1,9 +1,13 @@
 /* eslint-disable no-underscore-dangle */
 /* eslint-disable max-classes-per-file */
 import RustClass from './rustClass';
-import { Chatrouille, ChatrouilleQuery } from './rustyChatrouille';
+import {
+  Chatrouille, ChatrouilleQuery, ChatrouilleResponse, ChatrouilleSession,
+} from './rustyChatrouille';
 
-export { Chatrouille, ChatrouilleQuery };
+export {
+  Chatrouille, ChatrouilleQuery, ChatrouilleResponse, ChatrouilleSession,
+};
 
 export class NorganceAccessKey extends RustClass {
   static className = 'NorganceAccessKey';
@@ -45,6 +49,36 @@ export class NorganceVault extends RustClass {
   }
 }
 
+export class NorgancePasswordChange extends RustClass {
+  static className = 'NorgancePasswordChange';
+
+  static async derive(identifier, newPassword, oldVaultKey, encryptedDataBase64, maxSize) {
+    return this._callStatic('derive', {
+      args: [identifier, newPassword, oldVaultKey, encryptedDataBase64, maxSize],
+    });
+  }
+
+  async getAccessKeyBase64() {
+    return this._call('get_access_key_base64');
+  }
+
+  async getAeadData() {
+    return this._call('get_aead_data');
+  }
+
+  async takeAccessKey() {
+    return this._call('take_access_key', {
+      returnClassName: 'NorganceAccessKey',
+    });
+  }
+
+  async takeVaultKey() {
+    return this._call('take_vault_key', {
+      returnClassName: 'NorganceVaultKey',
+    });
+  }
+}
+
 export class NorganceRng extends RustClass {
   static className = 'NorganceRng';
 
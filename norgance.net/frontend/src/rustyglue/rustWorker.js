@@ -99,10 +99,13 @@ self.addEventListener('message', async (event) => {
           } else if (value && value.buffer instanceof ArrayBuffer) {
             buffer = value.buffer;
           }
-          if (!transferables) {
-            transferables = [buffer];
-          } else {
-            transferables.push(buffer);
+          // Strings and other values are copied
+          if (buffer) {
+            if (!transferables) {
+              transferables = [buffer];
+            } else {
+              transferables.push(buffer);
+            }
           }
           finalObject[key] = value;
         });
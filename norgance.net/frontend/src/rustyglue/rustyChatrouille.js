@@ -6,6 +6,28 @@ export class ChatrouilleQuery extends RustClass {
   static className = 'ChatrouilleQuery';
 }
 
+export class ChatrouilleResponse extends RustClass {
+  static className = 'ChatrouilleResponse';
+}
+
+// The frames must be sealed and opened in order
+export class ChatrouilleSession extends RustClass {
+  static className = 'ChatrouilleSession';
+
+  async seal(payload) {
+    return this._call('seal', {
+      args: [payload],
+    });
+  }
+
+  async open(frame) {
+    return this._call('open', {
+      args: [frame],
+      transfer: [frame.buffer],
+    });
+  }
+}
+
 export class Chatrouille extends RustClass {
   static className = 'Chatrouille';
 
@@ -28,15 +50,24 @@ export class Chatrouille extends RustClass {
     });
   }
 
+  // Opts into the hybrid post-quantum version, the signature is verified
+  // with the hardcoded public key given to withPublicKeyAndSignatureBase64
+  async enableHybridWithPublicKeyAndSignatureBase64(serverMlKemPublicKey, signature) {
+    return this._call('enable_hybrid_with_public_key_and_signature_base64', {
+      args: [serverMlKemPublicKey, signature],
+    });
+  }
+
   async setClientAccessKey(accessKey) {
     return this._call('set_client_access_key', {
       args: [accessKey],
     });
   }
 
-  async packUnsignedQuery(payload) {
-    return this._call('pack_unsigned_query', {
-      args: [payload],
+  // The rng is optional, a NorganceRng seeded with the user entropy
+  async packUnsignedQuery(payload, rng) {
+    return this._call(rng ? 'pack_unsigned_query_with_rng' : 'pack_unsigned_query', {
+      args: rng ? [payload, rng] : [payload],
       preload: {
         query: { functionName: 'get_query' },
       },
@@ -44,9 +75,10 @@ export class Chatrouille extends RustClass {
     });
   }
 
-  async packSignedQuery(payload) {
-    return this._call('pack_signed_query', {
-      args: [payload],
+  // The rng is optional, a NorganceRng seeded with the user entropy
+  async packSignedQuery(payload, rng) {
+    return this._call(rng ? 'pack_signed_query_with_rng' : 'pack_signed_query', {
+      args: rng ? [payload, rng] : [payload],
       preload: {
         query: { functionName: 'get_query' },
       },
@@ -54,10 +86,69 @@ export class Chatrouille extends RustClass {
     });
   }
 
+  // The GraphQL query is packed in the chatrouille envelope with its expiration time.
+  // The rng is optional, a NorganceRng seeded with the user entropy
+  async packUnsignedGraphql(graphql, exp, rng) {
+    return this._call(rng ? 'pack_unsigned_graphql_with_rng' : 'pack_unsigned_graphql', {
+      args: rng ? [graphql, exp, rng] : [graphql, exp],
+      preload: {
+        query: { functionName: 'get_query' },
+      },
+      returnClassName: 'ChatrouilleQuery',
+    });
+  }
+
+  // The citizen identifier is authenticated by the signature of the client access key
+  async packSignedGraphql(graphql, exp, citizenIdentifier, rng) {
+    return this._call(rng ? 'pack_signed_graphql_with_rng' : 'pack_signed_graphql', {
+      args: rng ? [graphql, exp, citizenIdentifier, rng] : [graphql, exp, citizenIdentifier],
+      preload: {
+        query: { functionName: 'get_query' },
+      },
+      returnClassName: 'ChatrouilleQuery',
+    });
+  }
+
+  // The first query of a session over a WebSocket
+  async packUnsignedSession(exp, rng) {
+    return this._call(rng ? 'pack_unsigned_session_with_rng' : 'pack_unsigned_session', {
+      args: rng ? [exp, rng] : [exp],
+      preload: {
+        query: { functionName: 'get_query' },
+      },
+      returnClassName: 'ChatrouilleQuery',
+    });
+  }
+
+  // The citizen is authenticated once for the whole session
+  async packSignedSession(exp, citizenIdentifier, rng) {
+    return this._call(rng ? 'pack_signed_session_with_rng' : 'pack_signed_session', {
+      args: rng ? [exp, citizenIdentifier, rng] : [exp, citizenIdentifier],
+      preload: {
+        query: { functionName: 'get_query' },
+      },
+      returnClassName: 'ChatrouilleQuery',
+    });
+  }
+
+  static async openSession(packedData, query) {
+    return this._callStatic('open_session', {
+      args: [packedData, query],
+      transfer: [packedData.buffer],
+      returnClassName: 'ChatrouilleSession',
+    });
+  }
+
   static async unpackResponse(packedData, query) {
     return this._callStatic('unpack_response', {
       args: [packedData, query],
       transfer: [packedData.buffer],
+      preload: {
+        payload: { functionName: 'get_payload' },
+        receipt: { functionName: 'get_receipt' },
+      },
+      freeResponseImmediately: true,
+      returnClassName: 'ChatrouilleResponse',
     });
   }
 }
@@ -43,6 +43,14 @@ export default {
       commit('reset');
       commit('progress', 'started');
 
+      // The rust objects holding keys are freed whatever happens,
+      // freeing them wipes the keys from the wasm memory
+      const instances = [];
+      const keep = (instance) => {
+        instances.push(instance);
+        return instance;
+      };
+
       try {
         const application = rootState.citizenApplication;
         const {
@@ -62,18 +70,18 @@ export default {
         const entropyInstance = entropy();
         commit('progress', 'accessKey');
         entropyInstance.ping();
-        const accessKey = await NorganceAccessKey.derive(identifier, password);
+        const accessKey = keep(await NorganceAccessKey.derive(identifier, password));
         commit('progress', 'symmetricKey');
         entropyInstance.ping();
-        const vaultKey = await NorganceVaultKey.derive(identifier, password);
+        const vaultKey = keep(await NorganceVaultKey.derive(identifier, password));
 
         commit('progress', 'asymmetricKeys');
         entropyInstance.ping();
-        const rng = await NorganceRng.fromEntropy(entropyInstance);
-        const x25519PrivateKey = await NorganceX25519DalekPrivateKey.fromRng(rng);
-        const x25519PublicKey = await x25519PrivateKey.getPublicKey();
-        const ed25519PrivateKey = await NorganceEd25519DalekPrivateKey.fromRng(rng);
-        const ed25519PublicKey = await ed25519PrivateKey.getPublicKey();
+        const rng = keep(await NorganceRng.fromEntropy(entropyInstance));
+        const x25519PrivateKey = keep(await NorganceX25519DalekPrivateKey.fromRng(rng));
+        const x25519PublicKey = keep(await x25519PrivateKey.getPublicKey());
+        const ed25519PrivateKey = keep(await NorganceEd25519DalekPrivateKey.fromRng(rng));
+        const ed25519PublicKey = keep(await ed25519PrivateKey.getPublicKey());
 
         commit('progress', 'registering');
 
@@ -97,32 +105,21 @@ export default {
         entropyInstance.ping();
         console.log(identity, identifierHash, accessKey, registration);
 
-        try {
-          const toto = await anonymousGraphql({
-            operationName: 'registerCitizenship',
-            variables: {
-              registration,
-            },
-            query: 'mutation registerCitizenship($registration: CitizenRegistration!) { registerCitizenship(registration: $registration) { success } }',
-          });
-          commit('done');
-          console.log(toto);
-        } finally {
-          // Cleaning
-          await Promise.all([
-            rng.free(),
-            // symmetricKey.free(),
-            vaultKey.free(),
-            accessKey.free(),
-            x25519PrivateKey.free(),
-            x25519PublicKey.free(),
-            ed25519PrivateKey.free(),
-            ed25519PublicKey.free(),
-          ]);
-        }
+        const toto = await anonymousGraphql({
+          operationName: 'registerCitizenship',
+          variables: {
+            registration,
+          },
+          query: 'mutation registerCitizenship($registration: CitizenRegistration!) { registerCitizenship(registration: $registration) { success } }',
+        });
+        commit('done');
+        console.log(toto);
       } catch (error) {
         commit('progress', 'error');
         console.error(error);
+      } finally {
+        // Cleaning
+        await Promise.all(instances.map((instance) => instance.free()));
       }
     },
   },